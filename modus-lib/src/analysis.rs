@@ -31,14 +31,13 @@ use std::iter;
 use std::ops::Range;
 
 use codespan_reporting::diagnostic::{Diagnostic, Label, Severity};
-use codespan_reporting::files::Files;
-use codespan_reporting::term::{self, Config};
+use codespan_reporting::term::Config;
 use petgraph::algo::find_negative_cycle;
 
-use crate::builtin::{select_builtin, OPERATOR_KIND_MAP};
+use crate::builtin::{select_builtin, LIST_BUILTINS, OPERATOR_KIND_MAP};
 use crate::logic::{self, Literal, Predicate, SpannedPosition};
 use crate::modusfile::{Expression, ModusClause, Operator};
-use crate::modusfile::{ModusTerm, Modusfile};
+use crate::modusfile::{ModusTerm, Modusfile, ModusfileSources, BUILD_ARG_PREDICATE};
 use crate::translate::translate_modusfile;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -739,6 +738,9 @@ fn term_check(mf: &Modusfile) -> Result<(), Vec<Diagnostic<()>>> {
     }
 
     fn body_term_check(body_lit: &Literal<ModusTerm>) -> Vec<Diagnostic<()>> {
+        if LIST_BUILTINS.contains(&body_lit.predicate.0.as_str()) {
+            return Vec::new();
+        }
         body_lit
             .args
             .iter()
@@ -753,19 +755,32 @@ fn term_check(mf: &Modusfile) -> Result<(), Vec<Diagnostic<()>>> {
         let allowed_list_ops = vec![
             Predicate("set_entrypoint".into()),
             Predicate("set_cmd".into()),
+            Predicate("exclude".into()),
+            Predicate("set_healthcheck".into()),
+            Predicate("cache_mount".into()),
+            Predicate("set_shell".into()),
         ];
 
+        let mut diags = Vec::new();
+        if let Err(msg) = op.positional_args() {
+            let mut diag = Diagnostic::error().with_message(msg);
+            if let Some(pos) = &op.position {
+                diag = diag.with_labels(vec![Label::primary((), Range::from(pos))]);
+            }
+            diags.push(diag);
+        }
         if !allowed_list_ops.contains(&op.predicate) {
-            op.args
-                .iter()
-                .filter_map(|arg| match arg {
-                    ModusTerm::List(position, _) => Some(generate_list_diag(position)),
-                    _ => None,
-                })
-                .collect()
-        } else {
-            Vec::new()
+            diags.extend(
+                op.args
+                    .iter()
+                    .chain(op.keyword_args.iter().map(|kw| &kw.value))
+                    .filter_map(|arg| match arg {
+                        ModusTerm::List(position, _) => Some(generate_list_diag(position)),
+                        _ => None,
+                    }),
+            );
         }
+        diags
     }
 
     let mut diags = Vec::new();
@@ -797,23 +812,160 @@ fn term_check(mf: &Modusfile) -> Result<(), Vec<Diagnostic<()>>> {
     }
 }
 
+/// Checks that format strings passed to intrinsics requiring a constant only interpolate
+/// variables that something else in the clause could ground.
+///
+/// A variable can be grounded if it appears in the head, or as an argument of a positive
+/// body literal in a position that does not itself require a constant.
+fn check_format_string_groundness(mf: &Modusfile) -> Vec<Diagnostic<()>> {
+    fn intrinsic_groundness(lit: &Literal<ModusTerm>) -> Option<&'static [bool]> {
+        let mock_lit = Literal {
+            positive: true,
+            position: None,
+            predicate: lit.predicate.clone(),
+            args: lit
+                .args
+                .iter()
+                .map(|_| logic::IRTerm::Constant("".to_string()))
+                .collect(),
+        };
+        match select_builtin(&mock_lit) {
+            (_, Some(builtin)) if builtin.kind() != Kind::Logic => Some(builtin.arg_groundness()),
+            _ => None,
+        }
+    }
+
+    let mut diags = Vec::new();
+
+    for modus_clause in &mf.0 {
+        let body_lits = modus_clause
+            .body
+            .as_ref()
+            .map(|b| b.literals())
+            .unwrap_or_default();
+
+        let mut groundable: HashSet<&str> = modus_clause
+            .head
+            .args
+            .iter()
+            .flat_map(|arg| arg.variable_strings())
+            .collect();
+        for lit in body_lits.iter().filter(|lit| lit.positive) {
+            let groundness = intrinsic_groundness(lit);
+            for (i, arg) in lit.args.iter().enumerate() {
+                if groundness.is_none_or(|g| g[i]) {
+                    groundable.extend(arg.variable_strings());
+                }
+            }
+        }
+
+        for lit in body_lits.iter().filter(|lit| lit.positive) {
+            let groundness = match intrinsic_groundness(lit) {
+                Some(g) => g,
+                None => continue,
+            };
+            for (arg, _) in lit.args.iter().zip(groundness).filter(|(_, &g)| !g) {
+                if let ModusTerm::FormatString { position, .. } = arg {
+                    let mut ungroundable = arg
+                        .variable_strings()
+                        .into_iter()
+                        .filter(|v| !groundable.contains(v))
+                        .collect::<Vec<_>>();
+                    ungroundable.sort_unstable();
+                    ungroundable.dedup();
+                    if !ungroundable.is_empty() {
+                        diags.push(
+                            Diagnostic::error()
+                                .with_message(format!(
+                                    "This format string can never be grounded, but {} requires a constant here.",
+                                    lit.predicate
+                                ))
+                                .with_labels(vec![Label::primary(
+                                    (),
+                                    position.offset..position.offset + position.length,
+                                )])
+                                .with_notes(vec![format!(
+                                    "{} not appear in the head or in any other literal that could ground {}.",
+                                    if ungroundable.len() == 1 {
+                                        format!("{} does", ungroundable[0])
+                                    } else {
+                                        format!("{} do", ungroundable.join(", "))
+                                    },
+                                    if ungroundable.len() == 1 { "it" } else { "them" }
+                                )]),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    diags
+}
+
+/// Checks that every build argument read with a constant name, as in `arg("VERSION", V)`,
+/// has a value, either from an `arg` fact giving its default or from `--build-arg`.
+fn check_build_args(mf: &Modusfile) -> Vec<Diagnostic<()>> {
+    let mut defined = HashSet::new();
+    for clause in mf.0.iter() {
+        if clause.head.predicate.0 != BUILD_ARG_PREDICATE {
+            continue;
+        }
+        match clause.head.args.first() {
+            Some(ModusTerm::Constant(name)) => {
+                defined.insert(name.as_str());
+            }
+            // A rule could give any argument a value.
+            _ => return Vec::new(),
+        }
+    }
+
+    let mut diags = Vec::new();
+    for clause in mf.0.iter() {
+        let body_lits = clause
+            .body
+            .as_ref()
+            .map(|b| b.literals())
+            .unwrap_or_default();
+        for lit in body_lits {
+            match &lit.args[..] {
+                [ModusTerm::Constant(name), _]
+                    if lit.predicate.0 == BUILD_ARG_PREDICATE
+                        && !defined.contains(name.as_str()) =>
+                {
+                    let mut diag = Diagnostic::error()
+                        .with_message(format!("Build argument {:?} has no value.", name))
+                        .with_notes(vec![format!(
+                            "Pass one with --build-arg {}=VALUE, or give it a default with a fact such as arg({:?}, \"VALUE\").",
+                            name, name
+                        )]);
+                    if let Some(pos) = &lit.position {
+                        diag = diag.with_labels(vec![Label::primary((), Range::from(pos))]);
+                    }
+                    diags.push(diag);
+                }
+                _ => {}
+            }
+        }
+    }
+    diags
+}
+
 /// Returns true if the results of the check were satisfactory; we don't need to terminate.
-pub fn check_and_output_analysis<
-    'files,
-    W: Write + codespan_reporting::term::termcolor::WriteColor,
-    F: Files<'files, FileId = ()>,
->(
+pub fn check_and_output_analysis<W: Write + codespan_reporting::term::termcolor::WriteColor>(
     kind_res: &KindResult,
     mf: &Modusfile,
     goal: Option<&Expression>,
     verbose: bool,
     out: &mut W,
     config: &Config,
-    file: &'files F,
+    sources: &ModusfileSources,
 ) -> bool {
     if verbose {
         for msg in &kind_res.messages {
-            term::emit(out, config, file, &msg).expect("Error when writing to stderr.");
+            sources
+                .emit(out, config, msg)
+                .expect("Error when writing to stderr.");
         }
     }
 
@@ -836,14 +988,21 @@ pub fn check_and_output_analysis<
         Vec::new()
     };
 
+    let format_string_errors = check_format_string_groundness(&mf);
+    let build_arg_errors = check_build_args(&mf);
+
     let errs = kind_res
         .errs
         .iter()
         .chain(&negation_errors)
         .chain(&term_errors)
+        .chain(&format_string_errors)
+        .chain(&build_arg_errors)
         .collect::<Vec<_>>();
     for err in &errs {
-        term::emit(out, config, file, err).expect("Error when writing to stderr.");
+        sources
+            .emit(out, config, err)
+            .expect("Error when writing to stderr.");
     }
 
     let is_stratifiable = mf.stratifiable();
@@ -857,7 +1016,9 @@ pub fn check_and_output_analysis<
         let diag = Diagnostic::error()
             .with_message("Program is not stratifiable. Recursive dependency on negation found.")
             .with_notes(vec![path_string]);
-        term::emit(out, config, file, &diag).expect("Error when writing to stderr.");
+        sources
+            .emit(out, config, &diag)
+            .expect("Error when writing to stderr.");
         return false;
     }
 
@@ -1116,6 +1277,68 @@ mod tests {
         assert_eq!(1 + 2, res.err().unwrap().len());
     }
 
+    #[test]
+    fn lists_only_allowed_in_list_builtins() {
+        let mf: Modusfile = "pkgs(R) :- list_difference([\"curl\", \"vim\"], [\"vim\"], R)."
+            .parse()
+            .unwrap();
+        assert!(term_check(&mf).is_ok());
+
+        let mf: Modusfile = "pkgs :- foo([\"curl\", \"vim\"]).".parse().unwrap();
+        assert!(term_check(&mf).is_err());
+    }
+
+    #[test]
+    fn errors_unknown_operator_keyword() {
+        let mf: Modusfile = "a :- b::set_env(key: \"A\", value: \"1\")."
+            .parse()
+            .unwrap();
+        assert!(term_check(&mf).is_ok());
+
+        let mf: Modusfile = "a :- b::set_env(\"A\", val: \"1\").".parse().unwrap();
+        assert!(term_check(&mf).is_err());
+    }
+
+    #[test]
+    fn errors_ungroundable_f_string_in_intrinsic() {
+        let src = "a :- from(\"alpine\"), run(f\"echo ${X}\").";
+        let mf: Modusfile = src.parse().unwrap();
+
+        let diags = check_format_string_groundness(&mf);
+        assert_eq!(1, diags.len());
+        assert_eq!(diags[0].severity, Severity::Error);
+        let f_string_start = src.find("f\"").unwrap();
+        assert_eq!(
+            f_string_start..f_string_start + "f\"echo ${X}\"".len(),
+            diags[0].labels[0].range
+        );
+
+        let clauses = [
+            "b(X) :- from(\"alpine\"), run(f\"echo ${X}\").",
+            "c :- from(\"alpine\"), version(X), run(f\"echo ${X}\").",
+        ];
+        let mf: Modusfile = clauses.join("\n").parse().unwrap();
+        assert!(check_format_string_groundness(&mf).is_empty());
+    }
+
+    #[test]
+    fn errors_build_arg_without_value() {
+        let src = "a :- from(\"alpine\"), arg(\"VERSION\", V), run(V).";
+        let mut mf: Modusfile = src.parse().unwrap();
+
+        let diags = check_build_args(&mf);
+        assert_eq!(1, diags.len());
+        assert_eq!(diags[0].severity, Severity::Error);
+        let arg_start = src.find("arg(").unwrap();
+        assert_eq!(
+            arg_start..arg_start + "arg(\"VERSION\", V)".len(),
+            diags[0].labels[0].range
+        );
+
+        mf.set_build_args(&[("VERSION".to_owned(), "1.0".to_owned())]);
+        assert!(check_build_args(&mf).is_empty());
+    }
+
     #[test]
     fn kind_errors_with_unknown_operator() {
         let clauses = vec!["head :- bar::foobar(X, Y), lar.", "lar."];
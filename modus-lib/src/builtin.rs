@@ -15,6 +15,8 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
 
 use crate::{
     analysis::Kind,
@@ -83,6 +85,12 @@ pub trait BuiltinPredicate {
     /// they must all be either auxillary or some existing variables from the
     /// input.
     fn apply(&self, lit: &Literal) -> Option<Literal>;
+
+    /// Explains why `apply` fails on the literal, for builtins that reject some constant
+    /// arguments, such as an invalid port. This is shown along with the failure.
+    fn failure_reason(&self, _lit: &Literal) -> Option<String> {
+        None
+    }
 }
 
 mod string_concat {
@@ -180,15 +188,14 @@ mod string_concat {
     }
 }
 
-mod equality {
-    use crate::logic::{IRTerm, Literal, Predicate};
-
+mod string {
     use super::BuiltinPredicate;
+    use crate::logic::{IRTerm, Literal, Predicate};
 
-    pub struct StringEq1;
-    impl BuiltinPredicate for StringEq1 {
+    pub struct CommonPrefix;
+    impl BuiltinPredicate for CommonPrefix {
         fn name(&self) -> &'static str {
-            "string_eq"
+            "common_prefix"
         }
 
         fn kind(&self) -> crate::analysis::Kind {
@@ -196,27 +203,38 @@ mod equality {
         }
 
         fn arg_groundness(&self) -> &'static [bool] {
-            &[false, true]
+            &[false, false, true]
         }
 
-        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+        /// Binds the third argument to the longest shared leading substring of the first two.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
             let a = lit.args[0].as_constant()?;
+            let b = lit.args[1].as_constant()?;
+            // Compare by chars so the prefix always ends on a char boundary.
+            let prefix_len = a
+                .char_indices()
+                .zip(b.chars())
+                .take_while(|((_, ca), cb)| ca == cb)
+                .last()
+                .map(|((i, c), _)| i + c.len_utf8())
+                .unwrap_or(0);
             Some(Literal {
                 positive: true,
                 position: lit.position.clone(),
-                predicate: Predicate("string_eq".to_owned()),
+                predicate: Predicate("common_prefix".to_owned()),
                 args: vec![
                     IRTerm::Constant(a.to_owned()),
-                    IRTerm::Constant(a.to_owned()),
+                    IRTerm::Constant(b.to_owned()),
+                    IRTerm::Constant(a[..prefix_len].to_owned()),
                 ],
             })
         }
     }
 
-    pub struct StringEq2;
-    impl BuiltinPredicate for StringEq2 {
+    pub struct IsAscii;
+    impl BuiltinPredicate for IsAscii {
         fn name(&self) -> &'static str {
-            "string_eq"
+            "is_ascii"
         }
 
         fn kind(&self) -> crate::analysis::Kind {
@@ -224,34 +242,188 @@ mod equality {
         }
 
         fn arg_groundness(&self) -> &'static [bool] {
-            &[true, false]
+            &[false]
         }
 
-        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
-            let b = lit.args[1].as_constant()?;
+        /// Succeeds only if the argument contains nothing but ASCII characters.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            if lit.args[0].as_constant()?.is_ascii() {
+                Some(lit.clone())
+            } else {
+                None
+            }
+        }
+    }
+
+    pub struct StringLength;
+    impl BuiltinPredicate for StringLength {
+        fn name(&self) -> &'static str {
+            "string_length"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the number of characters (not bytes) in the first.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let s = lit.args[0].as_constant()?;
+            let length = s.chars().count().to_string();
+            if let IRTerm::Constant(expected) = &lit.args[1] {
+                if expected != &length {
+                    return None;
+                }
+            }
             Some(Literal {
                 positive: true,
                 position: lit.position.clone(),
-                predicate: Predicate("string_eq".to_owned()),
+                predicate: Predicate("string_length".to_owned()),
+                args: vec![IRTerm::Constant(s.to_owned()), IRTerm::Constant(length)],
+            })
+        }
+    }
+
+    pub struct StringSplit;
+    impl BuiltinPredicate for StringSplit {
+        fn name(&self) -> &'static str {
+            "string_split"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false, true]
+        }
+
+        /// Binds the third argument to the list of pieces of the first argument, split on the
+        /// second. An empty separator splits the input into its characters.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let s = lit.args[0].as_constant()?;
+            let sep = lit.args[1].as_constant()?;
+            let pieces = if sep.is_empty() {
+                s.chars().map(|c| IRTerm::Constant(c.to_string())).collect()
+            } else {
+                s.split(sep)
+                    .map(|piece| IRTerm::Constant(piece.to_owned()))
+                    .collect()
+            };
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("string_split".to_owned()),
                 args: vec![
-                    IRTerm::Constant(b.to_owned()),
-                    IRTerm::Constant(b.to_owned()),
+                    IRTerm::Constant(s.to_owned()),
+                    IRTerm::Constant(sep.to_owned()),
+                    IRTerm::List(pieces),
                 ],
             })
         }
     }
-}
 
-mod number {
-    use super::BuiltinPredicate;
+    pub struct StringToLower;
+    impl BuiltinPredicate for StringToLower {
+        fn name(&self) -> &'static str {
+            "string_to_lower"
+        }
 
-    macro_rules! define_number_comparison {
-        ($name:ident, $cond:expr) => {
-            #[allow(non_camel_case_types)]
-            pub struct $name;
-            impl BuiltinPredicate for $name {
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the first in lowercase.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let s = lit.args[0].as_constant()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("string_to_lower".to_owned()),
+                args: vec![
+                    IRTerm::Constant(s.to_owned()),
+                    IRTerm::Constant(s.to_lowercase()),
+                ],
+            })
+        }
+    }
+
+    pub struct Slugify;
+    impl BuiltinPredicate for Slugify {
+        fn name(&self) -> &'static str {
+            "slugify"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the first in lowercase, with every run of characters
+        /// other than ASCII letters and digits replaced by a single `-`, and no `-` at either
+        /// end. For example, `feature/New_UI` becomes `feature-new-ui`.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let s = lit.args[0].as_constant()?;
+            let slug = s
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join("-")
+                .to_ascii_lowercase();
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("slugify".to_owned()),
+                args: vec![IRTerm::Constant(s.to_owned()), IRTerm::Constant(slug)],
+            })
+        }
+    }
+
+    pub struct StringToUpper;
+    impl BuiltinPredicate for StringToUpper {
+        fn name(&self) -> &'static str {
+            "string_to_upper"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the first in uppercase.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let s = lit.args[0].as_constant()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("string_to_upper".to_owned()),
+                args: vec![
+                    IRTerm::Constant(s.to_owned()),
+                    IRTerm::Constant(s.to_uppercase()),
+                ],
+            })
+        }
+    }
+
+    macro_rules! define_string_trim {
+        ($struct_name:ident, $name:literal, $trim:expr) => {
+            pub struct $struct_name;
+            impl BuiltinPredicate for $struct_name {
                 fn name(&self) -> &'static str {
-                    stringify!($name)
+                    $name
                 }
 
                 fn kind(&self) -> crate::analysis::Kind {
@@ -259,51 +431,104 @@ mod number {
                 }
 
                 fn arg_groundness(&self) -> &'static [bool] {
-                    &[false, false]
+                    &[false, true]
                 }
 
-                /// Parses and checks that arg1 > arg2.
-                fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
-                    let a: f64 = lit.args[0].as_constant().and_then(|s| s.parse().ok())?;
-                    let b: f64 = lit.args[1].as_constant().and_then(|s| s.parse().ok())?;
-                    if $cond(a, b) {
-                        Some(lit.clone())
-                    } else {
-                        None
-                    }
+                /// Binds the second argument to the first with whitespace trimmed.
+                fn apply(&self, lit: &Literal) -> Option<Literal> {
+                    let s = lit.args[0].as_constant()?;
+                    Some(Literal {
+                        positive: true,
+                        position: lit.position.clone(),
+                        predicate: Predicate($name.to_owned()),
+                        args: vec![
+                            IRTerm::Constant(s.to_owned()),
+                            IRTerm::Constant($trim(s).to_owned()),
+                        ],
+                    })
                 }
             }
         };
     }
 
-    define_number_comparison!(number_eq, |a, b| a == b);
-    define_number_comparison!(number_gt, |a, b| a > b);
-    define_number_comparison!(number_lt, |a, b| a < b);
-    define_number_comparison!(number_geq, |a, b| a >= b);
-    define_number_comparison!(number_leq, |a, b| a <= b);
-}
+    define_string_trim!(StringTrim, "string_trim", str::trim);
+    define_string_trim!(StringTrimStart, "string_trim_start", str::trim_start);
+    define_string_trim!(StringTrimEnd, "string_trim_end", str::trim_end);
 
-mod semver {
-    use super::BuiltinPredicate;
-    use semver::{Comparator, Op, Version};
+    pub struct StringReplace;
+    impl BuiltinPredicate for StringReplace {
+        fn name(&self) -> &'static str {
+            "string_replace"
+        }
 
-    fn parse_partial_version(s: &str) -> Option<Version> {
-        if let Ok(v) = Version::parse(s) {
-            return Some(v);
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
         }
-        let mut s = String::from(s);
-        s.push_str(".0");
-        if let Ok(v) = Version::parse(&s) {
-            return Some(v);
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false, false, true]
         }
-        s.push_str(".0");
-        if let Ok(v) = Version::parse(&s) {
-            return Some(v);
+
+        /// Binds the fourth argument to the first with every occurrence of the second replaced
+        /// by the third. An empty pattern leaves the input unchanged.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let s = lit.args[0].as_constant()?;
+            let from = lit.args[1].as_constant()?;
+            let to = lit.args[2].as_constant()?;
+            let replaced = if from.is_empty() {
+                s.to_owned()
+            } else {
+                s.replace(from, to)
+            };
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("string_replace".to_owned()),
+                args: vec![
+                    IRTerm::Constant(s.to_owned()),
+                    IRTerm::Constant(from.to_owned()),
+                    IRTerm::Constant(to.to_owned()),
+                    IRTerm::Constant(replaced),
+                ],
+            })
         }
-        return None;
     }
 
-    macro_rules! define_semver_comparison {
+    pub struct DockerfileEscape;
+    impl BuiltinPredicate for DockerfileEscape {
+        fn name(&self) -> &'static str {
+            "dockerfile_escape"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the first, quoted the same way the transpiler
+        /// quotes values in ENV, LABEL and RUN instructions.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let raw = lit.args[0].as_constant()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("dockerfile_escape".to_owned()),
+                args: vec![
+                    IRTerm::Constant(raw.to_owned()),
+                    IRTerm::Constant(crate::dockerfile::escape_value(raw)),
+                ],
+            })
+        }
+    }
+}
+
+mod substring {
+    use super::BuiltinPredicate;
+
+    macro_rules! define_substring_relation {
         ($name:ident, $cond:expr) => {
             #[allow(non_camel_case_types)]
             pub struct $name;
@@ -320,15 +545,11 @@ mod semver {
                     &[false, false]
                 }
 
-                /// Parses and checks that arg1 > arg2.
+                /// Checks that the first argument relates to the second as the name says.
                 fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
-                    let a: Version = lit.args[0]
-                        .as_constant()
-                        .and_then(|s| parse_partial_version(s))?;
-                    let b: Comparator = lit.args[1]
-                        .as_constant()
-                        .and_then(|s| Comparator::parse(&format!("{}{}", $cond, s)).ok())?;
-                    if b.matches(&a) {
+                    let s: &str = lit.args[0].as_constant()?;
+                    let sub: &str = lit.args[1].as_constant()?;
+                    if $cond(s, sub) {
                         Some(lit.clone())
                     } else {
                         None
@@ -338,317 +559,4327 @@ mod semver {
         };
     }
 
-    define_semver_comparison!(semver_exact, "=");
-    define_semver_comparison!(semver_gt, ">");
-    define_semver_comparison!(semver_lt, "<");
-    define_semver_comparison!(semver_geq, ">=");
-    define_semver_comparison!(semver_leq, "<=");
+    define_substring_relation!(string_contains, |s: &str, sub| s.contains(sub));
+    define_substring_relation!(string_prefix, |s: &str, sub| s.starts_with(sub));
+    define_substring_relation!(string_suffix, |s: &str, sub| s.ends_with(sub));
 }
 
-macro_rules! intrinsic_predicate {
-    ($name:ident, $kind:expr, $($arg_groundness:expr),*) => {
-        #[allow(non_camel_case_types)]
-        pub struct $name;
-        impl BuiltinPredicate for $name {
-            fn name(&self) -> &'static str {
-                stringify!($name)
-            }
+mod list {
+    use std::collections::HashSet;
 
-            fn kind(&self) -> Kind {
-                $kind
-            }
+    use regex::Regex;
 
-            fn arg_groundness(&self) -> &'static [bool] {
-                &[$($arg_groundness),*]
-            }
+    use super::BuiltinPredicate;
+    use crate::logic::{IRTerm, Literal, Predicate};
 
-            fn apply(&self, lit: &Literal) -> Option<Literal> {
-                Some(lit.clone())
-            }
+    fn as_list(term: &IRTerm) -> Option<&[IRTerm]> {
+        match term {
+            IRTerm::List(ts) => Some(ts),
+            _ => None,
         }
-    };
-}
+    }
 
-intrinsic_predicate!(run, crate::analysis::Kind::Layer, false);
-intrinsic_predicate!(from, crate::analysis::Kind::Image, false);
-intrinsic_predicate!(
-    _operator_copy_begin,
-    crate::analysis::Kind::Image,
-    false,
-    false,
-    false
-);
-intrinsic_predicate!(
-    _operator_copy_end,
-    crate::analysis::Kind::Image,
-    false,
-    false,
-    false
+    pub struct ListDifference;
+    impl BuiltinPredicate for ListDifference {
+        fn name(&self) -> &'static str {
+            "list_difference"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false, true]
+        }
+
+        /// Binds the third argument to the elements of the first list that are not in the
+        /// second, in their original order.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let a = as_list(&lit.args[0])?;
+            let b = as_list(&lit.args[1])?.iter().collect::<HashSet<_>>();
+            let difference = a.iter().filter(|t| !b.contains(t)).cloned().collect();
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("list_difference".to_owned()),
+                args: vec![
+                    lit.args[0].clone(),
+                    lit.args[1].clone(),
+                    IRTerm::List(difference),
+                ],
+            })
+        }
+    }
+
+    pub struct ListLength;
+    impl BuiltinPredicate for ListLength {
+        fn name(&self) -> &'static str {
+            "list_length"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the number of elements in the list.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let length = as_list(&lit.args[0])?.len().to_string();
+            if let IRTerm::Constant(expected) = &lit.args[1] {
+                if expected != &length {
+                    return None;
+                }
+            }
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("list_length".to_owned()),
+                args: vec![lit.args[0].clone(), IRTerm::Constant(length)],
+            })
+        }
+    }
+
+    pub struct ListMember;
+    impl BuiltinPredicate for ListMember {
+        fn name(&self) -> &'static str {
+            "list_member"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false]
+        }
+
+        /// Succeeds if the second argument is an element of the list.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            if as_list(&lit.args[0])?.contains(&lit.args[1]) {
+                Some(lit.clone())
+            } else {
+                None
+            }
+        }
+    }
+
+    pub struct Lookup;
+    impl BuiltinPredicate for Lookup {
+        fn name(&self) -> &'static str {
+            "lookup"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false, true]
+        }
+
+        /// Binds the third argument to the value of the first `[key, value]` pair in the
+        /// list whose key is the first argument. Fails if there is no such pair.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let value = as_list(&lit.args[1])?
+                .iter()
+                .find_map(|pair| match as_list(pair)? {
+                    [k, v] if k == &lit.args[0] => Some(v.clone()),
+                    _ => None,
+                })?;
+            if lit.args[2].is_constant_or_compound_constant() && lit.args[2] != value {
+                return None;
+            }
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("lookup".to_owned()),
+                args: vec![lit.args[0].clone(), lit.args[1].clone(), value],
+            })
+        }
+    }
+
+    pub struct AllMatch;
+    impl BuiltinPredicate for AllMatch {
+        fn name(&self) -> &'static str {
+            "all_match"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false]
+        }
+
+        /// Succeeds if every element of the list is a string matched by the regex in the second
+        /// argument. The regex is not anchored, so use `^` and `$` to match whole elements.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let pattern = Regex::new(lit.args[1].as_constant()?).ok()?;
+            let all_match = as_list(&lit.args[0])?.iter().all(|t| {
+                t.as_constant()
+                    .map(|s| pattern.is_match(s))
+                    .unwrap_or(false)
+            });
+            if all_match {
+                Some(lit.clone())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+pub(crate) mod path {
+    use std::collections::HashSet;
+    use std::path::{Component, Path, PathBuf};
+
+    use super::BuiltinPredicate;
+    use crate::logic::{IRTerm, Literal, Predicate};
+
+    /// Joins `path` onto `base` the way a container working directory is applied, so an
+    /// absolute `path` replaces `base` entirely.
+    pub fn join_path(base: &str, path: &str) -> String {
+        match Path::new(base).join(path).to_str() {
+            Some(s) => s.to_owned(),
+            None => panic!("Path containing invalid utf-8 are not allowed."),
+        }
+    }
+
+    /// Resolves `.` and `..` components without touching the filesystem.
+    /// Leading `..` components of a relative path are kept, and `..` at the root is dropped.
+    pub fn normalize_lexically(path: &Path) -> PathBuf {
+        let mut res = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => match res.components().next_back() {
+                    Some(Component::Normal(_)) => {
+                        res.pop();
+                    }
+                    Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                    _ => res.push(".."),
+                },
+                c => res.push(c),
+            }
+        }
+        res
+    }
+
+    pub struct PathWithin;
+    impl BuiltinPredicate for PathWithin {
+        fn name(&self) -> &'static str {
+            "path_within"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false]
+        }
+
+        /// Succeeds if the first path is the second path or somewhere under it, after both
+        /// are normalized lexically.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let child = normalize_lexically(Path::new(lit.args[0].as_constant()?));
+            let parent = normalize_lexically(Path::new(lit.args[1].as_constant()?));
+            match child.strip_prefix(&parent) {
+                // A relative parent may be empty after normalization, so make sure the rest
+                // doesn't climb out of it.
+                Ok(rest) if !rest.starts_with("..") => Some(lit.clone()),
+                _ => None,
+            }
+        }
+    }
+
+    pub struct ContainerPath;
+    impl BuiltinPredicate for ContainerPath {
+        fn name(&self) -> &'static str {
+            "container_path"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false, true]
+        }
+
+        /// Binds the third argument to the path the second argument refers to when used from
+        /// the working directory given by the first, i.e. the destination `copy` would use.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let workdir = lit.args[0].as_constant()?;
+            let rel = lit.args[1].as_constant()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("container_path".to_owned()),
+                args: vec![
+                    IRTerm::Constant(workdir.to_owned()),
+                    IRTerm::Constant(rel.to_owned()),
+                    IRTerm::Constant(join_path(workdir, rel)),
+                ],
+            })
+        }
+    }
+
+    pub struct NoPathCollision;
+    impl BuiltinPredicate for NoPathCollision {
+        fn name(&self) -> &'static str {
+            "no_path_collision"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false]
+        }
+
+        /// Succeeds if the paths in the list all have different base names, so copying
+        /// them into one directory won't overwrite any of them. Paths without a base
+        /// name, such as `/` or `..`, fail.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let paths = match &lit.args[0] {
+                IRTerm::List(ts) => ts,
+                _ => return None,
+            };
+            let mut seen = HashSet::new();
+            for path in paths {
+                let basename = Path::new(path.as_constant()?).file_name()?;
+                if !seen.insert(basename) {
+                    return None;
+                }
+            }
+            Some(lit.clone())
+        }
+    }
+}
+
+pub(crate) mod json {
+    use serde_json::{Map, Value};
+
+    use super::BuiltinPredicate;
+    use crate::logic::{IRTerm, Literal, Predicate};
+
+    pub struct ToJson;
+    impl BuiltinPredicate for ToJson {
+        fn name(&self) -> &'static str {
+            "to_json"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to a compact JSON object with the `[Key, Value]` lists of
+        /// the first as its members, with sorted keys. A value that is `"true"`, `"false"` or a
+        /// plain number, such as `"8080"` or `"-1.5"`, becomes that boolean or number. Any other
+        /// value is a string, including numbers with an exponent or extra zeros like `"1e3"`
+        /// or `"007"`. Fails on duplicate keys.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let pairs = match &lit.args[0] {
+                IRTerm::List(pairs) => pairs,
+                _ => return None,
+            };
+            let mut object = Map::new();
+            for pair in pairs {
+                let (key, value) = match pair {
+                    IRTerm::List(kv) if kv.len() == 2 => {
+                        (kv[0].as_constant()?, kv[1].as_constant()?)
+                    }
+                    _ => return None,
+                };
+                let value = match serde_json::from_str::<Value>(value) {
+                    Ok(v @ (Value::Bool(_) | Value::Number(_)))
+                        if serde_json::to_string(&v).ok().as_deref() == Some(value) =>
+                    {
+                        v
+                    }
+                    _ => Value::String(value.to_owned()),
+                };
+                if object.insert(key.to_owned(), value).is_some() {
+                    return None;
+                }
+            }
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("to_json".to_owned()),
+                args: vec![
+                    lit.args[0].clone(),
+                    IRTerm::Constant(Value::Object(object).to_string()),
+                ],
+            })
+        }
+    }
+
+    /// Converts a JSON value into nested lists, see [`FromJson`].
+    pub(crate) fn json_to_term(value: Value) -> IRTerm {
+        match value {
+            Value::Object(object) => IRTerm::List(
+                object
+                    .into_iter()
+                    .map(|(k, v)| IRTerm::List(vec![IRTerm::Constant(k), json_to_term(v)]))
+                    .collect(),
+            ),
+            Value::Array(values) => IRTerm::List(values.into_iter().map(json_to_term).collect()),
+            Value::String(s) => IRTerm::Constant(s),
+            v => IRTerm::Constant(v.to_string()),
+        }
+    }
+
+    pub struct FromJson;
+    impl BuiltinPredicate for FromJson {
+        fn name(&self) -> &'static str {
+            "from_json"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the JSON in the first as nested lists: an object is a
+        /// list of `[Key, Value]` lists, sorted by key, and an array a list of its values.
+        /// A string becomes its content, and numbers, booleans and `null` their JSON text, such
+        /// as `"8080"` or `"true"`. Fails if the first argument is not valid JSON.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let value = serde_json::from_str::<Value>(lit.args[0].as_constant()?).ok()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("from_json".to_owned()),
+                args: vec![lit.args[0].clone(), json_to_term(value)],
+            })
+        }
+    }
+}
+
+mod equality {
+    use crate::logic::{IRTerm, Literal, Predicate};
+
+    use super::BuiltinPredicate;
+
+    pub struct StringEq1;
+    impl BuiltinPredicate for StringEq1 {
+        fn name(&self) -> &'static str {
+            "string_eq"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let a = lit.args[0].as_constant()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("string_eq".to_owned()),
+                args: vec![
+                    IRTerm::Constant(a.to_owned()),
+                    IRTerm::Constant(a.to_owned()),
+                ],
+            })
+        }
+    }
+
+    pub struct StringEq2;
+    impl BuiltinPredicate for StringEq2 {
+        fn name(&self) -> &'static str {
+            "string_eq"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[true, false]
+        }
+
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let b = lit.args[1].as_constant()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("string_eq".to_owned()),
+                args: vec![
+                    IRTerm::Constant(b.to_owned()),
+                    IRTerm::Constant(b.to_owned()),
+                ],
+            })
+        }
+    }
+}
+
+mod number {
+    use super::BuiltinPredicate;
+
+    macro_rules! define_number_comparison {
+        ($name:ident, $cond:expr) => {
+            #[allow(non_camel_case_types)]
+            pub struct $name;
+            impl BuiltinPredicate for $name {
+                fn name(&self) -> &'static str {
+                    stringify!($name)
+                }
+
+                fn kind(&self) -> crate::analysis::Kind {
+                    crate::analysis::Kind::Logic
+                }
+
+                fn arg_groundness(&self) -> &'static [bool] {
+                    &[false, false]
+                }
+
+                /// Parses both arguments as numbers and checks that they satisfy the comparison.
+                fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+                    let a: f64 = lit.args[0].as_constant().and_then(|s| s.parse().ok())?;
+                    let b: f64 = lit.args[1].as_constant().and_then(|s| s.parse().ok())?;
+                    if $cond(a, b) {
+                        Some(lit.clone())
+                    } else {
+                        None
+                    }
+                }
+            }
+        };
+    }
+
+    define_number_comparison!(number_eq, |a, b| a == b);
+    define_number_comparison!(number_gt, |a, b| a > b);
+    define_number_comparison!(number_lt, |a, b| a < b);
+    define_number_comparison!(number_geq, |a, b| a >= b);
+    define_number_comparison!(number_leq, |a, b| a <= b);
+
+    macro_rules! define_number_operation {
+        ($name:ident, $op:expr) => {
+            #[allow(non_camel_case_types)]
+            pub struct $name;
+            impl BuiltinPredicate for $name {
+                fn name(&self) -> &'static str {
+                    stringify!($name)
+                }
+
+                fn kind(&self) -> crate::analysis::Kind {
+                    crate::analysis::Kind::Logic
+                }
+
+                fn arg_groundness(&self) -> &'static [bool] {
+                    &[false, false, true]
+                }
+
+                /// Parses the first two arguments as numbers and binds the third to the result
+                /// of the operation. Fails if the result is not a finite number.
+                fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+                    let a: f64 = lit.args[0].as_constant().and_then(|s| s.parse().ok())?;
+                    let b: f64 = lit.args[1].as_constant().and_then(|s| s.parse().ok())?;
+                    let res: f64 = $op(a, b)?;
+                    if !res.is_finite() {
+                        return None;
+                    }
+                    Some(crate::logic::Literal {
+                        positive: true,
+                        position: lit.position.clone(),
+                        predicate: crate::logic::Predicate(stringify!($name).to_owned()),
+                        args: vec![
+                            lit.args[0].clone(),
+                            lit.args[1].clone(),
+                            // Adding 0 turns -0 into 0. Display omits the ".0" of integral values.
+                            crate::logic::IRTerm::Constant((res + 0.0).to_string()),
+                        ],
+                    })
+                }
+            }
+        };
+    }
+
+    define_number_operation!(number_add, |a, b| Some(a + b));
+    define_number_operation!(number_sub, |a, b| Some(a - b));
+    define_number_operation!(number_mul, |a, b| Some(a * b));
+    define_number_operation!(number_div, |a, b| if b == 0.0 { None } else { Some(a / b) });
+
+    pub struct IsInteger;
+    impl BuiltinPredicate for IsInteger {
+        fn name(&self) -> &'static str {
+            "is_integer"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false]
+        }
+
+        /// Succeeds if the argument is an optionally signed sequence of decimal digits, of any
+        /// size. Numbers with a fractional part or exponent, even `1.0`, are not integers.
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let s = lit.args[0].as_constant()?;
+            let digits = s.strip_prefix(|c| c == '+' || c == '-').unwrap_or(s);
+            if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+                Some(lit.clone())
+            } else {
+                None
+            }
+        }
+    }
+
+    const BINARY_UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+    /// Formats a byte count with the largest binary unit it reaches, keeping one decimal
+    /// place when it isn't zero, e.g. `1.5 GiB`.
+    fn format_bytes(n: u64) -> String {
+        if n < 1024 {
+            return format!("{} B", n);
+        }
+        let mut unit = 0;
+        let mut value = n as f64 / 1024.0;
+        // Round first, so that 1048575 becomes "1 MiB" and not "1024 KiB".
+        while (value * 10.0).round() / 10.0 >= 1024.0 && unit + 1 < BINARY_UNITS.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+        let value = format!("{:.1}", value);
+        let value = value.strip_suffix(".0").unwrap_or(&value);
+        format!("{} {}", value, BINARY_UNITS[unit])
+    }
+
+    /// Parses a size such as `512`, `10K`, `1.5 GiB` or `2MB`. `K`, `M`, `G` and `T` are
+    /// powers of 1000, while `Ki`, `Mi`, `Gi` and `Ti` are powers of 1024; either may be
+    /// followed by `B`. Returns None if the size is not a whole number of bytes.
+    fn parse_bytes(s: &str) -> Option<u64> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (value, suffix) = s.split_at(split);
+        let suffix = suffix.trim_start();
+        let suffix = suffix.strip_suffix('B').unwrap_or(suffix);
+        let multiplier: u64 = match suffix {
+            "" => 1,
+            "K" => 1000,
+            "M" => 1000u64.pow(2),
+            "G" => 1000u64.pow(3),
+            "T" => 1000u64.pow(4),
+            "Ki" => 1 << 10,
+            "Mi" => 1 << 20,
+            "Gi" => 1 << 30,
+            "Ti" => 1 << 40,
+            _ => return None,
+        };
+        if value.is_empty() {
+            return None;
+        }
+        let bytes = value.parse::<f64>().ok()? * multiplier as f64;
+        if bytes.fract() != 0.0 || bytes >= u64::MAX as f64 {
+            return None;
+        }
+        Some(bytes as u64)
+    }
+
+    pub struct FormatBytes;
+    impl BuiltinPredicate for FormatBytes {
+        fn name(&self) -> &'static str {
+            "format_bytes"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the byte count in the first, a non-negative integer,
+        /// written with a binary unit.
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let n: u64 = lit.args[0].as_constant()?.parse().ok()?;
+            let human = format_bytes(n);
+            if let crate::logic::IRTerm::Constant(expected) = &lit.args[1] {
+                if expected != &human {
+                    return None;
+                }
+            }
+            Some(crate::logic::Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: crate::logic::Predicate("format_bytes".to_owned()),
+                args: vec![lit.args[0].clone(), crate::logic::IRTerm::Constant(human)],
+            })
+        }
+    }
+
+    pub struct ParseBytes;
+    impl BuiltinPredicate for ParseBytes {
+        fn name(&self) -> &'static str {
+            "parse_bytes"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the number of bytes in the size given by the first.
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let n = parse_bytes(lit.args[0].as_constant()?)?.to_string();
+            if let crate::logic::IRTerm::Constant(expected) = &lit.args[1] {
+                if expected != &n {
+                    return None;
+                }
+            }
+            Some(crate::logic::Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: crate::logic::Predicate("parse_bytes".to_owned()),
+                args: vec![lit.args[0].clone(), crate::logic::IRTerm::Constant(n)],
+            })
+        }
+    }
+}
+
+mod semver {
+    use super::BuiltinPredicate;
+    use semver::{Comparator, Op, Version, VersionReq};
+    use std::cmp::Ordering;
+
+    fn parse_partial_version(s: &str) -> Option<Version> {
+        if let Ok(v) = Version::parse(s) {
+            return Some(v);
+        }
+        let mut s = String::from(s);
+        s.push_str(".0");
+        if let Ok(v) = Version::parse(&s) {
+            return Some(v);
+        }
+        s.push_str(".0");
+        if let Ok(v) = Version::parse(&s) {
+            return Some(v);
+        }
+        return None;
+    }
+
+    macro_rules! define_semver_comparison {
+        ($name:ident, $cond:expr) => {
+            #[allow(non_camel_case_types)]
+            pub struct $name;
+            impl BuiltinPredicate for $name {
+                fn name(&self) -> &'static str {
+                    stringify!($name)
+                }
+
+                fn kind(&self) -> crate::analysis::Kind {
+                    crate::analysis::Kind::Logic
+                }
+
+                fn arg_groundness(&self) -> &'static [bool] {
+                    &[false, false]
+                }
+
+                /// Parses and checks that arg1 > arg2.
+                fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+                    let a: Version = lit.args[0]
+                        .as_constant()
+                        .and_then(|s| parse_partial_version(s))?;
+                    let b: Comparator = lit.args[1]
+                        .as_constant()
+                        .and_then(|s| Comparator::parse(&format!("{}{}", $cond, s)).ok())?;
+                    if b.matches(&a) {
+                        Some(lit.clone())
+                    } else {
+                        None
+                    }
+                }
+            }
+        };
+    }
+
+    define_semver_comparison!(semver_exact, "=");
+    define_semver_comparison!(semver_gt, ">");
+    define_semver_comparison!(semver_lt, "<");
+    define_semver_comparison!(semver_geq, ">=");
+    define_semver_comparison!(semver_leq, "<=");
+
+    pub struct SemverMatches;
+    impl BuiltinPredicate for SemverMatches {
+        fn name(&self) -> &'static str {
+            "semver_matches"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false]
+        }
+
+        /// Checks that the version in arg1 satisfies the range in arg2, such as
+        /// `">=1.2.0, <2.0.0"`. Missing minor or patch components of the version default to 0.
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let version = lit.args[0].as_constant().and_then(parse_partial_version)?;
+            let range = lit.args[1]
+                .as_constant()
+                .and_then(|s| VersionReq::parse(s).ok())?;
+            if range.matches(&version) {
+                Some(lit.clone())
+            } else {
+                None
+            }
+        }
+    }
+
+    pub struct VersionCompatible;
+    impl BuiltinPredicate for VersionCompatible {
+        fn name(&self) -> &'static str {
+            "version_compatible"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false, false]
+        }
+
+        /// Checks that the installed version in arg1 can be used where the version in arg2 is
+        /// required, under the policy in arg3: `"exact"` needs the same version, `"minor"` the
+        /// same major and minor version and `"major"` the same major version, and in both of
+        /// those the installed version must not be older than the required one. Build metadata
+        /// is ignored.
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let installed = lit.args[0].as_constant().and_then(parse_partial_version)?;
+            let required = lit.args[1].as_constant().and_then(parse_partial_version)?;
+            let precedence = |v: &Version| (v.major, v.minor, v.patch, v.pre.clone());
+            let compatible = match lit.args[2].as_constant()? {
+                "exact" => precedence(&installed) == precedence(&required),
+                "minor" => {
+                    installed.major == required.major
+                        && installed.minor == required.minor
+                        && precedence(&installed) >= precedence(&required)
+                }
+                "major" => {
+                    installed.major == required.major
+                        && precedence(&installed) >= precedence(&required)
+                }
+                _ => return None,
+            };
+            if compatible {
+                Some(lit.clone())
+            } else {
+                None
+            }
+        }
+    }
+
+    pub struct VersionBumpKind;
+    impl BuiltinPredicate for VersionBumpKind {
+        fn name(&self) -> &'static str {
+            "version_bump_kind"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false, true]
+        }
+
+        /// Binds arg3 to `"major"`, `"minor"`, `"patch"` or `"none"`, the most significant
+        /// component that changed going from the version in arg1 to the version in arg2. A change
+        /// of only the pre-release counts as `"patch"`, and build metadata is ignored. Fails if
+        /// arg2 is older than arg1.
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let old = lit.args[0].as_constant().and_then(parse_partial_version)?;
+            let new = lit.args[1].as_constant().and_then(parse_partial_version)?;
+            let precedence = |v: &Version| (v.major, v.minor, v.patch, v.pre.clone());
+            if precedence(&new) < precedence(&old) {
+                return None;
+            }
+            let kind = if new.major != old.major {
+                "major"
+            } else if new.minor != old.minor {
+                "minor"
+            } else if new.patch != old.patch || new.pre != old.pre {
+                "patch"
+            } else {
+                "none"
+            };
+            Some(crate::logic::Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: crate::logic::Predicate("version_bump_kind".to_owned()),
+                args: vec![
+                    lit.args[0].clone(),
+                    lit.args[1].clone(),
+                    crate::logic::IRTerm::Constant(kind.to_owned()),
+                ],
+            })
+        }
+    }
+
+    pub struct VersionInSeries;
+    impl BuiltinPredicate for VersionInSeries {
+        fn name(&self) -> &'static str {
+            "version_in_series"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false]
+        }
+
+        /// Checks that the version in arg1 belongs to the series in arg2, such as `"11"` or
+        /// `"11.0"`, i.e. that the series' components are the first components of the version.
+        /// So `"11"` contains `11.0.2` but not `110.0.0`. Pre-releases belong to their series.
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let v = lit.args[0].as_constant().and_then(parse_partial_version)?;
+            let series = lit.args[1]
+                .as_constant()?
+                .split('.')
+                .map(|c| {
+                    if !c.is_empty() && c.chars().all(|c| c.is_ascii_digit()) {
+                        c.parse::<u64>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Option<Vec<_>>>()?;
+            if series.len() > 3 {
+                return None;
+            }
+            if series
+                .iter()
+                .zip([v.major, v.minor, v.patch])
+                .all(|(a, b)| *a == b)
+            {
+                Some(lit.clone())
+            } else {
+                None
+            }
+        }
+    }
+
+    pub struct VersionTagSeries;
+    impl BuiltinPredicate for VersionTagSeries {
+        fn name(&self) -> &'static str {
+            "version_tag_series"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the rolling tags a release is usually published
+        /// under, e.g. `["1", "1.2", "1.2.3"]` for `1.2.3`. A pre-release only gets its full
+        /// version, since the shorter tags should keep pointing at the latest stable release.
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            use crate::logic::IRTerm;
+
+            let v = Version::parse(lit.args[0].as_constant()?).ok()?;
+            let tags = if v.pre.is_empty() {
+                vec![
+                    v.major.to_string(),
+                    format!("{}.{}", v.major, v.minor),
+                    format!("{}.{}.{}", v.major, v.minor, v.patch),
+                ]
+            } else {
+                vec![format!("{}.{}.{}-{}", v.major, v.minor, v.patch, v.pre)]
+            };
+            Some(crate::logic::Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: crate::logic::Predicate("version_tag_series".to_owned()),
+                args: vec![
+                    lit.args[0].clone(),
+                    IRTerm::List(tags.into_iter().map(IRTerm::Constant).collect()),
+                ],
+            })
+        }
+    }
+
+    /// Compares two strings in natural order, where runs of digits compare as numbers, so
+    /// `"rc2"` comes before `"rc10"`.
+    fn natural_cmp(a: &str, b: &str) -> Ordering {
+        fn chunks(s: &str) -> Vec<&str> {
+            let mut chunks = Vec::new();
+            let mut start = 0;
+            for (i, c) in s.char_indices().skip(1) {
+                let prev = s[..i].chars().last().unwrap();
+                if prev.is_ascii_digit() != c.is_ascii_digit() {
+                    chunks.push(&s[start..i]);
+                    start = i;
+                }
+            }
+            if !s.is_empty() {
+                chunks.push(&s[start..]);
+            }
+            chunks
+        }
+        let numeric = |c: &str| c.chars().all(|c| c.is_ascii_digit());
+        for (x, y) in chunks(a).into_iter().zip(chunks(b)) {
+            let ord = if numeric(x) && numeric(y) {
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            } else {
+                x.cmp(y)
+            };
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+        chunks(a).len().cmp(&chunks(b).len())
+    }
+
+    /// Orders two tags by how new they are, see [`TagNewer`].
+    fn tag_cmp(a: &str, b: &str) -> Ordering {
+        let (a, b) = (a.trim_start_matches('v'), b.trim_start_matches('v'));
+        if let (Some(va), Some(vb)) = (parse_partial_version(a), parse_partial_version(b)) {
+            let precedence = |v: &Version| (v.major, v.minor, v.patch, v.pre.clone());
+            return precedence(&va).cmp(&precedence(&vb));
+        }
+
+        /// Splits a tag into the numbers of its release and the rest.
+        fn split_release(s: &str) -> (Vec<u64>, &str) {
+            let end = s
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(s.len());
+            let (release, suffix) = s.split_at(end);
+            let components = release
+                .split('.')
+                .filter(|c| !c.is_empty())
+                .map(|c| c.parse::<u64>().unwrap_or(u64::MAX))
+                .collect::<Vec<_>>();
+            (components, suffix.trim_start_matches(['-', '.']))
+        }
+        let (mut ra, sa) = split_release(a);
+        let (mut rb, sb) = split_release(b);
+        let len = ra.len().max(rb.len());
+        ra.resize(len, 0);
+        rb.resize(len, 0);
+        ra.cmp(&rb)
+            .then_with(|| match (sa.is_empty(), sb.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => natural_cmp(sa, sb),
+            })
+    }
+
+    pub struct TagNewer;
+    impl BuiltinPredicate for TagNewer {
+        fn name(&self) -> &'static str {
+            "tag_newer"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, false]
+        }
+
+        /// Checks that the tag in arg1 is newer than the tag in arg2. A leading `v` is ignored.
+        /// If both tags are versions, possibly missing their minor or patch components, they are
+        /// compared by semver precedence. Otherwise each tag is split into a release, its leading
+        /// dot-separated numbers, and a suffix, the rest without a leading `-` or `.`. Releases
+        /// are compared numerically, with missing components counting as 0. Between equal
+        /// releases a tag without a suffix is newer, so `"1.2"` is newer than `"1.2-rc1"`, and
+        /// suffixes are otherwise compared in natural order, so `"1.2-rc10"` is newer than
+        /// `"1.2-rc2"`.
+        fn apply(&self, lit: &crate::logic::Literal) -> Option<crate::logic::Literal> {
+            let a = lit.args[0].as_constant()?;
+            let b = lit.args[1].as_constant()?;
+            if tag_cmp(a, b) == Ordering::Greater {
+                Some(lit.clone())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Settings for builtins that depend on the host environment rather than just
+/// their arguments. Such builtins make a build non-reproducible, so they fail
+/// unless `allow_impure` is set.
+#[derive(Debug, Clone)]
+pub struct ImpureBuiltinConfig {
+    pub allow_impure: bool,
+    /// The directory that impure builtins should look at, usually the build
+    /// context.
+    pub context_dir: PathBuf,
+}
+
+impl Default for ImpureBuiltinConfig {
+    fn default() -> Self {
+        Self {
+            allow_impure: false,
+            context_dir: PathBuf::from("."),
+        }
+    }
+}
+
+lazy_static! {
+    static ref IMPURE_BUILTIN_CONFIG: RwLock<ImpureBuiltinConfig> =
+        RwLock::new(ImpureBuiltinConfig::default());
+}
+
+pub fn set_impure_builtin_config(config: ImpureBuiltinConfig) {
+    *IMPURE_BUILTIN_CONFIG.write().unwrap() = config;
+}
+
+pub fn impure_builtin_config() -> ImpureBuiltinConfig {
+    IMPURE_BUILTIN_CONFIG.read().unwrap().clone()
+}
+
+mod impure {
+    use std::process::{Command, Stdio};
+
+    use super::BuiltinPredicate;
+    use crate::logic::{IRTerm, Literal, Predicate};
+
+    pub struct GitRevision;
+    impl BuiltinPredicate for GitRevision {
+        fn name(&self) -> &'static str {
+            "git_revision"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[true]
+        }
+
+        /// Binds the argument to the commit hash of HEAD in the context directory.
+        /// Fails if impure builtins are disabled or the context is not in a git repository.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let rev = head_revision().ok()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("git_revision".to_owned()),
+                args: vec![IRTerm::Constant(rev)],
+            })
+        }
+
+        fn failure_reason(&self, _lit: &Literal) -> Option<String> {
+            head_revision().err()
+        }
+    }
+
+    /// The commit hash of HEAD in the context directory, or why it can't be read.
+    fn head_revision() -> Result<String, String> {
+        let config = super::impure_builtin_config();
+        if !config.allow_impure {
+            return Err(
+                "git_revision depends on the host, so it requires --allow-impure.".to_owned(),
+            );
+        }
+        let output = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .current_dir(&config.context_dir)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Unable to run git: {}", e))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(if stderr.contains("not a git repository") {
+                format!("{} is not a git repository.", config.context_dir.display())
+            } else {
+                format!("git rev-parse HEAD failed: {}", stderr.trim())
+            });
+        }
+        String::from_utf8(output.stdout)
+            .map(|rev| rev.trim().to_owned())
+            .map_err(|_| "git printed a revision that is not valid UTF-8.".to_owned())
+    }
+
+    /// The real user and group ids of the modus process.
+    #[cfg(unix)]
+    fn host_ids() -> Option<(u32, u32)> {
+        // SAFETY: getuid and getgid are always successful and have no preconditions.
+        Some(unsafe { (libc::getuid(), libc::getgid()) })
+    }
+
+    /// Windows has no uid/gid that would be meaningful for file ownership in a container.
+    #[cfg(not(unix))]
+    fn host_ids() -> Option<(u32, u32)> {
+        None
+    }
+
+    fn host_id_literal(lit: &Literal, name: &str, id: u32) -> Option<Literal> {
+        if !super::impure_builtin_config().allow_impure {
+            return None;
+        }
+        Some(Literal {
+            positive: true,
+            position: lit.position.clone(),
+            predicate: Predicate(name.to_owned()),
+            args: vec![IRTerm::Constant(id.to_string())],
+        })
+    }
+
+    pub struct HostUid;
+    impl BuiltinPredicate for HostUid {
+        fn name(&self) -> &'static str {
+            "host_uid"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[true]
+        }
+
+        /// Binds the argument to the uid of the user running modus.
+        /// Fails if impure builtins are disabled, and always fails on Windows.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            host_id_literal(lit, "host_uid", host_ids()?.0)
+        }
+    }
+
+    pub struct HostGid;
+    impl BuiltinPredicate for HostGid {
+        fn name(&self) -> &'static str {
+            "host_gid"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[true]
+        }
+
+        /// Binds the argument to the primary gid of the user running modus.
+        /// Fails if impure builtins are disabled, and always fails on Windows.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            host_id_literal(lit, "host_gid", host_ids()?.1)
+        }
+    }
+
+    pub struct Env;
+    impl BuiltinPredicate for Env {
+        fn name(&self) -> &'static str {
+            "env"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the value of the environment variable named by the
+        /// first in the modus process. Fails if impure builtins are disabled or the variable is
+        /// unset or not valid unicode.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            if !super::impure_builtin_config().allow_impure {
+                return None;
+            }
+            let value = std::env::var(lit.args[0].as_constant()?).ok()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("env".to_owned()),
+                args: vec![lit.args[0].clone(), IRTerm::Constant(value)],
+            })
+        }
+    }
+}
+
+mod context {
+    use std::path::{Component, Path};
+
+    use sha2::{Digest, Sha256};
+
+    use super::BuiltinPredicate;
+    use crate::logic::{IRTerm, Literal, Predicate};
+
+    /// Whether a path is relative and stays inside the directory it is relative to.
+    fn is_context_relative(path: &str) -> bool {
+        Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    }
+
+    /// The files in the context directory matching a glob, as sorted paths relative to the
+    /// context, with `/` separators. Returns None for patterns that could reach outside the
+    /// context.
+    pub fn context_glob(context_dir: &Path, pattern: &str) -> Option<Vec<String>> {
+        if !is_context_relative(pattern) {
+            return None;
+        }
+        let full_pattern = format!(
+            "{}/{}",
+            glob::Pattern::escape(context_dir.to_str()?),
+            pattern
+        );
+        let mut matches = Vec::new();
+        for path in glob::glob(&full_pattern).ok()? {
+            let path = path.ok()?;
+            if path.is_file() {
+                let rel = path.strip_prefix(context_dir).ok()?;
+                let rel = rel
+                    .components()
+                    .map(|c| c.as_os_str().to_str())
+                    .collect::<Option<Vec<_>>>()?;
+                matches.push(rel.join("/"));
+            }
+        }
+        matches.sort();
+        Some(matches)
+    }
+
+    pub fn sha256_hex(hasher: Sha256) -> String {
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub struct ContextFilesHash;
+    impl BuiltinPredicate for ContextFilesHash {
+        fn name(&self) -> &'static str {
+            "context_files_hash"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to a SHA-256 hash of the names and contents of the
+        /// context files matching the glob in the first, for use as a cache key.
+        /// Fails if nothing matches.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let pattern = lit.args[0].as_constant()?;
+            let context_dir = super::impure_builtin_config().context_dir;
+            let files = context_glob(&context_dir, pattern)?;
+            if files.is_empty() {
+                return None;
+            }
+            let mut hasher = Sha256::new();
+            for file in files {
+                let content = std::fs::read(context_dir.join(&file)).ok()?;
+                // Separate and length-prefix entries so that different file sets can't
+                // produce the same input.
+                hasher.update(file.as_bytes());
+                hasher.update([0u8]);
+                hasher.update((content.len() as u64).to_le_bytes());
+                hasher.update(&content);
+            }
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("context_files_hash".to_owned()),
+                args: vec![
+                    IRTerm::Constant(pattern.to_owned()),
+                    IRTerm::Constant(sha256_hex(hasher)),
+                ],
+            })
+        }
+    }
+
+    /// Parses the value of a `KEY=VALUE` line. Values may be single-quoted, taken as is,
+    /// or double-quoted, with `\n`, `\"` and `\\` escapes. A ` #` starts a comment after
+    /// an unquoted value.
+    fn parse_env_value(value: &str) -> Option<String> {
+        if let Some(rest) = value.strip_prefix('\'') {
+            let (quoted, after) = rest.split_once('\'')?;
+            return (after.trim().is_empty() || after.trim_start().starts_with('#'))
+                .then(|| quoted.to_owned());
+        }
+        if let Some(rest) = value.strip_prefix('"') {
+            let mut res = String::new();
+            let mut chars = rest.chars();
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => match chars.next()? {
+                        'n' => res.push('\n'),
+                        c @ ('"' | '\\') => res.push(c),
+                        c => {
+                            res.push('\\');
+                            res.push(c);
+                        }
+                    },
+                    c => res.push(c),
+                }
+            }
+            let after = chars.as_str().trim();
+            return (after.is_empty() || after.starts_with('#')).then_some(res);
+        }
+        let value = match value.find(" #") {
+            Some(i) => &value[..i],
+            None => value,
+        };
+        Some(value.trim().to_owned())
+    }
+
+    /// Parses a dotenv-style file into its key/value pairs, in order. Blank lines and
+    /// lines starting with `#` are skipped, and a leading `export ` is allowed.
+    pub fn parse_env_file(content: &str) -> Option<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let key = key.trim();
+            if key.is_empty() || key.contains(char::is_whitespace) {
+                return None;
+            }
+            pairs.push((key.to_owned(), parse_env_value(value.trim())?));
+        }
+        Some(pairs)
+    }
+
+    pub struct LoadEnvFacts;
+    impl BuiltinPredicate for LoadEnvFacts {
+        fn name(&self) -> &'static str {
+            "load_env_facts"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to a list of `[Key, Value]` lists read from the
+        /// dotenv-style file at the context-relative path in the first.
+        /// Fails if the file can't be read or has a malformed line.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let path = lit.args[0].as_constant()?;
+            if !is_context_relative(path) {
+                return None;
+            }
+            let context_dir = super::impure_builtin_config().context_dir;
+            let content = std::fs::read_to_string(context_dir.join(path)).ok()?;
+            let pairs = parse_env_file(&content)?
+                .into_iter()
+                .map(|(key, value)| {
+                    IRTerm::List(vec![IRTerm::Constant(key), IRTerm::Constant(value)])
+                })
+                .collect();
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("load_env_facts".to_owned()),
+                args: vec![IRTerm::Constant(path.to_owned()), IRTerm::List(pairs)],
+            })
+        }
+    }
+
+    pub struct ReadFile;
+    impl BuiltinPredicate for ReadFile {
+        fn name(&self) -> &'static str {
+            "read_file"
+        }
+
+        fn kind(&self) -> crate::analysis::Kind {
+            crate::analysis::Kind::Logic
+        }
+
+        fn arg_groundness(&self) -> &'static [bool] {
+            &[false, true]
+        }
+
+        /// Binds the second argument to the contents of the file at the context-relative
+        /// path in the first, without leading and trailing whitespace, such as a version
+        /// kept in a `VERSION` file. Fails for paths that could reach outside the context, and
+        /// if the file can't be read as UTF-8.
+        fn apply(&self, lit: &Literal) -> Option<Literal> {
+            let path = lit.args[0].as_constant()?;
+            if !is_context_relative(path) {
+                return None;
+            }
+            let context_dir = super::impure_builtin_config().context_dir;
+            let content = std::fs::read_to_string(context_dir.join(path)).ok()?;
+            Some(Literal {
+                positive: true,
+                position: lit.position.clone(),
+                predicate: Predicate("read_file".to_owned()),
+                args: vec![
+                    IRTerm::Constant(path.to_owned()),
+                    IRTerm::Constant(content.trim().to_owned()),
+                ],
+            })
+        }
+    }
+}
+
+macro_rules! intrinsic_predicate {
+    ($name:ident, $kind:expr, $($arg_groundness:expr),*) => {
+        #[allow(non_camel_case_types)]
+        pub struct $name;
+        impl BuiltinPredicate for $name {
+            fn name(&self) -> &'static str {
+                stringify!($name)
+            }
+
+            fn kind(&self) -> Kind {
+                $kind
+            }
+
+            fn arg_groundness(&self) -> &'static [bool] {
+                &[$($arg_groundness),*]
+            }
+
+            fn apply(&self, lit: &Literal) -> Option<Literal> {
+                Some(lit.clone())
+            }
+        }
+    };
+}
+
+intrinsic_predicate!(run, crate::analysis::Kind::Layer, false);
+intrinsic_predicate!(from, crate::analysis::Kind::Image, false);
+intrinsic_predicate!(
+    _operator_copy_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_copy_end,
+    crate::analysis::Kind::Image,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_in_workdir_begin,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_in_workdir_end,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_workdir_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_workdir_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_entrypoint_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_entrypoint_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_cmd_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_cmd_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_label_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_label_end,
+    crate::analysis::Kind::Image,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_env_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_env_end,
+    crate::analysis::Kind::Image,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_in_env_begin,
+    crate::analysis::Kind::Layer,
+    false,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_in_env_end,
+    crate::analysis::Kind::Layer,
+    false,
+    false,
+    false
+);
+/// Whether `id` can name a build secret, which is given to `docker build` as `--secret id=<id>`.
+pub fn is_valid_secret_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+fn is_valid_secret_target(target: &str) -> bool {
+    !target.is_empty() && !target.contains(',')
+}
+
+intrinsic_predicate!(
+    _operator_append_path_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_append_path_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_user_begin,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_set_user_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+
+/// Parses a port specification such as `8080` or `53/udp` into the port number and
+/// protocol, which defaults to tcp.
+pub fn parse_port_spec(spec: &str) -> Option<(u16, &str)> {
+    let (port, protocol) = match spec.split_once('/') {
+        Some((port, protocol @ ("tcp" | "udp"))) => (port, protocol),
+        Some(_) => return None,
+        None => (spec, "tcp"),
+    };
+    if port.is_empty() || !port.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    match port.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(port) => Some((port, protocol)),
+    }
+}
+
+pub struct IsValidPort;
+impl BuiltinPredicate for IsValidPort {
+    fn name(&self) -> &'static str {
+        "is_valid_port"
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::Logic
+    }
+
+    fn arg_groundness(&self) -> &'static [bool] {
+        &[false]
+    }
+
+    /// Succeeds if the argument is a port `set_expose` accepts, such as `8080`, `8080/tcp`
+    /// or `53/udp`.
+    fn apply(&self, lit: &Literal) -> Option<Literal> {
+        parse_port_spec(lit.args[0].as_constant()?)?;
+        Some(lit.clone())
+    }
+}
+
+/// The commands that `run_is_safe` rejects, as the words a shell command starts with.
+pub const ESCALATION_PATTERNS: &[&str] = &["sudo", "su", "doas", "pkexec"];
+
+/// The first of the patterns that one of the commands in a shell command line, split on
+/// `;`, `&&`, `||`, `|` and newlines, starts with. Each pattern is a sequence of words.
+/// Leading `VAR=value` assignments are skipped.
+pub fn escalation_pattern<'a>(command: &str, patterns: &[&'a str]) -> Option<&'a str> {
+    let commands = command
+        .split([';', '&', '|', '\n'])
+        .map(|segment| {
+            segment
+                .split_whitespace()
+                .skip_while(|word| word.contains('='))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    patterns.iter().copied().find(|pattern| {
+        let pattern = pattern.split_whitespace().collect::<Vec<_>>();
+        !pattern.is_empty() && commands.iter().any(|words| words.starts_with(&pattern))
+    })
+}
+
+pub struct RunIsSafe;
+impl BuiltinPredicate for RunIsSafe {
+    fn name(&self) -> &'static str {
+        "run_is_safe"
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::Logic
+    }
+
+    fn arg_groundness(&self) -> &'static [bool] {
+        &[false]
+    }
+
+    /// Succeeds if the command doesn't escalate privileges, e.g. with `sudo` or `su -`,
+    /// according to [ESCALATION_PATTERNS].
+    fn apply(&self, lit: &Literal) -> Option<Literal> {
+        let command = lit.args[0].as_constant()?;
+        if escalation_pattern(command, ESCALATION_PATTERNS).is_some() {
+            return None;
+        }
+        Some(lit.clone())
+    }
+
+    fn failure_reason(&self, lit: &Literal) -> Option<String> {
+        let command = lit.args[0].as_constant()?;
+        escalation_pattern(command, ESCALATION_PATTERNS)
+            .map(|pattern| format!("The command escalates privileges with `{}`.", pattern))
+    }
+}
+
+pub struct NotLatest;
+impl BuiltinPredicate for NotLatest {
+    fn name(&self) -> &'static str {
+        "not_latest"
+    }
+
+    fn kind(&self) -> Kind {
+        Kind::Logic
+    }
+
+    fn arg_groundness(&self) -> &'static [bool] {
+        &[false]
+    }
+
+    /// Succeeds if the image reference names an explicit tag other than `latest`, or is
+    /// pinned by a digest, so that the image it refers to doesn't change between builds.
+    fn apply(&self, lit: &Literal) -> Option<Literal> {
+        let image_ref = lit.args[0].as_constant()?;
+        if image_ref.contains('@') {
+            return Some(lit.clone());
+        }
+        let image: crate::dockerfile::Image = image_ref.parse().ok()?;
+        match image.tag() {
+            "" | "latest" => None,
+            _ => Some(lit.clone()),
+        }
+    }
+}
+
+/// Like the other operator intrinsics, except that it fails on an invalid port.
+#[allow(non_camel_case_types)]
+pub struct _operator_set_expose_begin;
+impl BuiltinPredicate for _operator_set_expose_begin {
+    fn name(&self) -> &'static str {
+        "_operator_set_expose_begin"
+    }
+
+    fn kind(&self) -> Kind {
+        crate::analysis::Kind::Image
+    }
+
+    fn arg_groundness(&self) -> &'static [bool] {
+        &[false, false]
+    }
+
+    fn apply(&self, lit: &Literal) -> Option<Literal> {
+        parse_port_spec(lit.args[1].as_constant()?)?;
+        Some(lit.clone())
+    }
+
+    fn failure_reason(&self, lit: &Literal) -> Option<String> {
+        let port = lit.args[1].as_constant()?;
+        Some(format!(
+            "{:?} is not a valid port, expected a number from 1 to 65535, optionally followed by /tcp or /udp.",
+            port
+        ))
+    }
+}
+intrinsic_predicate!(
+    _operator_set_expose_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+/// The names of the Linux signals 1 to 31, in order. These are the stop signals that can be
+/// written into an image config by the buildkit frontend.
+pub const SIGNALS: [&str; 31] = [
+    "SIGHUP",
+    "SIGINT",
+    "SIGQUIT",
+    "SIGILL",
+    "SIGTRAP",
+    "SIGABRT",
+    "SIGBUS",
+    "SIGFPE",
+    "SIGKILL",
+    "SIGUSR1",
+    "SIGSEGV",
+    "SIGUSR2",
+    "SIGPIPE",
+    "SIGALRM",
+    "SIGTERM",
+    "SIGSTKFLT",
+    "SIGCHLD",
+    "SIGCONT",
+    "SIGSTOP",
+    "SIGTSTP",
+    "SIGTTIN",
+    "SIGTTOU",
+    "SIGURG",
+    "SIGXCPU",
+    "SIGXFSZ",
+    "SIGVTALRM",
+    "SIGPROF",
+    "SIGWINCH",
+    "SIGIO",
+    "SIGPWR",
+    "SIGSYS",
+];
+
+/// The name in [SIGNALS] of `signal`, which is either one of these names or a signal number.
+pub fn signal_name(signal: &str) -> Option<&'static str> {
+    if signal.starts_with('+') {
+        return None;
+    }
+    match signal.parse::<usize>() {
+        Ok(number) => SIGNALS.get(number.checked_sub(1)?).copied(),
+        Err(_) => SIGNALS.iter().find(|&&name| name == signal).copied(),
+    }
+}
+
+/// Whether `signal` is a signal name such as `SIGTERM` or a signal number that
+/// [signal_name] knows.
+pub fn is_valid_signal(signal: &str) -> bool {
+    signal_name(signal).is_some()
+}
+
+/// Like the other operator intrinsics, except that it fails on an invalid signal.
+#[allow(non_camel_case_types)]
+pub struct _operator_set_stopsignal_begin;
+impl BuiltinPredicate for _operator_set_stopsignal_begin {
+    fn name(&self) -> &'static str {
+        "_operator_set_stopsignal_begin"
+    }
+
+    fn kind(&self) -> Kind {
+        crate::analysis::Kind::Image
+    }
+
+    fn arg_groundness(&self) -> &'static [bool] {
+        &[false, false]
+    }
+
+    fn apply(&self, lit: &Literal) -> Option<Literal> {
+        if is_valid_signal(lit.args[1].as_constant()?) {
+            Some(lit.clone())
+        } else {
+            None
+        }
+    }
+
+    fn failure_reason(&self, lit: &Literal) -> Option<String> {
+        let signal = lit.args[1].as_constant()?;
+        Some(format!(
+            "{:?} is not a valid stop signal, expected a name such as \"SIGTERM\" or a number from 1 to 31.",
+            signal
+        ))
+    }
+}
+intrinsic_predicate!(
+    _operator_set_stopsignal_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
 );
+
+/// Like the other operator intrinsics, except that it fails unless the shell is a
+/// non-empty list, such as `["/bin/bash", "-c"]`.
+#[allow(non_camel_case_types)]
+pub struct _operator_set_shell_begin;
+impl BuiltinPredicate for _operator_set_shell_begin {
+    fn name(&self) -> &'static str {
+        "_operator_set_shell_begin"
+    }
+
+    fn kind(&self) -> Kind {
+        crate::analysis::Kind::Image
+    }
+
+    fn arg_groundness(&self) -> &'static [bool] {
+        &[false, false]
+    }
+
+    fn apply(&self, lit: &Literal) -> Option<Literal> {
+        match &lit.args[1] {
+            IRTerm::List(ts)
+                if !ts.is_empty()
+                    && ts
+                        .iter()
+                        .all(|t| matches!(t.as_constant(), Some(s) if !s.is_empty())) =>
+            {
+                Some(lit.clone())
+            }
+            _ => None,
+        }
+    }
+}
 intrinsic_predicate!(
-    _operator_in_workdir_begin,
+    _operator_set_shell_end,
+    crate::analysis::Kind::Image,
+    false,
+    false
+);
+intrinsic_predicate!(
+    _operator_exclude_begin,
     crate::analysis::Kind::Layer,
     false,
     false
 );
 intrinsic_predicate!(
-    _operator_in_workdir_end,
+    _operator_exclude_end,
     crate::analysis::Kind::Layer,
     false,
     false
 );
 intrinsic_predicate!(
-    _operator_set_workdir_begin,
-    crate::analysis::Kind::Image,
+    _operator_cache_mount_begin,
+    crate::analysis::Kind::Layer,
     false,
     false
 );
 intrinsic_predicate!(
-    _operator_set_workdir_end,
+    _operator_cache_mount_end,
+    crate::analysis::Kind::Layer,
+    false,
+    false
+);
+
+/// The optional arguments of `cache_mount`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CacheMountOptions {
+    pub id: Option<String>,
+    pub sharing: Option<String>,
+}
+
+impl CacheMountOptions {
+    /// Parses options given as `id=<name>` or `sharing=<shared|private|locked>`. Returns
+    /// None if any of them is unknown or invalid.
+    pub fn parse<'a, I: IntoIterator<Item = &'a str>>(options: I) -> Option<CacheMountOptions> {
+        let mut res = CacheMountOptions::default();
+        for option in options {
+            match option.split_once('=')? {
+                ("id", id) if !id.is_empty() => res.id = Some(id.to_owned()),
+                ("sharing", sharing @ ("shared" | "private" | "locked")) => {
+                    res.sharing = Some(sharing.to_owned())
+                }
+                _ => return None,
+            }
+        }
+        Some(res)
+    }
+}
+
+/// `cache_mount(Target, Options)`, which fails unless every option can be parsed by
+/// [CacheMountOptions::parse].
+pub struct CacheMountWithOptionsBegin;
+impl BuiltinPredicate for CacheMountWithOptionsBegin {
+    fn name(&self) -> &'static str {
+        "_operator_cache_mount_begin"
+    }
+
+    fn kind(&self) -> Kind {
+        crate::analysis::Kind::Layer
+    }
+
+    fn arg_groundness(&self) -> &'static [bool] {
+        &[false, false, false]
+    }
+
+    fn apply(&self, lit: &Literal) -> Option<Literal> {
+        let options = match &lit.args[2] {
+            IRTerm::List(ts) => ts
+                .iter()
+                .map(IRTerm::as_constant)
+                .collect::<Option<Vec<_>>>()?,
+            _ => return None,
+        };
+        CacheMountOptions::parse(options)?;
+        Some(lit.clone())
+    }
+}
+
+pub struct CacheMountWithOptionsEnd;
+impl BuiltinPredicate for CacheMountWithOptionsEnd {
+    fn name(&self) -> &'static str {
+        "_operator_cache_mount_end"
+    }
+
+    fn kind(&self) -> Kind {
+        crate::analysis::Kind::Layer
+    }
+
+    fn arg_groundness(&self) -> &'static [bool] {
+        &[false, false, false]
+    }
+
+    fn apply(&self, lit: &Literal) -> Option<Literal> {
+        Some(lit.clone())
+    }
+}
+intrinsic_predicate!(copy, crate::analysis::Kind::Layer, false, false);
+
+/// Whether `chown` can be given as the owner of copied files: a user name or uid,
+/// optionally followed by `:` and a group name or gid.
+pub fn is_valid_chown(chown: &str) -> bool {
+    let valid_part = |s: &str| {
+        !s.is_empty()
+            && !s.starts_with('-')
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    };
+    match chown.split_once(':') {
+        Some((user, group)) => valid_part(user) && valid_part(group),
+        None => valid_part(chown),
+    }
+}
+
+/// Whether `chmod` can be given as the mode of copied files: 3 or 4 octal digits, such as `755`
+/// or `0755`.
+pub fn is_valid_chmod(chmod: &str) -> bool {
+    (3..=4).contains(&chmod.len()) && chmod.chars().all(|c| ('0'..='7').contains(&c))
+}
+
+/// Whether the owner given to a copy that also sets the mode is valid. It may be empty, in which
+/// case the copied files keep the default owner.
+fn is_valid_optional_chown(chown: &str) -> bool {
+    chown.is_empty() || is_valid_chown(chown)
+}
+
+/// Defines an intrinsic whose last arguments must be accepted by the given functions, such
+/// as the variants of copy that take the owner (and mode) of the copied files.
+macro_rules! validated_intrinsic {
+    ($name:ident, $predicate:literal, $kind:expr, [$($is_valid:ident),*], $($arg_groundness:expr),*) => {
+        pub struct $name;
+        impl BuiltinPredicate for $name {
+            fn name(&self) -> &'static str {
+                $predicate
+            }
+
+            fn kind(&self) -> Kind {
+                $kind
+            }
+
+            fn arg_groundness(&self) -> &'static [bool] {
+                &[$($arg_groundness),*]
+            }
+
+            fn apply(&self, lit: &Literal) -> Option<Literal> {
+                let validators: &[fn(&str) -> bool] = &[$($is_valid),*];
+                let options = &lit.args[lit.args.len().checked_sub(validators.len())?..];
+                for (is_valid, option) in validators.iter().zip(options) {
+                    if !is_valid(option.as_constant()?) {
+                        return None;
+                    }
+                }
+                Some(lit.clone())
+            }
+        }
+    };
+}
+
+validated_intrinsic!(
+    CopyWithChown,
+    "copy",
+    crate::analysis::Kind::Layer,
+    [is_valid_chown],
+    false,
+    false,
+    false
+);
+validated_intrinsic!(
+    CopyWithChownBegin,
+    "_operator_copy_begin",
     crate::analysis::Kind::Image,
+    [is_valid_chown],
+    false,
+    false,
     false,
     false
 );
-intrinsic_predicate!(
-    _operator_set_entrypoint_begin,
+validated_intrinsic!(
+    CopyWithChownEnd,
+    "_operator_copy_end",
     crate::analysis::Kind::Image,
+    [is_valid_chown],
+    false,
+    false,
     false,
     false
 );
-intrinsic_predicate!(
-    _operator_set_entrypoint_end,
+validated_intrinsic!(
+    CopyWithChmod,
+    "copy",
+    crate::analysis::Kind::Layer,
+    [is_valid_optional_chown, is_valid_chmod],
+    false,
+    false,
+    false,
+    false
+);
+validated_intrinsic!(
+    CopyWithChmodBegin,
+    "_operator_copy_begin",
     crate::analysis::Kind::Image,
+    [is_valid_optional_chown, is_valid_chmod],
+    false,
+    false,
+    false,
     false,
     false
 );
-intrinsic_predicate!(
-    _operator_set_cmd_begin,
+validated_intrinsic!(
+    CopyWithChmodEnd,
+    "_operator_copy_end",
     crate::analysis::Kind::Image,
+    [is_valid_optional_chown, is_valid_chmod],
+    false,
+    false,
+    false,
+    false,
+    false
+);
+
+validated_intrinsic!(
+    InSecretBegin,
+    "_operator_in_secret_begin",
+    crate::analysis::Kind::Layer,
+    [is_valid_secret_id],
+    false,
+    false
+);
+validated_intrinsic!(
+    InSecretEnd,
+    "_operator_in_secret_end",
+    crate::analysis::Kind::Layer,
+    [is_valid_secret_id],
+    false,
+    false
+);
+validated_intrinsic!(
+    InSecretWithTargetBegin,
+    "_operator_in_secret_begin",
+    crate::analysis::Kind::Layer,
+    [is_valid_secret_id, is_valid_secret_target],
+    false,
+    false,
+    false
+);
+validated_intrinsic!(
+    InSecretWithTargetEnd,
+    "_operator_in_secret_end",
+    crate::analysis::Kind::Layer,
+    [is_valid_secret_id, is_valid_secret_target],
+    false,
     false,
     false
 );
+
+/// Whether `s` is a SHA-256 digest in hex, as printed by `sha256sum`.
+pub fn is_sha256(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// `add_url_checked(Url, Dst, Sha256)` downloads Url to Dst, failing the build if the
+/// downloaded file does not have the given SHA-256 digest.
+pub struct AddUrlChecked;
+impl BuiltinPredicate for AddUrlChecked {
+    fn name(&self) -> &'static str {
+        "add_url_checked"
+    }
+
+    fn kind(&self) -> Kind {
+        crate::analysis::Kind::Layer
+    }
+
+    fn arg_groundness(&self) -> &'static [bool] {
+        &[false, false, false]
+    }
+
+    fn apply(&self, lit: &Literal) -> Option<Literal> {
+        let url = lit.args[0].as_constant()?;
+        let is_http = url.starts_with("http://") || url.starts_with("https://");
+        if is_http && is_sha256(lit.args[2].as_constant()?) {
+            Some(lit.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `s` is a duration such as `30s`, `1m30s` or `1.5h`, in the format that
+/// HEALTHCHECK accepts.
+pub fn is_duration(s: &str) -> bool {
+    duration_nanos(s).is_some()
+}
+
+/// The number of nanoseconds in the duration `s`, such as `1m30s`, or None if it is not a
+/// duration. This is how durations are stored in an image config.
+pub fn duration_nanos(s: &str) -> Option<u64> {
+    let mut rest = s;
+    let mut nanos = 0f64;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let number = rest[..number_len].parse::<f64>().ok()?;
+        rest = &rest[number_len..];
+        // "ms" comes before "m" and "s", so that it isn't read as minutes.
+        let (unit, unit_nanos) = [
+            ("ns", 1f64),
+            ("us", 1e3),
+            ("ms", 1e6),
+            ("s", 1e9),
+            ("m", 60e9),
+            ("h", 3600e9),
+        ]
+        .iter()
+        .find(|(unit, _)| rest.starts_with(unit))?;
+        rest = &rest[unit.len()..];
+        nanos += number * unit_nanos;
+    }
+    (!s.is_empty()).then(|| nanos.round() as u64)
+}
+
+/// Whether `interval` or `timeout` of `set_healthcheck` is valid. It may be empty, in which case
+/// docker's default is used.
+fn is_valid_optional_duration(duration: &str) -> bool {
+    duration.is_empty() || is_duration(duration)
+}
+
+/// Whether `retries` of `set_healthcheck` is valid. Like the durations, it may be empty.
+fn is_valid_optional_retries(retries: &str) -> bool {
+    retries.is_empty() || retries.parse::<u32>().is_ok()
+}
+
 intrinsic_predicate!(
-    _operator_set_cmd_end,
+    _operator_set_healthcheck_begin,
     crate::analysis::Kind::Image,
     false,
     false
 );
 intrinsic_predicate!(
-    _operator_set_label_begin,
+    _operator_set_healthcheck_end,
     crate::analysis::Kind::Image,
     false,
+    false
+);
+validated_intrinsic!(
+    SetHealthcheckWithIntervalBegin,
+    "_operator_set_healthcheck_begin",
+    crate::analysis::Kind::Image,
+    [is_valid_optional_duration],
+    false,
     false,
     false
 );
-intrinsic_predicate!(
-    _operator_set_label_end,
+validated_intrinsic!(
+    SetHealthcheckWithIntervalEnd,
+    "_operator_set_healthcheck_end",
     crate::analysis::Kind::Image,
+    [is_valid_optional_duration],
     false,
     false,
     false
 );
-intrinsic_predicate!(
-    _operator_set_env_begin,
+validated_intrinsic!(
+    SetHealthcheckWithTimeoutBegin,
+    "_operator_set_healthcheck_begin",
+    crate::analysis::Kind::Image,
+    [is_valid_optional_duration, is_valid_optional_duration],
+    false,
+    false,
+    false,
+    false
+);
+validated_intrinsic!(
+    SetHealthcheckWithTimeoutEnd,
+    "_operator_set_healthcheck_end",
+    crate::analysis::Kind::Image,
+    [is_valid_optional_duration, is_valid_optional_duration],
+    false,
+    false,
+    false,
+    false
+);
+validated_intrinsic!(
+    SetHealthcheckWithRetriesBegin,
+    "_operator_set_healthcheck_begin",
+    crate::analysis::Kind::Image,
+    [
+        is_valid_optional_duration,
+        is_valid_optional_duration,
+        is_valid_optional_retries
+    ],
+    false,
+    false,
+    false,
+    false,
+    false
+);
+validated_intrinsic!(
+    SetHealthcheckWithRetriesEnd,
+    "_operator_set_healthcheck_end",
     crate::analysis::Kind::Image,
+    [
+        is_valid_optional_duration,
+        is_valid_optional_duration,
+        is_valid_optional_retries
+    ],
+    false,
+    false,
     false,
     false,
     false
 );
+
+intrinsic_predicate!(_operator_merge_begin, crate::analysis::Kind::Layer, false);
+intrinsic_predicate!(_operator_merge_end, crate::analysis::Kind::Layer, false);
+intrinsic_predicate!(_operator_squash_begin, crate::analysis::Kind::Layer, false);
+intrinsic_predicate!(_operator_squash_end, crate::analysis::Kind::Layer, false);
 intrinsic_predicate!(
-    _operator_set_env_end,
+    _operator_add_file_begin,
     crate::analysis::Kind::Image,
     false,
     false,
     false
 );
 intrinsic_predicate!(
-    _operator_in_env_begin,
-    crate::analysis::Kind::Layer,
+    _operator_add_file_end,
+    crate::analysis::Kind::Image,
+    false,
+    false,
+    false
+);
+validated_intrinsic!(
+    AddFileWithModeBegin,
+    "_operator_add_file_begin",
+    crate::analysis::Kind::Image,
+    [is_valid_chmod],
+    false,
+    false,
+    false,
+    false
+);
+validated_intrinsic!(
+    AddFileWithModeEnd,
+    "_operator_add_file_end",
+    crate::analysis::Kind::Image,
+    [is_valid_chmod],
+    false,
     false,
     false,
     false
 );
 intrinsic_predicate!(
-    _operator_in_env_end,
+    _operator_no_cache_begin,
     crate::analysis::Kind::Layer,
-    false,
-    false,
     false
 );
+intrinsic_predicate!(_operator_no_cache_end, crate::analysis::Kind::Layer, false);
 intrinsic_predicate!(
-    _operator_append_path_begin,
-    crate::analysis::Kind::Image,
-    false,
+    _operator_no_network_begin,
+    crate::analysis::Kind::Layer,
     false
 );
 intrinsic_predicate!(
-    _operator_append_path_end,
-    crate::analysis::Kind::Image,
-    false,
+    _operator_no_network_end,
+    crate::analysis::Kind::Layer,
     false
 );
 intrinsic_predicate!(
-    _operator_set_user_begin,
-    crate::analysis::Kind::Image,
+    _operator_cache_id_begin,
+    crate::analysis::Kind::Layer,
     false,
     false
 );
 intrinsic_predicate!(
-    _operator_set_user_end,
-    crate::analysis::Kind::Image,
+    _operator_cache_id_end,
+    crate::analysis::Kind::Layer,
     false,
     false
 );
-intrinsic_predicate!(copy, crate::analysis::Kind::Layer, false, false);
-intrinsic_predicate!(_operator_merge_begin, crate::analysis::Kind::Layer, false);
-intrinsic_predicate!(_operator_merge_end, crate::analysis::Kind::Layer, false);
 
-/// Convenience macro that returns Some(b) for the first b that can be selected.
-macro_rules! select_builtins {
-    ( $lit:expr, $( $x:expr ),+, ) => {{
-        let mut has_ground_mismatch = false;
-        $(
-            match $x.select($lit) {
-                SelectBuiltinResult::Match => return (SelectBuiltinResult::Match, Some(&$x)),
-                SelectBuiltinResult::GroundnessMismatch => {
-                    has_ground_mismatch = true;
-                },
-                _ => {}
-            }
-        );+
-        if has_ground_mismatch {
-            return (SelectBuiltinResult::GroundnessMismatch, None);
-        } else {
-            return (SelectBuiltinResult::NoMatch, None);
+/// Convenience macro that returns Some(b) for the first b that can be selected.
+macro_rules! select_builtins {
+    ( $lit:expr, $( $x:expr ),+, ) => {{
+        let mut has_ground_mismatch = false;
+        $(
+            match $x.select($lit) {
+                SelectBuiltinResult::Match => return (SelectBuiltinResult::Match, Some(&$x)),
+                SelectBuiltinResult::GroundnessMismatch => {
+                    has_ground_mismatch = true;
+                },
+                _ => {}
+            }
+        );+
+        if has_ground_mismatch {
+            return (SelectBuiltinResult::GroundnessMismatch, None);
+        } else {
+            return (SelectBuiltinResult::NoMatch, None);
+        }
+    }};
+}
+
+pub fn select_builtin<'a>(
+    lit: &Literal,
+) -> (SelectBuiltinResult, Option<&'a dyn BuiltinPredicate>) {
+    select_builtins!(
+        lit,
+        string_concat::StringConcat1,
+        string_concat::StringConcat2,
+        string_concat::StringConcat3,
+        string::CommonPrefix,
+        string::IsAscii,
+        string::StringLength,
+        string::StringSplit,
+        string::StringToLower,
+        string::StringToUpper,
+        string::Slugify,
+        string::StringReplace,
+        string::StringTrim,
+        string::StringTrimStart,
+        string::StringTrimEnd,
+        string::DockerfileEscape,
+        substring::string_contains,
+        substring::string_prefix,
+        substring::string_suffix,
+        path::PathWithin,
+        path::ContainerPath,
+        path::NoPathCollision,
+        list::ListDifference,
+        list::ListLength,
+        list::ListMember,
+        list::Lookup,
+        list::AllMatch,
+        run,
+        from,
+        _operator_copy_begin,
+        _operator_copy_end,
+        _operator_in_workdir_begin,
+        _operator_in_workdir_end,
+        _operator_set_workdir_begin,
+        _operator_set_workdir_end,
+        _operator_set_entrypoint_begin,
+        _operator_set_entrypoint_end,
+        _operator_set_cmd_begin,
+        _operator_set_cmd_end,
+        _operator_set_label_begin,
+        _operator_set_label_end,
+        _operator_set_env_begin,
+        _operator_set_env_end,
+        _operator_in_env_begin,
+        _operator_in_env_end,
+        InSecretBegin,
+        InSecretEnd,
+        InSecretWithTargetBegin,
+        InSecretWithTargetEnd,
+        _operator_exclude_begin,
+        _operator_exclude_end,
+        _operator_cache_mount_begin,
+        _operator_cache_mount_end,
+        CacheMountWithOptionsBegin,
+        CacheMountWithOptionsEnd,
+        _operator_append_path_begin,
+        _operator_append_path_end,
+        _operator_set_user_begin,
+        _operator_set_user_end,
+        _operator_set_expose_begin,
+        _operator_set_expose_end,
+        IsValidPort,
+        RunIsSafe,
+        NotLatest,
+        _operator_set_stopsignal_begin,
+        _operator_set_stopsignal_end,
+        _operator_set_shell_begin,
+        _operator_set_shell_end,
+        _operator_set_healthcheck_begin,
+        _operator_set_healthcheck_end,
+        SetHealthcheckWithIntervalBegin,
+        SetHealthcheckWithIntervalEnd,
+        SetHealthcheckWithTimeoutBegin,
+        SetHealthcheckWithTimeoutEnd,
+        SetHealthcheckWithRetriesBegin,
+        SetHealthcheckWithRetriesEnd,
+        copy,
+        CopyWithChown,
+        CopyWithChownBegin,
+        CopyWithChownEnd,
+        CopyWithChmod,
+        CopyWithChmodBegin,
+        CopyWithChmodEnd,
+        AddUrlChecked,
+        equality::StringEq1,
+        equality::StringEq2,
+        _operator_merge_begin,
+        _operator_merge_end,
+        _operator_squash_begin,
+        _operator_squash_end,
+        _operator_add_file_begin,
+        _operator_add_file_end,
+        AddFileWithModeBegin,
+        AddFileWithModeEnd,
+        _operator_no_cache_begin,
+        _operator_no_cache_end,
+        _operator_no_network_begin,
+        _operator_no_network_end,
+        _operator_cache_id_begin,
+        _operator_cache_id_end,
+        number::number_eq,
+        number::number_gt,
+        number::number_lt,
+        number::number_geq,
+        number::number_leq,
+        number::number_add,
+        number::number_sub,
+        number::number_mul,
+        number::number_div,
+        number::IsInteger,
+        number::FormatBytes,
+        number::ParseBytes,
+        semver::semver_exact,
+        semver::semver_gt,
+        semver::semver_lt,
+        semver::semver_geq,
+        semver::semver_leq,
+        semver::SemverMatches,
+        semver::VersionInSeries,
+        semver::VersionCompatible,
+        semver::VersionBumpKind,
+        semver::VersionTagSeries,
+        semver::TagNewer,
+        impure::GitRevision,
+        impure::HostUid,
+        impure::HostGid,
+        impure::Env,
+        context::ContextFilesHash,
+        context::LoadEnvFacts,
+        context::ReadFile,
+        json::ToJson,
+        json::FromJson,
+    )
+}
+
+/// Builtins that may be given list literals as arguments. Lists are rejected everywhere
+/// else in a rule body.
+pub const LIST_BUILTINS: &[&str] = &[
+    "string_split",
+    "list_difference",
+    "list_length",
+    "list_member",
+    "lookup",
+    "all_match",
+    "no_path_collision",
+    "to_json",
+];
+
+lazy_static! {
+    // An operator can take an expression of one kind and produce another kind.
+    pub static ref OPERATOR_KIND_MAP: HashMap<&'static str, (Kind, Kind)> = {
+        let mut m = HashMap::new();
+        m.insert("copy", (Kind::Image, Kind::Layer));
+        m.insert("set_env", (Kind::Image, Kind::Image));
+        m.insert("set_entrypoint", (Kind::Image, Kind::Image));
+        m.insert("set_cmd", (Kind::Image, Kind::Image));
+        m.insert("set_workdir", (Kind::Image, Kind::Image));
+        m.insert("set_label", (Kind::Image, Kind::Image));
+        m.insert("set_user", (Kind::Image, Kind::Image));
+        m.insert("set_expose", (Kind::Image, Kind::Image));
+        m.insert("set_healthcheck", (Kind::Image, Kind::Image));
+        m.insert("set_stopsignal", (Kind::Image, Kind::Image));
+        m.insert("set_shell", (Kind::Image, Kind::Image));
+        m.insert("append_path", (Kind::Image, Kind::Image));
+        m.insert("in_workdir", (Kind::Layer, Kind::Layer));
+        m.insert("in_env", (Kind::Layer, Kind::Layer));
+        m.insert("in_secret", (Kind::Layer, Kind::Layer));
+        m.insert("exclude", (Kind::Layer, Kind::Layer));
+        m.insert("cache_mount", (Kind::Layer, Kind::Layer));
+        m.insert("merge", (Kind::Layer, Kind::Layer));
+        m.insert("squash", (Kind::Layer, Kind::Layer));
+        m.insert("add_file", (Kind::Image, Kind::Image));
+        m.insert("no_cache", (Kind::Layer, Kind::Layer));
+        m.insert("no_network", (Kind::Layer, Kind::Layer));
+        m.insert("cache_id", (Kind::Layer, Kind::Layer));
+        m
+    };
+
+    /// The parameter names of each operator, in positional order, which keyword
+    /// arguments such as `set_env(key: "A", value: "1")` refer to.
+    pub static ref OPERATOR_PARAMETERS: HashMap<&'static str, &'static [&'static str]> = {
+        let mut m: HashMap<&'static str, &'static [&'static str]> = HashMap::new();
+        m.insert("copy", &["src", "dst", "chown", "chmod"]);
+        m.insert("set_env", &["key", "value"]);
+        m.insert("set_entrypoint", &["entrypoint"]);
+        m.insert("set_cmd", &["cmd"]);
+        m.insert("set_workdir", &["dir"]);
+        m.insert("set_label", &["key", "value"]);
+        m.insert("set_user", &["user"]);
+        m.insert("set_expose", &["port"]);
+        m.insert("set_healthcheck", &["command", "interval", "timeout", "retries"]);
+        m.insert("set_stopsignal", &["signal"]);
+        m.insert("set_shell", &["shell"]);
+        m.insert("append_path", &["path"]);
+        m.insert("in_workdir", &["dir"]);
+        m.insert("in_env", &["key", "value"]);
+        m.insert("in_secret", &["id", "target"]);
+        m.insert("exclude", &["paths"]);
+        m.insert("cache_mount", &["target", "options"]);
+        m.insert("add_file", &["path", "contents", "mode"]);
+        m.insert("cache_id", &["id"]);
+        m
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use serial_test::serial;
+
+    use crate::{analysis::Kind, builtin::SelectBuiltinResult, logic::IRTerm};
+
+    #[test]
+    pub fn test_select() {
+        use crate::logic::{Literal, Predicate};
+
+        let lit = Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("run".to_owned()),
+            args: vec![IRTerm::Constant("hello".to_owned())],
+        };
+        let b = super::select_builtin(&lit);
+        assert!(b.0.is_match());
+        let b = b.1.unwrap();
+        assert_eq!(b.name(), "run");
+        assert_eq!(b.kind(), Kind::Layer);
+        assert_eq!(b.apply(&lit), Some(lit));
+
+        let lit = Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("string_concat".to_owned()),
+            args: vec![
+                IRTerm::Constant("hello".to_owned()),
+                IRTerm::Constant("world".to_owned()),
+                IRTerm::UserVariable("X".to_owned()),
+            ],
+        };
+        let b = super::select_builtin(&lit);
+        assert!(b.0.is_match());
+        let b = b.1.unwrap();
+        assert_eq!(b.name(), "string_concat");
+        assert_eq!(b.kind(), Kind::Logic);
+        assert_eq!(
+            b.apply(&lit),
+            Some(Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("string_concat".to_owned()),
+                args: vec![
+                    IRTerm::Constant("hello".to_owned()),
+                    IRTerm::Constant("world".to_owned()),
+                    IRTerm::Constant("helloworld".to_owned()),
+                ]
+            })
+        );
+
+        let lit = Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("xxx".to_owned()),
+            args: vec![IRTerm::Constant("hello".to_owned())],
+        };
+        let b = super::select_builtin(&lit);
+        assert_eq!(b.0, SelectBuiltinResult::NoMatch);
+    }
+
+    #[test]
+    pub fn test_common_prefix() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("/usr/local/bin", "/usr/lib", "/usr/l"),
+            ("same", "same", "same"),
+            ("abc", "xyz", ""),
+            ("", "abc", ""),
+            ("héllo", "hélp", "hél"),
+        ];
+        for (a, b, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("common_prefix".to_owned()),
+                args: vec![
+                    IRTerm::Constant(a.to_owned()),
+                    IRTerm::Constant(b.to_owned()),
+                    IRTerm::UserVariable("P".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "common_prefix");
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[2].clone()),
+                Some(IRTerm::Constant(expected.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_is_ascii() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("hello, world", true),
+            ("", true),
+            ("PATH=/usr/bin:$PATH", true),
+            ("héllo", false),
+            ("日本語", false),
+            ("tab\tand\u{7f}", true),
+        ];
+        for (s, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("is_ascii".to_owned()),
+                args: vec![IRTerm::Constant(s.to_owned())],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "is_ascii");
+            assert_eq!(b.apply(&lit).is_some(), expected, "is_ascii({:?})", s);
+        }
+    }
+
+    #[test]
+    pub fn test_number_arithmetic() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("number_add", "8000", "80", Some("8080")),
+            ("number_add", "0.5", "0.25", Some("0.75")),
+            ("number_add", "1.5", "1.5", Some("3")),
+            ("number_sub", "10", "15", Some("-5")),
+            ("number_sub", "1", "1", Some("0")),
+            ("number_mul", "1024", "4", Some("4096")),
+            ("number_mul", "-1", "0", Some("0")),
+            ("number_div", "7", "2", Some("3.5")),
+            ("number_div", "9", "3", Some("3")),
+            ("number_div", "1", "0", None),
+            ("number_div", "0", "0", None),
+            ("number_mul", "1e300", "1e300", None),
+            ("number_add", "one", "1", None),
+        ];
+        for (name, a, b, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate(name.to_owned()),
+                args: vec![
+                    IRTerm::Constant(a.to_owned()),
+                    IRTerm::Constant(b.to_owned()),
+                    IRTerm::UserVariable("R".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), name);
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[2].clone()),
+                expected.map(|s| IRTerm::Constant(s.to_owned())),
+                "{}",
+                lit
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_run_is_safe() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("apt-get install -y curl", true),
+            ("echo sudo", true),
+            ("./configure && make", true),
+            ("sudo apt-get install -y curl", false),
+            ("su - postgres -c 'psql'", false),
+            ("cd /src && sudo make install", false),
+            ("DEBIAN_FRONTEND=noninteractive sudo apt-get update", false),
+            ("curl https://example.com/install.sh | doas sh", false),
+        ];
+        for (command, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("run_is_safe".to_owned()),
+                args: vec![IRTerm::Constant(command.to_owned())],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "run_is_safe");
+            assert_eq!(b.apply(&lit).is_some(), expected, "{}", command);
+            assert_eq!(b.failure_reason(&lit).is_none(), expected, "{}", command);
+        }
+
+        assert_eq!(
+            super::escalation_pattern("cd /src && sudo make install", super::ESCALATION_PATTERNS),
+            Some("sudo")
+        );
+        assert_eq!(
+            super::escalation_pattern("su - postgres", &["apt-get", "su -"]),
+            Some("su -")
+        );
+    }
+
+    #[test]
+    pub fn test_not_latest() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("alpine:3.18", true),
+            ("alpine:latest", false),
+            ("alpine", false),
+            ("library/alpine:3.18", true),
+            ("ghcr.io/modus-continens/modus:0.1", true),
+            ("ghcr.io/modus-continens/modus", false),
+            ("alpine@sha256:0123456789abcdef", true),
+        ];
+        for (image_ref, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("not_latest".to_owned()),
+                args: vec![IRTerm::Constant(image_ref.to_owned())],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "not_latest");
+            assert_eq!(b.apply(&lit).is_some(), expected, "{}", image_ref);
+        }
+    }
+
+    #[test]
+    pub fn test_is_valid_port() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("8080", true),
+            ("8080/tcp", true),
+            ("53/udp", true),
+            ("65535", true),
+            ("0", false),
+            ("65536", false),
+            ("8080/sctp", false),
+            ("8080/", false),
+            ("/tcp", false),
+            ("+80", false),
+            ("80-90", false),
+            ("http", false),
+            ("", false),
+        ];
+        for (spec, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("is_valid_port".to_owned()),
+                args: vec![IRTerm::Constant(spec.to_owned())],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "is_valid_port");
+            assert_eq!(
+                b.apply(&lit).is_some(),
+                expected,
+                "is_valid_port({:?})",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_is_integer() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("42", true),
+            ("-7", true),
+            ("+3", true),
+            ("0", true),
+            ("123456789012345678901234567890", true),
+            ("1.0", false),
+            ("3.14", false),
+            ("1e3", false),
+            ("", false),
+            ("-", false),
+            ("forty-two", false),
+        ];
+        for (s, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("is_integer".to_owned()),
+                args: vec![IRTerm::Constant(s.to_owned())],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "is_integer");
+            assert_eq!(b.apply(&lit).is_some(), expected, "is_integer({:?})", s);
+        }
+    }
+
+    #[test]
+    pub fn test_format_and_parse_bytes() {
+        use crate::logic::{Literal, Predicate};
+
+        fn apply(name: &str, arg: &str) -> Option<String> {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate(name.to_owned()),
+                args: vec![
+                    IRTerm::Constant(arg.to_owned()),
+                    IRTerm::UserVariable("R".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), name);
+            b.apply(&lit)
+                .map(|l| l.args[1].as_constant().unwrap().to_owned())
+        }
+
+        let cases = vec![
+            ("0", "0 B"),
+            ("1023", "1023 B"),
+            ("1024", "1 KiB"),
+            ("1536", "1.5 KiB"),
+            ("1048576", "1 MiB"),
+            ("1610612736", "1.5 GiB"),
+            ("5497558138880", "5 TiB"),
+        ];
+        for (n, human) in cases {
+            assert_eq!(apply("format_bytes", n).as_deref(), Some(human), "{}", n);
+            assert_eq!(apply("parse_bytes", human).as_deref(), Some(n), "{}", human);
+        }
+        assert_eq!(apply("format_bytes", "1048575").as_deref(), Some("1 MiB"));
+        assert_eq!(apply("format_bytes", "-1"), None);
+        assert_eq!(apply("format_bytes", "1.5"), None);
+
+        let cases = vec![
+            ("512", Some("512")),
+            ("10K", Some("10000")),
+            ("10KB", Some("10000")),
+            ("1Mi", Some("1048576")),
+            ("2 G", Some("2000000000")),
+            ("0.5Ki", Some("512")),
+            ("0.1Ki", None),
+            ("10 Xi", None),
+            ("10 kb", None),
+            ("GiB", None),
+            ("", None),
+        ];
+        for (human, expected) in cases {
+            assert_eq!(
+                apply("parse_bytes", human).as_deref(),
+                expected,
+                "{}",
+                human
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_semver_matches() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("1.2.3", ">=1.2.0, <2.0.0", true),
+            ("2.0.0", ">=1.2.0, <2.0.0", false),
+            ("1.1.9", ">=1.2.0, <2.0.0", false),
+            ("17", "^17", true),
+            ("11.0.2", "~11.0", true),
+            ("11.1.0", "~11.0", false),
+            ("not-a-version", ">=1.0.0", false),
+            ("1.0.0", "not a range", false),
+        ];
+        for (version, range, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("semver_matches".to_owned()),
+                args: vec![
+                    IRTerm::Constant(version.to_owned()),
+                    IRTerm::Constant(range.to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "semver_matches");
+            assert_eq!(
+                b.apply(&lit).is_some(),
+                expected,
+                "semver_matches({:?}, {:?})",
+                version,
+                range
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_version_compatible() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("1.2.3", "1.2.3", "exact", true),
+            ("1.2.3+build.5", "1.2.3", "exact", true),
+            ("1.2", "1.2.0", "exact", true),
+            ("1.2.4", "1.2.3", "exact", false),
+            ("1.2.3-rc.1", "1.2.3", "exact", false),
+            ("1.2.9", "1.2.3", "minor", true),
+            ("1.2.3", "1.2.3", "minor", true),
+            ("1.2.2", "1.2.3", "minor", false),
+            ("1.3.0", "1.2.3", "minor", false),
+            ("1.9.0", "1.2.3", "major", true),
+            ("1.2.3", "1.2.3", "major", true),
+            ("1.1.0", "1.2.3", "major", false),
+            ("2.0.0", "1.2.3", "major", false),
+            ("1.2.3", "1.2.3", "patch", false),
+            ("1.2.3", "1.2.3", "Exact", false),
+            ("not-a-version", "1.2.3", "major", false),
+            ("1.2.3", "latest", "major", false),
+        ];
+        for (installed, required, policy, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("version_compatible".to_owned()),
+                args: vec![
+                    IRTerm::Constant(installed.to_owned()),
+                    IRTerm::Constant(required.to_owned()),
+                    IRTerm::Constant(policy.to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "version_compatible");
+            assert_eq!(
+                b.apply(&lit).is_some(),
+                expected,
+                "version_compatible({:?}, {:?}, {:?})",
+                installed,
+                required,
+                policy
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_version_bump_kind() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("1.2.3", "2.0.0", Some("major")),
+            ("1.2.3", "1.3.0", Some("minor")),
+            ("1.2.3", "1.2.4", Some("patch")),
+            ("1.2.3-rc.1", "1.2.3", Some("patch")),
+            ("1.2.3", "1.2.3", Some("none")),
+            ("1.2", "1.2.0+build.7", Some("none")),
+            ("1.2.3", "1.2.2", None),
+            ("2.0.0", "1.9.9", None),
+            ("1.2.3", "1.2.3-rc.1", None),
+            ("not-a-version", "1.2.3", None),
+            ("1.2.3", "latest", None),
+        ];
+        for (old, new, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("version_bump_kind".to_owned()),
+                args: vec![
+                    IRTerm::Constant(old.to_owned()),
+                    IRTerm::Constant(new.to_owned()),
+                    IRTerm::UserVariable("K".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "version_bump_kind");
+            let kind = b
+                .apply(&lit)
+                .map(|res| res.args[2].as_constant().unwrap().to_owned());
+            assert_eq!(
+                kind.as_deref(),
+                expected,
+                "version_bump_kind({:?}, {:?}, K)",
+                old,
+                new
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_version_in_series() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("11.0.2", "11", true),
+            ("11.0.2", "11.0", true),
+            ("11.0.2", "11.0.2", true),
+            ("11.0.2-rc.1", "11.0", true),
+            ("11", "11.0", true),
+            ("11.1.0", "11.0", false),
+            ("110.0.0", "11", false),
+            ("11.0.20", "11.0.2", false),
+            ("1.10.0", "1.1", false),
+            ("11.0.2", "11.", false),
+            ("11.0.2", "11.x", false),
+            ("11.0.2", "11.0.2.0", false),
+            ("not-a-version", "11", false),
+        ];
+        for (version, series, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("version_in_series".to_owned()),
+                args: vec![
+                    IRTerm::Constant(version.to_owned()),
+                    IRTerm::Constant(series.to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "version_in_series");
+            assert_eq!(
+                b.apply(&lit).is_some(),
+                expected,
+                "version_in_series({:?}, {:?})",
+                version,
+                series
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_version_tag_series() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("1.2.3", Some(vec!["1", "1.2", "1.2.3"])),
+            ("0.10.0+build.5", Some(vec!["0", "0.10", "0.10.0"])),
+            ("2.0.0-rc.1", Some(vec!["2.0.0-rc.1"])),
+            ("1.2", None),
+            ("latest", None),
+        ];
+        for (version, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("version_tag_series".to_owned()),
+                args: vec![
+                    IRTerm::Constant(version.to_owned()),
+                    IRTerm::UserVariable("Tags".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "version_tag_series");
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[1].clone()),
+                expected.map(|tags| IRTerm::List(
+                    tags.into_iter()
+                        .map(|t| IRTerm::Constant(t.to_owned()))
+                        .collect()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_tag_newer() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("1.10.0", "1.9.3", true),
+            ("1.9.3", "1.10.0", false),
+            ("v2.0", "1.99.99", true),
+            ("1.2.3", "1.2.3", false),
+            ("1.2.0", "1.2.0-rc.1", true),
+            ("1.2", "1.2-rc1", true),
+            ("1.2-rc1", "1.2", false),
+            ("1.2-rc10", "1.2-rc2", true),
+            ("1.2.1-rc1", "1.2", true),
+            ("3.15-alpine", "3.9-alpine", true),
+            ("20220316", "20211201", true),
+        ];
+        for (a, b, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("tag_newer".to_owned()),
+                args: vec![
+                    IRTerm::Constant(a.to_owned()),
+                    IRTerm::Constant(b.to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "tag_newer");
+            assert_eq!(b.apply(&lit).is_some(), expected, "{}", lit);
+        }
+    }
+
+    #[test]
+    pub fn test_to_json() {
+        use crate::logic::{Literal, Predicate};
+
+        let pairs = |pairs: &[(&str, &str)]| {
+            IRTerm::List(
+                pairs
+                    .iter()
+                    .map(|(k, v)| {
+                        IRTerm::List(vec![
+                            IRTerm::Constant((*k).to_owned()),
+                            IRTerm::Constant((*v).to_owned()),
+                        ])
+                    })
+                    .collect(),
+            )
+        };
+        let cases = vec![
+            (pairs(&[("a", "b")]), Some(r#"{"a":"b"}"#)),
+            (pairs(&[]), Some("{}")),
+            (
+                pairs(&[
+                    ("port", "8080"),
+                    ("debug", "true"),
+                    ("ratio", "-0.5"),
+                    ("version", "1.0.0"),
+                    ("big", "1e3"),
+                    ("id", "007"),
+                    ("quote", "say \"hi\""),
+                ]),
+                Some(
+                    r#"{"big":"1e3","debug":true,"id":"007","port":8080,"quote":"say \"hi\"","ratio":-0.5,"version":"1.0.0"}"#,
+                ),
+            ),
+            (pairs(&[("a", "b"), ("a", "c")]), None),
+            (
+                IRTerm::List(vec![IRTerm::List(vec![IRTerm::Constant("a".to_owned())])]),
+                None,
+            ),
+            (IRTerm::Constant("a".to_owned()), None),
+        ];
+        for (arg, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("to_json".to_owned()),
+                args: vec![arg, IRTerm::UserVariable("Json".to_owned())],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "to_json");
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[1].clone()),
+                expected.map(|j| IRTerm::Constant(j.to_owned())),
+                "{}",
+                lit
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_from_json() {
+        use crate::logic::{Literal, Predicate};
+
+        let c = |s: &str| IRTerm::Constant(s.to_owned());
+        let cases = vec![
+            (
+                r#"{"name": "app", "port": 8080, "tags": ["a", "b"], "debug": false}"#,
+                Some(IRTerm::List(vec![
+                    IRTerm::List(vec![c("debug"), c("false")]),
+                    IRTerm::List(vec![c("name"), c("app")]),
+                    IRTerm::List(vec![c("port"), c("8080")]),
+                    IRTerm::List(vec![c("tags"), IRTerm::List(vec![c("a"), c("b")])]),
+                ])),
+            ),
+            (
+                r#"[1, "two", null, [], {}]"#,
+                Some(IRTerm::List(vec![
+                    c("1"),
+                    c("two"),
+                    c("null"),
+                    IRTerm::List(vec![]),
+                    IRTerm::List(vec![]),
+                ])),
+            ),
+            (r#""text""#, Some(c("text"))),
+            (r#"{"a": }"#, None),
+            ("[1, 2", None),
+            ("", None),
+        ];
+        for (json, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("from_json".to_owned()),
+                args: vec![c(json), IRTerm::UserVariable("Value".to_owned())],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "from_json");
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[1].clone()),
+                expected,
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_substring_relations() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("string_contains", "3.15-alpine-slim", "-alpine", true),
+            ("string_contains", "3.15-slim", "-alpine", false),
+            ("string_contains", "anything", "", true),
+            ("string_prefix", "3.15-alpine", "3.", true),
+            ("string_prefix", "13.15-alpine", "3.", false),
+            ("string_suffix", "3.15-alpine", "-alpine", true),
+            ("string_suffix", "3.15-alpine-slim", "-alpine", false),
+        ];
+        for (name, s, sub, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate(name.to_owned()),
+                args: vec![
+                    IRTerm::Constant(s.to_owned()),
+                    IRTerm::Constant(sub.to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), name);
+            assert_eq!(
+                b.apply(&lit),
+                if expected { Some(lit.clone()) } else { None },
+                "{}({:?}, {:?})",
+                name,
+                s,
+                sub
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_list_length_and_member() {
+        use crate::logic::{Literal, Predicate};
+
+        let ab = IRTerm::List(vec![
+            IRTerm::Constant("a".to_owned()),
+            IRTerm::Constant("b".to_owned()),
+        ]);
+        let lit = |name: &str, args: Vec<IRTerm>| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate(name.to_owned()),
+            args,
+        };
+
+        let length = lit(
+            "list_length",
+            vec![ab.clone(), IRTerm::UserVariable("N".to_owned())],
+        );
+        let b = super::select_builtin(&length);
+        assert!(b.0.is_match());
+        let b = b.1.unwrap();
+        assert_eq!(b.name(), "list_length");
+        assert_eq!(
+            b.apply(&length).map(|l| l.args[1].clone()),
+            Some(IRTerm::Constant("2".to_owned()))
+        );
+        let length = lit(
+            "list_length",
+            vec![ab.clone(), IRTerm::Constant("2".to_owned())],
+        );
+        assert_eq!(b.apply(&length), Some(length.clone()));
+        let length = lit(
+            "list_length",
+            vec![ab.clone(), IRTerm::Constant("3".to_owned())],
+        );
+        assert_eq!(b.apply(&length), None);
+
+        let member = lit(
+            "list_member",
+            vec![ab.clone(), IRTerm::Constant("a".to_owned())],
+        );
+        let b = super::select_builtin(&member);
+        assert!(b.0.is_match());
+        let b = b.1.unwrap();
+        assert_eq!(b.name(), "list_member");
+        assert_eq!(b.apply(&member), Some(member.clone()));
+        let member = lit("list_member", vec![ab, IRTerm::Constant("c".to_owned())]);
+        assert_eq!(b.apply(&member), None);
+    }
+
+    #[test]
+    pub fn test_lookup() {
+        use crate::logic::{Literal, Predicate};
+
+        let c = |s: &str| IRTerm::Constant(s.to_owned());
+        let pairs = IRTerm::List(vec![
+            IRTerm::List(vec![c("debian"), c("debian:bullseye")]),
+            IRTerm::List(vec![c("alpine"), c("alpine:3.15")]),
+        ]);
+        let lit = |key: &str| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("lookup".to_owned()),
+            args: vec![c(key), pairs.clone(), IRTerm::UserVariable("V".to_owned())],
+        };
+
+        let present = lit("alpine");
+        let b = super::select_builtin(&present);
+        assert!(b.0.is_match());
+        let b = b.1.unwrap();
+        assert_eq!(b.name(), "lookup");
+        assert_eq!(
+            b.apply(&present).map(|l| l.args[2].clone()),
+            Some(c("alpine:3.15"))
+        );
+        assert_eq!(b.apply(&lit("ubuntu")), None);
+    }
+
+    #[test]
+    pub fn test_all_match() {
+        use crate::logic::{Literal, Predicate};
+
+        let c = |s: &str| IRTerm::Constant(s.to_owned());
+        let lit = |elems: &[&str], pattern: &str| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("all_match".to_owned()),
+            args: vec![
+                IRTerm::List(elems.iter().map(|s| c(s)).collect()),
+                c(pattern),
+            ],
+        };
+
+        let cases = vec![
+            (lit(&["1.2", "3.4.5", "10.0"], r"^\d+(\.\d+)*$"), true),
+            (lit(&[], r"^\d+$"), true),
+            (lit(&["1.2", "latest", "10.0"], r"^\d+(\.\d+)*$"), false),
+            (lit(&["python3"], "python"), true),
+            (lit(&["python3"], "^python$"), false),
+            (lit(&["a"], "("), false),
+        ];
+        for (lit, expected) in cases {
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "all_match");
+            assert_eq!(b.apply(&lit).is_some(), expected, "{}", lit);
+        }
+    }
+
+    #[test]
+    pub fn test_container_path() {
+        use crate::logic::{Literal, Predicate};
+
+        let container_path = |workdir: &str, rel: &str| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("container_path".to_owned()),
+            args: vec![
+                IRTerm::Constant(workdir.to_owned()),
+                IRTerm::Constant(rel.to_owned()),
+                IRTerm::UserVariable("P".to_owned()),
+            ],
+        };
+
+        for (workdir, rel) in [
+            ("/app", "src"),
+            ("/app/", "src/main.rs"),
+            ("/app", "/etc/config"),
+            ("", "relative"),
+            ("/", "."),
+        ] {
+            let lit = container_path(workdir, rel);
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "container_path");
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[2].clone()),
+                Some(IRTerm::Constant(super::path::join_path(workdir, rel)))
+            );
+        }
+
+        let lit = container_path("/app", "src");
+        let b = super::select_builtin(&lit).1.unwrap();
+        assert_eq!(
+            b.apply(&lit).map(|l| l.args[2].clone()),
+            Some(IRTerm::Constant("/app/src".to_owned()))
+        );
+        let lit = container_path("/app", "/etc/config");
+        assert_eq!(
+            b.apply(&lit).map(|l| l.args[2].clone()),
+            Some(IRTerm::Constant("/etc/config".to_owned()))
+        );
+    }
+
+    #[test]
+    pub fn test_no_path_collision() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            (vec!["src/main.rs", "src/lib.rs", "Cargo.toml"], true),
+            (vec!["build/", "/tmp/cache"], true),
+            (vec![], true),
+            (vec!["a/config.json", "b/config.json"], false),
+            (vec!["out/bin/", "bin"], false),
+            (vec!["src/..", "README"], false),
+        ];
+        for (paths, distinct) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("no_path_collision".to_owned()),
+                args: vec![IRTerm::List(
+                    paths
+                        .iter()
+                        .map(|p| IRTerm::Constant(p.to_string()))
+                        .collect(),
+                )],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "no_path_collision");
+            assert_eq!(b.apply(&lit).is_some(), distinct, "{:?}", paths);
+        }
+    }
+
+    #[test]
+    pub fn test_list_difference() {
+        use crate::logic::{Literal, Predicate};
+
+        fn list(items: &[&str]) -> IRTerm {
+            IRTerm::List(
+                items
+                    .iter()
+                    .map(|s| IRTerm::Constant(s.to_string()))
+                    .collect(),
+            )
+        }
+
+        let cases = vec![
+            (
+                list(&["curl", "git", "vim", "make"]),
+                list(&["vim", "emacs"]),
+                list(&["curl", "git", "make"]),
+            ),
+            (list(&["a", "b"]), list(&["c", "d"]), list(&["a", "b"])),
+            (list(&["a", "b", "a"]), list(&["b"]), list(&["a", "a"])),
+            (list(&["a", "b"]), list(&["b", "a"]), list(&[])),
+            (list(&[]), list(&["a"]), list(&[])),
+        ];
+        for (a, b, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("list_difference".to_owned()),
+                args: vec![a, b, IRTerm::UserVariable("R".to_owned())],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "list_difference");
+            assert_eq!(b.apply(&lit).map(|l| l.args[2].clone()), Some(expected));
+        }
+    }
+
+    #[test]
+    pub fn test_string_length() {
+        use crate::logic::{Literal, Predicate};
+
+        let string_length = |s: &str, length: IRTerm| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("string_length".to_owned()),
+            args: vec![IRTerm::Constant(s.to_owned()), length],
+        };
+
+        for (s, expected) in [("abc", "3"), ("", "0"), ("héllo", "5"), ("日本語", "3")] {
+            let lit = string_length(s, IRTerm::UserVariable("L".to_owned()));
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "string_length");
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[1].clone()),
+                Some(IRTerm::Constant(expected.to_owned()))
+            );
+        }
+
+        let lit = string_length("abc", IRTerm::Constant("3".to_owned()));
+        let b = super::select_builtin(&lit).1.unwrap();
+        assert_eq!(b.apply(&lit), Some(lit));
+        let lit = string_length("abc", IRTerm::Constant("4".to_owned()));
+        assert_eq!(b.apply(&lit), None);
+    }
+
+    #[test]
+    pub fn test_string_split() {
+        use crate::logic::{Literal, Predicate};
+
+        let string_split = |s: &str, sep: &str| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("string_split".to_owned()),
+            args: vec![
+                IRTerm::Constant(s.to_owned()),
+                IRTerm::Constant(sep.to_owned()),
+                IRTerm::UserVariable("X".to_owned()),
+            ],
+        };
+        let list = |items: &[&str]| {
+            IRTerm::List(
+                items
+                    .iter()
+                    .map(|item| IRTerm::Constant(item.to_string()))
+                    .collect(),
+            )
+        };
+
+        for (s, sep, expected) in [
+            ("a,b,c", ",", list(&["a", "b", "c"])),
+            ("a, b", ", ", list(&["a", "b"])),
+            ("a,,b", ",", list(&["a", "", "b"])),
+            ("abc", ";", list(&["abc"])),
+            ("abc", "", list(&["a", "b", "c"])),
+        ] {
+            let lit = string_split(s, sep);
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "string_split");
+            assert_eq!(b.apply(&lit).map(|l| l.args[2].clone()), Some(expected));
+        }
+    }
+
+    #[test]
+    pub fn test_slugify() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("Hello World", "hello-world"),
+            ("feature/New_UI", "feature-new-ui"),
+            ("--release: 1.0!--", "release-1-0"),
+            ("a   b//c", "a-b-c"),
+            ("app(\"1.0\", \"debug\")", "app-1-0-debug"),
+            ("café", "caf"),
+            ("...", ""),
+        ];
+        for (s, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("slugify".to_owned()),
+                args: vec![
+                    IRTerm::Constant(s.to_owned()),
+                    IRTerm::UserVariable("X".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "slugify");
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[1].clone()),
+                Some(IRTerm::Constant(expected.to_owned())),
+                "slugify({:?})",
+                s
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_string_case() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("string_to_lower", "ALPINE", "alpine"),
+            ("string_to_lower", "My-Image_1.0", "my-image_1.0"),
+            ("string_to_upper", "alpine", "ALPINE"),
+            ("string_to_upper", "straße", "STRASSE"),
+        ];
+        for (name, s, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate(name.to_owned()),
+                args: vec![
+                    IRTerm::Constant(s.to_owned()),
+                    IRTerm::UserVariable("X".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), name);
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[1].clone()),
+                Some(IRTerm::Constant(expected.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_is_valid_chown() {
+        for valid in [
+            "app",
+            "1000",
+            "app:app",
+            "1000:1000",
+            "www-data:www-data",
+            "a.b:c_d",
+        ] {
+            assert!(super::is_valid_chown(valid), "{}", valid);
+        }
+        for invalid in [
+            "",
+            ":",
+            "app:",
+            ":app",
+            "a:b:c",
+            "-R",
+            "app:-R",
+            "app group",
+            "$(id)",
+        ] {
+            assert!(!super::is_valid_chown(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    pub fn test_is_valid_chmod() {
+        for valid in ["755", "0755", "644", "4755", "000"] {
+            assert!(super::is_valid_chmod(valid), "{}", valid);
         }
-    }};
-}
+        for invalid in ["", "75", "07555", "0758", "rwx", "+x", "u+x"] {
+            assert!(!super::is_valid_chmod(invalid), "{}", invalid);
+        }
+    }
 
-pub fn select_builtin<'a>(
-    lit: &Literal,
-) -> (SelectBuiltinResult, Option<&'a dyn BuiltinPredicate>) {
-    select_builtins!(
-        lit,
-        string_concat::StringConcat1,
-        string_concat::StringConcat2,
-        string_concat::StringConcat3,
-        run,
-        from,
-        _operator_copy_begin,
-        _operator_copy_end,
-        _operator_in_workdir_begin,
-        _operator_in_workdir_end,
-        _operator_set_workdir_begin,
-        _operator_set_workdir_end,
-        _operator_set_entrypoint_begin,
-        _operator_set_entrypoint_end,
-        _operator_set_cmd_begin,
-        _operator_set_cmd_end,
-        _operator_set_label_begin,
-        _operator_set_label_end,
-        _operator_set_env_begin,
-        _operator_set_env_end,
-        _operator_in_env_begin,
-        _operator_in_env_end,
-        _operator_append_path_begin,
-        _operator_append_path_end,
-        _operator_set_user_begin,
-        _operator_set_user_end,
-        copy,
-        equality::StringEq1,
-        equality::StringEq2,
-        _operator_merge_begin,
-        _operator_merge_end,
-        number::number_eq,
-        number::number_gt,
-        number::number_lt,
-        number::number_geq,
-        number::number_leq,
-        semver::semver_exact,
-        semver::semver_gt,
-        semver::semver_lt,
-        semver::semver_geq,
-        semver::semver_leq,
-    )
-}
+    #[test]
+    pub fn test_is_sha256() {
+        assert!(super::is_sha256(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        ));
+        assert!(super::is_sha256(
+            "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855"
+        ));
+        assert!(!super::is_sha256(""));
+        assert!(!super::is_sha256(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85"
+        ));
+        assert!(!super::is_sha256(
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        ));
+    }
 
-lazy_static! {
-    // An operator can take an expression of one kind and produce another kind.
-    pub static ref OPERATOR_KIND_MAP: HashMap<&'static str, (Kind, Kind)> = {
-        let mut m = HashMap::new();
-        m.insert("copy", (Kind::Image, Kind::Layer));
-        m.insert("set_env", (Kind::Image, Kind::Image));
-        m.insert("set_entrypoint", (Kind::Image, Kind::Image));
-        m.insert("set_cmd", (Kind::Image, Kind::Image));
-        m.insert("set_workdir", (Kind::Image, Kind::Image));
-        m.insert("set_label", (Kind::Image, Kind::Image));
-        m.insert("set_user", (Kind::Image, Kind::Image));
-        m.insert("append_path", (Kind::Image, Kind::Image));
-        m.insert("in_workdir", (Kind::Layer, Kind::Layer));
-        m.insert("in_env", (Kind::Layer, Kind::Layer));
-        m.insert("merge", (Kind::Layer, Kind::Layer));
-        m
-    };
-}
+    #[test]
+    pub fn test_is_valid_signal() {
+        for valid in ["SIGTERM", "SIGKILL", "SIGSYS", "9", "15", "31"] {
+            assert!(super::is_valid_signal(valid), "{}", valid);
+        }
+        for invalid in [
+            "TERM", "SIG", "sigterm", "SIGTERM ", "SIGRTMIN", "SIGINFO", "0", "32", "64", "+9",
+            "-9", "",
+        ] {
+            assert!(!super::is_valid_signal(invalid), "{}", invalid);
+        }
+        assert_eq!(super::signal_name("15"), Some("SIGTERM"));
+        assert_eq!(super::signal_name("SIGHUP"), Some("SIGHUP"));
+    }
 
-#[cfg(test)]
-mod test {
-    use crate::{analysis::Kind, builtin::SelectBuiltinResult, logic::IRTerm};
+    #[test]
+    pub fn test_parse_port_spec() {
+        assert_eq!(super::parse_port_spec("8080"), Some((8080, "tcp")));
+        assert_eq!(super::parse_port_spec("53/udp"), Some((53, "udp")));
+        assert_eq!(super::parse_port_spec("443/tcp"), Some((443, "tcp")));
+        for invalid in ["0", "http", "80/sctp", "70000", "+80", "", "/udp"] {
+            assert_eq!(super::parse_port_spec(invalid), None, "{}", invalid);
+        }
+    }
 
     #[test]
-    pub fn test_select() {
+    pub fn test_duration_nanos() {
+        use super::duration_nanos;
+
+        assert_eq!(duration_nanos("30s"), Some(30_000_000_000));
+        assert_eq!(duration_nanos("1m30s"), Some(90_000_000_000));
+        assert_eq!(duration_nanos("1.5h"), Some(5_400_000_000_000));
+        assert_eq!(duration_nanos("500ms"), Some(500_000_000));
+        assert_eq!(duration_nanos(""), None);
+        assert_eq!(duration_nanos("30"), None);
+        assert_eq!(duration_nanos("1d"), None);
+    }
+
+    #[test]
+    pub fn test_string_trim() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("string_trim", "  hi \n", "hi"),
+            ("string_trim", "1.2.3\r\n", "1.2.3"),
+            ("string_trim", "\u{3000}wide\u{3000}", "wide"),
+            ("string_trim", "a b", "a b"),
+            ("string_trim_start", "  hi \n", "hi \n"),
+            ("string_trim_end", "  hi \n", "  hi"),
+        ];
+        for (name, s, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate(name.to_owned()),
+                args: vec![
+                    IRTerm::Constant(s.to_owned()),
+                    IRTerm::UserVariable("X".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), name);
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[1].clone()),
+                Some(IRTerm::Constant(expected.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_string_replace() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("1.2.3", ".", "_", "1_2_3"),
+            ("1.2.3", ".", "", "123"),
+            ("aaa", "aa", "b", "ba"),
+            ("alpine", "x", "y", "alpine"),
+            ("alpine", "", "y", "alpine"),
+        ];
+        for (s, from, to, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("string_replace".to_owned()),
+                args: vec![
+                    IRTerm::Constant(s.to_owned()),
+                    IRTerm::Constant(from.to_owned()),
+                    IRTerm::Constant(to.to_owned()),
+                    IRTerm::UserVariable("X".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "string_replace");
+            assert_eq!(
+                b.apply(&lit).map(|l| l.args[3].clone()),
+                Some(IRTerm::Constant(expected.to_owned()))
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_dockerfile_escape() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("simple", r#""simple""#),
+            ("with spaces", r#""with spaces""#),
+            (r#"say "hi""#, r#""say \"hi\"""#),
+            ("C:\\temp", r#""C:\\temp""#),
+            ("$HOME", r#""\$HOME""#),
+            ("line1\nline2\r\n", r#""line1\nline2\r\n""#),
+        ];
+        for (raw, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("dockerfile_escape".to_owned()),
+                args: vec![
+                    IRTerm::Constant(raw.to_owned()),
+                    IRTerm::UserVariable("E".to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "dockerfile_escape");
+            let escaped = b.apply(&lit).unwrap().args[1]
+                .as_constant()
+                .unwrap()
+                .to_owned();
+            assert_eq!(escaped, expected);
+
+            // The result must stay on one line and read back as one word through a Dockerfile.
+            assert!(!escaped.contains('\n') && !escaped.contains('\r'));
+            let df: crate::dockerfile::Dockerfile<_> =
+                format!("FROM alpine\nENV KEY={}\nRUN true", escaped)
+                    .parse()
+                    .unwrap();
+            assert_eq!(df.0.len(), 3);
+        }
+    }
+
+    #[test]
+    pub fn test_path_within() {
+        use crate::logic::{Literal, Predicate};
+
+        let cases = vec![
+            ("/app/src/main.rs", "/app", true),
+            ("/app/./src/../bin", "/app/", true),
+            ("app/data", "app", true),
+            ("/app", "/app", true),
+            ("/app/src/..", "/app", true),
+            ("/app/../etc/passwd", "/app", false),
+            ("../outside", ".", false),
+            ("/application", "/app", false),
+            ("/app", "/app/src", false),
+            ("app", "/app", false),
+        ];
+        for (child, parent, expected) in cases {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("path_within".to_owned()),
+                args: vec![
+                    IRTerm::Constant(child.to_owned()),
+                    IRTerm::Constant(parent.to_owned()),
+                ],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), "path_within");
+            assert_eq!(
+                b.apply(&lit).is_some(),
+                expected,
+                "path_within({:?}, {:?})",
+                child,
+                parent
+            );
+        }
+    }
+
+    #[test]
+    #[serial]
+    pub fn test_git_revision() {
         use crate::logic::{Literal, Predicate};
+        use std::process::Command;
+
+        let repo = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(["-c", "user.name=modus", "-c", "user.email=modus@localhost"])
+                .args(args)
+                .current_dir(repo.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "initial"]);
+        let head = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .current_dir(repo.path())
+            .output()
+            .unwrap();
+        let head = String::from_utf8(head.stdout).unwrap().trim().to_owned();
 
         let lit = Literal {
             positive: true,
             position: None,
-            predicate: Predicate("run".to_owned()),
-            args: vec![IRTerm::Constant("hello".to_owned())],
+            predicate: Predicate("git_revision".to_owned()),
+            args: vec![IRTerm::UserVariable("Rev".to_owned())],
         };
         let b = super::select_builtin(&lit);
         assert!(b.0.is_match());
         let b = b.1.unwrap();
-        assert_eq!(b.name(), "run");
-        assert_eq!(b.kind(), Kind::Layer);
-        assert_eq!(b.apply(&lit), Some(lit));
+        assert_eq!(b.name(), "git_revision");
 
-        let lit = Literal {
+        super::set_impure_builtin_config(super::ImpureBuiltinConfig {
+            allow_impure: false,
+            context_dir: repo.path().to_owned(),
+        });
+        assert_eq!(b.apply(&lit), None);
+        assert!(b.failure_reason(&lit).unwrap().contains("--allow-impure"));
+
+        super::set_impure_builtin_config(super::ImpureBuiltinConfig {
+            allow_impure: true,
+            context_dir: repo.path().to_owned(),
+        });
+        assert_eq!(
+            b.apply(&lit).map(|l| l.args[0].clone()),
+            Some(IRTerm::Constant(head))
+        );
+
+        let not_a_repo = tempfile::tempdir().unwrap();
+        super::set_impure_builtin_config(super::ImpureBuiltinConfig {
+            allow_impure: true,
+            context_dir: not_a_repo.path().to_owned(),
+        });
+        assert_eq!(b.apply(&lit), None);
+        assert!(b
+            .failure_reason(&lit)
+            .unwrap()
+            .ends_with("is not a git repository."));
+
+        super::set_impure_builtin_config(Default::default());
+    }
+
+    #[test]
+    #[serial]
+    pub fn test_env() {
+        use crate::logic::{Literal, Predicate};
+
+        let lit = |name: &str| Literal {
             positive: true,
             position: None,
-            predicate: Predicate("string_concat".to_owned()),
+            predicate: Predicate("env".to_owned()),
             args: vec![
-                IRTerm::Constant("hello".to_owned()),
-                IRTerm::Constant("world".to_owned()),
-                IRTerm::UserVariable("X".to_owned()),
+                IRTerm::Constant(name.to_owned()),
+                IRTerm::UserVariable("Value".to_owned()),
             ],
         };
-        let b = super::select_builtin(&lit);
+        std::env::set_var("MODUS_TEST_ENV_BUILTIN", "abc123");
+        std::env::remove_var("MODUS_TEST_ENV_BUILTIN_UNSET");
+        let b = super::select_builtin(&lit("MODUS_TEST_ENV_BUILTIN"));
         assert!(b.0.is_match());
         let b = b.1.unwrap();
-        assert_eq!(b.name(), "string_concat");
-        assert_eq!(b.kind(), Kind::Logic);
+        assert_eq!(b.name(), "env");
+
+        super::set_impure_builtin_config(Default::default());
+        assert_eq!(b.apply(&lit("MODUS_TEST_ENV_BUILTIN")), None);
+
+        super::set_impure_builtin_config(super::ImpureBuiltinConfig {
+            allow_impure: true,
+            ..Default::default()
+        });
         assert_eq!(
-            b.apply(&lit),
-            Some(Literal {
-                positive: true,
-                position: None,
-                predicate: Predicate("string_concat".to_owned()),
-                args: vec![
-                    IRTerm::Constant("hello".to_owned()),
-                    IRTerm::Constant("world".to_owned()),
-                    IRTerm::Constant("helloworld".to_owned()),
-                ]
-            })
+            b.apply(&lit("MODUS_TEST_ENV_BUILTIN"))
+                .map(|l| l.args[1].clone()),
+            Some(IRTerm::Constant("abc123".to_owned()))
         );
+        assert_eq!(b.apply(&lit("MODUS_TEST_ENV_BUILTIN_UNSET")), None);
 
-        let lit = Literal {
+        std::env::remove_var("MODUS_TEST_ENV_BUILTIN");
+        super::set_impure_builtin_config(Default::default());
+    }
+
+    #[test]
+    #[serial]
+    pub fn test_context_files_hash() {
+        use crate::logic::{Literal, Predicate};
+
+        let context = tempfile::tempdir().unwrap();
+        std::fs::create_dir(context.path().join("src")).unwrap();
+        std::fs::write(context.path().join("src/a.rs"), "fn a() {}").unwrap();
+        std::fs::write(context.path().join("src/b.rs"), "fn b() {}").unwrap();
+        std::fs::write(context.path().join("README.md"), "readme").unwrap();
+        super::set_impure_builtin_config(super::ImpureBuiltinConfig {
+            allow_impure: false,
+            context_dir: context.path().to_owned(),
+        });
+
+        let lit = |pattern: &str| Literal {
             positive: true,
             position: None,
-            predicate: Predicate("xxx".to_owned()),
-            args: vec![IRTerm::Constant("hello".to_owned())],
+            predicate: Predicate("context_files_hash".to_owned()),
+            args: vec![
+                IRTerm::Constant(pattern.to_owned()),
+                IRTerm::UserVariable("Hash".to_owned()),
+            ],
         };
-        let b = super::select_builtin(&lit);
-        assert_eq!(b.0, SelectBuiltinResult::NoMatch);
+        let b = super::select_builtin(&lit("src/*.rs"));
+        assert!(b.0.is_match());
+        let b = b.1.unwrap();
+        assert_eq!(b.name(), "context_files_hash");
+        let hash = |pattern: &str| b.apply(&lit(pattern)).map(|l| l.args[1].clone());
+
+        let before = hash("src/*.rs").unwrap();
+        assert_eq!(hash("src/*.rs"), Some(before.clone()));
+        assert_eq!(before.as_constant().unwrap().len(), 64);
+
+        std::fs::write(context.path().join("README.md"), "changed readme").unwrap();
+        assert_eq!(hash("src/*.rs"), Some(before.clone()));
+        std::fs::write(context.path().join("src/b.rs"), "fn b() { }").unwrap();
+        assert_ne!(hash("src/*.rs"), Some(before));
+
+        assert_eq!(hash("*.txt"), None);
+        assert_eq!(hash("../*"), None);
+        assert_eq!(hash("/etc/*"), None);
+
+        super::set_impure_builtin_config(Default::default());
+    }
+
+    #[test]
+    #[serial]
+    pub fn test_load_env_facts() {
+        use crate::logic::{Literal, Predicate};
+
+        let context = tempfile::tempdir().unwrap();
+        std::fs::write(
+            context.path().join("build.env"),
+            "# build settings\n\
+             VERSION=1.2.3\n\
+             \n\
+             export NAME = 'my app' # the display name\n\
+             GREETING=\"hello \\\"world\\\"\"\n\
+             EMPTY=\n",
+        )
+        .unwrap();
+        std::fs::write(context.path().join("bad.env"), "VERSION\n").unwrap();
+        super::set_impure_builtin_config(super::ImpureBuiltinConfig {
+            allow_impure: false,
+            context_dir: context.path().to_owned(),
+        });
+
+        let lit = |path: &str| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("load_env_facts".to_owned()),
+            args: vec![
+                IRTerm::Constant(path.to_owned()),
+                IRTerm::UserVariable("Pairs".to_owned()),
+            ],
+        };
+        let b = super::select_builtin(&lit("build.env"));
+        assert!(b.0.is_match());
+        let b = b.1.unwrap();
+        assert_eq!(b.name(), "load_env_facts");
+
+        let pair = |k: &str, v: &str| {
+            IRTerm::List(vec![
+                IRTerm::Constant(k.to_owned()),
+                IRTerm::Constant(v.to_owned()),
+            ])
+        };
+        assert_eq!(
+            b.apply(&lit("build.env")).map(|l| l.args[1].clone()),
+            Some(IRTerm::List(vec![
+                pair("VERSION", "1.2.3"),
+                pair("NAME", "my app"),
+                pair("GREETING", "hello \"world\""),
+                pair("EMPTY", ""),
+            ]))
+        );
+        assert_eq!(b.apply(&lit("bad.env")), None);
+        assert_eq!(b.apply(&lit("missing.env")), None);
+        assert_eq!(b.apply(&lit("../build.env")), None);
+
+        super::set_impure_builtin_config(Default::default());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[serial]
+    pub fn test_read_file() {
+        use crate::logic::{Literal, Predicate};
+
+        let context = tempfile::tempdir().unwrap();
+        std::fs::write(context.path().join("VERSION"), "1.4.2\n").unwrap();
+        std::fs::create_dir(context.path().join("config")).unwrap();
+        std::fs::write(context.path().join("config/name"), "  my app ").unwrap();
+        super::set_impure_builtin_config(super::ImpureBuiltinConfig {
+            allow_impure: false,
+            context_dir: context.path().join("config"),
+        });
+
+        let lit = |path: &str| Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("read_file".to_owned()),
+            args: vec![
+                IRTerm::Constant(path.to_owned()),
+                IRTerm::UserVariable("Content".to_owned()),
+            ],
+        };
+        let b = super::select_builtin(&lit("name"));
+        assert!(b.0.is_match());
+        let b = b.1.unwrap();
+        assert_eq!(b.name(), "read_file");
+
+        assert_eq!(
+            b.apply(&lit("./name")).map(|l| l.args[1].clone()),
+            Some(IRTerm::Constant("my app".to_owned()))
+        );
+        assert_eq!(b.apply(&lit("missing")), None);
+        assert_eq!(b.apply(&lit("../VERSION")), None);
+        let absolute = context.path().join("VERSION");
+        assert_eq!(b.apply(&lit(absolute.to_str().unwrap())), None);
+
+        super::set_impure_builtin_config(super::ImpureBuiltinConfig {
+            allow_impure: false,
+            context_dir: context.path().to_owned(),
+        });
+        assert_eq!(
+            b.apply(&lit("VERSION")).map(|l| l.args[1].clone()),
+            Some(IRTerm::Constant("1.4.2".to_owned()))
+        );
+
+        super::set_impure_builtin_config(Default::default());
+    }
+
+    #[test]
+    #[serial]
+    pub fn test_host_ids() {
+        use crate::logic::{Literal, Predicate};
+
+        for name in ["host_uid", "host_gid"] {
+            let lit = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate(name.to_owned()),
+                args: vec![IRTerm::UserVariable("Id".to_owned())],
+            };
+            let b = super::select_builtin(&lit);
+            assert!(b.0.is_match());
+            let b = b.1.unwrap();
+            assert_eq!(b.name(), name);
+
+            super::set_impure_builtin_config(Default::default());
+            assert_eq!(b.apply(&lit), None);
+
+            super::set_impure_builtin_config(super::ImpureBuiltinConfig {
+                allow_impure: true,
+                ..Default::default()
+            });
+            let id = b.apply(&lit).unwrap().args[0]
+                .as_constant()
+                .unwrap()
+                .to_owned();
+            assert!(id.parse::<u32>().is_ok(), "{} gave {:?}", name, id);
+        }
+
+        super::set_impure_builtin_config(Default::default());
     }
 
     #[test]
@@ -683,7 +4914,7 @@ mod test {
             predicate: Predicate("a".to_owned()),
             args: vec![],
         }];
-        let tree = crate::sld::sld(&rules, &goals, 100, true).tree;
+        let tree = crate::sld::sld(&rules, &goals, 100, true, false).tree;
         let solutions = crate::sld::solutions(&tree);
         assert_eq!(solutions.len(), 1);
         assert!(solutions.contains(&goals));
@@ -699,6 +4930,7 @@ mod test {
             (
                 "number_eq",
                 vec![
+                    ("42", "42.0"),
                     ("1", "1"),
                     ("1.0", "1"),
                     ("0.0", "0.0"),
@@ -709,12 +4941,16 @@ mod test {
                     ("42.0", "42.0"),
                 ],
                 vec![
+                    ("1", "2"),
                     ("0", "1"),
                     ("0", "0.01"),
                     ("1", "-1"),
                     ("1e-10", "0"),
                     ("42.0", "-273.15"),
                     ("NaN", "NaN"),
+                    ("NaN", "1"),
+                    ("1", "nan"),
+                    ("one", "one"),
                 ],
             ),
             (
@@ -735,12 +4971,14 @@ mod test {
             (
                 "number_lt",
                 vec![
+                    ("1", "2"),
                     ("0", "1"),
                     ("0", "1e-10"),
                     ("-273.15", "42.0"),
                     ("0", "1e100"),
                 ],
                 vec![
+                    ("2", "1"),
                     ("42.0", "42.0"),
                     ("42.1", "42.0"),
                     ("1e-10", "0"),
@@ -62,19 +62,19 @@ pub enum Instruction<P> {
     Cmd(String),
     Label(String, String),
     // Maintainer(String),
-    // Expose(String),
+    Expose(String),
     Env(Env),
-    // Add(String),
+    Add(String),
     Copy(Copy),
     Entrypoint(String),
     // Volume(String),
-    // User(String),
+    User(String),
     Workdir(Workdir),
     Arg(Arg),
     // Onbuild(String),
-    // Stopsignal(String),
-    // Healthcheck(String),
-    // Shell(String)
+    Stopsignal(String),
+    Healthcheck(String),
+    Shell(String),
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -82,6 +82,30 @@ pub struct Dockerfile<P>(pub Vec<Instruction<P>>);
 
 pub type ResolvedDockerfile = Dockerfile<ResolvedParent>;
 
+/// Quotes a value so that it is read back as a single word by the Dockerfile
+/// instructions that do variable substitution, such as `ENV` and `LABEL`, and
+/// by `sh` inside a `RUN`.
+///
+/// Dockerfiles have no way to put a newline inside a value, so newlines and
+/// carriage returns are written as `\n` and `\r`.
+pub fn escape_value(value: &str) -> String {
+    let mut res = String::with_capacity(value.len() + 2);
+    res.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '$' => {
+                res.push('\\');
+                res.push(c);
+            }
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
 impl Image {
     pub fn from_repo_tag(repo: String, tag: String) -> Image {
         Image {
@@ -91,6 +115,11 @@ impl Image {
             tag,
         }
     }
+
+    /// The tag of the image, which is empty if it was omitted, meaning `latest`.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
 }
 
 impl fmt::Display for Image {
@@ -199,13 +228,21 @@ where
             match i {
                 Instruction::Arg(s) => writeln!(f, "ARG {}", s),
                 Instruction::Copy(s) => writeln!(f, "COPY {}", s),
+                Instruction::Add(s) => writeln!(f, "ADD {}", s),
                 Instruction::From(image) => writeln!(f, "\nFROM {}", image),
                 Instruction::Run(s) => writeln!(f, "RUN {}", s),
                 Instruction::Env(s) => writeln!(f, "ENV {}", s),
                 Instruction::Workdir(s) => writeln!(f, "WORKDIR {}", s),
                 Instruction::Entrypoint(s) => writeln!(f, "ENTRYPOINT {}", s),
                 Instruction::Cmd(s) => writeln!(f, "CMD {}", s),
-                Instruction::Label(k, v) => writeln!(f, "LABEL {:?}={:?}", k, v),
+                Instruction::Expose(s) => writeln!(f, "EXPOSE {}", s),
+                Instruction::Healthcheck(s) => writeln!(f, "HEALTHCHECK {}", s),
+                Instruction::Stopsignal(s) => writeln!(f, "STOPSIGNAL {}", s),
+                Instruction::Shell(s) => writeln!(f, "SHELL {}", s),
+                Instruction::User(s) => writeln!(f, "USER {}", s),
+                Instruction::Label(k, v) => {
+                    writeln!(f, "LABEL {}={}", escape_value(k), escape_value(v))
+                }
             }?;
         }
         Ok(())
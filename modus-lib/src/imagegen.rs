@@ -16,16 +16,18 @@
 
 use std::collections::{HashMap, HashSet};
 use std::iter::{self, FromIterator};
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::time::SystemTime;
 
 use crate::analysis::{Kind, ModusSemantics};
+use crate::builtin::{path::join_path, CacheMountOptions};
 use crate::logic::{Clause, IRTerm, Literal, Predicate};
-use crate::modusfile::{self, Modusfile};
+use crate::modusfile::{self, ModusTerm, Modusfile, PlatformImage, PLATFORM_IMAGE_PREDICATE};
 use crate::sld::{self, ClauseId, Proof, ResolutionError};
 use crate::translate::translate_modusfile;
 use crate::unification::Substitute;
 
-use codespan_reporting::diagnostic::Diagnostic;
+use codespan_reporting::diagnostic::{Diagnostic, Label};
 use serde::{Deserialize, Serialize};
 
 const MODUS_LABEL: &str = "com.modus-continens.literal";
@@ -36,6 +38,9 @@ pub struct BuildPlan {
     pub nodes: Vec<BuildNode>,
     pub dependencies: Vec<Vec<NodeId>>,
     pub outputs: Vec<Output>,
+    /// Likely mistakes found while planning, which don't stop the build.
+    #[serde(skip)]
+    pub warnings: Vec<Diagnostic<()>>,
 }
 
 impl BuildPlan {
@@ -44,17 +49,19 @@ impl BuildPlan {
             nodes: Vec::new(),
             dependencies: Vec::new(),
             outputs: Vec::new(),
+            warnings: Vec::new(),
         }
     }
 
     pub fn new_node(&mut self, node: BuildNode, deps: Vec<NodeId>) -> NodeId {
         let id = self.nodes.len();
         self.nodes.push(node);
-        self.dependencies.push(
-            HashSet::<_>::from_iter(deps.into_iter())
-                .into_iter()
-                .collect(),
-        );
+        let mut deps = HashSet::<_>::from_iter(deps.into_iter())
+            .into_iter()
+            .collect::<Vec<_>>();
+        // Sorted so that the same Modusfile always gives the same plan.
+        deps.sort_unstable();
+        self.dependencies.push(deps);
         debug_assert_eq!(self.nodes.len(), self.dependencies.len());
         id
     }
@@ -84,6 +91,167 @@ impl BuildPlan {
         }
         topological_order
     }
+
+    /// Follows the parents of a node back to the `from` (or scratch) node its image
+    /// is ultimately built on. Images that are only copied from are not followed.
+    pub fn base_node(&self, mut node: NodeId) -> NodeId {
+        while let Some(parent) = self.parent(node) {
+            node = parent;
+        }
+        node
+    }
+
+    /// The node whose image the image built by `node` is built on, or None for a
+    /// `from` (or scratch) node.
+    pub fn parent(&self, node: NodeId) -> Option<NodeId> {
+        match &self.nodes[node] {
+            BuildNode::From { .. } | BuildNode::FromScratch { .. } => None,
+            BuildNode::Run { parent, .. }
+            | BuildNode::CopyFromImage { parent, .. }
+            | BuildNode::CopyFromLocal { parent, .. }
+            | BuildNode::AddUrl { parent, .. }
+            | BuildNode::CreateFile { parent, .. }
+            | BuildNode::SetWorkdir { parent, .. }
+            | BuildNode::SetEntrypoint { parent, .. }
+            | BuildNode::SetCmd { parent, .. }
+            | BuildNode::SetLabel { parent, .. }
+            | BuildNode::Merge(MergeNode { parent, .. })
+            | BuildNode::SetEnv { parent, .. }
+            | BuildNode::AppendEnvValue { parent, .. }
+            | BuildNode::SetUser { parent, .. }
+            | BuildNode::SetExpose { parent, .. }
+            | BuildNode::SetHealthcheck { parent, .. }
+            | BuildNode::SetStopSignal { parent, .. }
+            | BuildNode::SetShell { parent, .. }
+            | BuildNode::Squash { parent, .. } => Some(*parent),
+        }
+    }
+
+    /// The shell set by the most recent `set_shell` the image built by `node` is based on,
+    /// if any. Like `base_node`, images that are only copied from are not followed.
+    pub fn shell(&self, mut node: NodeId) -> Option<&[String]> {
+        loop {
+            node = match &self.nodes[node] {
+                BuildNode::SetShell { shell, .. } => return Some(shell),
+                // The squashed operations are built on top of the parent, and may set a shell.
+                BuildNode::Squash { squashed, .. } => *squashed,
+                _ => self.parent(node)?,
+            };
+        }
+    }
+
+    /// The user set by the most recent `set_user` the image built by `node` is based on,
+    /// if any. Like `shell`, images that are only copied from are not followed.
+    pub fn user(&self, mut node: NodeId) -> Option<&str> {
+        loop {
+            node = match &self.nodes[node] {
+                BuildNode::SetUser { user, .. } => return Some(user),
+                BuildNode::Squash { squashed, .. } => *squashed,
+                _ => self.parent(node)?,
+            };
+        }
+    }
+
+    /// The `set_healthcheck` node that sets the healthcheck of the image built by `node`, if
+    /// any. Like `shell`, images that are only copied from are not followed.
+    pub fn healthcheck(&self, mut node: NodeId) -> Option<NodeId> {
+        loop {
+            node = match &self.nodes[node] {
+                BuildNode::SetHealthcheck { .. } => return Some(node),
+                BuildNode::Squash { squashed, .. } => *squashed,
+                _ => self.parent(node)?,
+            };
+        }
+    }
+
+    /// Marks the nodes whose inputs may have changed since `since`, because they copy files
+    /// from the build context that were modified after it, or because they depend on such a
+    /// node. Only nodes reachable from the outputs are marked.
+    pub fn nodes_changed_since(
+        &self,
+        context: &Path,
+        since: SystemTime,
+    ) -> std::io::Result<Vec<bool>> {
+        /// Whether anything at or under `path` was modified after `since`. Paths that can't
+        /// be checked (wildcards, missing files) count as modified.
+        fn modified_since(path: &Path, since: SystemTime) -> std::io::Result<bool> {
+            if path.to_string_lossy().contains(&['*', '?', '['][..]) {
+                return Ok(true);
+            }
+            let metadata = match std::fs::symlink_metadata(path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+                Err(e) => return Err(e),
+            };
+            if metadata.modified()? > since {
+                return Ok(true);
+            }
+            if metadata.is_dir() {
+                for entry in std::fs::read_dir(path)? {
+                    if modified_since(&entry?.path(), since)? {
+                        return Ok(true);
+                    }
+                }
+            }
+            Ok(false)
+        }
+
+        let local_path = |src_path: &str| context.join(src_path.trim_start_matches('/'));
+        let mut changed = vec![false; self.nodes.len()];
+        for node in self.topological_order() {
+            changed[node] = self.dependencies[node].iter().any(|&dep| changed[dep])
+                || match &self.nodes[node] {
+                    BuildNode::CopyFromLocal { src_path, .. } => {
+                        modified_since(&local_path(src_path), since)?
+                    }
+                    BuildNode::Merge(MergeNode { operations, .. }) => {
+                        let mut any_modified = false;
+                        for op in operations {
+                            if let MergeOperation::CopyFromLocal { src_path, .. } = op {
+                                any_modified =
+                                    any_modified || modified_since(&local_path(src_path), since)?;
+                            }
+                        }
+                        any_modified
+                    }
+                    _ => false,
+                };
+        }
+        Ok(changed)
+    }
+
+    /// Sets the user of every output image to `user`, by appending a `set_user` node to each
+    /// output. Outputs whose Modusfile already sets a user get a warning, since it is overridden.
+    pub fn override_output_user(&mut self, user: &str) {
+        let mut overridden = HashMap::new();
+        for i in 0..self.outputs.len() {
+            let node = self.outputs[i].node;
+            if let Some(&new_node) = overridden.get(&node) {
+                self.outputs[i].node = new_node;
+                continue;
+            }
+            if let Some(old_user) = self.user(node) {
+                let message = format!(
+                    "The user {:?} set by the Modusfile is overridden by --run-as {:?}.",
+                    old_user, user
+                );
+                let diag = Diagnostic::warning().with_message(message);
+                self.warnings.push(match &self.outputs[i].source_literal {
+                    Some(lit) => with_literal_label(diag, lit),
+                    None => diag,
+                });
+            }
+            let new_node = self.new_node(
+                BuildNode::SetUser {
+                    parent: node,
+                    user: user.to_owned(),
+                },
+                vec![node],
+            );
+            overridden.insert(node, new_node);
+            self.outputs[i].node = new_node;
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -92,20 +260,30 @@ struct State {
     cwd: String,
     current_merge: Option<MergeNode>,
     additional_envs: HashMap<String, String>,
+    excludes: Vec<String>,
+    cache_mounts: Vec<CacheMount>,
+    secrets: Vec<SecretMount>,
+    cache: CachePolicy,
+    no_network: bool,
 }
 
 impl State {
-    fn with_new_cwd<F: FnOnce(&mut Self)>(&mut self, new_cwd: String, f: F) {
+    fn with_new_cwd<T, F: FnOnce(&mut Self) -> T>(&mut self, new_cwd: String, f: F) -> T {
         let old_cwd = std::mem::replace(&mut self.cwd, new_cwd);
-        f(self);
+        let res = f(self);
         self.cwd = old_cwd;
+        res
     }
 
-    fn with_new_merge<F: FnOnce(&mut Self)>(&mut self, new_merge: MergeNode, f: F) -> MergeNode {
+    fn with_new_merge<T, F: FnOnce(&mut Self) -> T>(
+        &mut self,
+        new_merge: MergeNode,
+        f: F,
+    ) -> (MergeNode, T) {
         debug_assert!(self.current_merge.is_none());
         self.current_merge = Some(new_merge);
-        f(self);
-        self.current_merge.take().unwrap()
+        let res = f(self);
+        (self.current_merge.take().unwrap(), res)
     }
 
     fn has_base(&self) -> bool {
@@ -117,15 +295,64 @@ impl State {
         self.current_node = Some(node);
     }
 
-    fn with_additional_envs<E: IntoIterator<Item = (String, String)>, F: FnOnce(&mut Self)>(
+    fn with_additional_envs<
+        E: IntoIterator<Item = (String, String)>,
+        T,
+        F: FnOnce(&mut Self) -> T,
+    >(
         &mut self,
         envs: E,
         f: F,
-    ) {
+    ) -> T {
         let old_envs = self.additional_envs.clone();
         self.additional_envs.extend(envs);
-        f(self);
+        let res = f(self);
         self.additional_envs = old_envs;
+        res
+    }
+
+    fn with_excludes<E: IntoIterator<Item = String>, T, F: FnOnce(&mut Self) -> T>(
+        &mut self,
+        excludes: E,
+        f: F,
+    ) -> T {
+        let old_len = self.excludes.len();
+        self.excludes.extend(excludes);
+        let res = f(self);
+        self.excludes.truncate(old_len);
+        res
+    }
+
+    fn with_cache_mount<T, F: FnOnce(&mut Self) -> T>(
+        &mut self,
+        cache_mount: CacheMount,
+        f: F,
+    ) -> T {
+        self.cache_mounts.push(cache_mount);
+        let res = f(self);
+        self.cache_mounts.pop();
+        res
+    }
+
+    fn with_secret<T, F: FnOnce(&mut Self) -> T>(&mut self, secret: SecretMount, f: F) -> T {
+        self.secrets.push(secret);
+        let res = f(self);
+        self.secrets.pop();
+        res
+    }
+
+    fn with_no_network<T, F: FnOnce(&mut Self) -> T>(&mut self, f: F) -> T {
+        let old_no_network = std::mem::replace(&mut self.no_network, true);
+        let res = f(self);
+        self.no_network = old_no_network;
+        res
+    }
+
+    fn with_cache_policy<T, F: FnOnce(&mut Self) -> T>(&mut self, cache: CachePolicy, f: F) -> T {
+        let old_cache = std::mem::replace(&mut self.cache, cache);
+        let res = f(self);
+        self.cache = old_cache;
+        res
     }
 }
 
@@ -145,8 +372,6 @@ pub type NodeId = usize;
 /// In the case of copy, src_path and dst_path should be resolved relative to
 /// the source image's workdir and the destination (parent) image's workdir,
 /// respectively.
-///
-/// TODO: add caching control
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BuildNode {
     From {
@@ -164,17 +389,57 @@ pub enum BuildNode {
         command: String,
         cwd: String,
         additional_envs: HashMap<String, String>,
+        cache_mounts: Vec<CacheMount>,
+        secrets: Vec<SecretMount>,
+        /// Whether buildkit may reuse a cached result of the command.
+        #[serde(default)]
+        cache: CachePolicy,
+        /// Whether the command is run without network access, set by `no_network`.
+        #[serde(default)]
+        no_network: bool,
     },
     CopyFromImage {
         parent: NodeId,
         src_image: NodeId,
         src_path: String,
         dst_path: String,
+        /// The owner to give the copied files, a user or uid optionally followed by `:`
+        /// and a group or gid. They are owned by root if this is None.
+        chown: Option<String>,
+        /// The mode to give the copied files, as 3 or 4 octal digits such as `0755`. They
+        /// keep their original mode if this is None.
+        chmod: Option<String>,
     },
     CopyFromLocal {
         parent: NodeId,
         src_path: String,
         dst_path: String,
+        /// Glob patterns, relative to src_path, of files to leave out of the copy. They
+        /// match at any depth, so `node_modules` excludes every `node_modules` under src_path.
+        excludes: Vec<String>,
+        /// The owner to give the copied files, a user or uid optionally followed by `:`
+        /// and a group or gid. They are owned by root if this is None.
+        chown: Option<String>,
+        /// The mode to give the copied files, as 3 or 4 octal digits such as `0755`. They
+        /// keep their original mode if this is None.
+        chmod: Option<String>,
+    },
+    /// Downloads `url` to `dst_path`. The build fails if the downloaded file does not
+    /// have the given SHA-256 digest.
+    AddUrl {
+        parent: NodeId,
+        url: String,
+        dst_path: String,
+        sha256: String,
+    },
+    /// Writes `contents` to a new file at `path`, replacing any file already there.
+    CreateFile {
+        parent: NodeId,
+        path: String,
+        contents: String,
+        /// The mode to give the file, as 3 or 4 octal digits such as `0600`. It is
+        /// `0644` if this is None.
+        mode: Option<String>,
     },
     SetWorkdir {
         parent: NodeId,
@@ -182,10 +447,12 @@ pub enum BuildNode {
     },
     SetEntrypoint {
         parent: NodeId,
+        /// Empty to clear the entrypoint of the parent image, like `ENTRYPOINT []`.
         new_entrypoint: Vec<String>,
     },
     SetCmd {
         parent: NodeId,
+        /// Empty to clear the command of the parent image, like `CMD []`.
         new_cmd: Vec<String>,
     },
     SetLabel {
@@ -208,6 +475,45 @@ pub enum BuildNode {
         parent: NodeId,
         user: String,
     },
+    SetExpose {
+        parent: NodeId,
+        /// A port with an optional protocol, such as `8080` or `53/udp`.
+        port: String,
+    },
+    SetStopSignal {
+        parent: NodeId,
+        /// A signal name such as `SIGTERM`, or a signal number.
+        signal: String,
+    },
+    /// Sets the shell that later `run`s in this image use, like the `SHELL`
+    /// instruction. The command is passed as the last argument of `shell`, such as
+    /// `["/bin/bash", "-c"]`. Commands in a `merge` are still run with `sh`.
+    SetShell {
+        parent: NodeId,
+        shell: Vec<String>,
+    },
+    SetHealthcheck {
+        parent: NodeId,
+        /// The command to run, in exec form. A command given as a single string
+        /// is run with `/bin/sh -c`.
+        command: Vec<String>,
+        interval: Option<String>,
+        timeout: Option<String>,
+        retries: Option<u32>,
+    },
+    /// The result of a `squash` operator. `squashed` is the image built by the
+    /// operations inside the operator on top of `parent`, and should be exported
+    /// as `parent` plus a single layer holding everything those operations
+    /// changed.
+    ///
+    /// Unlike `merge`, which turns its operations into one combined run
+    /// command, the operations here are built (and cached) one by one as usual,
+    /// and only their resulting layers are collapsed. Files deleted by the
+    /// squashed operations will still be present in `parent`'s layers.
+    Squash {
+        parent: NodeId,
+        squashed: NodeId,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -222,18 +528,70 @@ pub enum MergeOperation {
         command: String,
         cwd: String,
         additional_envs: HashMap<String, String>,
+        cache_mounts: Vec<CacheMount>,
+        secrets: Vec<SecretMount>,
+        /// Whether buildkit may reuse a cached result of the command. The merge is built
+        /// as a single command, so this applies to the whole merge.
+        #[serde(default)]
+        cache: CachePolicy,
+        /// Whether the command is run without network access. The merge only runs without
+        /// it if all its commands do.
+        #[serde(default)]
+        no_network: bool,
     },
     CopyFromImage {
         src_image: NodeId,
         src_path: String,
         dst_path: String,
+        chown: Option<String>,
+        chmod: Option<String>,
     },
     CopyFromLocal {
         src_path: String,
         dst_path: String,
+        excludes: Vec<String>,
+        chown: Option<String>,
+        chmod: Option<String>,
     },
 }
 
+/// A buildkit cache mount attached to a `run`, which keeps the files under `target` across
+/// builds, for example the package caches of apt or npm. They are not part of the image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheMount {
+    /// Where the cache is mounted. A relative target is resolved against the working
+    /// directory of the run, like its `cwd`.
+    pub target: String,
+    /// Which cache to mount. Defaults to the target.
+    pub id: Option<String>,
+    /// One of `shared`, `private` or `locked`. Defaults to `shared`.
+    pub sharing: Option<String>,
+}
+
+/// Whether buildkit may reuse a cached result of a `run`, set by the `no_cache` and
+/// `cache_id` operators.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CachePolicy {
+    /// Reuse a cached result, unless the build is run with `--no-cache`.
+    #[default]
+    Default,
+    /// Always run the command, even if it was run before with the same inputs.
+    Never,
+    /// Only reuse a cached result of the command run with the same id, so changing the
+    /// id runs it again. The id is given to the command as `MODUS_CACHE_ID`.
+    Id(String),
+}
+
+/// A build secret made available to a `run`. Only the id is part of the plan, the
+/// secret itself is passed to buildkit when building.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecretMount {
+    pub id: String,
+    /// Where the secret is mounted, `/run/secrets/<id>` if this is None. A relative
+    /// target is resolved against the working directory of the run, like its `cwd`.
+    pub target: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Output {
     pub node: NodeId,
@@ -241,14 +599,165 @@ pub struct Output {
     pub source_literal: Option<Literal>,
 }
 
+/// The owner and mode of copied files given by the optional arguments of a copy, which follow
+/// its paths. An empty owner leaves the default one.
+fn copy_options(
+    lit: &Literal,
+    options: &[IRTerm],
+) -> Result<(Option<String>, Option<String>), Diagnostic<()>> {
+    let chown = options
+        .first()
+        .map(|t| t.as_constant().unwrap())
+        .filter(|chown| !chown.is_empty())
+        .map(ToOwned::to_owned);
+    let chmod = match options.get(1).map(|t| t.as_constant().unwrap()) {
+        Some(chmod) if !crate::builtin::is_valid_chmod(chmod) => {
+            return Err(literal_error(
+                lit,
+                format!(
+                    "Invalid mode {:?} for copy, expected 3 or 4 octal digits such as \"0755\".",
+                    chmod
+                ),
+            ))
+        }
+        chmod => chmod.map(ToOwned::to_owned),
+    };
+    Ok((chown, chmod))
+}
+
+/// Whether `lit` is the `_operator_<op>_begin` or `_end` marker (depending on `suffix`) of
+/// an operator, returning its name.
+fn operator_marker<'a>(lit: &'a Literal, suffix: &str) -> Option<&'a str> {
+    lit.predicate
+        .0
+        .strip_prefix("_operator_")
+        .and_then(|s| s.strip_suffix(suffix))
+}
+
+/// Checks that every operator begin marker in the proof is followed by its end marker
+/// at the same level, and that there are no end markers without a begin.
+/// `build_dag_from_proofs` relies on this to find what an operator applies to.
+fn check_operator_markers(proof: &Proof) -> Result<(), Diagnostic<()>> {
+    fn marker<'a>(child: &'a Proof, suffix: &str) -> Option<(&'a str, &'a Literal)> {
+        match &child.clause {
+            ClauseId::Builtin(lit) => operator_marker(lit, suffix).map(|op| (op, lit)),
+            _ => None,
+        }
+    }
+
+    let mut matched_ends = HashSet::new();
+    for (i, child) in proof.children.iter().enumerate() {
+        if let Some((op_name, lit)) = marker(child, "_begin") {
+            let end = proof.children[i + 1..].iter().position(|other| {
+                marker(other, "_end").is_some_and(|(other_name, other_lit)| {
+                    other_name == op_name && other_lit.args.first() == lit.args.first()
+                })
+            });
+            match end {
+                Some(offset) => {
+                    matched_ends.insert(i + 1 + offset);
+                }
+                None => {
+                    return Err(Diagnostic::error().with_message(format!(
+                        "Internal error: the {} operator application {} has no matching end marker in its proof.",
+                        op_name, lit
+                    )))
+                }
+            }
+        } else if let Some((op_name, lit)) = marker(child, "_end") {
+            if !matched_ends.contains(&i) {
+                return Err(Diagnostic::error().with_message(format!(
+                    "Internal error: the end marker {} of the {} operator has no matching begin marker in its proof.",
+                    lit, op_name
+                )));
+            }
+        }
+        check_operator_markers(child)?;
+    }
+    Ok(())
+}
+
+/// Checks that the arguments of every build instruction in the proof are ground, since
+/// `build_dag_from_proofs` reads them as constants. Resolution should already ensure this,
+/// so this turns a variable that slipped through into an error rather than a panic.
+fn check_intrinsics_ground(proof: &Proof) -> Result<(), Diagnostic<()>> {
+    fn free_variables<'a>(term: &'a IRTerm, vars: &mut Vec<&'a IRTerm>) {
+        match term {
+            IRTerm::Constant(_) => {}
+            IRTerm::List(ts) => ts.iter().for_each(|t| free_variables(t, vars)),
+            v => vars.push(v.get_original()),
+        }
+    }
+
+    if let ClauseId::Builtin(lit) = &proof.clause {
+        let mock_lit = Literal {
+            args: lit
+                .args
+                .iter()
+                .map(|_| IRTerm::Constant("".to_string()))
+                .collect(),
+            ..lit.clone()
+        };
+        let is_intrinsic = crate::builtin::select_builtin(&mock_lit)
+            .1
+            .is_some_and(|b| b.kind() != Kind::Logic);
+        let mut vars = Vec::new();
+        lit.args.iter().for_each(|t| free_variables(t, &mut vars));
+        if let Some(var) = vars.first().filter(|_| is_intrinsic) {
+            let op_name = operator_marker(lit, "_begin")
+                .or_else(|| operator_marker(lit, "_end"))
+                .unwrap_or(&lit.predicate.0);
+            return Err(literal_error(
+                lit,
+                format!("The variable `{var}` in this {op_name} is not bound to a value."),
+            )
+            .with_notes(vec![format!(
+                "Build instructions need constant arguments, so `{var}` must be grounded by the head or an earlier literal."
+            )]));
+        }
+    }
+    proof.children.iter().try_for_each(check_intrinsics_ground)
+}
+
+/// An error about a literal of the proof, labelled with its position if it has one.
+fn literal_error(lit: &Literal, message: impl Into<String>) -> Diagnostic<()> {
+    with_literal_label(Diagnostic::error().with_message(message), lit)
+}
+
+/// Labels the diagnostic with the position of the literal, if it has one.
+fn with_literal_label(diag: Diagnostic<()>, lit: &Literal) -> Diagnostic<()> {
+    match &lit.position {
+        Some(pos) => diag.with_labels(vec![Label::primary(
+            (),
+            pos.offset..pos.offset + pos.length,
+        )]),
+        None => diag,
+    }
+}
+
+/// A ground (solved) query and its proof tree.
+pub type QueryProof = (Literal, Proof);
+
 /// Given a list of pairs of ground (solved) queries and their proof tree, output
 /// a build graph which builds all the queried images.
+///
+/// Fails if a proof doesn't describe a valid build, for example if it runs a command
+/// before any `from`, or if a query doesn't build anything. Proofs whose operator markers
+/// don't pair up, or whose build instructions have unbound arguments, are rejected first.
 pub fn build_dag_from_proofs(
     query_and_proofs: &[(Literal, Proof)],
     rules: &Vec<Clause<IRTerm>>,
-) -> BuildPlan {
+) -> Result<BuildPlan, Vec<Diagnostic<()>>> {
+    build_dag_from_proof_groups(&[(query_and_proofs, rules)])
+}
+
+/// Like [build_dag_from_proofs], for proofs found with different rules, such as the same
+/// Modusfile with different build arguments. The proofs of each group refer to the clauses
+/// of that group, and only images built from the same group are shared.
+pub fn build_dag_from_proof_groups(
+    groups: &[(&[QueryProof], &Vec<Clause<IRTerm>>)],
+) -> Result<BuildPlan, Vec<Diagnostic<()>>> {
     let mut res = BuildPlan::new();
-    let mut image_literals: HashMap<Literal, NodeId> = HashMap::new();
 
     /// Takes in a part of the build tree, assuming that it is building an image
     /// (for example, the tree of an image literal, or a slice of a bigger tree,
@@ -265,12 +774,17 @@ pub fn build_dag_from_proofs(
         res: &mut BuildPlan,
         image_literals: &mut HashMap<Literal, NodeId>,
         tag_with_literal: Option<String>,
-    ) -> Option<NodeId> {
+    ) -> Result<Option<NodeId>, Diagnostic<()>> {
         let mut curr_state = State {
             current_node: None,
             cwd: "".to_string(),
             current_merge: None,
             additional_envs: HashMap::new(),
+            excludes: Vec::new(),
+            cache_mounts: Vec::new(),
+            secrets: Vec::new(),
+            cache: CachePolicy::Default,
+            no_network: false,
         };
 
         /* We go through the proof tree in depth-first order, since this is
@@ -297,13 +811,13 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
-        ) {
+        ) -> Result<(), Diagnostic<()>> {
             match proof.clause {
                 ClauseId::Query => {}
                 ClauseId::Builtin(ref intrinsic) => {
-                    process_intrinsic(intrinsic, res, image_literals, curr_state);
+                    process_intrinsic(intrinsic, res, image_literals, curr_state)?;
                     debug_assert!(proof.children.is_empty()); // Intrinsics should not have children.
-                    return;
+                    return Ok(());
                 }
                 ClauseId::Rule(rid) => {
                     let substituted_lit = rules[rid].head.substitute(&proof.valuation);
@@ -316,7 +830,7 @@ pub fn build_dag_from_proofs(
                         // Do the optimization mentioned above.
                         if let Some(&node_id) = image_literals.get(&substituted_lit) {
                             curr_state.set_node(node_id);
-                            return; // no need to recurse to children anymore.
+                            return Ok(()); // no need to recurse to children anymore.
                         } else {
                             if let Some(node_id) = process_image(
                                 &proof.children.iter().collect::<Vec<_>>()[..],
@@ -324,12 +838,12 @@ pub fn build_dag_from_proofs(
                                 res,
                                 image_literals,
                                 Some(substituted_lit.to_string()),
-                            ) {
+                            )? {
                                 curr_state.set_node(node_id);
                                 image_literals.insert(substituted_lit, node_id);
-                                return; // no need to recurse to children anymore, since I just built the content of this literal.
+                                return Ok(()); // no need to recurse to children anymore, since I just built the content of this literal.
                             } else {
-                                return; // the literal doesn't do any docker thing, so we can safely skip it.
+                                return Ok(()); // the literal doesn't do any docker thing, so we can safely skip it.
                             }
                         }
                     } else {
@@ -339,6 +853,11 @@ pub fn build_dag_from_proofs(
                     }
                 }
                 ClauseId::NegationCheck(_) => {}
+                ClauseId::Tabled(_) => {
+                    return Err(Diagnostic::bug().with_message(
+                        "Tabled proofs omit the derivations of their answers and can't be built.",
+                    ));
+                }
             }
 
             process_children(
@@ -347,7 +866,7 @@ pub fn build_dag_from_proofs(
                 res,
                 image_literals,
                 curr_state,
-            );
+            )
         }
 
         fn process_intrinsic(
@@ -355,16 +874,22 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
-        ) {
+        ) -> Result<(), Diagnostic<()>> {
             let name = &intrinsic.predicate.0[..];
             assert!(!name.starts_with("_operator_")); // operators handled separately below.
             match name {
                 "from" => {
                     if curr_state.current_merge.is_some() {
-                        panic!("You can not generate a new image inside a merge.");
+                        return Err(literal_error(
+                            intrinsic,
+                            "You can not generate a new image inside a merge.",
+                        ));
                     }
                     if curr_state.has_base() {
-                        panic!("from must be the first build instruction.");
+                        return Err(literal_error(
+                            intrinsic,
+                            "from must be the first build instruction.",
+                        ));
                     }
                     // Special sharing for the "from" intrinsic.
                     if let Some(&existing_node) = image_literals.get(&intrinsic) {
@@ -395,10 +920,20 @@ pub fn build_dag_from_proofs(
                             command,
                             cwd: curr_state.cwd.clone(),
                             additional_envs: curr_state.additional_envs.clone(),
+                            cache_mounts: curr_state.cache_mounts.clone(),
+                            secrets: curr_state.secrets.clone(),
+                            cache: curr_state.cache.clone(),
+                            no_network: curr_state.no_network,
                         });
                     } else {
                         if !curr_state.has_base() {
-                            panic!("No base layer yet.");
+                            return Err(literal_error(
+                                intrinsic,
+                                format!(
+                                    "{} needs a base image, but there is no from before it.",
+                                    name
+                                ),
+                            ));
                         }
                         let parent = curr_state.current_node.unwrap();
                         curr_state.set_node(res.new_node(
@@ -407,6 +942,10 @@ pub fn build_dag_from_proofs(
                                 command: command,
                                 cwd: curr_state.cwd.clone(),
                                 additional_envs: curr_state.additional_envs.clone(),
+                                cache_mounts: curr_state.cache_mounts.clone(),
+                                secrets: curr_state.secrets.clone(),
+                                cache: curr_state.cache.clone(),
+                                no_network: curr_state.no_network,
                             },
                             vec![parent],
                         ));
@@ -415,17 +954,32 @@ pub fn build_dag_from_proofs(
                 "copy" => {
                     let src_path = intrinsic.args[0].as_constant().unwrap().to_owned();
                     if src_path.starts_with("/") {
-                        panic!("The source of a local copy can not be an absolute path.");
+                        return Err(literal_error(
+                            intrinsic,
+                            "The source of a local copy can not be an absolute path.",
+                        ));
                     }
                     let dst_path = intrinsic.args[1].as_constant().unwrap();
                     let dst_path = join_path(&curr_state.cwd, dst_path);
+                    let excludes = curr_state.excludes.clone();
+                    let (chown, chmod) = copy_options(intrinsic, &intrinsic.args[2..])?;
                     if let Some(ref mut curr_merge) = curr_state.current_merge {
-                        curr_merge
-                            .operations
-                            .push(MergeOperation::CopyFromLocal { src_path, dst_path });
+                        curr_merge.operations.push(MergeOperation::CopyFromLocal {
+                            src_path,
+                            dst_path,
+                            excludes,
+                            chown,
+                            chmod,
+                        });
                     } else {
                         if !curr_state.has_base() {
-                            panic!("No base layer yet.");
+                            return Err(literal_error(
+                                intrinsic,
+                                format!(
+                                    "{} needs a base image, but there is no from before it.",
+                                    name
+                                ),
+                            ));
                         }
                         let parent = curr_state.current_node.unwrap();
                         curr_state.set_node(res.new_node(
@@ -433,15 +987,50 @@ pub fn build_dag_from_proofs(
                                 parent,
                                 src_path,
                                 dst_path,
+                                excludes,
+                                chown,
+                                chmod,
                             },
                             vec![parent],
                         ));
                     }
                 }
+                "add_url_checked" => {
+                    if curr_state.current_merge.is_some() {
+                        return Err(literal_error(
+                            intrinsic,
+                            "add_url_checked can not be used inside a merge.",
+                        ));
+                    }
+                    if !curr_state.has_base() {
+                        return Err(literal_error(
+                            intrinsic,
+                            format!(
+                                "{} needs a base image, but there is no from before it.",
+                                name
+                            ),
+                        ));
+                    }
+                    let url = intrinsic.args[0].as_constant().unwrap().to_owned();
+                    let dst_path =
+                        join_path(&curr_state.cwd, intrinsic.args[1].as_constant().unwrap());
+                    let sha256 = intrinsic.args[2].as_constant().unwrap().to_lowercase();
+                    let parent = curr_state.current_node.unwrap();
+                    curr_state.set_node(res.new_node(
+                        BuildNode::AddUrl {
+                            parent,
+                            url,
+                            dst_path,
+                            sha256,
+                        },
+                        vec![parent],
+                    ));
+                }
                 _ => {
                     // do nothing - there might be stuff like string_concat.
                 }
             }
+            Ok(())
         }
 
         fn process_operator(
@@ -452,28 +1041,46 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
-        ) {
+        ) -> Result<(), Diagnostic<()>> {
             match op_name {
                 // Image-to-image copy. (local copy is not an operator)
                 "copy" => {
-                    let src_image = process_image(subtree_in_op, rules, res, image_literals, None)
-                        .expect("Stuff inside this copy does not build an image.");
+                    if !curr_state.excludes.is_empty() {
+                        return Err(literal_error(
+                            lit,
+                            "exclude only applies to copies from the build context.",
+                        ));
+                    }
+                    let src_image = process_image(subtree_in_op, rules, res, image_literals, None)?
+                        .ok_or_else(|| {
+                            literal_error(lit, "The source of this copy does not build an image.")
+                        })?;
                     let src_path = lit.args[1].as_constant().unwrap().to_owned();
                     let dst_path = join_path(&curr_state.cwd, lit.args[2].as_constant().unwrap());
+                    let (chown, chmod) = copy_options(lit, &lit.args[3..])?;
                     if let Some(ref mut curr_merge) = curr_state.current_merge {
                         curr_merge.operations.push(MergeOperation::CopyFromImage {
                             src_image,
                             src_path,
                             dst_path,
+                            chown,
+                            chmod,
                         });
                     } else {
-                        let parent = curr_state.current_node.expect("No base layer yet.");
+                        let parent = curr_state.current_node.ok_or_else(|| {
+                            literal_error(
+                                lit,
+                                "copy needs a base image, but there is no from before it.",
+                            )
+                        })?;
                         let node = res.new_node(
                             BuildNode::CopyFromImage {
                                 parent,
                                 src_image,
                                 src_path,
                                 dst_path,
+                                chown,
+                                chmod,
                             },
                             vec![parent, src_image],
                         );
@@ -483,24 +1090,51 @@ pub fn build_dag_from_proofs(
                 "in_workdir" => {
                     let new_p = lit.args[1].as_constant().unwrap();
                     let new_cwd = join_path(&curr_state.cwd, new_p);
+                    let had_base = curr_state.has_base();
                     curr_state.with_new_cwd(new_cwd, |new_state| {
-                        process_children(subtree_in_op, rules, res, image_literals, new_state);
-                    });
-                    // TODO: emit a warning if the tree inside attempts
-                    // to build a fresh image - this is probably an incorrect usage.
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                    if !had_base && curr_state.has_base() {
+                        // This is probably an incorrect usage, since the image doesn't keep
+                        // the working directory.
+                        res.warnings.push(
+                            with_literal_label(
+                                Diagnostic::warning().with_message(
+                                    "A new image is built inside in_workdir, which only changes the working directory of the instructions inside it.",
+                                ),
+                                lit,
+                            )
+                            .with_notes(vec![
+                                "Use set_workdir to change the working directory of the image."
+                                    .to_owned(),
+                            ]),
+                        );
+                    }
                 }
                 "set_workdir" | "set_entrypoint" | "set_cmd" | "set_env" | "append_path"
-                | "set_label" | "set_user" => {
+                | "set_label" | "set_user" | "set_expose" | "set_healthcheck"
+                | "set_stopsignal" | "set_shell" | "add_file" => {
                     if curr_state.current_merge.is_some() {
-                        panic!("You can not generate a new image inside a merge.");
+                        return Err(literal_error(
+                            lit,
+                            "You can not generate a new image inside a merge.",
+                        ));
                     }
-                    let img = process_image(subtree_in_op, rules, res, image_literals, None)
-                        .expect(&format!("{} should be applied to an image.", op_name));
+                    let img = process_image(subtree_in_op, rules, res, image_literals, None)?
+                        .ok_or_else(|| {
+                            literal_error(
+                                lit,
+                                format!("{} should be applied to an image.", op_name),
+                            )
+                        })?;
                     if curr_state.has_base() {
-                        panic!(
-                            "{} generates a new image, so it should be the first instruction.",
-                            op_name
-                        );
+                        return Err(literal_error(
+                            lit,
+                            format!(
+                                "{} generates a new image, so it should be the first instruction.",
+                                op_name
+                            ),
+                        ));
                     }
 
                     match op_name {
@@ -591,25 +1225,123 @@ pub fn build_dag_from_proofs(
                                 res.new_node(BuildNode::SetUser { parent: img, user }, vec![img]),
                             );
                         }
+                        "add_file" => {
+                            let path = lit.args[1].as_constant().unwrap();
+                            if path.is_empty() || path.ends_with('/') {
+                                return Err(literal_error(
+                                    lit,
+                                    "The path of add_file should name a file, not a directory.",
+                                ));
+                            }
+                            curr_state.set_node(
+                                res.new_node(
+                                    BuildNode::CreateFile {
+                                        parent: img,
+                                        path: join_path(&curr_state.cwd, path),
+                                        contents: lit.args[2].as_constant().unwrap().to_owned(),
+                                        mode: lit
+                                            .args
+                                            .get(3)
+                                            .map(|t| t.as_constant().unwrap().to_owned()),
+                                    },
+                                    vec![img],
+                                ),
+                            );
+                        }
+                        "set_expose" => {
+                            let port = lit.args[1].as_constant().unwrap().to_owned();
+                            curr_state.set_node(
+                                res.new_node(BuildNode::SetExpose { parent: img, port }, vec![img]),
+                            );
+                        }
+                        "set_stopsignal" => {
+                            let signal = lit.args[1].as_constant().unwrap().to_owned();
+                            curr_state.set_node(res.new_node(
+                                BuildNode::SetStopSignal {
+                                    parent: img,
+                                    signal,
+                                },
+                                vec![img],
+                            ));
+                        }
+                        "set_shell" => {
+                            let shell = match &lit.args[1] {
+                                IRTerm::List(ts) => ts
+                                    .iter()
+                                    .map(|t| t.as_constant().unwrap().to_owned())
+                                    .collect::<Vec<_>>(),
+                                _ => unreachable!(),
+                            };
+                            curr_state.set_node(
+                                res.new_node(BuildNode::SetShell { parent: img, shell }, vec![img]),
+                            );
+                        }
+                        "set_healthcheck" => {
+                            let command = match &lit.args[1] {
+                                IRTerm::List(ts) => ts
+                                    .iter()
+                                    .map(|t| t.as_constant().unwrap().to_owned())
+                                    .collect::<Vec<_>>(),
+                                IRTerm::Constant(c) => {
+                                    vec!["/bin/sh".to_owned(), "-c".to_owned(), c.to_owned()]
+                                }
+                                _ => unreachable!(),
+                            };
+                            // The timing arguments are optional, and may be left empty to
+                            // keep docker's default.
+                            let option = |i: usize| {
+                                lit.args
+                                    .get(i)
+                                    .map(|t| t.as_constant().unwrap())
+                                    .filter(|o| !o.is_empty())
+                            };
+                            let retries = option(4)
+                                .map(|r| {
+                                    r.parse::<u32>().map_err(|_| {
+                                        literal_error(
+                                            lit,
+                                            format!("Invalid healthcheck retries {:?}.", r),
+                                        )
+                                    })
+                                })
+                                .transpose()?;
+                            curr_state.set_node(res.new_node(
+                                BuildNode::SetHealthcheck {
+                                    parent: img,
+                                    command,
+                                    interval: option(2).map(ToOwned::to_owned),
+                                    timeout: option(3).map(ToOwned::to_owned),
+                                    retries,
+                                },
+                                vec![img],
+                            ));
+                        }
                         _ => unreachable!(),
                     }
                 }
                 "merge" => {
                     if curr_state.current_merge.is_some() {
-                        process_children(subtree_in_op, rules, res, image_literals, curr_state);
-                        return;
+                        return process_children(
+                            subtree_in_op,
+                            rules,
+                            res,
+                            image_literals,
+                            curr_state,
+                        );
                     }
                     if !curr_state.has_base() {
-                        panic!("merge requires a base layer outside.");
+                        return Err(literal_error(lit, "merge requires a base layer outside."));
                     }
                     let parent = curr_state.current_node.unwrap();
                     let merge_node = MergeNode {
                         parent,
                         operations: vec![],
                     };
-                    let merge_node = curr_state.with_new_merge(merge_node, |new_state| {
-                        process_children(subtree_in_op, rules, res, image_literals, new_state);
-                    });
+                    let (merge_node, merge_res) =
+                        curr_state.with_new_merge(merge_node, |new_state| {
+                            process_children(subtree_in_op, rules, res, image_literals, new_state)
+                        });
+                    merge_res?;
                     let mut deps: Vec<NodeId> = merge_node
                         .operations
                         .iter()
@@ -624,17 +1356,98 @@ pub fn build_dag_from_proofs(
                     deps.push(parent);
                     curr_state.set_node(res.new_node(BuildNode::Merge(merge_node), deps));
                 }
+                "squash" => {
+                    if curr_state.current_merge.is_some() {
+                        // Everything in a merge already ends up in one layer.
+                        return process_children(
+                            subtree_in_op,
+                            rules,
+                            res,
+                            image_literals,
+                            curr_state,
+                        );
+                    }
+                    let parent = curr_state.current_node.ok_or_else(|| {
+                        literal_error(lit, "squash requires a base layer outside.")
+                    })?;
+                    process_children(subtree_in_op, rules, res, image_literals, curr_state)?;
+                    let squashed = curr_state.current_node.unwrap();
+                    if squashed != parent {
+                        curr_state.set_node(res.new_node(
+                            BuildNode::Squash { parent, squashed },
+                            vec![parent, squashed],
+                        ));
+                    }
+                }
                 "in_env" => {
                     let env_k = lit.args[1].as_constant().unwrap().to_owned();
                     let env_v = lit.args[2].as_constant().unwrap().to_owned();
                     curr_state.with_additional_envs([(env_k, env_v)], |new_state| {
-                        process_children(subtree_in_op, rules, res, image_literals, new_state);
-                    });
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "exclude" => {
+                    let patterns = match &lit.args[1] {
+                        IRTerm::Constant(c) => vec![c.to_owned()],
+                        IRTerm::List(ts) => ts
+                            .iter()
+                            .map(|t| t.as_constant().unwrap().to_owned())
+                            .collect(),
+                        _ => unreachable!(),
+                    };
+                    curr_state.with_excludes(patterns, |new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "in_secret" => {
+                    let secret = SecretMount {
+                        id: lit.args[1].as_constant().unwrap().to_owned(),
+                        target: lit.args.get(2).map(|t| t.as_constant().unwrap().to_owned()),
+                    };
+                    curr_state.with_secret(secret, |new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "no_cache" => {
+                    curr_state.with_cache_policy(CachePolicy::Never, |new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "no_network" => {
+                    curr_state.with_no_network(|new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "cache_id" => {
+                    let id = lit.args[1].as_constant().unwrap().to_owned();
+                    curr_state.with_cache_policy(CachePolicy::Id(id), |new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
+                }
+                "cache_mount" => {
+                    let target = lit.args[1].as_constant().unwrap().to_owned();
+                    let options = match lit.args.get(2) {
+                        Some(IRTerm::List(ts)) => {
+                            CacheMountOptions::parse(ts.iter().map(|t| t.as_constant().unwrap()))
+                                .unwrap()
+                        }
+                        Some(_) => unreachable!(),
+                        None => CacheMountOptions::default(),
+                    };
+                    let cache_mount = CacheMount {
+                        target,
+                        id: options.id,
+                        sharing: options.sharing,
+                    };
+                    curr_state.with_cache_mount(cache_mount, |new_state| {
+                        process_children(subtree_in_op, rules, res, image_literals, new_state)
+                    })?;
                 }
                 _ => {
-                    panic!("Unkown operator: {}", op_name);
+                    return Err(literal_error(lit, format!("Unknown operator: {}", op_name)));
                 }
             }
+            Ok(())
         }
 
         fn process_children(
@@ -643,31 +1456,37 @@ pub fn build_dag_from_proofs(
             res: &mut BuildPlan,
             image_literals: &mut HashMap<Literal, NodeId>,
             curr_state: &mut State,
-        ) {
+        ) -> Result<(), Diagnostic<()>> {
             let mut i = 0usize;
             while i < children.len() {
                 let child = children[i];
                 if let ClauseId::Builtin(ref lit) = child.clause {
-                    let name = &lit.predicate.0;
-                    if let Some(op_name) = name
-                        .strip_prefix("_operator_")
-                        .and_then(|s| s.strip_suffix("_begin"))
-                    {
+                    if let Some(op_name) = operator_marker(lit, "_begin") {
                         // due to the way things work, the end predicate for this is
-                        // guarenteed to be in the same level.
+                        // guarenteed to be in the same level (checked by check_operator_markers
+                        // before processing).
                         let end_name = format!("_operator_{}_end", op_name);
                         let pair_id = lit.args[0].as_constant().unwrap();
-                        let mut j = i + 1;
-                        while !{
-                            if let ClauseId::Builtin(ref lit) = children[j].clause {
-                                lit.predicate.0 == end_name
-                                    && lit.args[0].as_constant() == Some(pair_id)
-                            } else {
-                                false
-                            }
-                        } {
-                            j += 1;
-                        }
+                        let j = i
+                            + 1
+                            + children[i + 1..]
+                                .iter()
+                                .position(|child| match child.clause {
+                                    ClauseId::Builtin(ref lit) => {
+                                        lit.predicate.0 == end_name
+                                            && lit.args[0].as_constant() == Some(pair_id)
+                                    }
+                                    _ => false,
+                                })
+                                .ok_or_else(|| {
+                                    literal_error(
+                                        lit,
+                                        format!(
+                                            "Internal error: the {} operator application {} has no matching end marker in its proof.",
+                                            op_name, lit
+                                        ),
+                                    )
+                                })?;
                         // at this point j points to the end predicate.
 
                         let subtree_in_op = &children[i + 1..j];
@@ -679,17 +1498,18 @@ pub fn build_dag_from_proofs(
                             res,
                             image_literals,
                             curr_state,
-                        );
+                        )?;
                         i = j + 1;
                         continue;
                     }
                 }
-                process_tree(child, rules, res, image_literals, curr_state);
+                process_tree(child, rules, res, image_literals, curr_state)?;
                 i += 1;
             }
+            Ok(())
         }
 
-        process_children(subtree, rules, res, image_literals, &mut curr_state);
+        process_children(subtree, rules, res, image_literals, &mut curr_state)?;
 
         debug_assert!(curr_state.current_merge.is_none());
 
@@ -705,56 +1525,99 @@ pub fn build_dag_from_proofs(
             );
             curr_state.set_node(tagged_node);
         }
-        curr_state.current_node
+        Ok(curr_state.current_node)
     }
 
-    for (query, proof) in query_and_proofs.into_iter() {
-        debug_assert!(query
-            .args
-            .iter()
-            .all(|x| x.is_constant_or_compound_constant()));
-        if let Some(&existing_node_id) = image_literals.get(&query) {
-            // TODO: unreachable?
-            res.outputs.push(Output {
-                node: existing_node_id,
-                source_literal: Some(query.clone()),
-            });
-            continue;
-        }
-        if let Some(node_id) = process_image(
-            &[proof],
-            rules,
-            &mut res,
-            &mut image_literals,
-            Some(query.to_string()),
-        ) {
-            image_literals.insert(query.clone(), node_id);
-            res.outputs.push(Output {
-                node: node_id,
-                source_literal: Some(query.clone()),
-            });
-        } else {
-            panic!("{} does not resolve to any docker instructions.", query);
-        }
+    let mut errs = groups
+        .iter()
+        .flat_map(|(query_and_proofs, _)| query_and_proofs.iter())
+        .filter_map(|(_, proof)| {
+            check_operator_markers(proof)
+                .and_then(|_| check_intrinsics_ground(proof))
+                .err()
+        })
+        .collect::<Vec<_>>();
+    if !errs.is_empty() {
+        return Err(errs);
     }
 
-    res
-}
+    for (query_and_proofs, rules) in groups {
+        let mut image_literals: HashMap<Literal, NodeId> = HashMap::new();
+        for (query, proof) in query_and_proofs.iter() {
+            debug_assert!(query
+                .args
+                .iter()
+                .all(|x| x.is_constant_or_compound_constant()));
+            if let Some(&existing_node_id) = image_literals.get(query) {
+                // TODO: unreachable?
+                res.outputs.push(Output {
+                    node: existing_node_id,
+                    source_literal: Some(query.clone()),
+                });
+                continue;
+            }
+            match process_image(
+                &[proof],
+                rules,
+                &mut res,
+                &mut image_literals,
+                Some(query.to_string()),
+            ) {
+                Ok(Some(node_id)) => {
+                    image_literals.insert(query.clone(), node_id);
+                    res.outputs.push(Output {
+                        node: node_id,
+                        source_literal: Some(query.clone()),
+                    });
+                }
+                Ok(None) => errs.push(literal_error(
+                    query,
+                    format!("{} does not resolve to any docker instructions.", query),
+                )),
+                Err(e) => errs.push(e),
+            }
+        }
+    }
 
-fn join_path(base: &str, path: &str) -> String {
-    match Path::new(base).join(path).to_str() {
-        Some(s) => s.to_owned(),
-        None => panic!("Path containing invalid utf-8 are not allowed."),
+    if errs.is_empty() {
+        Ok(res)
+    } else {
+        Err(errs)
     }
 }
 
 pub fn plan_from_modusfile(
     mf: Modusfile,
     query: modusfile::Expression,
+    max_depth: usize,
+) -> Result<BuildPlan, Vec<Diagnostic<()>>> {
+    plan_from_modusfile_queries(mf, std::slice::from_ref(&query), max_depth)
+}
+
+/// Plans several queries at once, so that the images they have in common are only built
+/// once. Every query has to succeed, and the outputs of each query follow those of the
+/// previous one.
+pub fn plan_from_modusfile_queries(
+    mf: Modusfile,
+    queries: &[modusfile::Expression],
+    max_depth: usize,
 ) -> Result<BuildPlan, Vec<Diagnostic<()>>> {
-    // 1. Adds a new clause based on the user's expression query to the Modusfile, `_query :- ...`.
+    plan_from_modusfile_queries_with_args(mf, queries, &vec![Vec::new(); queries.len()], max_depth)
+}
+
+/// Like [plan_from_modusfile_queries], but each query is resolved with its own build
+/// arguments on top of those of the Modusfile, given at the same index in `query_build_args`.
+/// Images are only shared between consecutive queries with the same arguments.
+pub fn plan_from_modusfile_queries_with_args(
+    mf: Modusfile,
+    queries: &[modusfile::Expression],
+    query_build_args: &[Vec<(String, String)>],
+    max_depth: usize,
+) -> Result<BuildPlan, Vec<Diagnostic<()>>> {
+    // 1. Adds a new clause based on each of the user's expression queries to the Modusfile,
+    //    `_query_<i> :- ...`.
     // 2. Translates the Modusfile to IR.
-    // 3. Find proof for `_query`. We need to do this, and not just find proof of the image literal due to any
+    // 3. Find proof for each goal. We need to do this, and not just find proof of the image literal due to any
     //    possible logical constraints.
     // 4. Modify proof to give proof for the single image literal. The other literals, if any, should
     //    only be logic literals.
@@ -778,11 +1641,14 @@ pub fn plan_from_modusfile(
         }
     }
 
-    fn get_image_literal(
+    /// Finds the image literal in each alternative of the query, i.e. each translated
+    /// `_query` clause. A query may be a disjunction, but every alternative has to
+    /// produce exactly one image.
+    fn get_image_literals(
         query: &modusfile::Expression,
         mf_with_query: &Modusfile,
-        ir_q_clause: &Clause,
-    ) -> Result<Literal<IRTerm>, Vec<Diagnostic<()>>> {
+        ir_q_clauses: &[&Clause],
+    ) -> Result<Vec<Literal<IRTerm>>, Vec<Diagnostic<()>>> {
         let mut errs = Vec::new();
 
         if let Err(mut es) = validate_query_expression(query) {
@@ -793,14 +1659,6 @@ pub fn plan_from_modusfile(
 
         let kind_res = mf_with_query.kinds();
 
-        let image_count = query_lits
-            .iter()
-            .filter(|query_lit| kind_res.pred_kind.get(&query_lit.predicate) == Some(&Kind::Image))
-            .count();
-        if image_count != 1 {
-            errs.push(Diagnostic::error().with_message(format!("There must be exactly one image predicate in the query, but {image_count} were found.")));
-        }
-
         let layer_count = query_lits
             .iter()
             .filter(|query_lit| kind_res.pred_kind.get(&query_lit.predicate) == Some(&Kind::Layer))
@@ -811,45 +1669,1258 @@ pub fn plan_from_modusfile(
             )));
         }
 
+        let mut image_literals = Vec::with_capacity(ir_q_clauses.len());
+        for (i, q_clause) in ir_q_clauses.iter().enumerate() {
+            let branch_image_lits = q_clause
+                .body
+                .iter()
+                .filter(|lit| kind_res.pred_kind.get(&lit.predicate) == Some(&Kind::Image))
+                .collect::<Vec<_>>();
+            match branch_image_lits[..] {
+                [image_literal] => image_literals.push(image_literal.clone()),
+                _ => {
+                    let image_count = branch_image_lits.len();
+                    errs.push(Diagnostic::error().with_message(if ir_q_clauses.len() == 1 {
+                        format!("There must be exactly one image predicate in the query, but {image_count} were found.")
+                    } else {
+                        format!("There must be exactly one image predicate in each alternative of the query, but {image_count} were found in alternative {}.", i + 1)
+                    }));
+                }
+            }
+        }
+
         if !errs.is_empty() {
             return Err(errs);
         }
+        Ok(image_literals)
+    }
+
+    /// Fails early if nothing in the Modusfile could prove the query, so that the user
+    /// doesn't get a confusing resolution error instead.
+    fn check_has_rules(
+        mf: &Modusfile,
+        query: &modusfile::Expression,
+    ) -> Result<(), Vec<Diagnostic<()>>> {
+        if mf.0.is_empty() {
+            return Err(vec![Diagnostic::error().with_message(
+                "The Modusfile has no rules, so there is nothing to build.",
+            )]);
+        }
 
-        let expression_image_literal = query_lits
+        let is_builtin = |lit: &Literal<ModusTerm>| {
+            let mock_lit = Literal {
+                positive: true,
+                position: None,
+                predicate: lit.predicate.clone(),
+                args: lit
+                    .args
+                    .iter()
+                    .map(|_| IRTerm::Constant("".to_string()))
+                    .collect(),
+            };
+            !matches!(
+                crate::builtin::select_builtin(&mock_lit).0,
+                crate::builtin::SelectBuiltinResult::NoMatch
+            )
+        };
+        let query_lits = query.literals();
+        let has_rule = query_lits
             .iter()
-            .find(|lit| kind_res.pred_kind.get(&lit.predicate) == Some(&Kind::Image))
-            .unwrap();
-        let image_literal = ir_q_clause
+            .any(|lit| is_builtin(lit) || mf.0.iter().any(|c| c.head.predicate == lit.predicate));
+        if has_rule {
+            return Ok(());
+        }
+        let mut preds = query_lits
+            .iter()
+            .map(|lit| lit.predicate.to_string())
+            .collect::<Vec<_>>();
+        preds.sort();
+        preds.dedup();
+        Err(vec![Diagnostic::error().with_message(format!(
+            "The Modusfile has no rules for {}, so there is nothing to build {} from.",
+            preds.join(", "),
+            query
+        ))])
+    }
+
+    /// The proofs of the image literals of some queries, and the clauses they refer to.
+    type QueryGroup = (Vec<QueryProof>, Vec<Clause>);
+
+    /// Finds the proofs of the image literals of the queries, which are resolved together
+    /// with the same build arguments.
+    fn prove_queries(
+        mf: &Modusfile,
+        queries: &[modusfile::Expression],
+        max_depth: usize,
+    ) -> Result<QueryGroup, Vec<Diagnostic<()>>> {
+        let goal_preds = (0..queries.len())
+            .map(|i| Predicate(format!("_query_{}", i)))
+            .collect::<Vec<_>>();
+        let mut mf_with_query = mf.clone();
+        for (goal_pred, query) in goal_preds.iter().zip(queries) {
+            mf_with_query.add_named_goal(&goal_pred.0, query.clone());
+        }
+        let ir_clauses: Vec<Clause> = translate_modusfile(&mf_with_query);
+
+        let mut query_and_proofs = Vec::new();
+        for (goal_pred, query) in goal_preds.iter().zip(queries) {
+            // A disjunctive query is translated into one goal clause per alternative.
+            let q_clauses = ir_clauses
+                .iter()
+                .filter(|c| &c.head.predicate == goal_pred)
+                .collect::<Vec<_>>();
+            debug_assert!(!q_clauses.is_empty());
+
+            let image_literals = get_image_literals(query, &mf_with_query, &q_clauses)?;
+
+            // Resolve each alternative separately so that every proof can be paired with the image
+            // literal of the alternative it came from. Alternatives that don't resolve are skipped,
+            // as long as at least one does.
+            let mut nb_proofs = 0;
+            let mut errs = Vec::new();
+            for (q_clause, image_literal) in q_clauses.iter().zip(image_literals) {
+                let query_goal = &q_clause.body;
+
+                // don't store full tree as this takes a lot of memory, and is probably not needed
+                // when building/transpiling
+                match Result::from(sld::sld(&ir_clauses, query_goal, max_depth, false, false)) {
+                    Ok(success_tree) => {
+                        let proofs = sld::proofs(&success_tree, &ir_clauses, query_goal);
+                        nb_proofs += proofs.len();
+                        query_and_proofs.extend(
+                            proofs
+                                .into_values()
+                                .map(|p| (image_literal.substitute(&p.valuation), p)),
+                        );
+                    }
+                    Err(mut es) => errs.append(&mut es),
+                }
+            }
+            if nb_proofs == 0 {
+                return Err(errs);
+            }
+        }
+        Ok((query_and_proofs, ir_clauses))
+    }
+
+    for query in queries {
+        check_has_rules(&mf, query)?;
+    }
+
+    let mut groups = Vec::new();
+    let mut start = 0;
+    while start < queries.len() {
+        let build_args = &query_build_args[start];
+        let end = start
+            + query_build_args[start..]
+                .iter()
+                .take_while(|args| *args == build_args)
+                .count();
+        let mut group_mf = mf.clone();
+        group_mf.set_build_args(build_args);
+        groups.push(prove_queries(&group_mf, &queries[start..end], max_depth)?);
+        start = end;
+    }
+    build_dag_from_proof_groups(
+        &groups
+            .iter()
+            .map(|(query_and_proofs, ir_clauses)| (&query_and_proofs[..], ir_clauses))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Resolves a multi-platform image reference to the image of one platform. This needs
+/// registry access, so it's up to the caller to provide one.
+pub trait PlatformResolver {
+    /// Returns a reference to the image of the given platform, pinned by its digest.
+    fn resolve_platform(&self, image: &PlatformImage) -> Result<String, String>;
+}
+
+/// Resolves the images the Modusfile selects with `platform_image(Ref, Os, Arch, Resolved)`,
+/// adding a `platform_image` fact for each one.
+///
+/// This is the first phase of planning such a Modusfile, before [plan_from_modusfile]. The
+/// literals are collected without solving anything, so their Ref, Os and Arch have to be
+/// string constants. Like other builtins that depend on the host, querying the registry
+/// needs `allow_impure`.
+pub fn resolve_platform_images(
+    mf: &mut Modusfile,
+    resolver: &dyn PlatformResolver,
+    allow_impure: bool,
+) -> Result<(), Vec<Diagnostic<()>>> {
+    let mut images = HashSet::new();
+    let mut errs = Vec::new();
+    for clause in mf.0.iter() {
+        let body_lits = clause
             .body
+            .as_ref()
+            .map(|b| b.literals())
+            .unwrap_or_default();
+        for lit in body_lits {
+            if lit.predicate.0 != PLATFORM_IMAGE_PREDICATE || lit.args.len() != 4 {
+                continue;
+            }
+            let message = match &lit.args[..3] {
+                [ModusTerm::Constant(_), ModusTerm::Constant(_), ModusTerm::Constant(_)]
+                    if !allow_impure =>
+                {
+                    "platform_image queries the registry, so it requires --allow-impure."
+                }
+                [ModusTerm::Constant(image_ref), ModusTerm::Constant(os), ModusTerm::Constant(arch)] =>
+                {
+                    images.insert(PlatformImage {
+                        image_ref: image_ref.to_owned(),
+                        os: os.to_owned(),
+                        arch: arch.to_owned(),
+                    });
+                    continue;
+                }
+                _ => "The image reference, OS and architecture given to platform_image must be string constants.",
+            };
+            let mut diag = Diagnostic::error().with_message(message);
+            if let Some(pos) = &lit.position {
+                diag = diag.with_labels(vec![Label::primary(
+                    (),
+                    pos.offset..pos.offset + pos.length,
+                )]);
+            }
+            errs.push(diag);
+        }
+    }
+    if !errs.is_empty() {
+        return Err(errs);
+    }
+
+    let mut images = images.into_iter().collect::<Vec<_>>();
+    images.sort();
+    let mut resolved = Vec::with_capacity(images.len());
+    for image in images {
+        match resolver.resolve_platform(&image) {
+            Ok(resolved_ref) => resolved.push((image, resolved_ref)),
+            Err(e) => errs.push(Diagnostic::error().with_message(format!(
+                "Unable to resolve {} for {}/{}: {}",
+                image.image_ref, image.os, image.arch, e
+            ))),
+        }
+    }
+    if !errs.is_empty() {
+        return Err(errs);
+    }
+    mf.add_platform_images(&resolved);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output_names(plan: &BuildPlan) -> Vec<String> {
+        let mut names = plan
+            .outputs
             .iter()
-            .find(|lit| lit.predicate == expression_image_literal.predicate)
-            .expect("should find matching predicate name after translation");
-        Ok(image_literal.clone())
+            .map(|o| o.source_literal.as_ref().unwrap().predicate.0.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
     }
 
-    let max_depth = 175;
+    #[test]
+    fn disjunctive_query_builds_each_alternative() {
+        let mf: Modusfile = "a :- from(\"alpine\").\nb :- from(\"ubuntu\")."
+            .parse()
+            .unwrap();
+
+        let query: modusfile::Expression = "a ; b".parse().unwrap();
+        let plan = plan_from_modusfile(mf.clone(), query, sld::DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(output_names(&plan), vec!["a", "b"]);
 
-    let goal_pred = Predicate("_query".to_owned());
-    let mut mf_with_query = mf.clone();
-    mf_with_query.add_goal(query.clone());
-    let ir_clauses: Vec<Clause> = translate_modusfile(&mf_with_query);
+        let query: modusfile::Expression = "(a, number_gt(\"1\", \"2\")) ; b".parse().unwrap();
+        let plan = plan_from_modusfile(mf.clone(), query, sld::DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(output_names(&plan), vec!["b"]);
 
-    let q_clause = ir_clauses
-        .iter()
-        .find(|c| c.head.predicate == goal_pred)
-        .expect("should find same predicate name after translation");
-    let query_goal = &q_clause.body;
+        let query: modusfile::Expression = "(a, b) ; b".parse().unwrap();
+        assert!(plan_from_modusfile(mf, query, sld::DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn empty_modusfile_is_an_error() {
+        let query: modusfile::Expression = "app".parse().unwrap();
+        let mf: Modusfile = "# nothing here yet\n".parse().unwrap();
+        let errs = plan_from_modusfile(mf, query.clone(), sld::DEFAULT_MAX_DEPTH).unwrap_err();
+        assert!(errs[0].message.contains("no rules"));
 
-    let image_literal = get_image_literal(&query, &mf_with_query, q_clause)?;
+        let mf: Modusfile = "base :- from(\"alpine\").".parse().unwrap();
+        let errs = plan_from_modusfile(mf, query, sld::DEFAULT_MAX_DEPTH).unwrap_err();
+        assert!(errs[0].message.contains("no rules for app"));
+    }
 
-    // don't store full tree as this takes a lot of memory, and is probably not needed
-    // when building/transpiling
-    let success_tree = Result::from(sld::sld(&ir_clauses, &query_goal, max_depth, false))?;
-    let proofs = sld::proofs(&success_tree, &ir_clauses, &query_goal);
+    #[test]
+    fn max_depth_limits_resolution() {
+        let mut rules = vec!["step0 :- from(\"alpine\").".to_string()];
+        rules.extend((1..50).map(|i| format!("step{} :- step{}.", i, i - 1)));
+        let mf: Modusfile = rules.join("\n").parse().unwrap();
+        let query: modusfile::Expression = "step49".parse().unwrap();
 
-    let query_and_proofs = proofs
-        .into_iter()
-        .map(|(_, p)| (image_literal.substitute(&p.valuation), p))
-        .collect::<Vec<_>>();
-    Ok(build_dag_from_proofs(&query_and_proofs[..], &ir_clauses))
+        assert!(plan_from_modusfile(mf.clone(), query.clone(), 500).is_ok());
+
+        let errs = plan_from_modusfile(mf, query, 10).unwrap_err();
+        assert!(errs[0].message.contains("maximum depth of 10"));
+    }
+
+    #[test]
+    fn logic_only_proof_is_an_error() {
+        let mf: Modusfile = "a :- string_eq(\"x\", \"x\").".parse().unwrap();
+        let (goal, clauses, sld_result) =
+            sld::tree_from_modusfile(mf.clone(), "a".parse().unwrap(), 20, false);
+        let tree = Result::from(sld_result).unwrap();
+        let query_and_proofs = sld::proofs(&tree, &clauses, &goal)
+            .into_iter()
+            .map(|(_, p)| (goal[0].clone(), p))
+            .collect::<Vec<_>>();
+
+        let errs = build_dag_from_proofs(&query_and_proofs, &clauses).unwrap_err();
+        assert!(errs[0]
+            .message
+            .contains("does not resolve to any docker instructions"));
+
+        assert!(plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn several_queries_share_images() {
+        let mf: Modusfile = "base :- from(\"alpine\"), run(\"apk add gcc\").\n\
+                             app(\"debug\") :- base, run(\"make debug\").\n\
+                             app(\"release\") :- base, run(\"make release\")."
+            .parse()
+            .unwrap();
+
+        let queries: Vec<modusfile::Expression> = vec![
+            "app(\"debug\")".parse().unwrap(),
+            "app(\"release\")".parse().unwrap(),
+        ];
+        let plan =
+            plan_from_modusfile_queries(mf.clone(), &queries, sld::DEFAULT_MAX_DEPTH).unwrap();
+        let outputs = plan
+            .outputs
+            .iter()
+            .map(|o| o.source_literal.as_ref().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, vec!["app(\"debug\")", "app(\"release\")"]);
+        let nb_runs = |command: &str| {
+            plan.nodes
+                .iter()
+                .filter(|node| matches!(node, BuildNode::Run { command: c, .. } if c == command))
+                .count()
+        };
+        assert_eq!(nb_runs("apk add gcc"), 1);
+        assert_eq!(nb_runs("make debug"), 1);
+
+        let queries: Vec<modusfile::Expression> = vec![
+            "app(\"debug\")".parse().unwrap(),
+            "app(\"test\")".parse().unwrap(),
+        ];
+        assert!(plan_from_modusfile_queries(mf, &queries, sld::DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn copy_chown_is_optional() {
+        let mf: Modusfile = "builder :- from(\"alpine\"), run(\"make\").\n\
+                             a :- from(\"alpine\"), \
+                                  copy(\"config\", \"/etc/app\", \"app:app\"), \
+                                  copy(\"README\", \"/\"), \
+                                  builder::copy(\"/out\", \"/bin\", \"1000\"), \
+                                  builder::copy(\"/out\", \"/opt\").\n\
+                             b :- from(\"alpine\"), copy(\"config\", \"/etc/app\", \"-R\")."
+            .parse()
+            .unwrap();
+        let plan =
+            plan_from_modusfile(mf.clone(), "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let chowns =
+            plan.nodes
+                .iter()
+                .filter_map(|node| match node {
+                    BuildNode::CopyFromLocal { chown, .. }
+                    | BuildNode::CopyFromImage { chown, .. } => Some(chown.as_deref()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+        assert_eq!(chowns, vec![Some("app:app"), None, Some("1000"), None]);
+
+        assert!(plan_from_modusfile(mf, "b".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn copy_chmod_is_optional() {
+        let mf: Modusfile = "builder :- from(\"alpine\"), run(\"make\").\n\
+                             a :- from(\"alpine\"), \
+                                  copy(\"run.sh\", \"/bin/\", \"\", \"0755\"), \
+                                  builder::copy(\"/out\", \"/opt\", \"app\", \"640\").\n\
+                             b :- from(\"alpine\"), copy(\"run.sh\", \"/bin/\", \"\", \"u+x\")."
+            .parse()
+            .unwrap();
+        let plan =
+            plan_from_modusfile(mf.clone(), "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let options = plan
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                BuildNode::CopyFromLocal { chown, chmod, .. }
+                | BuildNode::CopyFromImage { chown, chmod, .. } => {
+                    Some((chown.as_deref(), chmod.as_deref()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            options,
+            vec![(None, Some("0755")), (Some("app"), Some("640"))]
+        );
+
+        assert!(plan_from_modusfile(mf, "b".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn add_url_checked_carries_checksum() {
+        let sha256 = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        let mf: Modusfile = format!(
+            "a :- from(\"alpine\"), \
+                  add_url_checked(\"https://example.com/tool.tar.gz\", \"tool.tar.gz\", \"{}\")::in_workdir(\"/opt\").\n\
+             b :- from(\"alpine\"), add_url_checked(\"https://example.com/tool.tar.gz\", \"/\", \"abc\").",
+            sha256
+        )
+        .parse()
+        .unwrap();
+        let plan =
+            plan_from_modusfile(mf.clone(), "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let add_url = plan
+            .nodes
+            .iter()
+            .find_map(|node| match node {
+                BuildNode::AddUrl {
+                    url,
+                    dst_path,
+                    sha256,
+                    ..
+                } => Some((url.as_str(), dst_path.as_str(), sha256.clone())),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            add_url,
+            (
+                "https://example.com/tool.tar.gz",
+                "/opt/tool.tar.gz",
+                sha256.to_lowercase()
+            )
+        );
+
+        assert!(plan_from_modusfile(mf, "b".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn cache_mount_is_attached_to_runs() {
+        let mf: Modusfile = "a :- from(\"node\"), \
+                (run(\"npm ci\")::in_workdir(\"/app\"))::cache_mount(\"/root/.npm\"), \
+                run(\"apt-get update\")::cache_mount(\"/var/cache/apt\", [\"id=apt\", \"sharing=locked\"]), \
+                run(\"ls\").\n\
+             b :- from(\"node\"), run(\"ls\")::cache_mount(\"/tmp\", [\"sharing=exclusive\"])."
+            .parse()
+            .unwrap();
+        let plan =
+            plan_from_modusfile(mf.clone(), "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let runs = plan
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                BuildNode::Run {
+                    command,
+                    cache_mounts,
+                    ..
+                } => Some((command.as_str(), cache_mounts.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            runs,
+            vec![
+                (
+                    "npm ci",
+                    vec![CacheMount {
+                        target: "/root/.npm".to_owned(),
+                        id: None,
+                        sharing: None,
+                    }]
+                ),
+                (
+                    "apt-get update",
+                    vec![CacheMount {
+                        target: "/var/cache/apt".to_owned(),
+                        id: Some("apt".to_owned()),
+                        sharing: Some("locked".to_owned()),
+                    }]
+                ),
+                ("ls", vec![]),
+            ]
+        );
+
+        assert!(plan_from_modusfile(mf, "b".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn missing_operator_end_is_reported() {
+        fn builtin(name: &str, args: &[&str]) -> Proof {
+            Proof {
+                clause: ClauseId::Builtin(Literal {
+                    positive: true,
+                    position: None,
+                    predicate: Predicate(name.to_owned()),
+                    args: args
+                        .iter()
+                        .map(|arg| IRTerm::Constant((*arg).to_owned()))
+                        .collect(),
+                }),
+                valuation: HashMap::new(),
+                children: Vec::new(),
+            }
+        }
+        let proof = |children: Vec<Proof>| Proof {
+            clause: ClauseId::Query,
+            valuation: HashMap::new(),
+            children,
+        };
+
+        let well_formed = proof(vec![
+            builtin("from", &["alpine"]),
+            builtin("_operator_in_workdir_begin", &["0", "/app"]),
+            builtin("run", &["make"]),
+            builtin("_operator_in_workdir_end", &["0", "/app"]),
+        ]);
+        assert!(check_operator_markers(&well_formed).is_ok());
+
+        let missing_end = proof(vec![
+            builtin("from", &["alpine"]),
+            proof(vec![
+                builtin("_operator_in_workdir_begin", &["0", "/app"]),
+                builtin("run", &["make"]),
+                builtin("_operator_in_workdir_end", &["1", "/app"]),
+            ]),
+        ]);
+        let err = check_operator_markers(&missing_end).unwrap_err();
+        assert!(err.message.contains("in_workdir"), "{}", err.message);
+        let query = Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("a".to_owned()),
+            args: Vec::new(),
+        };
+        let errs = build_dag_from_proofs(&[(query, missing_end)], &Vec::new()).unwrap_err();
+        assert!(
+            errs[0].message.contains("in_workdir"),
+            "{}",
+            errs[0].message
+        );
+
+        let missing_begin = proof(vec![
+            builtin("from", &["alpine"]),
+            builtin("run", &["make"]),
+            builtin("_operator_in_workdir_end", &["0", "/app"]),
+        ]);
+        assert!(check_operator_markers(&missing_begin).is_err());
+    }
+
+    #[test]
+    fn ungrounded_intrinsic_args_are_reported() {
+        let mf: Modusfile = "a :- from(\"alpine\"), run(X).".parse().unwrap();
+        let query: modusfile::Expression = "a".parse().unwrap();
+        assert!(plan_from_modusfile(mf, query, sld::DEFAULT_MAX_DEPTH).is_err());
+
+        let run = |arg: IRTerm| Proof {
+            clause: ClauseId::Builtin(Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("run".to_owned()),
+                args: vec![arg],
+            }),
+            valuation: HashMap::new(),
+            children: Vec::new(),
+        };
+        let proof = |children: Vec<Proof>| Proof {
+            clause: ClauseId::Query,
+            valuation: HashMap::new(),
+            children,
+        };
+
+        let ground = proof(vec![run(IRTerm::Constant("make".to_owned()))]);
+        assert!(check_intrinsics_ground(&ground).is_ok());
+
+        let free = proof(vec![
+            run(IRTerm::Constant("make".to_owned())),
+            run(IRTerm::RenamedVariable(
+                3,
+                Box::new(IRTerm::UserVariable("X".to_owned())),
+            )),
+        ]);
+        let err = check_intrinsics_ground(&free).unwrap_err();
+        assert_eq!(
+            err.message,
+            "The variable `X` in this run is not bound to a value."
+        );
+    }
+
+    #[test]
+    fn in_secret_is_attached_to_runs() {
+        let mf: Modusfile = "a :- from(\"node\"), \
+                (run(\"npm ci\")::in_secret(\"npmrc\", \"/root/.npmrc\"), \
+                 run(\"npm test\"))::in_secret(\"token\").\n\
+             b :- from(\"node\"), run(\"ls\")::in_secret(\"id=token\")."
+            .parse()
+            .unwrap();
+        let plan =
+            plan_from_modusfile(mf.clone(), "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let runs = plan
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                BuildNode::Run {
+                    command, secrets, ..
+                } => Some((command.as_str(), secrets.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let token = SecretMount {
+            id: "token".to_owned(),
+            target: None,
+        };
+        let npmrc = SecretMount {
+            id: "npmrc".to_owned(),
+            target: Some("/root/.npmrc".to_owned()),
+        };
+        assert_eq!(
+            runs,
+            vec![
+                ("npm ci", vec![token.clone(), npmrc]),
+                ("npm test", vec![token]),
+            ]
+        );
+
+        assert!(plan_from_modusfile(mf, "b".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn set_stopsignal_adds_node() {
+        let mf: Modusfile = "a :- from(\"nginx\")::set_stopsignal(\"SIGQUIT\").\n\
+                             b :- from(\"nginx\")::set_stopsignal(\"QUIT\")."
+            .parse()
+            .unwrap();
+        let plan =
+            plan_from_modusfile(mf.clone(), "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        assert!(plan.nodes.iter().any(|node| matches!(
+            node,
+            BuildNode::SetStopSignal { signal, .. } if signal == "SIGQUIT"
+        )));
+
+        let errs =
+            plan_from_modusfile(mf, "b".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap_err();
+        assert!(errs
+            .iter()
+            .flat_map(|d| &d.notes)
+            .any(|note| note.contains("\"QUIT\" is not a valid stop signal")));
+    }
+
+    #[test]
+    fn in_workdir_warns_about_fresh_images() {
+        // The kind checker rejects images inside in_workdir, so this builds the proofs by hand.
+        fn builtin(name: &str, args: &[&str]) -> Proof {
+            Proof {
+                clause: ClauseId::Builtin(Literal {
+                    positive: true,
+                    position: None,
+                    predicate: Predicate(name.to_owned()),
+                    args: args
+                        .iter()
+                        .map(|arg| IRTerm::Constant((*arg).to_owned()))
+                        .collect(),
+                }),
+                valuation: HashMap::new(),
+                children: Vec::new(),
+            }
+        }
+        let warnings = |children: Vec<Proof>| {
+            let query = Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("app".to_owned()),
+                args: Vec::new(),
+            };
+            let proof = Proof {
+                clause: ClauseId::Query,
+                valuation: HashMap::new(),
+                children,
+            };
+            build_dag_from_proofs(&[(query, proof)], &Vec::new())
+                .unwrap()
+                .warnings
+        };
+
+        let inside_image = warnings(vec![
+            builtin("from", &["alpine"]),
+            builtin("_operator_in_workdir_begin", &["0", "/src"]),
+            builtin("run", &["make"]),
+            builtin("_operator_in_workdir_end", &["0", "/src"]),
+        ]);
+        assert!(inside_image.is_empty());
+
+        let fresh_image = warnings(vec![
+            builtin("_operator_in_workdir_begin", &["0", "/src"]),
+            builtin("from", &["alpine"]),
+            builtin("run", &["make"]),
+            builtin("_operator_in_workdir_end", &["0", "/src"]),
+        ]);
+        assert_eq!(fresh_image.len(), 1);
+        assert_eq!(
+            fresh_image[0].severity,
+            codespan_reporting::diagnostic::Severity::Warning
+        );
+        assert!(fresh_image[0].message.contains("in_workdir"));
+    }
+
+    #[test]
+    fn nodes_changed_since_follows_local_copies() {
+        let context = tempfile::tempdir().unwrap();
+        std::fs::create_dir(context.path().join("src")).unwrap();
+        std::fs::write(context.path().join("src/main.c"), "int main() {}").unwrap();
+        std::fs::write(context.path().join("README"), "hi").unwrap();
+
+        let mf: Modusfile = "a :- from(\"gcc\"), copy(\"README\", \"/README\"), \
+                                  copy(\"src\", \"/src\"), run(\"gcc src/main.c\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        // from, the two copies, run and the labels of the output.
+        assert_eq!(plan.nodes.len(), 6);
+
+        let hour = std::time::Duration::from_secs(3600);
+        let before = SystemTime::now() - hour;
+        let changed = plan.nodes_changed_since(context.path(), before).unwrap();
+        assert_eq!(changed, vec![false, true, true, true, true, true]);
+
+        let after = SystemTime::now() + hour;
+        let changed = plan.nodes_changed_since(context.path(), after).unwrap();
+        assert_eq!(changed, vec![false; 6]);
+
+        std::fs::remove_file(context.path().join("src/main.c")).unwrap();
+        std::fs::remove_dir(context.path().join("src")).unwrap();
+        let changed = plan.nodes_changed_since(context.path(), after).unwrap();
+        assert_eq!(changed, vec![false, false, true, true, true, true]);
+    }
+
+    #[test]
+    fn set_healthcheck_adds_node() {
+        let mf: Modusfile = "a :- from(\"nginx\")::set_healthcheck( \
+                                 [\"curl\", \"-f\", \"http://localhost/\"], \
+                                 interval: \"30s\", timeout: \"\", retries: \"3\").\n\
+                             b :- from(\"nginx\")::set_healthcheck(\"pgrep nginx\").\n\
+                             c :- from(\"nginx\")::set_healthcheck(\"true\", interval: \"soon\")."
+            .parse()
+            .unwrap();
+
+        let plan =
+            plan_from_modusfile(mf.clone(), "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let healthcheck = plan
+            .nodes
+            .iter()
+            .find(|node| matches!(node, BuildNode::SetHealthcheck { .. }))
+            .expect("plan has no healthcheck node");
+        match healthcheck {
+            BuildNode::SetHealthcheck {
+                command,
+                interval,
+                timeout,
+                retries,
+                ..
+            } => {
+                assert_eq!(command, &["curl", "-f", "http://localhost/"]);
+                assert_eq!(interval.as_deref(), Some("30s"));
+                assert_eq!(timeout, &None);
+                assert_eq!(retries, &Some(3));
+            }
+            _ => unreachable!(),
+        }
+
+        let plan =
+            plan_from_modusfile(mf.clone(), "b".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        assert!(plan.nodes.iter().any(|node| matches!(
+            node,
+            BuildNode::SetHealthcheck { command, interval: None, .. }
+                if command == &["/bin/sh", "-c", "pgrep nginx"]
+        )));
+        let output = plan.outputs[0].node;
+        assert!(matches!(
+            plan.nodes[plan.healthcheck(output).unwrap()],
+            BuildNode::SetHealthcheck { .. }
+        ));
+        assert_eq!(plan.healthcheck(plan.base_node(output)), None);
+
+        assert!(plan_from_modusfile(mf, "c".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn squash_wraps_operations() {
+        let mf: Modusfile = "a :- from(\"alpine\")::set_workdir(\"/app\"), \
+                               (run(\"apk add gcc\"), run(\"make\"))::squash."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let (parent, squashed) = plan
+            .nodes
+            .iter()
+            .find_map(|node| match node {
+                BuildNode::Squash { parent, squashed } => Some((*parent, *squashed)),
+                _ => None,
+            })
+            .expect("plan has no squash node");
+        assert!(matches!(plan.nodes[parent], BuildNode::SetWorkdir { .. }));
+        match &plan.nodes[squashed] {
+            BuildNode::Run {
+                parent: run_parent,
+                command,
+                ..
+            } => {
+                assert_eq!(command, "make");
+                assert!(matches!(
+                    &plan.nodes[*run_parent],
+                    BuildNode::Run { parent: p, .. } if *p == parent
+                ));
+            }
+            node => panic!("expected a run node, got {:?}", node),
+        }
+    }
+
+    #[test]
+    fn set_expose_adds_node() {
+        let mf: Modusfile = "a :- from(\"nginx\")::set_expose(\"8080/tcp\").\n\
+                             b :- from(\"nginx\")::set_expose(\"http\")."
+            .parse()
+            .unwrap();
+        let plan =
+            plan_from_modusfile(mf.clone(), "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        assert!(plan.nodes.iter().any(|node| matches!(
+            node,
+            BuildNode::SetExpose { port, .. } if port == "8080/tcp"
+        )));
+
+        let errs =
+            plan_from_modusfile(mf, "b".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap_err();
+        assert!(errs
+            .iter()
+            .flat_map(|d| &d.notes)
+            .any(|note| note.contains("\"http\" is not a valid port")));
+    }
+
+    #[test]
+    fn exclude_is_attached_to_local_copies() {
+        let mf: Modusfile = "a :- from(\"node\"), \
+                (copy(\"package.json\", \".\"), copy(\".\", \"/app\"))::exclude([\"node_modules\", \"*.log\"]), \
+                copy(\"README.md\", \"/app\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+
+        let copies = plan
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                BuildNode::CopyFromLocal {
+                    src_path, excludes, ..
+                } => Some((src_path.as_str(), excludes.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let excluded = vec!["node_modules".to_owned(), "*.log".to_owned()];
+        assert_eq!(
+            copies,
+            vec![
+                ("package.json", excluded.clone()),
+                (".", excluded),
+                ("README.md", Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn build_args_override_defaults() {
+        let mut mf: Modusfile = "arg(\"VERSION\", \"1.0\").\n\
+                                 a :- from(\"alpine\"), arg(\"VERSION\", V), run(f\"echo ${V}\")."
+            .parse()
+            .unwrap();
+        let run_commands = |mf: Modusfile| {
+            plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH)
+                .unwrap()
+                .nodes
+                .into_iter()
+                .filter_map(|node| match node {
+                    BuildNode::Run { command, .. } => Some(command),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run_commands(mf.clone()), vec!["echo 1.0"]);
+
+        mf.set_build_args(&[("VERSION".to_owned(), "2.0".to_owned())]);
+        assert_eq!(run_commands(mf), vec!["echo 2.0"]);
+    }
+
+    #[test]
+    fn queries_use_their_own_build_args() {
+        let mf: Modusfile = "arg(\"VERSION\", \"1.0\").\n\
+                             a :- from(\"alpine\"), arg(\"VERSION\", V), run(f\"echo ${V}\")."
+            .parse()
+            .unwrap();
+        let queries = vec!["a".parse().unwrap(), "a".parse().unwrap()];
+        let plan = plan_from_modusfile_queries_with_args(
+            mf,
+            &queries,
+            &[Vec::new(), vec![("VERSION".to_owned(), "2.0".to_owned())]],
+            sld::DEFAULT_MAX_DEPTH,
+        )
+        .unwrap();
+        let output_commands = plan
+            .outputs
+            .iter()
+            .map(|output| {
+                let mut node = output.node;
+                loop {
+                    match &plan.nodes[node] {
+                        BuildNode::Run { command, .. } => break command.as_str(),
+                        _ => node = plan.parent(node).unwrap(),
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(output_commands, vec!["echo 1.0", "echo 2.0"]);
+    }
+
+    #[test]
+    fn features_enable_guarded_rules() {
+        let mf: Modusfile = "a :- from(\"alpine\"), run(\"echo base\"), debug.\n\
+                             debug :- feature(\"debug\"), run(\"echo debug\").\n\
+                             debug :- !feature(\"debug\")."
+            .parse()
+            .unwrap();
+        let run_commands = |mf: Modusfile| {
+            plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH)
+                .unwrap()
+                .nodes
+                .into_iter()
+                .filter_map(|node| match node {
+                    BuildNode::Run { command, .. } => Some(command),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run_commands(mf.clone()), vec!["echo base"]);
+
+        let mut with_debug = mf.clone();
+        with_debug.set_features(&["debug".to_owned()]);
+        assert_eq!(run_commands(with_debug), vec!["echo base", "echo debug"]);
+
+        let mut with_other = mf;
+        with_other.set_features(&["other".to_owned()]);
+        assert_eq!(run_commands(with_other), vec!["echo base"]);
+    }
+
+    #[test]
+    fn add_file_creates_a_file() {
+        let mf: Modusfile =
+            "a :- from(\"alpine\")::add_file(\"/etc/foo.conf\", \"key = value\\n\", \"0600\")."
+                .parse()
+                .unwrap();
+        let plan = plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let created = plan
+            .nodes
+            .iter()
+            .find_map(|node| match node {
+                BuildNode::CreateFile {
+                    path,
+                    contents,
+                    mode,
+                    ..
+                } => Some((path, contents, mode)),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            created,
+            (
+                &"/etc/foo.conf".to_owned(),
+                &"key = value\n".to_owned(),
+                &Some("0600".to_owned())
+            )
+        );
+        let json = serde_json::to_value(&plan).unwrap();
+        assert!(json["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|node| node["CreateFile"]["contents"] == "key = value\n"));
+
+        let invalid_mode: Modusfile =
+            "a :- from(\"alpine\")::add_file(\"/etc/foo.conf\", \"\", \"rw\")."
+                .parse()
+                .unwrap();
+        assert!(
+            plan_from_modusfile(invalid_mode, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn empty_entrypoint_and_cmd_clear_the_parents() {
+        let mf: Modusfile = "a :- from(\"alpine\")::set_entrypoint([])::set_cmd([])."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        assert!(plan.nodes.iter().any(|node| matches!(
+            node,
+            BuildNode::SetEntrypoint { new_entrypoint, .. } if new_entrypoint.is_empty()
+        )));
+        assert!(plan.nodes.iter().any(|node| matches!(
+            node,
+            BuildNode::SetCmd { new_cmd, .. } if new_cmd.is_empty()
+        )));
+    }
+
+    #[test]
+    fn cache_policy_is_serialized() {
+        let mf: Modusfile = "a :- from(\"alpine\"),\n\
+                                  run(\"apk update\")::no_cache,\n\
+                                  run(\"make\")::cache_id(\"v2\"),\n\
+                                  run(\"true\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let json = serde_json::to_value(&plan).unwrap();
+        let caches = json["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|node| node.get("Run"))
+            .map(|run| (run["command"].as_str().unwrap(), run["cache"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            caches,
+            vec![
+                ("apk update", serde_json::json!("Never")),
+                ("make", serde_json::json!({ "Id": "v2" })),
+                ("true", serde_json::json!("Default")),
+            ]
+        );
+
+        let round_trip: BuildPlan = serde_json::from_value(json).unwrap();
+        assert!(matches!(
+            &round_trip.nodes[1],
+            BuildNode::Run {
+                cache: CachePolicy::Never,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn merge_keeps_cache_policy() {
+        let mf: Modusfile = "a :- from(\"alpine\"),\n\
+                                  (run(\"apk update\")::no_cache, run(\"make\")::cache_id(\"v2\"))::merge."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let operations = plan
+            .nodes
+            .iter()
+            .find_map(|node| match node {
+                BuildNode::Merge(MergeNode { operations, .. }) => Some(operations),
+                _ => None,
+            })
+            .expect("plan has no merge");
+        let caches = operations
+            .iter()
+            .map(|op| match op {
+                MergeOperation::Run { cache, .. } => cache.clone(),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            caches,
+            vec![CachePolicy::Never, CachePolicy::Id("v2".to_owned())]
+        );
+    }
+
+    #[test]
+    fn no_network_applies_to_runs_in_scope() {
+        let mf: Modusfile = "a :- from(\"alpine\"),\n\
+                                  (run(\"make\"), (run(\"a\"), run(\"b\"))::merge)::no_network,\n\
+                                  run(\"apk update\")."
+            .parse()
+            .unwrap();
+        let plan = plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let runs = plan
+            .nodes
+            .iter()
+            .flat_map(|node| match node {
+                BuildNode::Run {
+                    command,
+                    no_network,
+                    ..
+                } => vec![(command.as_str(), *no_network)],
+                BuildNode::Merge(MergeNode { operations, .. }) => operations
+                    .iter()
+                    .map(|op| match op {
+                        MergeOperation::Run {
+                            command,
+                            no_network,
+                            ..
+                        } => (command.as_str(), *no_network),
+                        _ => unreachable!(),
+                    })
+                    .collect(),
+                _ => vec![],
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            runs,
+            vec![
+                ("make", true),
+                ("a", true),
+                ("b", true),
+                ("apk update", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn run_as_overrides_output_user() {
+        let mf: Modusfile = "a :- from(\"alpine\"), run(\"true\").\n\
+                             b :- a::set_user(\"root\")."
+            .parse()
+            .unwrap();
+        let mut plan = plan_from_modusfile_queries(
+            mf,
+            &["a".parse().unwrap(), "b".parse().unwrap()],
+            sld::DEFAULT_MAX_DEPTH,
+        )
+        .unwrap();
+        assert!(plan.warnings.is_empty());
+        let old_outputs = plan.outputs.iter().map(|o| o.node).collect::<Vec<_>>();
+        plan.override_output_user("nobody");
+
+        for (output, old_node) in plan.outputs.iter().zip(old_outputs) {
+            match &plan.nodes[output.node] {
+                BuildNode::SetUser { parent, user } => {
+                    assert_eq!(*parent, old_node);
+                    assert_eq!(user, "nobody");
+                }
+                node => panic!("expected a set_user node, got {:?}", node),
+            }
+            assert_eq!(plan.user(output.node), Some("nobody"));
+        }
+        // Only b sets a user in the Modusfile.
+        assert_eq!(plan.warnings.len(), 1);
+    }
+
+    #[test]
+    fn build_arg_default_selects_base_image() {
+        let mut mf: Modusfile = "a :- from(f\"alpine:${ALPINE_VERSION:-3.15}\"), run(\"true\")."
+            .parse()
+            .unwrap();
+        let from_refs = |mf: Modusfile| {
+            plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH)
+                .unwrap()
+                .nodes
+                .into_iter()
+                .filter_map(|node| match node {
+                    BuildNode::From { image_ref, .. } => Some(image_ref),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(from_refs(mf.clone()), vec!["alpine:3.15"]);
+
+        mf.set_build_args(&[("ALPINE_VERSION".to_owned(), "3.16".to_owned())]);
+        assert_eq!(from_refs(mf), vec!["alpine:3.16"]);
+    }
+
+    #[test]
+    fn platform_image_is_resolved_before_planning() {
+        struct StubResolver;
+        impl PlatformResolver for StubResolver {
+            fn resolve_platform(&self, image: &PlatformImage) -> Result<String, String> {
+                match (image.os.as_str(), image.arch.as_str()) {
+                    ("linux", "arm64") => {
+                        Ok(format!("{}@sha256:{}", image.image_ref, "a".repeat(64)))
+                    }
+                    _ => Err("no such platform".to_owned()),
+                }
+            }
+        }
+
+        let mut mf: Modusfile =
+            "a :- platform_image(\"alpine\", \"linux\", \"arm64\", R), from(R)."
+                .parse()
+                .unwrap();
+        let errs = resolve_platform_images(&mut mf.clone(), &StubResolver, false).unwrap_err();
+        assert!(errs[0].message.contains("--allow-impure"));
+        resolve_platform_images(&mut mf, &StubResolver, true).unwrap();
+        let plan = plan_from_modusfile(mf, "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let image_refs = plan
+            .nodes
+            .iter()
+            .filter_map(|node| match node {
+                BuildNode::From { image_ref, .. } => Some(image_ref.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            image_refs,
+            vec![format!("alpine@sha256:{}", "a".repeat(64))]
+        );
+
+        let mut mf: Modusfile =
+            "a :- platform_image(\"alpine\", \"linux\", \"s390x\", R), from(R)."
+                .parse()
+                .unwrap();
+        assert!(resolve_platform_images(&mut mf, &StubResolver, true).is_err());
+
+        let mut mf: Modusfile =
+            "a(Arch) :- platform_image(\"alpine\", \"linux\", Arch, R), from(R)."
+                .parse()
+                .unwrap();
+        assert!(resolve_platform_images(&mut mf, &StubResolver, true).is_err());
+    }
+
+    #[test]
+    fn set_shell_applies_to_later_runs() {
+        let mf: Modusfile = "base :- from(\"ubuntu\")::set_shell([\"/bin/bash\", \"-c\"]).\n\
+                             a :- base, run(\"echo $BASH_VERSION\").\n\
+                             b :- from(\"ubuntu\"), run(\"echo $0\").\n\
+                             c :- from(\"ubuntu\")::set_shell(\"bash\")."
+            .parse()
+            .unwrap();
+
+        let plan =
+            plan_from_modusfile(mf.clone(), "a".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        let run = plan
+            .nodes
+            .iter()
+            .position(|node| matches!(node, BuildNode::Run { .. }))
+            .unwrap();
+        assert_eq!(
+            plan.shell(run),
+            Some(&["/bin/bash".to_owned(), "-c".to_owned()][..])
+        );
+
+        let plan =
+            plan_from_modusfile(mf.clone(), "b".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(plan.shell(plan.outputs[0].node), None);
+
+        assert!(plan_from_modusfile(mf, "c".parse().unwrap(), sld::DEFAULT_MAX_DEPTH).is_err());
+    }
 }
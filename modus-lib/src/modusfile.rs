@@ -16,24 +16,31 @@
 
 use codespan_reporting::diagnostic::Diagnostic;
 use codespan_reporting::diagnostic::Label;
+use codespan_reporting::files::{SimpleFile, SimpleFiles};
+use codespan_reporting::term::{self, termcolor::WriteColor, Config};
 use nom::character::complete::line_ending;
 use nom::character::complete::not_line_ending;
 use nom_supreme::error::BaseErrorKind;
 use nom_supreme::error::ErrorTree;
 use nom_supreme::error::StackContext;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str;
 
+use thiserror::Error;
+
+use crate::builtin::json::json_to_term;
+use crate::builtin::OPERATOR_PARAMETERS;
 use crate::logic;
 use crate::logic::parser::Span;
 use crate::logic::Predicate;
 use crate::logic::SpannedPosition;
 use crate::sld;
 
-use self::parser::process_raw_string;
+use self::parser::{escape_raw_string, process_raw_string};
 
 /// Represents expressions that could be found in the body of a ModusClause.
 /// Each enum variant will have some notion of span and whether it's negated.
@@ -179,6 +186,48 @@ impl Expression {
                 .collect(),
         }
     }
+
+    fn substitute_build_args(&self, build_args: &HashMap<String, String>) -> Expression {
+        let substitute_all = |args: &[ModusTerm]| {
+            args.iter()
+                .map(|arg| arg.substitute_build_args(build_args))
+                .collect()
+        };
+        match self {
+            Expression::Literal(lit) => Expression::Literal(Literal {
+                args: substitute_all(&lit.args),
+                ..lit.clone()
+            }),
+            Expression::OperatorApplication(s, e, op) => Expression::OperatorApplication(
+                s.clone(),
+                Box::new(e.substitute_build_args(build_args)),
+                Operator {
+                    args: substitute_all(&op.args),
+                    keyword_args: op
+                        .keyword_args
+                        .iter()
+                        .map(|kw| KeywordArg {
+                            name: kw.name.clone(),
+                            value: kw.value.substitute_build_args(build_args),
+                        })
+                        .collect(),
+                    ..op.clone()
+                },
+            ),
+            Expression::And(s, positive, e1, e2) => Expression::And(
+                s.clone(),
+                *positive,
+                Box::new(e1.substitute_build_args(build_args)),
+                Box::new(e2.substitute_build_args(build_args)),
+            ),
+            Expression::Or(s, positive, e1, e2) => Expression::Or(
+                s.clone(),
+                *positive,
+                Box::new(e1.substitute_build_args(build_args)),
+                Box::new(e2.substitute_build_args(build_args)),
+            ),
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -206,6 +255,9 @@ pub enum FormatStringFragment {
     /// A variable as a string, and its span.
     InterpolatedVariable(SpannedPosition, String),
     InterpolatedAnonymousVariable(SpannedPosition),
+    /// A build argument, its default (raw, like `StringContent`), and the span of the whole
+    /// `${NAME:-default}`. The default is used if the build argument has no `arg` fact.
+    InterpolatedBuildArg(SpannedPosition, String, String),
 }
 
 impl fmt::Display for FormatStringFragment {
@@ -214,6 +266,9 @@ impl fmt::Display for FormatStringFragment {
             FormatStringFragment::StringContent(_, s) => write!(f, "{s}"),
             FormatStringFragment::InterpolatedVariable(_, v) => write!(f, "${{{v}}}"),
             FormatStringFragment::InterpolatedAnonymousVariable(_) => write!(f, "${{_}}"),
+            FormatStringFragment::InterpolatedBuildArg(_, name, default) => {
+                write!(f, "${{{name}:-{default}}}")
+            }
         }
     }
 }
@@ -221,6 +276,9 @@ impl fmt::Display for FormatStringFragment {
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum ModusTerm {
     Constant(String),
+    /// An unquoted integer or decimal number such as `42` or `-3.14`, as written. It is
+    /// the same constant as the quoted number in the IR, so the number builtins accept both.
+    Number(String),
     /// A format string with '\$' left unhandled. This should be dealt with when
     /// converting to the IR.
     FormatString {
@@ -248,13 +306,50 @@ impl ModusTerm {
                 .iter()
                 .filter_map(|fragment| match fragment {
                     FormatStringFragment::StringContent(_, _)
-                    | FormatStringFragment::InterpolatedAnonymousVariable(_) => None,
+                    | FormatStringFragment::InterpolatedAnonymousVariable(_)
+                    | FormatStringFragment::InterpolatedBuildArg(..) => None,
                     FormatStringFragment::InterpolatedVariable(_, s) => Some(s.as_str()),
                 })
                 .collect(),
             ModusTerm::UserVariable(s) => vec![s],
             ModusTerm::List(_, ts) => ts.iter().flat_map(ModusTerm::variable_strings).collect(),
-            ModusTerm::Constant(_) | ModusTerm::AnonymousVariable => Vec::new(),
+            ModusTerm::Constant(_) | ModusTerm::Number(_) | ModusTerm::AnonymousVariable => {
+                Vec::new()
+            }
+        }
+    }
+
+    /// Replaces the build arguments interpolated in format strings that have a value in
+    /// `build_args` with that value.
+    fn substitute_build_args(&self, build_args: &HashMap<String, String>) -> ModusTerm {
+        match self {
+            ModusTerm::FormatString {
+                position,
+                fragments,
+            } => ModusTerm::FormatString {
+                position: position.clone(),
+                fragments: fragments
+                    .iter()
+                    .map(|fragment| match fragment {
+                        FormatStringFragment::InterpolatedBuildArg(span, name, _)
+                            if build_args.contains_key(name) =>
+                        {
+                            FormatStringFragment::StringContent(
+                                span.clone(),
+                                build_args[name].clone(),
+                            )
+                        }
+                        fragment => fragment.clone(),
+                    })
+                    .collect(),
+            },
+            ModusTerm::List(position, ts) => ModusTerm::List(
+                position.clone(),
+                ts.iter()
+                    .map(|t| t.substitute_build_args(build_args))
+                    .collect(),
+            ),
+            t => t.clone(),
         }
     }
 
@@ -270,6 +365,7 @@ impl fmt::Display for ModusTerm {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ModusTerm::Constant(s) => write!(f, "\"{}\"", s),
+            ModusTerm::Number(n) => write!(f, "{}", n),
             ModusTerm::UserVariable(s) => write!(f, "{}", s),
             ModusTerm::FormatString {
                 position: _,
@@ -313,12 +409,13 @@ impl From<ModusTerm> for logic::IRTerm {
     fn from(modus_term: ModusTerm) -> Self {
         match modus_term {
             ModusTerm::Constant(c) => logic::IRTerm::Constant(process_raw_string(&c)),
+            ModusTerm::Number(n) => logic::IRTerm::Constant(n),
             ModusTerm::FormatString { .. } => {
                 unreachable!("BUG: analysis should've handled this case.")
             }
             ModusTerm::UserVariable(v) => logic::IRTerm::UserVariable(v),
             ModusTerm::AnonymousVariable => sld::Auxiliary::aux(true),
-            ModusTerm::List(_, _) => unimplemented!(),
+            ModusTerm::List(_, ts) => logic::IRTerm::List(ts.into_iter().map(Into::into).collect()),
         }
     }
 }
@@ -358,17 +455,34 @@ impl From<Literal> for logic::Literal {
     }
 }
 
+/// A `name: value` argument to an operator, as in `set_env(key: "A", value: "1")`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct KeywordArg {
+    pub name: String,
+    pub value: ModusTerm,
+}
+
+impl fmt::Display for KeywordArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.value)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Operator {
     pub position: Option<SpannedPosition>,
     pub predicate: Predicate,
     pub args: Vec<ModusTerm>,
+    /// Keyword arguments, which always come after the positional ones.
+    pub keyword_args: Vec<KeywordArg>,
 }
 
 impl Operator {
     #[cfg(test)]
     pub fn eq_ignoring_position(&self, other: &Operator) -> bool {
-        self.predicate == other.predicate && self.args == other.args
+        self.predicate == other.predicate
+            && self.args == other.args
+            && self.keyword_args == other.keyword_args
     }
 
     pub fn with_position(self, position: Option<SpannedPosition>) -> Operator {
@@ -378,26 +492,72 @@ impl Operator {
     pub fn variable_strings(&self) -> Vec<&str> {
         self.args
             .iter()
+            .chain(self.keyword_args.iter().map(|kw| &kw.value))
             .flat_map(|arg| arg.variable_strings())
             .collect()
     }
+
+    /// Returns the arguments in positional order, with each keyword argument moved into
+    /// the slot of the parameter it names (see [OPERATOR_PARAMETERS]).
+    ///
+    /// Fails if a keyword is not a parameter of this operator, if a slot is given twice,
+    /// or if a keyword leaves an earlier parameter without a value.
+    pub fn positional_args(&self) -> Result<Vec<ModusTerm>, String> {
+        if self.keyword_args.is_empty() {
+            return Ok(self.args.clone());
+        }
+        let params = OPERATOR_PARAMETERS
+            .get(self.predicate.0.as_str())
+            .copied()
+            .unwrap_or_default();
+
+        let mut slots: Vec<Option<&ModusTerm>> = self.args.iter().map(Some).collect();
+        for kw in &self.keyword_args {
+            let i = params.iter().position(|&p| p == kw.name).ok_or_else(|| {
+                format!(
+                    "{} has no parameter named {}; expected one of: {}",
+                    self.predicate,
+                    kw.name,
+                    params.join(", ")
+                )
+            })?;
+            if slots.len() <= i {
+                slots.resize(i + 1, None);
+            }
+            if slots[i].is_some() {
+                return Err(format!("{} is given more than once", kw.name));
+            }
+            slots[i] = Some(&kw.value);
+        }
+
+        slots
+            .into_iter()
+            .enumerate()
+            .map(|(i, slot)| {
+                slot.cloned().ok_or_else(|| {
+                    format!("{} is missing a value for {}", self.predicate, params[i])
+                })
+            })
+            .collect()
+    }
 }
 
 impl fmt::Display for Operator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &*self.args {
-            [] => write!(f, "{}", self.predicate),
-            _ => write!(
-                f,
-                "{}({})",
-                self.predicate,
-                self.args
-                    .iter()
-                    .map(|t| t.to_string())
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            ),
+        if self.args.is_empty() && self.keyword_args.is_empty() {
+            return write!(f, "{}", self.predicate);
         }
+        write!(
+            f,
+            "{}({})",
+            self.predicate,
+            self.args
+                .iter()
+                .map(|t| t.to_string())
+                .chain(self.keyword_args.iter().map(|kw| kw.to_string()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
     }
 }
 
@@ -408,17 +568,163 @@ impl Modusfile {
     /// Adds a rule with a head literal that serves as the goal `_query :- [body]`.
     /// Note: does not check whether there is an existing goal, or other checks.
     pub fn add_goal(&mut self, goal: Expression) -> &mut Self {
+        self.add_named_goal("_query", goal)
+    }
+
+    /// Like `add_goal`, but the head of the goal is `name`, so that several goals can be
+    /// told apart.
+    pub fn add_named_goal(&mut self, name: &str, goal: Expression) -> &mut Self {
         self.0.push(ModusClause {
             head: Literal {
                 positive: true,
                 position: None,
-                predicate: Predicate("_query".into()),
+                predicate: Predicate(name.into()),
                 args: Vec::new(),
             },
             body: Some(goal),
         });
         self
     }
+
+    /// Sets the given build arguments, which rules read with `arg(Name, Value)`. Each one
+    /// replaces the `arg(Name, Default)` facts of the Modusfile, which give its default.
+    pub fn set_build_args(&mut self, build_args: &[(String, String)]) -> &mut Self {
+        self.0.retain(|clause| {
+            build_arg_fact_name(clause).is_none_or(|name| build_args.iter().all(|(n, _)| n != name))
+        });
+        for (name, value) in build_args {
+            self.0.push(ModusClause::fact(
+                BUILD_ARG_PREDICATE,
+                &[name.as_str(), value.as_str()],
+            ));
+        }
+        self
+    }
+
+    /// Enables the given features by adding a `feature("NAME")` fact for each one, so rules
+    /// can be guarded with `feature("NAME")` in their body. The Modusfile's own `feature`
+    /// facts stay, so they are always enabled, and a feature that isn't enabled simply fails
+    /// the branch that requires it.
+    pub fn set_features(&mut self, features: &[String]) -> &mut Self {
+        for name in features {
+            let fact = ModusClause::fact(FEATURE_PREDICATE, &[name.as_str()]);
+            if !self.0.contains(&fact) {
+                self.0.push(fact);
+            }
+        }
+        self
+    }
+
+    /// Replaces each build argument interpolated as `${NAME:-default}` in a format string with
+    /// the value of its `arg` fact. Arguments without a fact are left to use their default
+    /// when translated.
+    pub fn substitute_build_args(&self) -> Modusfile {
+        let mut build_args = HashMap::new();
+        for clause in self.0.iter() {
+            if let (Some(name), [_, ModusTerm::Constant(value)]) =
+                (build_arg_fact_name(clause), &clause.head.args[..])
+            {
+                build_args
+                    .entry(name.to_owned())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+        if build_args.is_empty() {
+            return self.clone();
+        }
+        Modusfile(
+            self.0
+                .iter()
+                .map(|clause| ModusClause {
+                    head: clause.head.clone(),
+                    body: clause
+                        .body
+                        .as_ref()
+                        .map(|body| body.substitute_build_args(&build_args)),
+                })
+                .collect(),
+        )
+    }
+
+    /// Adds a `platform_image(Ref, Os, Arch, Resolved)` fact for each image that was resolved
+    /// for a platform.
+    pub fn add_platform_images(&mut self, resolved: &[(PlatformImage, String)]) -> &mut Self {
+        for (
+            PlatformImage {
+                image_ref,
+                os,
+                arch,
+            },
+            resolved_ref,
+        ) in resolved
+        {
+            self.0.push(ModusClause::fact(
+                PLATFORM_IMAGE_PREDICATE,
+                &[
+                    image_ref.as_str(),
+                    os.as_str(),
+                    arch.as_str(),
+                    resolved_ref.as_str(),
+                ],
+            ));
+        }
+        self
+    }
+}
+
+impl ModusClause {
+    fn fact(predicate: &str, args: &[&str]) -> ModusClause {
+        ModusClause {
+            head: Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate(predicate.into()),
+                args: args
+                    .iter()
+                    .map(|a| ModusTerm::Constant((*a).to_owned()))
+                    .collect(),
+            },
+            body: None,
+        }
+    }
+}
+
+/// The predicate of build argument facts, `arg(Name, Value)`.
+pub const BUILD_ARG_PREDICATE: &str = "arg";
+
+/// The predicate of enabled features, `feature(Name)`.
+pub const FEATURE_PREDICATE: &str = "feature";
+
+/// The directive that imports the rows of a CSV file as facts, `csv_facts "path" as pred`, or
+/// `csv_facts "path" as pred with header` to skip a header row.
+pub const CSV_FACTS_DIRECTIVE: &str = "csv_facts";
+
+/// The directive that imports the fields of a JSON object as facts, `json_facts "path" as pred`.
+pub const JSON_FACTS_DIRECTIVE: &str = "json_facts";
+
+/// The predicate that selects the image of one platform from a multi-platform image,
+/// `platform_image(Ref, Os, Arch, Resolved)`.
+pub const PLATFORM_IMAGE_PREDICATE: &str = "platform_image";
+
+/// An image reference, which may name a manifest list, and the platform to select from it.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct PlatformImage {
+    pub image_ref: String,
+    pub os: String,
+    pub arch: String,
+}
+
+/// The name of the build argument the clause gives a value to, if it is an
+/// `arg(Name, Value)` fact.
+pub fn build_arg_fact_name(clause: &ModusClause) -> Option<&str> {
+    match (&clause.head.args[..], &clause.body) {
+        ([ModusTerm::Constant(name), _], None)
+            if clause.head.predicate.0 == BUILD_ARG_PREDICATE =>
+        {
+            Some(name)
+        }
+        _ => None,
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -430,9 +736,25 @@ pub struct Version {
     build: String,
 }
 
+/// Produced when a negation is applied to an expression that contains an operator application.
+/// Operators describe how an image is built, so asking whether one is *not* provable has no
+/// sensible meaning.
+#[derive(Error, Debug)]
+#[error("cannot negate an expression containing an operator application")]
+pub struct NegatedOperatorApplication;
+
 /// Combines nom_supreme's error tree type, codespan's reporting and some custom logic
 /// that selects only a subset of a span to produce better error messages.
 fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
+    /// Our own parser errors already describe the problem, so we skip nom-supreme's
+    /// "external error" preamble for them.
+    fn base_error_message(kind: &BaseErrorKind) -> String {
+        match kind {
+            BaseErrorKind::External(err) => err.to_string(),
+            _ => kind.to_string(),
+        }
+    }
+
     fn generate_base_label(span: &Span, kind: &BaseErrorKind) -> Label<()> {
         let length = if let BaseErrorKind::Expected(nom_supreme::error::Expectation::Tag(t)) = kind
         {
@@ -480,7 +802,7 @@ fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
                     .chain(generate_labels(contexts, base_range))
                     .collect();
                 let diag = Diagnostic::error()
-                    .with_message(kind.to_string())
+                    .with_message(base_error_message(&kind))
                     .with_labels(labels);
                 diags.push(diag);
             }
@@ -496,7 +818,7 @@ fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
                     ErrorTree::Base { location, kind } => {
                         labels.push(generate_base_label(&location, &kind));
                         base_range = labels[0].range.clone();
-                        diag = Diagnostic::error().with_message(kind.to_string());
+                        diag = Diagnostic::error().with_message(base_error_message(&kind));
                     }
                     ErrorTree::Stack { .. } => panic!("base of an error stack was a stack"),
                     ErrorTree::Alt(alts) => {
@@ -538,14 +860,441 @@ fn better_convert_error(e: ErrorTree<Span>) -> Vec<Diagnostic<()>> {
 impl str::FromStr for Modusfile {
     type Err = Vec<Diagnostic<()>>;
 
+    /// Parses a Modusfile without includes or imported data files, since there is no file to
+    /// resolve their paths against. See `Modusfile::from_file_with_includes`.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let span = Span::new(s);
-        match parser::modusfile(span) {
-            Result::Ok((_, o)) => Ok(o),
-            Result::Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(better_convert_error(e)),
-            _ => unimplemented!(),
+        let items = parse_items(s)?;
+        let mut clauses = Vec::new();
+        let mut errors = Vec::new();
+        for item in items {
+            match item {
+                ModusfileItem::Clause(clause) => clauses.push(clause),
+                ModusfileItem::Include(span, _) => errors.push(
+                    Diagnostic::error()
+                        .with_message("include can only be used in a Modusfile read from a file")
+                        .with_labels(vec![Label::primary((), &span)]),
+                ),
+                ModusfileItem::ImportFacts(span, data_file) => errors.push(
+                    Diagnostic::error()
+                        .with_message(format!(
+                            "{} can only be used in a Modusfile read from a file",
+                            data_file.directive()
+                        ))
+                        .with_labels(vec![Label::primary((), &span)]),
+                ),
+            }
+        }
+        if errors.is_empty() {
+            Ok(Modusfile(clauses))
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// An item of a Modusfile: a clause, or a directive with its span.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ModusfileItem {
+    Clause(ModusClause),
+    /// `include "path"`, with the raw path.
+    Include(SpannedPosition, String),
+    ImportFacts(SpannedPosition, DataFile),
+}
+
+/// A directive that imports facts from a data file, whose raw path is relative to the
+/// Modusfile. The facts are read along with the Modusfile, see [read_data_file].
+#[derive(Clone, PartialEq, Debug)]
+pub enum DataFile {
+    /// `csv_facts "path" as pred`, a fact per row with an argument per column. With
+    /// `with header`, the first row is skipped.
+    Csv {
+        path: String,
+        predicate: String,
+        header: bool,
+    },
+    /// `json_facts "path" as pred`, a `pred(Key, Value)` fact per field of a JSON object.
+    Json { path: String, predicate: String },
+}
+
+impl DataFile {
+    fn directive(&self) -> &'static str {
+        match self {
+            DataFile::Csv { .. } => CSV_FACTS_DIRECTIVE,
+            DataFile::Json { .. } => JSON_FACTS_DIRECTIVE,
+        }
+    }
+}
+
+fn parse_items(s: &str) -> Result<Vec<ModusfileItem>, Vec<Diagnostic<()>>> {
+    match parser::modusfile_items(Span::new(s)) {
+        Result::Ok((_, o)) => Ok(o),
+        Result::Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(better_convert_error(e)),
+        Result::Err(nom::Err::Incomplete(_)) => Err(vec![Diagnostic::error()
+            .with_message("Unexpected end of the Modusfile")
+            .with_labels(vec![Label::primary((), s.len()..s.len())])]),
+    }
+}
+
+/// An error reading a Modusfile or a file it includes, with the file the diagnostics
+/// refer to.
+#[derive(Debug)]
+pub struct IncludeError {
+    pub file: SimpleFile<String, String>,
+    pub diagnostics: Vec<Diagnostic<()>>,
+}
+
+/// The sources of a Modusfile and of the files it includes, for showing diagnostics.
+///
+/// The positions of the clauses of each file are offset by the length of the files read
+/// before it, as if the files were concatenated, so a position tells which file it points
+/// into. The Modusfile itself comes first, so its positions are unchanged.
+#[derive(Debug)]
+pub struct ModusfileSources {
+    files: SimpleFiles<String, String>,
+    /// The offset the positions of each file start at.
+    starts: Vec<usize>,
+    next_start: usize,
+}
+
+impl ModusfileSources {
+    /// Adds a file, returning the offset its positions start at.
+    fn add(&mut self, name: String, source: String) -> usize {
+        let start = self.next_start;
+        // Leave room for a position at the very end of the file.
+        self.next_start += source.len() + 1;
+        self.files.add(name, source);
+        self.starts.push(start);
+        start
+    }
+
+    /// The file an offset points into, and the offset within that file.
+    fn locate(&self, offset: usize) -> (usize, usize) {
+        let file = self
+            .starts
+            .iter()
+            .rposition(|&start| start <= offset)
+            .unwrap_or_default();
+        (
+            file,
+            offset - self.starts.get(file).copied().unwrap_or_default(),
+        )
+    }
+
+    /// The diagnostic with each label pointing into the file it belongs to.
+    pub fn file_diagnostic(&self, diagnostic: &Diagnostic<()>) -> Diagnostic<usize> {
+        Diagnostic {
+            severity: diagnostic.severity,
+            code: diagnostic.code.clone(),
+            message: diagnostic.message.clone(),
+            labels: diagnostic
+                .labels
+                .iter()
+                .map(|label| {
+                    let (file_id, start) = self.locate(label.range.start);
+                    Label {
+                        style: label.style,
+                        file_id,
+                        range: start..start + label.range.len(),
+                        message: label.message.clone(),
+                    }
+                })
+                .collect(),
+            notes: diagnostic.notes.clone(),
         }
     }
+
+    /// Writes a diagnostic about the Modusfile, showing each label in its file.
+    pub fn emit(
+        &self,
+        writer: &mut dyn WriteColor,
+        config: &Config,
+        diagnostic: &Diagnostic<()>,
+    ) -> Result<(), codespan_reporting::files::Error> {
+        term::emit(
+            writer,
+            config,
+            &self.files,
+            &self.file_diagnostic(diagnostic),
+        )
+    }
+}
+
+impl Modusfile {
+    /// Reads and parses the Modusfile at `path`, replacing each `include "path"` with the
+    /// clauses of the included file. Included paths are relative to the including file, and
+    /// a file included more than once only has its clauses added the first time.
+    ///
+    /// The positions of included clauses are offset as described in [ModusfileSources],
+    /// which is returned along with the Modusfile to show diagnostics in the right file.
+    pub fn from_file_with_includes(
+        path: impl AsRef<Path>,
+    ) -> Result<(Modusfile, ModusfileSources), IncludeError> {
+        let path = path.as_ref();
+        let read_error = |e: std::io::Error| IncludeError {
+            file: SimpleFile::new(path.display().to_string(), String::new()),
+            diagnostics: vec![Diagnostic::error().with_message(format!(
+                "Error reading {}: {}",
+                path.display(),
+                e
+            ))],
+        };
+        let source = std::fs::read_to_string(path).map_err(read_error)?;
+        let canonical = path.canonicalize().map_err(read_error)?;
+        let mut clauses = Vec::new();
+        let mut sources = ModusfileSources {
+            files: SimpleFiles::new(),
+            starts: Vec::new(),
+            next_start: 0,
+        };
+        read_with_includes(
+            path,
+            source,
+            &mut vec![canonical.clone()],
+            &mut HashSet::from([canonical]),
+            &mut clauses,
+            &mut sources,
+        )?;
+        Ok((Modusfile(clauses), sources))
+    }
+}
+
+/// Reads the facts a directive imports from a data file. The path of the data file is
+/// relative to the Modusfile at `path`, and the facts are given the position of the directive.
+///
+/// Quoted CSV fields may contain commas, quotes and newlines, and every row must have the same
+/// number of fields. JSON values are converted like `from_json` does: a string becomes its
+/// content, numbers, booleans and `null` their JSON text, an array a list of its values, and a
+/// nested object a list of `[Key, Value]` lists, sorted by key, which can be queried with
+/// `lookup`.
+fn read_data_file(
+    data_file: &DataFile,
+    path: &Path,
+    position: &SpannedPosition,
+) -> Result<Vec<ModusClause>, String> {
+    let (DataFile::Csv {
+        path: data_path,
+        predicate,
+        ..
+    }
+    | DataFile::Json {
+        path: data_path,
+        predicate,
+    }) = data_file;
+    let data_path = path
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(process_raw_string(data_path));
+    let fact = |args: Vec<ModusTerm>| ModusClause {
+        head: Literal {
+            positive: true,
+            position: Some(position.clone()),
+            predicate: Predicate(predicate.clone()),
+            args,
+        },
+        body: None,
+    };
+    // The facts are clauses of the Modusfile, so their constants are raw strings.
+    fn to_modus_term(term: logic::IRTerm, position: &SpannedPosition) -> ModusTerm {
+        match term {
+            logic::IRTerm::List(terms) => ModusTerm::List(
+                position.clone(),
+                terms
+                    .into_iter()
+                    .map(|term| to_modus_term(term, position))
+                    .collect(),
+            ),
+            term => ModusTerm::Constant(escape_raw_string(term.as_constant().unwrap())),
+        }
+    }
+
+    match data_file {
+        DataFile::Csv { header, .. } => {
+            let read_error =
+                |e: csv::Error| format!("Error reading {}: {}", data_path.display(), e);
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(*header)
+                .from_path(&data_path)
+                .map_err(read_error)?;
+            reader
+                .records()
+                .map(|record| {
+                    let record = record.map_err(read_error)?;
+                    Ok(fact(
+                        record
+                            .iter()
+                            .map(|field| ModusTerm::Constant(escape_raw_string(field)))
+                            .collect(),
+                    ))
+                })
+                .collect()
+        }
+        DataFile::Json { .. } => {
+            let content = std::fs::read_to_string(&data_path)
+                .map_err(|e| format!("Error reading {}: {}", data_path.display(), e))?;
+            let object = match serde_json::from_str(&content) {
+                Ok(serde_json::Value::Object(object)) => object,
+                Ok(_) => {
+                    return Err(format!(
+                        "{} does not contain a JSON object.",
+                        data_path.display()
+                    ))
+                }
+                Err(e) => return Err(format!("Error parsing {}: {}", data_path.display(), e)),
+            };
+            Ok(object
+                .into_iter()
+                .map(|(key, value)| {
+                    fact(vec![
+                        ModusTerm::Constant(escape_raw_string(&key)),
+                        to_modus_term(json_to_term(value), position),
+                    ])
+                })
+                .collect())
+        }
+    }
+}
+
+fn offset_term_positions(term: &mut ModusTerm, by: usize) {
+    match term {
+        ModusTerm::FormatString {
+            position,
+            fragments,
+        } => {
+            position.offset += by;
+            for fragment in fragments {
+                match fragment {
+                    FormatStringFragment::StringContent(position, _)
+                    | FormatStringFragment::InterpolatedVariable(position, _)
+                    | FormatStringFragment::InterpolatedAnonymousVariable(position)
+                    | FormatStringFragment::InterpolatedBuildArg(position, _, _) => {
+                        position.offset += by
+                    }
+                }
+            }
+        }
+        ModusTerm::List(position, terms) => {
+            position.offset += by;
+            for term in terms {
+                offset_term_positions(term, by);
+            }
+        }
+        ModusTerm::Constant(_)
+        | ModusTerm::Number(_)
+        | ModusTerm::UserVariable(_)
+        | ModusTerm::AnonymousVariable => {}
+    }
+}
+
+fn offset_literal_positions(lit: &mut Literal, by: usize) {
+    if let Some(position) = &mut lit.position {
+        position.offset += by;
+    }
+    for arg in lit.args.iter_mut() {
+        offset_term_positions(arg, by);
+    }
+}
+
+/// Moves every position in an expression `by` bytes later, see [ModusfileSources].
+fn offset_expression_positions(expr: &mut Expression, by: usize) {
+    match expr {
+        Expression::Literal(lit) => offset_literal_positions(lit, by),
+        Expression::OperatorApplication(position, e, op) => {
+            if let Some(position) = position {
+                position.offset += by;
+            }
+            offset_expression_positions(e, by);
+            if let Some(position) = &mut op.position {
+                position.offset += by;
+            }
+            for arg in op
+                .args
+                .iter_mut()
+                .chain(op.keyword_args.iter_mut().map(|kw| &mut kw.value))
+            {
+                offset_term_positions(arg, by);
+            }
+        }
+        Expression::And(position, _, e1, e2) | Expression::Or(position, _, e1, e2) => {
+            if let Some(position) = position {
+                position.offset += by;
+            }
+            offset_expression_positions(e1, by);
+            offset_expression_positions(e2, by);
+        }
+    }
+}
+
+fn read_with_includes(
+    path: &Path,
+    source: String,
+    stack: &mut Vec<PathBuf>,
+    seen: &mut HashSet<PathBuf>,
+    clauses: &mut Vec<ModusClause>,
+    sources: &mut ModusfileSources,
+) -> Result<(), IncludeError> {
+    let items = parse_items(&source);
+    let error = |diagnostics| IncludeError {
+        file: SimpleFile::new(path.display().to_string(), source.clone()),
+        diagnostics,
+    };
+    let items = items.map_err(error)?;
+    let start = sources.add(path.display().to_string(), source.clone());
+    for item in items {
+        let (span, included) = match item {
+            ModusfileItem::Clause(mut clause) => {
+                offset_literal_positions(&mut clause.head, start);
+                if let Some(body) = &mut clause.body {
+                    offset_expression_positions(body, start);
+                }
+                clauses.push(clause);
+                continue;
+            }
+            ModusfileItem::ImportFacts(span, data_file) => {
+                let mut position = span.clone();
+                position.offset += start;
+                let facts = read_data_file(&data_file, path, &position).map_err(|message| {
+                    error(vec![Diagnostic::error()
+                        .with_message(message)
+                        .with_labels(vec![Label::primary((), &span)])])
+                })?;
+                clauses.extend(facts);
+                continue;
+            }
+            ModusfileItem::Include(span, included) => (span, included),
+        };
+        let included = path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(process_raw_string(&included));
+        let include_error = |message: String| {
+            error(vec![Diagnostic::error()
+                .with_message(message)
+                .with_labels(vec![Label::primary((), &span)])])
+        };
+        let canonical = included
+            .canonicalize()
+            .map_err(|e| include_error(format!("Error reading {}: {}", included.display(), e)))?;
+        if let Some(start) = stack.iter().position(|p| p == &canonical) {
+            let cycle = stack[start..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>();
+            return Err(include_error(format!(
+                "Include cycle: {}",
+                cycle.join(" includes ")
+            )));
+        }
+        if !seen.insert(canonical.clone()) {
+            continue;
+        }
+        let included_source = std::fs::read_to_string(&canonical)
+            .map_err(|e| include_error(format!("Error reading {}: {}", included.display(), e)))?;
+        stack.push(canonical);
+        read_with_includes(&included, included_source, stack, seen, clauses, sources)?;
+        stack.pop();
+    }
+    Ok(())
 }
 
 impl str::FromStr for Expression {
@@ -639,9 +1388,11 @@ pub mod parser {
 
     use super::*;
 
-    use nom::bytes::complete::{escaped, is_a};
-    use nom::character::complete::{multispace0, none_of, one_of};
-    use nom::combinator::{cut, opt, recognize};
+    use nom::bytes::complete::{escaped, take_until};
+    use nom::character::complete::{
+        alphanumeric1, digit1, multispace0, multispace1, none_of, one_of,
+    };
+    use nom::combinator::{cut, fail, map_res, not, opt, recognize};
     use nom::error::context;
     use nom::multi::{many0_count, many1, separated_list0, separated_list1};
     use nom::sequence::{pair, tuple};
@@ -746,44 +1497,102 @@ pub mod parser {
     }
 
     /// Parses a parenthesized expression, taking into account any preceding negation.
+    ///
+    /// Negating an expression that contains an operator application is rejected, since
+    /// there is no meaningful negation of a build step.
     fn parenthesized_expr(i: Span) -> IResult<Span, Expression> {
         let l_paren_with_comments = |i| terminated(tag("("), comments)(i);
         let r_paren_with_comments = |i| preceded(comments, cut(tag(")")))(i);
 
+        let (rest, neg_count) =
+            many0_count(terminated(nom::character::complete::char('!'), token_sep0))(i)?;
+        if neg_count % 2 == 0 {
+            delimited(l_paren_with_comments, cut(body), r_paren_with_comments)(rest)
+        } else {
+            cut(map_res(
+                delimited(l_paren_with_comments, body, r_paren_with_comments),
+                |expr| {
+                    if expr.operators().is_empty() {
+                        // negate the expression
+                        Ok(expr.negate_current())
+                    } else {
+                        Err(NegatedOperatorApplication)
+                    }
+                },
+            ))(rest)
+        }
+    }
+
+    /// Parses an operator based on a literal, failing if negation is encountered.
+    enum OperatorArg {
+        Positional(ModusTerm),
+        Keyword(KeywordArg),
+    }
+
+    /// Parses `name: value`. The `:` must not begin a `::`.
+    fn keyword_arg(i: Span) -> IResult<Span, KeywordArg> {
         map(
-            pair(
-                many0_count(terminated(nom::character::complete::char('!'), token_sep0)),
-                delimited(l_paren_with_comments, cut(body), r_paren_with_comments),
+            separated_pair(
+                terminated(literal_identifier, token_sep0),
+                terminated(terminated(tag(":"), not(tag(":"))), token_sep0),
+                modus_term,
             ),
-            |(neg_count, expr)| {
-                if neg_count % 2 == 0 {
-                    expr
-                } else {
-                    // negate the expression
-                    expr.negate_current()
-                }
+            |(name, value)| KeywordArg {
+                name: name.fragment().to_string(),
+                value,
             },
         )(i)
     }
 
-    /// Parses an operator based on a literal, failing if negation is encountered.
+    /// Parses the comma-separated arguments of an operator, where any keyword arguments
+    /// must come after all of the positional ones.
+    fn operator_args(i: Span) -> IResult<Span, (Vec<ModusTerm>, Vec<KeywordArg>)> {
+        let (rest, args) = separated_list1(
+            terminated(tag(","), token_sep0),
+            terminated(
+                alt((
+                    map(keyword_arg, OperatorArg::Keyword),
+                    map(modus_term, OperatorArg::Positional),
+                )),
+                token_sep0,
+            ),
+        )(i)?;
+
+        let mut positional = Vec::new();
+        let mut keyword = Vec::new();
+        for arg in args {
+            match arg {
+                OperatorArg::Positional(t) if keyword.is_empty() => positional.push(t),
+                OperatorArg::Positional(_) => {
+                    return cut(context(
+                        "keyword arguments must come after positional arguments",
+                        fail,
+                    ))(i)
+                }
+                OperatorArg::Keyword(kw) => keyword.push(kw),
+            }
+        }
+        Ok((rest, (positional, keyword)))
+    }
+
     fn operator(i: Span) -> IResult<Span, Operator> {
         map(
             recognized_span(pair(
                 terminated(literal_identifier, token_sep0),
                 opt(delimited(
                     terminated(tag("("), token_sep0),
-                    separated_list1(
-                        terminated(tag(","), token_sep0),
-                        terminated(modus_term, token_sep0),
-                    ),
+                    operator_args,
                     cut(terminated(tag(")"), token_sep0)),
                 )),
             )),
-            |(spanned_pos, (name, args))| Operator {
-                position: Some(spanned_pos),
-                predicate: Predicate(name.fragment().to_string()),
-                args: args.unwrap_or(Vec::new()),
+            |(spanned_pos, (name, args))| {
+                let (args, keyword_args) = args.unwrap_or_default();
+                Operator {
+                    position: Some(spanned_pos),
+                    predicate: Predicate(name.fragment().to_string()),
+                    args,
+                    keyword_args,
+                }
             },
         )(i)
     }
@@ -955,6 +1764,11 @@ pub mod parser {
         processed
     }
 
+    /// Escapes the given string so that `process_raw_string` gives it back unchanged.
+    pub fn escape_raw_string(s: &str) -> String {
+        s.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
     const STRING_ESCAPE_CHARS: &str = "\"\\nrt0\n";
     const FORMAT_STRING_ESCAPE_CHARS: &str = "$\"\\nrt0\n";
 
@@ -982,15 +1796,66 @@ pub mod parser {
         Ok((i, parsed_str.to_owned()))
     }
 
+    /// Parses a triple-quoted string, such as a multi-line script, whose content is taken
+    /// literally: newlines and quotes need no escaping, and backslashes are not escapes.
+    /// The content is returned escaped like that of a regular string, so processing it gives
+    /// back exactly what was written.
+    fn triple_quoted_string(i: Span) -> IResult<Span, String> {
+        let (i, o) = preceded(
+            tag(r#"""""#),
+            cut(terminated(take_until(r#"""""#), tag(r#"""""#))),
+        )(i)?;
+        Ok((i, escape_raw_string(o.fragment())))
+    }
+
     pub fn modus_const(i: Span) -> IResult<Span, String> {
         context(
             stringify!(modus_const),
-            delimited(tag("\""), string_content, cut(tag("\""))),
+            alt((
+                triple_quoted_string,
+                delimited(tag("\""), string_content, cut(tag("\""))),
+            )),
+        )(i)
+    }
+
+    /// Parses the default of a build argument interpolated in a format string, which may be
+    /// empty.
+    fn build_arg_default(i: Span) -> IResult<Span, String> {
+        let escape_parser = escaped(none_of("\\\"$}"), '\\', one_of(FORMAT_STRING_ESCAPE_CHARS));
+        let (i, o) = opt(escape_parser)(i)?;
+        Ok((
+            i,
+            o.map(|span| span.fragment().to_string())
+                .unwrap_or_default(),
+        ))
+    }
+
+    /// Parses `${NAME:-default}`, the value of the build argument `NAME` or `default` if it
+    /// has none.
+    fn build_arg_interpolation(i: Span) -> IResult<Span, (Span, String)> {
+        delimited(
+            terminated(tag("${"), token_sep0),
+            separated_pair(
+                modus_var,
+                preceded(token_sep0, tag(":-")),
+                build_arg_default,
+            ),
+            cut(tag("}")),
         )(i)
     }
 
     fn format_string_fragment(i: Span) -> IResult<Span, FormatStringFragment> {
         alt((
+            map(
+                recognized_span(build_arg_interpolation),
+                |(span, (name, default))| {
+                    FormatStringFragment::InterpolatedBuildArg(
+                        span,
+                        name.fragment().to_string(),
+                        default,
+                    )
+                },
+            ),
             map(string_interpolation, |v_span| {
                 if v_span.fragment().chars().all(|c| c == '_') {
                     FormatStringFragment::InterpolatedAnonymousVariable(v_span.into())
@@ -1049,11 +1914,29 @@ pub mod parser {
         )(i)
     }
 
+    /// Parses an optionally negative integer or decimal number, such as `42` or `-3.14`,
+    /// with an optional exponent.
+    pub fn modus_number(i: Span) -> IResult<Span, Span> {
+        terminated(
+            recognize(tuple((
+                opt(tag("-")),
+                digit1,
+                opt(pair(tag("."), digit1)),
+                opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+            ))),
+            // `1abc` is not a number followed by something else.
+            not(alt((alphanumeric1, tag("_")))),
+        )(i)
+    }
+
     pub fn modus_term(i: Span) -> IResult<Span, ModusTerm> {
         context(
             stringify!(modus_term),
             alt((
                 map(modus_const, ModusTerm::Constant),
+                map(modus_number, |n| {
+                    ModusTerm::Number(n.fragment().to_string())
+                }),
                 map(recognized_span(modus_list_term), |(span, terms)| {
                     ModusTerm::List(span, terms)
                 }),
@@ -1063,9 +1946,14 @@ pub mod parser {
                         fragments,
                     }
                 }),
-                map(is_a("_"), |_| ModusTerm::AnonymousVariable),
+                // Like in format strings, `_` (or `__`, ...) is a fresh variable each time,
+                // but `_x` is an ordinary variable.
                 map(modus_var, |s| {
-                    ModusTerm::UserVariable(s.fragment().to_string())
+                    if s.fragment().chars().all(|c| c == '_') {
+                        ModusTerm::AnonymousVariable
+                    } else {
+                        ModusTerm::UserVariable(s.fragment().to_string())
+                    }
                 }),
             )),
         )(i)
@@ -1075,6 +1963,66 @@ pub mod parser {
         alt((rule, fact))(i)
     }
 
+    /// Parses `include "path"`, giving the span of the whole directive and the raw path.
+    fn include(i: Span) -> IResult<Span, (SpannedPosition, String)> {
+        recognized_span(preceded(pair(tag("include"), token_sep0), modus_const))(i)
+    }
+
+    /// Parses a Modusfile that may include other files.
+    /// Parses `as pred`, the predicate of the facts a data file directive imports.
+    fn imported_predicate(i: Span) -> IResult<Span, String> {
+        map(
+            preceded(
+                tuple((token_sep0, tag("as"), multispace1)),
+                literal_identifier,
+            ),
+            |predicate| predicate.fragment().to_string(),
+        )(i)
+    }
+
+    /// Parses `csv_facts "path" as pred`, optionally followed by `with header`, or
+    /// `json_facts "path" as pred`, giving the span of the whole directive.
+    fn import_facts(i: Span) -> IResult<Span, (SpannedPosition, DataFile)> {
+        let csv_facts = map(
+            pair(
+                preceded(pair(tag(CSV_FACTS_DIRECTIVE), token_sep0), modus_const),
+                cut(pair(
+                    imported_predicate,
+                    opt(tuple((token_sep0, tag("with"), multispace1, tag("header")))),
+                )),
+            ),
+            |(path, (predicate, header))| DataFile::Csv {
+                path,
+                predicate,
+                header: header.is_some(),
+            },
+        );
+        let json_facts = map(
+            pair(
+                preceded(pair(tag(JSON_FACTS_DIRECTIVE), token_sep0), modus_const),
+                cut(imported_predicate),
+            ),
+            |(path, predicate)| DataFile::Json { path, predicate },
+        );
+        recognized_span(alt((csv_facts, json_facts)))(i)
+    }
+
+    pub fn modusfile_items(i: Span) -> IResult<Span, Vec<ModusfileItem>> {
+        terminated(
+            many0(preceded(
+                token_sep0,
+                alt((
+                    map(include, |(span, path)| ModusfileItem::Include(span, path)),
+                    map(import_facts, |(span, data_file)| {
+                        ModusfileItem::ImportFacts(span, data_file)
+                    }),
+                    map(modus_clause, ModusfileItem::Clause),
+                )),
+            )),
+            terminated(token_sep0, eof),
+        )(i)
+    }
+
     pub fn modusfile(i: Span) -> IResult<Span, Modusfile> {
         map(
             terminated(
@@ -1188,6 +2136,7 @@ mod tests {
             position: None,
             predicate: logic::Predicate("merge".into()),
             args: Vec::new(),
+            keyword_args: Vec::new(),
         };
         let r1 = Rule {
             head: foo.clone(),
@@ -1243,6 +2192,7 @@ mod tests {
             position: None,
             predicate: logic::Predicate("merge".into()),
             args: Vec::new(),
+            keyword_args: Vec::new(),
         };
         let r = Rule {
             head: foo,
@@ -1275,6 +2225,7 @@ mod tests {
             position: None,
             predicate: logic::Predicate("merge".into()),
             args: Vec::new(),
+            keyword_args: Vec::new(),
         };
         let r1 = Rule {
             head: foo.clone(),
@@ -1351,6 +2302,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn triple_quoted_strings() {
+        let script = "set -e\nif [ \"$1\" = 'x' ]; then\n  echo \"a \\\n  b\"\nfi\n";
+        let term: ModusTerm = format!(r#""""{}""""#, script).parse().unwrap();
+        assert_eq!(
+            logic::IRTerm::from(term.clone()),
+            logic::IRTerm::Constant(script.to_owned())
+        );
+        // Printed as a regular string, it parses back to the same constant.
+        assert_eq!(term.to_string().parse::<ModusTerm>().unwrap(), term);
+
+        let clause: ModusClause = format!(r#"a :- from("alpine"), run("""{}""")."#, script)
+            .parse()
+            .unwrap();
+        let ir: Vec<logic::Clause> = (&clause).into();
+        assert_eq!(
+            ir[0].body[1].args,
+            vec![logic::IRTerm::Constant(script.to_owned())]
+        );
+
+        // Regular strings keep their continuation behaviour.
+        let term: ModusTerm = "\"a \\\n   b\"".parse().unwrap();
+        assert_eq!(
+            logic::IRTerm::from(term),
+            logic::IRTerm::Constant("a b".to_owned())
+        );
+    }
+
+    #[test]
+    fn numeric_literals() {
+        let parse = |s: &str| s.parse::<ModusTerm>().unwrap();
+        assert_eq!(parse("42"), ModusTerm::Number("42".to_owned()));
+        assert_eq!(parse("-3.14"), ModusTerm::Number("-3.14".to_owned()));
+        assert_eq!(parse("1e-3"), ModusTerm::Number("1e-3".to_owned()));
+        assert_eq!(parse(r#""42""#), ModusTerm::Constant("42".to_owned()));
+        assert!(parser::modus_term(Span::new("3abc")).is_err());
+
+        let actual: Literal = "number_gt(10, -2.5)".parse().unwrap();
+        assert_eq!(
+            actual.args,
+            vec![
+                ModusTerm::Number("10".to_owned()),
+                ModusTerm::Number("-2.5".to_owned())
+            ]
+        );
+        let clause: ModusClause = "a(1) :- b(2.0).".parse().unwrap();
+        assert_eq!(clause.to_string(), "a(1) :- b(2.0).");
+    }
+
     #[test]
     fn anonymous_variables() {
         let expected = Literal {
@@ -1483,6 +2483,7 @@ mod tests {
                             ModusTerm::Constant("a".to_owned()),
                             ModusTerm::Constant("foobar".to_owned()),
                         ],
+                        keyword_args: Vec::new(),
                     },
                 )),
                 Box::new(Expression::OperatorApplication(
@@ -1495,6 +2496,7 @@ mod tests {
                             ModusTerm::Constant("a".to_owned()),
                             ModusTerm::Constant("baz".to_owned()),
                         ],
+                        keyword_args: Vec::new(),
                     },
                 )),
             )),
@@ -1506,6 +2508,52 @@ mod tests {
         assert!(a.eq_ignoring_position(&actual));
     }
 
+    #[test]
+    fn op_application_with_keyword_args() {
+        let r: Rule = r#"a :- foo::set_env("PATH", value : "/bin")."#.parse().unwrap();
+        let op = match r.body {
+            Some(Expression::OperatorApplication(_, _, op)) => op,
+            _ => panic!("expected an operator application"),
+        };
+        assert_eq!(vec![ModusTerm::Constant("PATH".to_owned())], op.args);
+        assert_eq!(
+            vec![KeywordArg {
+                name: "value".to_owned(),
+                value: ModusTerm::Constant("/bin".to_owned()),
+            }],
+            op.keyword_args
+        );
+        assert_eq!(r#"set_env("PATH", value: "/bin")"#, op.to_string());
+        assert_eq!(
+            Ok(vec![
+                ModusTerm::Constant("PATH".to_owned()),
+                ModusTerm::Constant("/bin".to_owned()),
+            ]),
+            op.positional_args()
+        );
+
+        assert!(r#"a :- foo::set_env(key: "PATH", "/bin")."#.parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn keyword_args_must_name_free_parameters() {
+        let positional_args = |s: &str| match s.parse::<Rule>().unwrap().body {
+            Some(Expression::OperatorApplication(_, _, op)) => op.positional_args(),
+            _ => panic!("expected an operator application"),
+        };
+
+        assert_eq!(
+            Ok(vec![
+                ModusTerm::Constant("a".to_owned()),
+                ModusTerm::Constant("b".to_owned()),
+            ]),
+            positional_args(r#"a :- foo::in_env(value: "b", key: "a")."#)
+        );
+        assert!(positional_args(r#"a :- foo::in_env(name: "a", value: "b")."#).is_err());
+        assert!(positional_args(r#"a :- foo::in_env("a", key: "b")."#).is_err());
+        assert!(positional_args(r#"a :- foo::in_secret(target: "/run/s")."#).is_err());
+    }
+
     #[test]
     fn op_application_chained_with_spaces() {
         let r1: Rule = "a :- foo::set_env::in_env.".parse().unwrap();
@@ -1533,12 +2581,14 @@ mod tests {
                         position: None,
                         predicate: logic::Predicate("set_env".into()),
                         args: Vec::new(),
+                        keyword_args: Vec::new(),
                     },
                 )),
                 Operator {
                     position: None,
                     predicate: logic::Predicate("in_env".into()),
                     args: Vec::new(),
+                    keyword_args: Vec::new(),
                 },
             )),
         };
@@ -1674,6 +2724,28 @@ mod tests {
         assert!(diags[0].labels[2].message.contains("rule"));
     }
 
+    #[test]
+    fn rejects_negated_operator_application() {
+        let cases = [
+            "foo :- !(a::copy(\".\", \".\")).",
+            "foo :- !(a, b::set_env(\"A\", \"B\")).",
+            "foo :- !(a ; !(b::merge)).",
+        ];
+        for case in cases {
+            let modus_file: Result<Modusfile, Vec<Diagnostic<()>>> = case.parse();
+            let diags = modus_file.err().unwrap();
+            assert!(diags
+                .iter()
+                .any(|d| d.message
+                    == "cannot negate an expression containing an operator application"));
+        }
+
+        // a doubly negated expression is not negated at all
+        assert!("foo :- !!(a::copy(\".\", \".\"))."
+            .parse::<Modusfile>()
+            .is_ok());
+    }
+
     #[test]
     fn format_string() {
         let case = "f\"foo ${ X }\"";
@@ -1704,6 +2776,47 @@ mod tests {
         assert_eq!(expected, modus_term(Span::new(case)).unwrap().1);
     }
 
+    #[test]
+    fn format_string_with_build_arg() {
+        let case = r#"f"alpine:${ VERSION :-3.15}""#;
+
+        let expected = ModusTerm::FormatString {
+            position: SpannedPosition {
+                offset: 0,
+                length: 28,
+            },
+            fragments: vec![
+                FormatStringFragment::StringContent(
+                    SpannedPosition {
+                        offset: 2,
+                        length: 7,
+                    },
+                    "alpine:".to_string(),
+                ),
+                FormatStringFragment::InterpolatedBuildArg(
+                    SpannedPosition {
+                        offset: 9,
+                        length: 18,
+                    },
+                    "VERSION".to_string(),
+                    "3.15".to_string(),
+                ),
+            ],
+        };
+        assert_eq!(expected, modus_term(Span::new(case)).unwrap().1);
+
+        let empty_default: ModusTerm = r#"f"${VERSION:-}""#.parse().unwrap();
+        assert!(matches!(
+            empty_default,
+            ModusTerm::FormatString { fragments, .. }
+                if fragments == vec![FormatStringFragment::InterpolatedBuildArg(
+                    SpannedPosition { offset: 2, length: 12 },
+                    "VERSION".to_string(),
+                    String::new(),
+                )]
+        ));
+    }
+
     #[test]
     fn format_string_starts_with_variable() {
         let case = r#"f"${var1} foo bar\tbaz""#;
@@ -1843,4 +2956,98 @@ mod tests {
         );
         assert_eq!(expected, modus_term(Span::new(case)).unwrap().1);
     }
+
+    #[test]
+    fn modus_nested_list_term() {
+        let case = r#"["nginx", ["-g", "daemon off;"], [], X]"#;
+        let term = modus_term(Span::new(case)).unwrap().1;
+        let inner = match &term {
+            ModusTerm::List(_, ts) => ts,
+            t => panic!("expected a list, got {:?}", t),
+        };
+        assert_eq!(inner.len(), 4);
+        assert!(matches!(&inner[1], ModusTerm::List(_, ts) if ts == &vec![
+            ModusTerm::Constant("-g".to_string()),
+            ModusTerm::Constant("daemon off;".to_string()),
+        ]));
+        assert!(matches!(&inner[2], ModusTerm::List(_, ts) if ts.is_empty()));
+
+        assert_eq!(term.to_string(), case);
+        let reparsed: ModusTerm = term.to_string().parse().unwrap();
+        assert_eq!(reparsed.to_string(), case);
+
+        assert_eq!(
+            logic::IRTerm::from(term),
+            logic::IRTerm::List(vec![
+                logic::IRTerm::Constant("nginx".to_string()),
+                logic::IRTerm::List(vec![
+                    logic::IRTerm::Constant("-g".to_string()),
+                    logic::IRTerm::Constant("daemon off;".to_string()),
+                ]),
+                logic::IRTerm::List(Vec::new()),
+                logic::IRTerm::UserVariable("X".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn includes_are_read_relative_to_the_including_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("lib")).unwrap();
+        std::fs::write(
+            dir.path().join("Modusfile"),
+            "include \"lib/base.Modusfile\"\n\
+             include \"lib/common.Modusfile\"\n\
+             app :- base, run(\"make\").",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("lib/base.Modusfile"),
+            "include \"common.Modusfile\"\nbase :- from(\"alpine\").",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("lib/common.Modusfile"), "common(\"x\").").unwrap();
+
+        let (mf, sources) =
+            Modusfile::from_file_with_includes(dir.path().join("Modusfile")).unwrap();
+        let heads = mf.0.iter().map(|c| c.head.to_string()).collect::<Vec<_>>();
+        // common.Modusfile is included twice, but only added once.
+        assert_eq!(heads, vec!["common(\"x\")", "base", "app"]);
+
+        // Files are added as they are read: the Modusfile, base, then common.
+        let label_of = |clause: &ModusClause| {
+            let position = clause.head.position.as_ref().unwrap();
+            let diagnostic = Diagnostic::error().with_labels(vec![Label::primary((), position)]);
+            let label = &sources.file_diagnostic(&diagnostic).labels[0];
+            (label.file_id, label.range.clone())
+        };
+        assert_eq!(label_of(&mf.0[0]), (2, 0..11));
+        assert_eq!(label_of(&mf.0[1]), (1, 27..32));
+        assert_eq!(label_of(&mf.0[2]), (0, 60..64));
+
+        assert!("include \"lib/base.Modusfile\""
+            .parse::<Modusfile>()
+            .is_err());
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), "include \"b\"\na.").unwrap();
+        std::fs::write(dir.path().join("b"), "b.\ninclude \"a\"").unwrap();
+
+        let e = Modusfile::from_file_with_includes(dir.path().join("a")).unwrap_err();
+        assert!(e.file.name().ends_with("b"));
+        assert_eq!(e.diagnostics.len(), 1);
+        assert!(e.diagnostics[0].message.starts_with("Include cycle: "));
+        assert_eq!(
+            e.diagnostics[0].labels[0].range,
+            3..14,
+            "the label should cover the include of a in b"
+        );
+
+        std::fs::write(dir.path().join("b"), "include \"missing\"").unwrap();
+        let e = Modusfile::from_file_with_includes(dir.path().join("a")).unwrap_err();
+        assert!(e.diagnostics[0].message.starts_with("Error reading "));
+    }
 }
@@ -25,7 +25,7 @@ use crate::{
     analysis, builtin,
     logic::Predicate,
     modusfile::{self, Modusfile},
-    translate::translate_modusfile,
+    translate::{self, translate_modusfile},
     unification::{compose_extend, compose_no_extend, Rename, Substitution},
 };
 use crate::{builtin::SelectBuiltinResult, unification::RenameWithSubstitution};
@@ -38,7 +38,7 @@ use codespan_reporting::diagnostic::{Diagnostic, Label, Severity};
 use colored::Colorize;
 use itertools::Itertools;
 use logic::{Clause, IRTerm, Literal};
-use ptree::{item::StringItem, print_tree, TreeBuilder, TreeItem};
+use ptree::{item::StringItem, print_tree, write_tree_with, PrintConfig, TreeBuilder, TreeItem};
 
 pub trait Auxiliary: Rename<Self> + Sized {
     fn aux(anonymous: bool) -> Self;
@@ -59,6 +59,10 @@ pub enum ClauseId {
     /// Stores the literal which we attempted to prove.
     /// So it should be a positive literal.
     NegationCheck(Literal<IRTerm>),
+
+    /// Stores a previously computed answer of a tabled subgoal, which is applied
+    /// as a fact. Its derivation is not part of the tree.
+    Tabled(Literal<IRTerm>),
 }
 
 impl ClauseId {
@@ -209,6 +213,7 @@ impl Tree {
                         ClauseId::Query => "query".to_string(),
                         ClauseId::Builtin(lit) => lit.to_string(),
                         ClauseId::NegationCheck(lit) => format!("Check {lit}?"),
+                        ClauseId::Tabled(lit) => format!("{lit} (tabled)"),
                     };
                     edges.push((curr_index, new_index, edge_label));
                 }
@@ -337,6 +342,9 @@ impl Tree {
                         ClauseId::NegationCheck(lit) => {
                             format!("{} to have no proof", lit)
                         }
+                        ClauseId::Tabled(lit) => {
+                            format!("{} to be a tabled answer", lit.substitute(&v.0))
+                        }
                     };
                     let curr_attempt = format!(
                         "{} {}",
@@ -566,6 +574,9 @@ impl Proof {
                         }
                     },
                     ClauseId::NegationCheck(_) => {} // negation checks are omitted from the proof tree
+                    ClauseId::Tabled(lit) => {
+                        builder.add_empty_child(lit.substitute(&child.valuation).to_string());
+                    }
                 }
             }
         }
@@ -584,6 +595,22 @@ impl Proof {
     ) -> io::Result<()> {
         print_tree(&self.get_tree(clauses, pred_kind, compact))
     }
+
+    /// Like [Proof::pretty_print], but writes to `out` with the default tree style, so
+    /// that the output doesn't depend on the environment.
+    pub fn write_pretty<W: io::Write>(
+        &self,
+        out: W,
+        clauses: &Vec<Clause>,
+        pred_kind: &HashMap<Predicate, analysis::Kind>,
+        compact: bool,
+    ) -> io::Result<()> {
+        write_tree_with(
+            &self.get_tree(clauses, pred_kind, compact),
+            out,
+            &PrintConfig::default(),
+        )
+    }
 }
 
 impl PartialOrd for Proof {
@@ -619,14 +646,17 @@ pub enum ResolutionError {
     InsufficientGroundness(Vec<Literal>),
     /// Contains the goals when the max depth was exceeded.
     MaximumDepthExceeded(Vec<Literal>, usize),
-    /// Contains the relevant literal (builtin call), and the name of the selected builtin.
-    BuiltinFailure(Literal, &'static str),
+    /// Contains the relevant literal (builtin call), the name of the selected builtin, and
+    /// the reason it failed if the builtin gives one.
+    BuiltinFailure(Literal, &'static str, Option<String>),
     /// Contains the literal that didn't match with any rule head.
     InsufficientRules(Literal),
     /// Contains the set of inconsistent signatures.
     InconsistentGroundnessSignature(Vec<Signature>),
-    /// Proof of a negated literal was found.
-    NegationProof(Literal),
+    /// Contains the negated literal and the literals whose proof refuted it.
+    NegationProof(Literal, Vec<Literal>),
+    /// Contains the selected literal of a goal that is a variant of one of its ancestors.
+    InfiniteRecursion(Literal),
 }
 
 impl fmt::Display for ResolutionError {
@@ -645,7 +675,7 @@ impl fmt::Display for ResolutionError {
             ResolutionError::MaximumDepthExceeded(_, max_depth) => {
                 write!(f, "exceeded maximum depth of {}", max_depth)
             }
-            ResolutionError::BuiltinFailure(l, builtin_name) => {
+            ResolutionError::BuiltinFailure(l, builtin_name, _) => {
                 write!(f, "builtin {builtin_name} failed to apply or unify: {l}")
             }
             ResolutionError::InsufficientRules(literal) => write!(
@@ -658,9 +688,25 @@ impl fmt::Display for ResolutionError {
                 "{} clause(s) have inconsistent signatures",
                 signatures.len()
             ),
-            ResolutionError::NegationProof(lit) => {
-                write!(f, "A proof was found for {}", lit.negated())
+            ResolutionError::NegationProof(_, proven) => {
+                let conjunction = proven.iter().join(", ");
+                if proven.len() == 1 {
+                    write!(
+                        f,
+                        "expected `!{conjunction}` to hold but `{conjunction}` was provable"
+                    )
+                } else {
+                    write!(
+                        f,
+                        "expected `!({conjunction})` to hold but `{conjunction}` was provable"
+                    )
+                }
             }
+            ResolutionError::InfiniteRecursion(literal) => write!(
+                f,
+                "cut a likely infinite recursion through {}, which led back to the same goal",
+                literal.predicate
+            ),
         }
     }
 }
@@ -681,7 +727,7 @@ impl ResolutionError {
             ResolutionError::MaximumDepthExceeded(_, max_depth) => {
                 format!("exceeded depth of {}", max_depth)
             }
-            ResolutionError::BuiltinFailure(l, builtin_name) => {
+            ResolutionError::BuiltinFailure(l, builtin_name, _) => {
                 format!("{builtin_name} failed")
             }
             ResolutionError::InsufficientRules(literal) => {
@@ -690,8 +736,11 @@ impl ResolutionError {
             ResolutionError::InconsistentGroundnessSignature(_) => {
                 format!("clauses with inconsistent signatures",)
             }
-            ResolutionError::NegationProof(lit) => {
-                format!("proof found for {}", lit.negated())
+            ResolutionError::NegationProof(_, proven) => {
+                format!("`{}` was provable", proven.iter().join(", "))
+            }
+            ResolutionError::InfiniteRecursion(literal) => {
+                format!("infinite recursion: {}", literal.predicate)
             }
         }
     }
@@ -701,10 +750,11 @@ impl ResolutionError {
             ResolutionError::UnknownPredicate(_) => Severity::Error,
             ResolutionError::InsufficientGroundness(_) => Severity::Error,
             ResolutionError::MaximumDepthExceeded(_, _) => Severity::Warning,
-            ResolutionError::BuiltinFailure(_, _) => Severity::Warning,
+            ResolutionError::BuiltinFailure(..) => Severity::Warning,
             ResolutionError::InsufficientRules(_) => Severity::Warning,
             ResolutionError::InconsistentGroundnessSignature(_) => Severity::Error,
-            ResolutionError::NegationProof(_) => Severity::Warning,
+            ResolutionError::NegationProof(_, _) => Severity::Warning,
+            ResolutionError::InfiniteRecursion(_) => Severity::Warning,
         }
     }
 
@@ -719,12 +769,13 @@ impl ResolutionError {
                 Some(ls.iter().map(|x| x.to_string()).collect())
             }
             ResolutionError::MaximumDepthExceeded(_, _) => None,
-            ResolutionError::BuiltinFailure(_, _) => None,
+            ResolutionError::BuiltinFailure(_, _, reason) => reason.clone().map(|r| vec![r]),
             ResolutionError::InsufficientRules(_) => None,
             ResolutionError::InconsistentGroundnessSignature(sigs) => {
                 Some(sigs.into_iter().map(|x| x.to_string()).collect())
             }
-            ResolutionError::NegationProof(_) => None,
+            ResolutionError::NegationProof(_, _) => None,
+            ResolutionError::InfiniteRecursion(_) => None,
         }
     }
 
@@ -763,9 +814,12 @@ impl ResolutionError {
             ResolutionError::MaximumDepthExceeded(literals, _) => {
                 (get_position_labels(&literals), get_notes(&literals))
             }
-            ResolutionError::BuiltinFailure(literal, _) => (
+            ResolutionError::BuiltinFailure(literal, _, reason) => (
                 get_position_labels(&[literal.clone()]),
-                get_notes(&[literal.clone()]),
+                get_notes(&[literal.clone()])
+                    .into_iter()
+                    .chain(reason.clone())
+                    .collect(),
             ),
             ResolutionError::InsufficientRules(literal) => (
                 get_position_labels(&[literal.clone()]),
@@ -774,10 +828,14 @@ impl ResolutionError {
             ResolutionError::InconsistentGroundnessSignature(sigs) => {
                 (Vec::new(), sigs.iter().map(|sig| sig.to_string()).collect())
             }
-            ResolutionError::NegationProof(lit) => (
+            ResolutionError::NegationProof(lit, _) => (
                 get_position_labels(&[lit.clone()]),
                 get_notes(&[lit.clone()]),
             ),
+            ResolutionError::InfiniteRecursion(lit) => (
+                get_position_labels(std::slice::from_ref(lit)),
+                get_notes(std::slice::from_ref(lit)),
+            ),
         };
 
         Diagnostic::new(self.severity())
@@ -801,8 +859,8 @@ impl ResolutionError {
                 ls.into_iter().map(|x| x.normalized_terms()).collect(),
                 s,
             ),
-            ResolutionError::BuiltinFailure(l, s) => {
-                ResolutionError::BuiltinFailure(l.normalized_terms(), s)
+            ResolutionError::BuiltinFailure(l, s, reason) => {
+                ResolutionError::BuiltinFailure(l.normalized_terms(), s, reason)
             }
             ResolutionError::InsufficientRules(l) => {
                 ResolutionError::InsufficientRules(l.normalized_terms())
@@ -810,8 +868,12 @@ impl ResolutionError {
             ResolutionError::InconsistentGroundnessSignature(sigs) => {
                 ResolutionError::InconsistentGroundnessSignature(sigs.to_vec())
             }
-            ResolutionError::NegationProof(l) => {
-                ResolutionError::NegationProof(l.normalized_terms())
+            ResolutionError::NegationProof(l, proven) => ResolutionError::NegationProof(
+                l.normalized_terms(),
+                proven.into_iter().map(|x| x.normalized_terms()).collect(),
+            ),
+            ResolutionError::InfiniteRecursion(l) => {
+                ResolutionError::InfiniteRecursion(l.normalized_terms())
             }
         }
     }
@@ -827,28 +889,101 @@ pub struct SLDResult {
 }
 
 impl From<SLDResult> for Result<Tree, Vec<Diagnostic<()>>> {
+    /// When there is no proof and some branch was cut off by the depth limit, the
+    /// diagnostics start with an error saying so, since the query may still be provable
+    /// with a larger limit.
     fn from(sld_result: SLDResult) -> Self {
         if sld_result.tree.is_success() {
             Ok(sld_result.tree)
         } else {
-            Err(sld_result
-                .errors
+            let max_depth = sld_result.errors.iter().find_map(|e| match e {
+                ResolutionError::MaximumDepthExceeded(_, max_depth) => Some(*max_depth),
+                _ => None,
+            });
+            let depth_diag = max_depth.map(|max_depth| {
+                Diagnostic::error()
+                    .with_message(format!(
+                        "No proof was found within the maximum depth of {}.",
+                        max_depth
+                    ))
+                    .with_notes(vec![
+                        "Deeply recursive rules may need a higher limit, which can be set with --max-depth."
+                            .to_string(),
+                    ])
+            });
+            Err(depth_diag
                 .into_iter()
-                .map(ResolutionError::normalize)
-                .unique()
-                .map(ResolutionError::get_diagnostic)
+                .chain(
+                    sld_result
+                        .errors
+                        .into_iter()
+                        .map(ResolutionError::normalize)
+                        .unique()
+                        .map(ResolutionError::get_diagnostic),
+                )
                 .collect::<Vec<_>>())
         }
     }
 }
 
+/// Answers of tabled subgoals, keyed by [`variant_key`]. `None` marks a subgoal whose
+/// answers are still being computed.
+type Table = HashMap<Goal, Option<(Vec<Literal>, Option<ResolutionError>)>>;
+
+/// What every step of a resolution shares: the rules, the depth limit, the groundness
+/// requirements of user-defined predicates, and the answer table if tabling is enabled.
+struct SldContext<'a> {
+    rules: &'a [Clause<IRTerm>],
+    maxdepth: TreeLevel,
+    grounded: &'a HashMap<Signature, Vec<bool>>,
+    table: Option<Table>,
+}
+
+/// Renames the variables of a goal by their order of first occurrence, so that
+/// goals that only differ in variable names share the same key.
+fn variant_key<'a>(goal: impl IntoIterator<Item = &'a Literal>) -> Goal {
+    fn rename_term(t: &IRTerm, seen: &mut HashMap<IRTerm, u32>) -> IRTerm {
+        match t {
+            IRTerm::Constant(_) => t.clone(),
+            IRTerm::List(ts) => IRTerm::List(ts.iter().map(|t| rename_term(t, seen)).collect()),
+            _ => {
+                let next = seen.len() as u32;
+                IRTerm::AuxiliaryVariable(*seen.entry(t.clone()).or_insert(next))
+            }
+        }
+    }
+    let mut seen = HashMap::new();
+    goal.into_iter()
+        .map(|l| Literal {
+            position: None,
+            args: l.args.iter().map(|t| rename_term(t, &mut seen)).collect(),
+            ..l.clone()
+        })
+        .collect()
+}
+
 /// Returns a tree that contains both successful and failed paths, also, any resolution errors.
 /// To save on memory usage, can avoid storing the failed paths by passing false to `store_full_tree`.
+///
+/// With `tabling`, the answers of each user-defined subgoal are computed once and reused
+/// whenever a variant of that subgoal is selected again, instead of resolving it with the
+/// rules. The solutions are the same, but the tree no longer contains the derivations
+/// of reused answers, so tabled trees are not suitable for building images.
+/// For example, if each `p_k(X)` is defined by two rules with the body `p_{k-1}(X)`,
+/// the untabled tree has 2^k branches; with k = 16 it takes about 5s to build in a debug
+/// build, against 3ms with tabling.
+/// Since cached answers are computed at the depth the subgoal was first seen, they may
+/// differ from the untabled ones when the depth limit is reached.
+///
+/// A goal that is a variant of one of its ancestors on the same branch is not expanded
+/// again, and is reported as a likely infinite recursion instead of running until the
+/// depth limit.
 pub fn sld(
     rules: &[Clause<IRTerm>],
     goal: &Goal,
     maxdepth: TreeLevel,
     store_full_tree: bool,
+    tabling: bool,
 ) -> SLDResult {
     /// Select leftmost literal with compatible groundness.
     fn select(
@@ -946,15 +1081,30 @@ pub fn sld(
         g.substitute(mgu)
     }
 
+    /// The literals whose proof refuted the negated literal `l`. Negated expressions are
+    /// translated into auxiliary rules, so for those this is the body of the rule that was
+    /// proven rather than the auxiliary literal itself.
+    fn refuting_literals(l: &Literal, tree: &Tree) -> Vec<Literal> {
+        let is_auxiliary = l
+            .predicate
+            .0
+            .starts_with(translate::NEGATION_PREDICATE_PREFIX);
+        match tree.success_resolvents.values().next() {
+            Some((_, _, subtree)) if is_auxiliary && !subtree.goal.is_empty() => {
+                subtree.goal.iter().map(|lit| lit.literal.clone()).collect()
+            }
+            _ => vec![l.negated()],
+        }
+    }
+
     fn handle_negated_literal(
+        cx: &mut SldContext,
         lid: LiteralGoalId,
         l: LiteralWithHistory,
         goal: &GoalWithHistory,
-        rules: &[Clause<IRTerm>],
-        maxdepth: TreeLevel,
         level: TreeLevel,
-        grounded: &HashMap<Signature, Vec<bool>>,
         store_full_tree: bool,
+        ancestors: &mut HashSet<Goal>,
     ) -> SLDResult {
         let mut errs: HashSet<ResolutionError> = HashSet::new();
 
@@ -964,15 +1114,8 @@ pub fn sld(
         }];
 
         // Perform SLD resolution with this goal and check if it succeeds or not.
-        let sld_res = inner(
-            rules,
-            &singleton_goal,
-            // The stratifiability check should make it safe to use the same maxdepth.
-            maxdepth,
-            0,
-            grounded,
-            store_full_tree,
-        );
+        // The stratifiability check should make it safe to use the same maxdepth.
+        let sld_res = inner(cx, &singleton_goal, 0, store_full_tree, &mut HashSet::new());
 
         let rid = ClauseId::NegationCheck(l.literal.negated());
         let mgu = HashMap::new();
@@ -984,11 +1127,12 @@ pub fn sld(
         // the negation proof should also fail if there is an error in the subtree
         let subtree_error = sld_res.tree.contains_error_severity();
         if sld_res.tree.is_success() || subtree_error {
+            let refuted_by = refuting_literals(&l.literal, &sld_res.tree);
             if store_full_tree {
                 fail_resolvents.insert((lid, rid), (mgu, renaming, sld_res.tree));
             }
 
-            let err = ResolutionError::NegationProof(l.literal);
+            let err = ResolutionError::NegationProof(l.literal, refuted_by);
             if !subtree_error {
                 errs.insert(err.clone());
             }
@@ -1013,14 +1157,11 @@ pub fn sld(
                 },
                 level + 1,
             );
-            let SLDResult { tree, errors } = inner(
-                rules,
-                &resolvent,
-                maxdepth,
-                level + 1,
-                grounded,
-                store_full_tree,
-            );
+            let goal_key = variant_key(goal.iter().map(|l| &l.literal));
+            ancestors.insert(goal_key.clone());
+            let SLDResult { tree, errors } =
+                inner(cx, &resolvent, level + 1, store_full_tree, ancestors);
+            ancestors.remove(&goal_key);
 
             if tree.is_success() {
                 success_resolvents.insert((lid, rid), (mgu, renaming, tree));
@@ -1041,13 +1182,40 @@ pub fn sld(
         }
     }
 
+    /// Returns the answers of the tabled literal `l` along with the error of its
+    /// resolution, computing them first if this variant hasn't been seen yet.
+    /// Returns `None` when tabling is disabled, or when the answers of `l` are still being
+    /// computed higher up in the tree, in which case `l` is resolved with the rules.
+    fn table_answers(
+        cx: &mut SldContext,
+        l: &LiteralWithHistory,
+        level: TreeLevel,
+        errs: &mut HashSet<ResolutionError>,
+    ) -> Option<(Vec<Literal>, Option<ResolutionError>)> {
+        let key = variant_key(iter::once(&l.literal));
+        if let Some(entry) = cx.table.as_ref()?.get(&key) {
+            return entry.clone();
+        }
+
+        cx.table.as_mut()?.insert(key.clone(), None);
+        let SLDResult { tree, errors } =
+            inner(cx, &vec![l.clone()], level, false, &mut HashSet::new());
+        errs.extend(errors);
+        let answers = solutions(&tree)
+            .into_iter()
+            .map(|mut answer| answer.remove(0))
+            .collect::<Vec<_>>();
+        let entry = (answers, tree.error);
+        cx.table.as_mut()?.insert(key, Some(entry.clone()));
+        Some(entry)
+    }
+
     fn inner(
-        rules: &[Clause<IRTerm>],
+        cx: &mut SldContext,
         goal: &GoalWithHistory,
-        maxdepth: TreeLevel,
         level: TreeLevel,
-        grounded: &HashMap<Signature, Vec<bool>>,
         store_full_tree: bool,
+        ancestors: &mut HashSet<Goal>,
     ) -> SLDResult {
         if goal.is_empty() {
             let t = Tree {
@@ -1061,12 +1229,12 @@ pub fn sld(
                 tree: t,
                 errors: HashSet::new(),
             }
-        } else if level >= maxdepth {
+        } else if level >= cx.maxdepth {
             let error = ResolutionError::MaximumDepthExceeded(
                 goal.iter()
                     .map(|lit_hist| lit_hist.literal.clone())
                     .collect(),
-                maxdepth,
+                cx.maxdepth,
             );
             let t = Tree {
                 goal: goal.to_owned(),
@@ -1078,7 +1246,7 @@ pub fn sld(
             let errors = vec![error].into_iter().collect();
             SLDResult { tree: t, errors }
         } else {
-            let selection_res = select(goal, grounded);
+            let selection_res = select(goal, cx.grounded);
             if let Err(e) = selection_res {
                 let t = Tree {
                     goal: goal.to_owned(),
@@ -1094,17 +1262,27 @@ pub fn sld(
             }
             let (lid, l) = selection_res.unwrap();
 
-            if !l.literal.positive {
-                return handle_negated_literal(
-                    lid,
-                    l,
-                    goal,
-                    rules,
-                    maxdepth,
+            // A goal that is a variant of one of its ancestors can only be proven in the
+            // same ways as that ancestor, so expanding it again would recurse until the
+            // depth limit without finding anything new.
+            let goal_key = variant_key(goal.iter().map(|l| &l.literal));
+            if ancestors.contains(&goal_key) {
+                let e = ResolutionError::InfiniteRecursion(l.literal);
+                let t = Tree {
+                    goal: goal.to_owned(),
                     level,
-                    grounded,
-                    store_full_tree,
-                );
+                    success_resolvents: HashMap::default(),
+                    fail_resolvents: HashMap::default(),
+                    error: Some(e.clone()),
+                };
+                return SLDResult {
+                    tree: t,
+                    errors: vec![e].into_iter().collect(),
+                };
+            }
+
+            if !l.literal.positive {
+                return handle_negated_literal(cx, lid, l, goal, level, store_full_tree, ancestors);
             }
 
             let mut errs: HashSet<ResolutionError> = HashSet::new();
@@ -1138,38 +1316,70 @@ pub fn sld(
 
             let mut leaf_error = None;
             if selected_builtin.0.is_match() && builtin_resolves.is_none() {
+                let builtin = selected_builtin.1.expect("match should provide builtin");
                 let err = ResolutionError::BuiltinFailure(
                     l.literal.clone(),
-                    selected_builtin
-                        .1
-                        .expect("match should provide builtin")
-                        .name(),
+                    builtin.name(),
+                    builtin.failure_reason(&l.literal),
                 );
                 errs.insert(err.clone());
                 leaf_error = Some(err);
             }
 
-            let user_rules_resolves = rules
-                .iter()
-                .enumerate()
-                .filter(|(_, c)| c.head.signature() == l.literal.signature())
-                .map(|(rid, c)| (ClauseId::Rule(rid), c.rename_with_sub()))
-                .filter_map(|(rid, (c, renaming))| {
-                    c.head.unify(&l.literal).map(|mgu| {
-                        (
-                            rid.clone(),
-                            mgu.clone(),
-                            renaming,
-                            resolve(lid, rid, goal, &mgu, &c, level + 1),
-                        )
+            let tabled_answers = if selected_builtin.0.is_match() {
+                None
+            } else {
+                table_answers(cx, &l, level, &mut errs)
+            };
+
+            let user_rules_resolves = if let Some((answers, answers_error)) = tabled_answers {
+                leaf_error = answers_error;
+                answers
+                    .iter()
+                    .map(|answer| {
+                        Clause {
+                            head: answer.clone(),
+                            body: Vec::new(),
+                        }
+                        .rename_with_sub()
                     })
-                })
-                .collect::<Vec<_>>();
-            if !selected_builtin.0.is_match() && user_rules_resolves.is_empty() {
-                let err = ResolutionError::InsufficientRules(l.literal.clone());
-                errs.insert(err.clone());
-                leaf_error = leaf_error.or(Some(err));
-            }
+                    .filter_map(|(c, renaming)| {
+                        c.head.unify(&l.literal).map(|mgu| {
+                            let rid = ClauseId::Tabled(c.head.clone());
+                            (
+                                rid.clone(),
+                                mgu.clone(),
+                                renaming,
+                                resolve(lid, rid, goal, &mgu, &c, level + 1),
+                            )
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            } else {
+                let user_rules_resolves = cx
+                    .rules
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.head.signature() == l.literal.signature())
+                    .map(|(rid, c)| (ClauseId::Rule(rid), c.rename_with_sub()))
+                    .filter_map(|(rid, (c, renaming))| {
+                        c.head.unify(&l.literal).map(|mgu| {
+                            (
+                                rid.clone(),
+                                mgu.clone(),
+                                renaming,
+                                resolve(lid, rid, goal, &mgu, &c, level + 1),
+                            )
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                if !selected_builtin.0.is_match() && user_rules_resolves.is_empty() {
+                    let err = ResolutionError::InsufficientRules(l.literal.clone());
+                    errs.insert(err.clone());
+                    leaf_error = leaf_error.or(Some(err));
+                }
+                user_rules_resolves
+            };
 
             let mut success_resolvents: HashMap<
                 (LiteralGoalId, ClauseId),
@@ -1179,17 +1389,12 @@ pub fn sld(
                 (LiteralGoalId, ClauseId),
                 (Substitution, Substitution, Tree),
             > = HashMap::new();
+            ancestors.insert(goal_key.clone());
             for (rid, mgu, renaming, resolvent) in
                 builtin_resolves.into_iter().chain(user_rules_resolves)
             {
-                let SLDResult { tree, errors } = inner(
-                    rules,
-                    &resolvent,
-                    maxdepth,
-                    level + 1,
-                    grounded,
-                    store_full_tree,
-                );
+                let SLDResult { tree, errors } =
+                    inner(cx, &resolvent, level + 1, store_full_tree, ancestors);
                 if tree.is_success() {
                     success_resolvents.insert((lid, rid), (mgu, renaming, tree));
                 } else if store_full_tree {
@@ -1197,6 +1402,7 @@ pub fn sld(
                 }
                 errs.extend(errors);
             }
+            ancestors.remove(&goal_key);
 
             let tree = Tree {
                 goal: goal.to_owned(),
@@ -1228,12 +1434,16 @@ pub fn sld(
         .collect();
     match grounded_result {
         Ok(grounded) => inner(
-            rules,
+            &mut SldContext {
+                rules,
+                maxdepth,
+                grounded: &grounded,
+                table: if tabling { Some(Table::new()) } else { None },
+            },
             &goal_with_history,
-            maxdepth,
             0,
-            &grounded,
             store_full_tree,
+            &mut HashSet::new(),
         ),
         Err(e) => SLDResult {
             tree: Tree {
@@ -1363,6 +1573,7 @@ pub fn proofs(tree: &Tree, rules: &[Clause], goal: &Goal) -> HashMap<Goal, Proof
             // There shouldn't be a subtree here since the tree is currently only stored
             // if the negation check failed (i.e. we found a proof).
             ClauseId::NegationCheck(_) => assert_eq!(children_length, 0),
+            ClauseId::Tabled(_) => assert_eq!(children_length, 0),
         };
 
         let mut sublevels = Vec::<TreeLevel>::with_capacity(sublevels_map.len());
@@ -1418,6 +1629,9 @@ pub fn proofs(tree: &Tree, rules: &[Clause], goal: &Goal) -> HashMap<Goal, Proof
     solution_to_proof_tree
 }
 
+/// The depth limit of resolution when none is given, e.g. with `--max-depth`.
+pub const DEFAULT_MAX_DEPTH: usize = 175;
+
 pub fn tree_from_modusfile(
     mf: Modusfile,
     query: modusfile::Expression,
@@ -1442,7 +1656,7 @@ pub fn tree_from_modusfile(
     (
         goal.clone(),
         clauses.clone(),
-        sld(&clauses, &goal, max_depth, full_tree),
+        sld(&clauses, &goal, max_depth, full_tree, false),
     )
 }
 
@@ -1486,7 +1700,7 @@ mod tests {
                 body: vec![],
             },
         ];
-        let tree = sld(&clauses, &goal, 10, true).tree;
+        let tree = sld(&clauses, &goal, 10, true, false).tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 2);
 
@@ -1511,7 +1725,7 @@ mod tests {
                 body: vec![],
             },
         ];
-        let tree = sld(&clauses, &goal, 10, true).tree;
+        let tree = sld(&clauses, &goal, 10, true, false).tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 1);
 
@@ -1529,7 +1743,7 @@ mod tests {
             "a(X) :- !b(X).".parse().unwrap(),
             "b(\"d\").".parse().unwrap(),
         ];
-        let sld_res = sld(&clauses, &goal, 10, true);
+        let sld_res = sld(&clauses, &goal, 10, true, false);
         let tree = sld_res.tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 1);
@@ -1548,7 +1762,7 @@ mod tests {
             head: "a(X)".parse().unwrap(),
             body: vec![],
         }];
-        let tree = sld(&clauses, &goal, 10, true).tree;
+        let tree = sld(&clauses, &goal, 10, true, false).tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 1);
         assert!(contains_ignoring_position(
@@ -1565,7 +1779,7 @@ mod tests {
             head: "a(X)".parse().unwrap(),
             body: vec![],
         }];
-        let result = sld(&clauses, &goal, 10, true);
+        let result = sld(&clauses, &goal, 10, true, false);
         assert_eq!(
             vec![ResolutionError::InsufficientGroundness(goal)],
             result.errors.into_iter().collect::<Vec<_>>()
@@ -1594,7 +1808,7 @@ mod tests {
                 body: vec![],
             },
         ];
-        let tree = sld(&clauses, &goal, 10, true).tree;
+        let tree = sld(&clauses, &goal, 10, true, false).tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 1);
         assert!(contains_ignoring_position(
@@ -1626,7 +1840,7 @@ mod tests {
                 body: vec![],
             },
         ];
-        let tree = sld(&clauses, &goal, 10, true).tree;
+        let tree = sld(&clauses, &goal, 10, true, false).tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 2);
         assert!(contains_ignoring_position(
@@ -1675,7 +1889,7 @@ mod tests {
                 body: vec![],
             },
         ];
-        let tree = sld(&clauses, &goal, 15, true).tree;
+        let tree = sld(&clauses, &goal, 15, true, false).tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 4);
         assert!(contains_ignoring_position(
@@ -1702,7 +1916,7 @@ mod tests {
         let goal: Goal<logic::IRTerm> =
             vec!["string_concat(\"hello\", \"world\", X)".parse().unwrap()];
         let clauses: Vec<logic::Clause> = vec![];
-        let tree = sld(&clauses, &goal, 10, true).tree;
+        let tree = sld(&clauses, &goal, 10, true, false).tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 1);
         assert!(contains_ignoring_position(
@@ -1734,7 +1948,7 @@ mod tests {
                     .parse()
                     .unwrap(),
             ];
-            let tree_res = sld(&clauses, &goal, 50, true);
+            let tree_res = sld(&clauses, &goal, 50, true, false);
             if is_good {
                 let solutions = solutions(&tree_res.tree);
                 assert_eq!(solutions.len(), 1);
@@ -1768,7 +1982,7 @@ mod tests {
             "bar(\"test\").".parse().unwrap(),
             "foo(\"test\").".parse().unwrap(),
         ];
-        let tree = sld(&clauses, &goal, 15, true).tree;
+        let tree = sld(&clauses, &goal, 15, true, false).tree;
         let sld_proofs = proofs(&tree, &clauses, &goal);
         assert_eq!(sld_proofs.len(), 1);
         assert_eq!(
@@ -1779,6 +1993,68 @@ mod tests {
         );
     }
 
+    #[test]
+    #[serial]
+    fn infinite_recursion_is_cut() {
+        let goal: Goal<logic::IRTerm> = vec!["a".parse().unwrap()];
+        let clauses: Vec<logic::Clause> = vec!["a :- a.".parse().unwrap()];
+        let sld_res = sld(&clauses, &goal, 50, true, false);
+        assert!(!sld_res.tree.is_success());
+        assert!(sld_res
+            .errors
+            .iter()
+            .all(|e| !matches!(e, ResolutionError::MaximumDepthExceeded(..))));
+
+        let diags = Result::from(sld_res).unwrap_err();
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].severity, Severity::Warning);
+        assert!(diags[0].message.contains("infinite recursion through a"));
+    }
+
+    #[test]
+    #[serial]
+    fn bounded_recursion_is_not_cut() {
+        let goal: Goal<logic::IRTerm> = vec!["path(\"a\", \"c\")".parse().unwrap()];
+        let clauses: Vec<logic::Clause> = vec![
+            "edge(\"a\", \"b\").".parse().unwrap(),
+            "edge(\"b\", \"a\").".parse().unwrap(),
+            "edge(\"b\", \"c\").".parse().unwrap(),
+            "path(X, Y) :- edge(X, Y).".parse().unwrap(),
+            "path(X, Y) :- edge(X, Z), path(Z, Y).".parse().unwrap(),
+        ];
+        let sld_res = sld(&clauses, &goal, 50, true, false);
+        assert!(sld_res.tree.is_success());
+        assert_eq!(solutions(&sld_res.tree).len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn tabling_preserves_solutions() {
+        let clauses: Vec<logic::Clause> = vec![
+            "edge(\"a\", \"b\").".parse().unwrap(),
+            "edge(\"a\", \"c\").".parse().unwrap(),
+            "edge(\"b\", \"d\").".parse().unwrap(),
+            "edge(\"c\", \"d\").".parse().unwrap(),
+            "edge(\"d\", \"e\").".parse().unwrap(),
+            "path(X, Y) :- edge(X, Y).".parse().unwrap(),
+            "path(X, Y) :- edge(X, Z), path(Z, Y).".parse().unwrap(),
+            "twice(X, Y) :- path(X, Z), path(Z, Y).".parse().unwrap(),
+        ];
+        let to_strings = |tree: &Tree| {
+            solutions(tree)
+                .into_iter()
+                .map(|g| g.iter().map(|l| l.to_string()).collect::<Vec<_>>())
+                .collect::<HashSet<_>>()
+        };
+        for goal in ["path(\"a\", Y)", "twice(X, Y)", "twice(\"a\", \"e\")"] {
+            let goal: Goal<logic::IRTerm> = vec![goal.parse().unwrap()];
+            let untabled = sld(&clauses, &goal, 20, false, false).tree;
+            let tabled = sld(&clauses, &goal, 20, false, true).tree;
+            assert!(untabled.is_success());
+            assert_eq!(to_strings(&untabled), to_strings(&tabled));
+        }
+    }
+
     #[test]
     #[serial]
     fn tree_from_expression_query() {
@@ -1796,6 +2072,44 @@ mod tests {
         assert!(sld_res.tree.is_success());
     }
 
+    #[test]
+    #[serial]
+    fn pretty_printed_proof() {
+        use crate::analysis::ModusSemantics;
+
+        colored::control::set_override(false);
+        let mf: Modusfile = "base :- from(\"alpine\").\n\
+                             app :- base, (run(\"make\"))::in_workdir(\"/src\")."
+            .parse()
+            .unwrap();
+        let kind_res = mf.kinds();
+        let (goal, clauses, sld_res) =
+            tree_from_modusfile(mf, "app".parse().unwrap(), DEFAULT_MAX_DEPTH, false);
+        let tree = Result::from(sld_res).unwrap();
+        let proofs = proofs(&tree, &clauses, &goal);
+        assert_eq!(proofs.len(), 1);
+
+        let mut out = Vec::new();
+        proofs
+            .values()
+            .next()
+            .unwrap()
+            .write_pretty(&mut out, &clauses, &kind_res.pred_kind, false)
+            .unwrap();
+        colored::control::unset_override();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"
+└─ app
+   ├─ base
+   │  └─ from("alpine")
+   ├─ (
+   │  └─ run("make")
+   └─ )::in_workdir("/src")
+"#
+        );
+    }
+
     #[test]
     #[serial]
     fn negation_and_builtins() {
@@ -1805,7 +2119,7 @@ mod tests {
                 .parse()
                 .unwrap(),
         ];
-        let sld_res = sld(&clauses, &goal, 10, true);
+        let sld_res = sld(&clauses, &goal, 10, true, false);
         let tree = sld_res.tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 1);
@@ -1827,7 +2141,7 @@ mod tests {
                 .parse()
                 .unwrap(),
         ];
-        let sld_res = sld(&clauses, &goal, 10, true);
+        let sld_res = sld(&clauses, &goal, 10, true, false);
         let tree = sld_res.tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 1);
@@ -1840,7 +2154,7 @@ mod tests {
     fn negation_errors_when_unknown() {
         let goal: Goal<logic::IRTerm> = vec!["!is_alpine(\"notalpine3.15\", _)".parse().unwrap()];
         let clauses: Vec<logic::Clause> = vec![];
-        let sld_res = sld(&clauses, &goal, 10, true);
+        let sld_res = sld(&clauses, &goal, 10, true, false);
 
         assert_eq!(sld_res.errors.len(), 1);
         let is_match = matches!(
@@ -1850,6 +2164,23 @@ mod tests {
         assert!(is_match);
     }
 
+    #[test]
+    #[serial]
+    fn negation_errors_when_provable() {
+        let src = "foo :- !bar.\nbar.";
+        let mf: Modusfile = src.parse().unwrap();
+        let (_, _, sld_res) =
+            tree_from_modusfile(mf, "foo".parse().unwrap(), DEFAULT_MAX_DEPTH, false);
+        let errs = Result::from(sld_res).unwrap_err();
+
+        let diag = errs
+            .iter()
+            .find(|d| d.message == "expected `!bar` to hold but `bar` was provable")
+            .expect("missing negation diagnostic");
+        assert_eq!(diag.labels.len(), 1);
+        assert_eq!(&src[diag.labels[0].range.clone()], "!bar");
+    }
+
     #[test]
     #[serial]
     fn lists() {
@@ -1859,7 +2190,7 @@ mod tests {
                 .parse()
                 .unwrap(),
         ];
-        let sld_res = sld(&clauses, &goal, 10, true);
+        let sld_res = sld(&clauses, &goal, 10, true, false);
         let tree = sld_res.tree;
         let solutions = solutions(&tree);
         assert_eq!(solutions.len(), 1);
@@ -1881,7 +2212,7 @@ mod tests {
                 .parse()
                 .unwrap(),
         ];
-        let sld_res = sld(&clauses, &goal, 10, true);
+        let sld_res = sld(&clauses, &goal, 10, true, false);
         assert_eq!(sld_res.errors.len(), 1);
         let is_match = matches!(
             sld_res.errors.iter().next(),
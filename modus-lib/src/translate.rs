@@ -82,6 +82,23 @@ fn convert_format_string(
                 ],
             })
         }
+        // Build arguments with a value were substituted by the Modusfile, see
+        // `Modusfile::substitute_build_args`, so this one gets its default.
+        Some(FormatStringFragment::InterpolatedBuildArg(span, _, default)) => {
+            new_literals.push(logic::Literal {
+                positive: true,
+                position: Some(SpannedPosition {
+                    offset: f_string_start,
+                    length: span.offset + span.length - f_string_start,
+                }),
+                predicate: concat_predicate.clone(),
+                args: vec![
+                    IRTerm::Constant("".to_owned()),
+                    IRTerm::Constant(process_raw_string(default).replace("\\$", "$")),
+                    prev_variable.clone(),
+                ],
+            })
+        }
         None => (),
     }
 
@@ -101,6 +118,10 @@ fn convert_format_string(
                 FormatStringFragment::InterpolatedAnonymousVariable(span) => {
                     (span, sld::Auxiliary::aux(true))
                 }
+                FormatStringFragment::InterpolatedBuildArg(span, _, default) => (
+                    span,
+                    IRTerm::Constant(process_raw_string(default).replace("\\$", "$")),
+                ),
             };
             new_literals.push(logic::Literal {
                 positive: true,
@@ -137,6 +158,9 @@ pub(crate) fn reset_operator_pair_id() {
     OPERATOR_PAIR_ID.store(0, std::sync::atomic::Ordering::SeqCst);
 }
 
+/// Prefix of the predicates introduced for the rules that replace negated expressions.
+pub(crate) const NEGATION_PREDICATE_PREFIX: &str = "_negate_";
+
 /// Used to generate unique predicate names in literals that replace negated expressions.
 static NEGATION_LITERAL_ID: AtomicUsize = AtomicUsize::new(0);
 
@@ -157,6 +181,7 @@ pub(crate) fn reset_negation_literal_id() {
 fn translate_term(t: &ModusTerm) -> (IRTerm, Vec<logic::Literal>) {
     match t {
         ModusTerm::Constant(c) => (IRTerm::Constant(process_raw_string(c)), Vec::new()),
+        ModusTerm::Number(n) => (IRTerm::Constant(n.to_owned()), Vec::new()),
         ModusTerm::FormatString {
             position,
             fragments,
@@ -185,7 +210,11 @@ fn handle_negation(modus_clause: &modusfile::ModusClause) -> Vec<modusfile::Modu
         logic::Literal {
             positive: true,
             position: None,
-            predicate: Predicate(format!("_negate_{}", fetch_add_negation_literal_id())),
+            predicate: Predicate(format!(
+                "{}{}",
+                NEGATION_PREDICATE_PREFIX,
+                fetch_add_negation_literal_id()
+            )),
             args: args.into_iter().unique().collect(),
         }
     }
@@ -305,10 +334,13 @@ impl From<&crate::modusfile::ModusClause> for Vec<logic::Clause> {
                 .into_iter()
                 .map(|c| {
                     let mut body = Vec::with_capacity(c.body.len() + 2);
-                    let mut op_args = Vec::with_capacity(op.args.len() + 1);
+                    // Keyword arguments that cannot be placed are reported by the term
+                    // check; leaving them out here means no intrinsic will match.
+                    let args = op.positional_args().unwrap_or_else(|_| op.args.clone());
+                    let mut op_args = Vec::with_capacity(args.len() + 1);
                     let id = OPERATOR_PAIR_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
                     op_args.push(IRTerm::Constant(id.to_string()));
-                    op_args.extend(op.args.iter().map(|t| {
+                    op_args.extend(args.iter().map(|t| {
                         let (t, nl) = translate_term(t);
                         body.extend_from_slice(&nl);
                         t
@@ -399,7 +431,11 @@ impl From<&crate::modusfile::ModusClause> for Vec<logic::Clause> {
 }
 
 pub fn translate_modusfile(mf: &modusfile::Modusfile) -> Vec<logic::Clause> {
-    mf.0.iter().flat_map(Vec::from).collect()
+    mf.substitute_build_args()
+        .0
+        .iter()
+        .flat_map(Vec::from)
+        .collect()
 }
 
 #[cfg(test)]
@@ -425,6 +461,32 @@ mod tests {
         assert_eq!(ir_term, translate_term(&modus_term1).0)
     }
 
+    #[test]
+    fn translate_number_term() {
+        let number = ModusTerm::Number("-3.14".to_owned());
+        assert_eq!(
+            IRTerm::Constant("-3.14".to_owned()),
+            translate_term(&number).0
+        );
+    }
+
+    #[test]
+    fn operator_keyword_args_fill_their_slots() {
+        let clause: ModusClause = r#"a :- foo::set_label(value: "1", key: "version")."#
+            .parse()
+            .unwrap();
+        let clauses: Vec<logic::Clause> = (&clause).into();
+        let begin = &clauses[0].body[0];
+        assert_eq!("_operator_set_label_begin", begin.predicate.0);
+        assert_eq!(
+            vec![
+                IRTerm::Constant("version".to_owned()),
+                IRTerm::Constant("1".to_owned()),
+            ],
+            begin.args[1..]
+        );
+    }
+
     #[test]
     #[serial]
     fn format_string_empty() {
@@ -738,4 +800,134 @@ mod tests {
             assert!(a.eq_ignoring_position(&b), "{} {}", a, b);
         }
     }
+
+    #[test]
+    #[serial]
+    fn anonymous_variables_are_distinct() {
+        setup();
+
+        let modus_clause: ModusClause = "foo(_, _, _x, _x) :- bar(_).".parse().unwrap();
+        let actual: Vec<logic::Clause> = (&modus_clause).into();
+        assert_eq!(actual.len(), 1);
+        let args = &actual[0].head.args;
+        assert!(matches!(args[0], IRTerm::AnonymousVariable(_)));
+        assert!(matches!(args[1], IRTerm::AnonymousVariable(_)));
+        assert_ne!(args[0], args[1]);
+        assert_eq!(args[2], IRTerm::UserVariable("_x".to_string()));
+        assert_eq!(args[2], args[3]);
+        assert!(matches!(
+            actual[0].body[0].args[0],
+            IRTerm::AnonymousVariable(_)
+        ));
+        assert_ne!(actual[0].body[0].args[0], args[0]);
+        assert_ne!(actual[0].body[0].args[0], args[1]);
+    }
+
+    #[test]
+    #[serial]
+    fn csv_facts_are_imported() {
+        setup();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("versions.csv"),
+            "name,version\n\"alpine, edge\",3.17\ndebian,\"11 \"\"bullseye\"\"\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("Modusfile"),
+            "csv_facts \"versions.csv\" as release with header\n\
+             csv_facts \"versions.csv\" as row\n\
+             csv_facts(\"versions.csv\", \"not a directive\").",
+        )
+        .unwrap();
+        let mf = modusfile::Modusfile::from_file_with_includes(dir.path().join("Modusfile"))
+            .unwrap()
+            .0;
+        let clauses = translate_modusfile(&mf);
+        assert_eq!(
+            clauses
+                .iter()
+                .map(|c| c.head.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "release(\"alpine, edge\", \"3.17\")",
+                "release(\"debian\", \"11 \"bullseye\"\")",
+                "row(\"name\", \"version\")",
+                "row(\"alpine, edge\", \"3.17\")",
+                "row(\"debian\", \"11 \"bullseye\"\")",
+                "csv_facts(\"versions.csv\", \"not a directive\")",
+            ]
+        );
+
+        let goal = vec!["release(\"debian\", V)".parse().unwrap()];
+        let tree = sld::sld(&clauses, &goal, 10, false, false).tree;
+        let solutions = sld::solutions(&tree);
+        assert_eq!(solutions.len(), 1);
+        assert_eq!(
+            solutions.into_iter().next().unwrap()[0].args[1],
+            IRTerm::Constant("11 \"bullseye\"".to_owned())
+        );
+
+        std::fs::write(
+            dir.path().join("Modusfile"),
+            "csv_facts \"missing.csv\" as row",
+        )
+        .unwrap();
+        let e = modusfile::Modusfile::from_file_with_includes(dir.path().join("Modusfile"))
+            .unwrap_err();
+        assert!(e.diagnostics[0].message.starts_with("Error reading "));
+    }
+
+    #[test]
+    #[serial]
+    fn json_facts_are_imported() {
+        setup();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("config.json"),
+            r#"{"name": "app", "port": 8080, "debug": false, "tags": ["a", "b"],
+                "build": {"os": "linux", "args": []}}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("Modusfile"),
+            "json_facts \"config.json\" as config",
+        )
+        .unwrap();
+        let mf = modusfile::Modusfile::from_file_with_includes(dir.path().join("Modusfile"))
+            .unwrap()
+            .0;
+        let constant = |s: &str| IRTerm::Constant(s.to_owned());
+        assert_eq!(
+            translate_modusfile(&mf)
+                .into_iter()
+                .map(|c| c.head.args)
+                .collect::<Vec<_>>(),
+            vec![
+                vec![
+                    constant("build"),
+                    IRTerm::List(vec![
+                        IRTerm::List(vec![constant("args"), IRTerm::List(vec![])]),
+                        IRTerm::List(vec![constant("os"), constant("linux")]),
+                    ])
+                ],
+                vec![constant("debug"), constant("false")],
+                vec![constant("name"), constant("app")],
+                vec![constant("port"), constant("8080")],
+                vec![
+                    constant("tags"),
+                    IRTerm::List(vec![constant("a"), constant("b")])
+                ],
+            ]
+        );
+
+        std::fs::write(dir.path().join("config.json"), "[1, 2]").unwrap();
+        let e = modusfile::Modusfile::from_file_with_includes(dir.path().join("Modusfile"))
+            .unwrap_err();
+        assert!(e.diagnostics[0]
+            .message
+            .ends_with("does not contain a JSON object."));
+    }
 }
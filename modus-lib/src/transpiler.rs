@@ -14,13 +14,16 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{io::Write, str::FromStr};
+use std::{collections::HashMap, io::Write, str::FromStr};
 
 use codespan_reporting::diagnostic::Diagnostic;
 
 use crate::{
-    dockerfile::{Dockerfile, Image, Instruction, ResolvedDockerfile, ResolvedParent, Run},
-    imagegen::{self, BuildPlan, MergeNode, NodeId},
+    builtin::path::join_path,
+    dockerfile::{escape_value, Dockerfile, Instruction, ResolvedDockerfile, ResolvedParent},
+    imagegen::{
+        self, BuildPlan, CacheMount, CachePolicy, MergeNode, MergeOperation, NodeId, SecretMount,
+    },
     logic::{self, Clause, IRTerm, Literal, Predicate},
     modusfile::{self, Modusfile},
     sld::{self, ClauseId, ResolutionError, SLDResult, Tree},
@@ -38,192 +41,496 @@ pub fn render_tree<W: Write>(clauses: &Vec<Clause>, sld_result: SLDResult, outpu
 pub fn transpile(
     mf: Modusfile,
     query: modusfile::Expression,
+    max_depth: usize,
 ) -> Result<Dockerfile<ResolvedParent>, Vec<Diagnostic<()>>> {
-    let build_plan = imagegen::plan_from_modusfile(mf, query)?;
-    Ok(plan_to_docker(&build_plan))
+    let build_plan = imagegen::plan_from_modusfile(mf, query, max_depth)?;
+    plan_to_docker(&build_plan)
 }
 
-fn plan_to_docker(plan: &BuildPlan) -> ResolvedDockerfile {
-    let topological_order = plan.topological_order();
+/// `COPY --exclude` flags for the given exclusion patterns, each followed by a space.
+/// Patterns are matched at any depth under the copy source.
+fn exclude_flags(excludes: &[String]) -> String {
+    excludes
+        .iter()
+        .map(|pattern| format!("--exclude={} --exclude=**/{} ", pattern, pattern))
+        .collect()
+}
+
+/// The `COPY --chown` and `--chmod` flags for the given owner and mode, each followed by a space.
+fn copy_flags(chown: &Option<String>, chmod: &Option<String>) -> String {
+    let chown = chown.as_ref().map(|chown| format!("--chown={} ", chown));
+    let chmod = chmod.as_ref().map(|chmod| format!("--chmod={} ", chmod));
+    chown.into_iter().chain(chmod).collect()
+}
+
+/// The HEALTHCHECK instruction of a `set_healthcheck`, with a flag for each timing argument
+/// that is set.
+fn healthcheck_instruction<T>(
+    command: &[String],
+    interval: &Option<String>,
+    timeout: &Option<String>,
+    retries: &Option<u32>,
+) -> Instruction<T> {
+    let interval = interval.as_ref().map(|i| format!("--interval={} ", i));
+    let timeout = timeout.as_ref().map(|t| format!("--timeout={} ", t));
+    let retries = retries.map(|r| format!("--retries={} ", r));
+    let flags: String = interval.into_iter().chain(timeout).chain(retries).collect();
+    Instruction::Healthcheck(format!("{}CMD {:?}", flags, command))
+}
 
-    let mut instructions = topological_order
+/// The `RUN --mount` flags for the given cache and secret mounts, each followed by a space.
+/// Relative targets are resolved against `cwd`, since the command itself is run there with `cd`.
+fn run_mount_flags(cwd: &str, cache_mounts: &[CacheMount], secrets: &[SecretMount]) -> String {
+    let cache_flags = cache_mounts.iter().map(|cache_mount| {
+        let mut flag = format!(
+            "--mount=type=cache,target={}",
+            join_path(cwd, &cache_mount.target)
+        );
+        if let Some(id) = &cache_mount.id {
+            flag.push_str(&format!(",id={}", id));
+        }
+        if let Some(sharing) = &cache_mount.sharing {
+            flag.push_str(&format!(",sharing={}", sharing));
+        }
+        flag + " "
+    });
+    let secret_flags = secrets.iter().map(|secret| {
+        let mut flag = format!("--mount=type=secret,id={}", secret.id);
+        if let Some(target) = &secret.target {
+            flag.push_str(&format!(",target={}", join_path(cwd, target)));
+        }
+        flag + " "
+    });
+    cache_flags.chain(secret_flags).collect()
+}
+
+/// The name of the stage that ends with the image built by `node`.
+fn stage_name(node: NodeId) -> String {
+    format!("n_{}", node)
+}
+
+/// The shell command of a `RUN` that runs `command` in `cwd`, with `additional_envs` set
+/// only for this command.
+fn run_command(command: &str, cwd: &str, additional_envs: &HashMap<String, String>) -> String {
+    let mut envs = additional_envs.iter().collect::<Vec<_>>();
+    envs.sort_unstable();
+    let exports = envs
         .into_iter()
-        .map(|node_id| {
-            use crate::dockerfile::*;
-            let node = &plan.nodes[node_id];
-            let str_id = format!("n_{}", node_id);
-            match node {
-                BuildNode::FromScratch { .. } => {
-                    vec![Instruction::From(From {
-                        parent: ResolvedParent::Image(Image::from_str("scratch").unwrap()),
-                        alias: Some(str_id),
-                    })]
-                }
-                BuildNode::From {
-                    image_ref,
-                    display_name: _,
-                } => vec![Instruction::From(From {
-                    parent: ResolvedParent::Image(Image::from_str(image_ref).unwrap()),
-                    alias: Some(str_id),
-                })],
-                BuildNode::Run {
-                    parent,
-                    command,
-                    cwd,
-                    additional_envs,
-                } => {
-                    let mut instructions = vec![Instruction::From(From {
-                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
-                        alias: Some(str_id),
-                    })];
-                    for (k, v) in additional_envs.iter() {
-                        instructions.push(Instruction::Env(Env(format!("{}={}", k, v))));
-                    }
-                    instructions.push(Instruction::Run(Run(if cwd.is_empty() {
-                        command.to_owned()
-                    } else {
-                        format!("cd {:?} || exit 1; {}", cwd, command)
-                    })));
-                    instructions
-                }
-                BuildNode::CopyFromImage {
-                    parent,
-                    src_image,
-                    src_path,
-                    dst_path,
-                } => vec![
-                    Instruction::From(From {
-                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
-                        alias: Some(str_id),
-                    }),
-                    Instruction::Copy(Copy(format!(
-                        "--from=n_{} {:?} {:?}", // TODO: is this really correct?
-                        src_image, src_path, dst_path
-                    ))),
-                ],
-                BuildNode::CopyFromLocal {
-                    parent,
-                    src_path,
-                    dst_path,
-                } => vec![
-                    Instruction::From(From {
-                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
-                        alias: Some(str_id),
-                    }),
-                    Instruction::Copy(Copy(format!("{:?} {:?}", src_path, dst_path))),
-                ],
-                BuildNode::SetWorkdir {
-                    parent,
-                    new_workdir,
-                } => vec![
-                    Instruction::From(From {
-                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
-                        alias: Some(str_id),
-                    }),
-                    Instruction::Workdir(Workdir(new_workdir.to_string())),
-                ],
-                BuildNode::SetEntrypoint {
-                    parent,
-                    new_entrypoint,
-                } => vec![
-                    Instruction::From(From {
-                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
-                        alias: Some(str_id),
-                    }),
-                    Instruction::Entrypoint(format!("{:?}", new_entrypoint)),
-                ],
-                BuildNode::SetCmd { parent, new_cmd } => vec![
-                    Instruction::From(From {
-                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
-                        alias: Some(str_id),
-                    }),
-                    Instruction::Cmd(format!("{:?}", new_cmd)),
-                ],
-                BuildNode::SetLabel {
-                    parent,
-                    label,
-                    value,
-                } => vec![
-                    Instruction::From(From {
-                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
-                        alias: Some(str_id),
-                    }),
-                    Instruction::Label(label.to_owned(), value.to_owned()),
-                ],
-                BuildNode::Merge(MergeNode { parent, operations }) => {
-                    let mut insts = Vec::new();
-                    insts.push(Instruction::From(From {
-                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
-                        alias: Some(str_id),
-                    }));
-                    for op in operations {
-                        use imagegen::MergeOperation;
-                        match op {
-                            MergeOperation::Run {
-                                command,
-                                cwd,
-                                additional_envs,
-                            } => {
-                                for (k, v) in additional_envs.iter() {
-                                    insts.push(Instruction::Env(Env(format!("{}={}", k, v))));
-                                }
-                                insts.push(Instruction::Run(Run(if cwd.is_empty() {
-                                    command.to_owned()
-                                } else {
-                                    format!("cd {:?} || exit 1; {}", cwd, command)
-                                })));
-                            }
-                            MergeOperation::CopyFromLocal { src_path, dst_path } => {
-                                insts.push(Instruction::Copy(Copy(format!(
-                                    "{:?} {:?}",
-                                    src_path, dst_path
-                                ))));
-                            }
-                            MergeOperation::CopyFromImage {
-                                src_image,
-                                src_path,
-                                dst_path,
-                            } => {
-                                insts.push(Instruction::Copy(Copy(format!(
-                                    "--from=n_{} {:?} {:?}",
-                                    src_image, src_path, dst_path
-                                ))));
-                            }
-                        }
+        .map(|(k, v)| format!("export {}={}; ", k, escape_value(v)))
+        .collect::<String>();
+    if cwd.is_empty() {
+        format!("{}{}", exports, command)
+    } else {
+        format!("{}cd {} || exit 1; {}", exports, escape_value(cwd), command)
+    }
+}
+
+/// An error for a node of the plan that can't be written as a Dockerfile instruction.
+fn no_dockerfile_equivalent(what: &str) -> Diagnostic<()> {
+    Diagnostic::error()
+        .with_message(format!("{} has no Dockerfile equivalent.", what))
+        .with_notes(vec![
+            "Build it with modus build, or use transpile --with-syntax to output what modus build passes to docker build."
+                .to_owned(),
+        ])
+}
+
+/// The `RUN` instruction of a command, with its mounts and the environment it is run with.
+///
+/// A `cache_id` is exported as `MODUS_CACHE_ID`, which changes the instruction and so its
+/// cache key, like `modus build` does. A Dockerfile can't disable the cache of a single
+/// instruction, so `no_cache` is an error. `no_network` is written as `--network=none`.
+fn run_instruction(
+    command: &str,
+    cwd: &str,
+    additional_envs: &HashMap<String, String>,
+    cache_mounts: &[CacheMount],
+    secrets: &[SecretMount],
+    cache: &CachePolicy,
+    no_network: bool,
+) -> Result<Instruction<ResolvedParent>, Diagnostic<()>> {
+    use crate::dockerfile::Run;
+    let mut envs = additional_envs.clone();
+    match cache {
+        CachePolicy::Default => {}
+        CachePolicy::Never => return Err(no_dockerfile_equivalent("no_cache")),
+        CachePolicy::Id(id) => {
+            envs.insert("MODUS_CACHE_ID".to_owned(), id.to_owned());
+        }
+    }
+    let network_flag = if no_network { "--network=none " } else { "" };
+    Ok(Instruction::Run(Run(format!(
+        "{}{}{}",
+        network_flag,
+        run_mount_flags(cwd, cache_mounts, secrets),
+        run_command(command, cwd, &envs)
+    ))))
+}
+
+/// The `COPY` instruction of a copy from the image of `src_image`.
+fn copy_from_image_instruction(
+    src_image: NodeId,
+    src_path: &str,
+    dst_path: &str,
+    chown: &Option<String>,
+    chmod: &Option<String>,
+) -> Instruction<ResolvedParent> {
+    Instruction::Copy(crate::dockerfile::Copy(format!(
+        "--from={} {}{:?} {:?}",
+        stage_name(src_image),
+        copy_flags(chown, chmod),
+        src_path,
+        dst_path
+    )))
+}
+
+/// The `COPY` instruction of a copy from the build context.
+fn copy_from_local_instruction(
+    src_path: &str,
+    dst_path: &str,
+    excludes: &[String],
+    chown: &Option<String>,
+    chmod: &Option<String>,
+) -> Instruction<ResolvedParent> {
+    Instruction::Copy(crate::dockerfile::Copy(format!(
+        "{}{}{:?} {:?}",
+        exclude_flags(excludes),
+        copy_flags(chown, chmod),
+        src_path,
+        dst_path
+    )))
+}
+
+/// The instructions of the operations of a merge, one after the other. This builds the same
+/// files as the merge, but with a layer for each operation.
+fn merge_instructions(
+    operations: &[MergeOperation],
+) -> Result<Vec<Instruction<ResolvedParent>>, Diagnostic<()>> {
+    operations
+        .iter()
+        .map(|operation| match operation {
+            MergeOperation::Run {
+                command,
+                cwd,
+                additional_envs,
+                cache_mounts,
+                secrets,
+                cache,
+                no_network,
+            } => run_instruction(
+                command,
+                cwd,
+                additional_envs,
+                cache_mounts,
+                secrets,
+                cache,
+                *no_network,
+            ),
+            MergeOperation::CopyFromImage {
+                src_image,
+                src_path,
+                dst_path,
+                chown,
+                chmod,
+            } => Ok(copy_from_image_instruction(
+                *src_image, src_path, dst_path, chown, chmod,
+            )),
+            MergeOperation::CopyFromLocal {
+                src_path,
+                dst_path,
+                excludes,
+                chown,
+                chmod,
+            } => Ok(copy_from_local_instruction(
+                src_path, dst_path, excludes, chown, chmod,
+            )),
+        })
+        .collect()
+}
+
+/// The instructions that build the image of `node` on top of its parent's image, or the `FROM`
+/// instruction for a node without a parent.
+fn node_instructions(node: &BuildNode) -> Result<Vec<Instruction<ResolvedParent>>, Diagnostic<()>> {
+    use crate::dockerfile::*;
+    let from_image = |image_ref: &str| -> Result<_, Diagnostic<()>> {
+        let image = Image::from_str(image_ref).map_err(|e| {
+            Diagnostic::error().with_message(format!("Invalid image {:?}: {}", image_ref, e))
+        })?;
+        Ok(vec![Instruction::From(From {
+            parent: ResolvedParent::Image(image),
+            alias: None,
+        })])
+    };
+    Ok(match node {
+        BuildNode::FromScratch { .. } => from_image("scratch")?,
+        BuildNode::From { image_ref, .. } => from_image(image_ref)?,
+        BuildNode::Run {
+            command,
+            cwd,
+            additional_envs,
+            cache_mounts,
+            secrets,
+            cache,
+            no_network,
+            ..
+        } => vec![run_instruction(
+            command,
+            cwd,
+            additional_envs,
+            cache_mounts,
+            secrets,
+            cache,
+            *no_network,
+        )?],
+        BuildNode::CopyFromImage {
+            src_image,
+            src_path,
+            dst_path,
+            chown,
+            chmod,
+            ..
+        } => vec![copy_from_image_instruction(
+            *src_image, src_path, dst_path, chown, chmod,
+        )],
+        BuildNode::CopyFromLocal {
+            src_path,
+            dst_path,
+            excludes,
+            chown,
+            chmod,
+            ..
+        } => vec![copy_from_local_instruction(
+            src_path, dst_path, excludes, chown, chmod,
+        )],
+        BuildNode::AddUrl {
+            url,
+            dst_path,
+            sha256,
+            ..
+        } => vec![Instruction::Add(format!(
+            "--checksum=sha256:{} {:?} {:?}",
+            sha256, url, dst_path
+        ))],
+        BuildNode::SetWorkdir { new_workdir, .. } => {
+            vec![Instruction::Workdir(Workdir(new_workdir.to_string()))]
+        }
+        BuildNode::SetEntrypoint { new_entrypoint, .. } => {
+            vec![Instruction::Entrypoint(format!("{:?}", new_entrypoint))]
+        }
+        BuildNode::SetCmd { new_cmd, .. } => vec![Instruction::Cmd(format!("{:?}", new_cmd))],
+        BuildNode::SetLabel { label, value, .. } => {
+            vec![Instruction::Label(label.to_owned(), value.to_owned())]
+        }
+        BuildNode::SetEnv { key, value, .. } => vec![Instruction::Env(Env(format!(
+            "{}={}",
+            key,
+            escape_value(value)
+        )))],
+        BuildNode::AppendEnvValue { key, value, .. } => vec![Instruction::Env(Env(format!(
+            "{}=${{{}}}{}",
+            key,
+            key,
+            escape_value(value)
+        )))],
+        BuildNode::SetUser { user, .. } => vec![Instruction::User(user.to_owned())],
+        BuildNode::SetExpose { port, .. } => vec![Instruction::Expose(port.to_owned())],
+        BuildNode::SetStopSignal { signal, .. } => {
+            vec![Instruction::Stopsignal(signal.to_owned())]
+        }
+        BuildNode::SetShell { shell, .. } => vec![Instruction::Shell(format!("{:?}", shell))],
+        BuildNode::SetHealthcheck {
+            command,
+            interval,
+            timeout,
+            retries,
+            ..
+        } => vec![healthcheck_instruction(command, interval, timeout, retries)],
+        BuildNode::CreateFile { .. } => return Err(no_dockerfile_equivalent("add_file")),
+        BuildNode::Merge(MergeNode { operations, .. }) => merge_instructions(operations)?,
+        BuildNode::Squash { .. } => return Err(no_dockerfile_equivalent("A squash")),
+    })
+}
+
+/// Writes a build plan as a multi-stage Dockerfile.
+///
+/// Each image built on by exactly one other node continues the stage of its parent, so a
+/// chain of operations becomes a single stage. Images that are copied from, output, or built
+/// on by several nodes end a stage named after their node, `AS n_<id>`, which the stages using
+/// them start `FROM` or `COPY --from`. Stages come in the order of the build.
+///
+/// The operations of a merge are written one after the other. Fails with a diagnostic for
+/// each node that has no Dockerfile equivalent, such as a squash.
+pub fn plan_to_docker(plan: &BuildPlan) -> Result<ResolvedDockerfile, Vec<Diagnostic<()>>> {
+    use crate::dockerfile::From;
+
+    let topological_order = plan.topological_order();
+
+    let mut children = vec![0; plan.nodes.len()];
+    let mut named = vec![false; plan.nodes.len()];
+    for &node in topological_order.iter() {
+        if let Some(parent) = plan.parent(node) {
+            children[parent] += 1;
+        }
+        match &plan.nodes[node] {
+            BuildNode::CopyFromImage { src_image, .. } => named[*src_image] = true,
+            BuildNode::Merge(MergeNode { operations, .. }) => {
+                for operation in operations {
+                    if let MergeOperation::CopyFromImage { src_image, .. } = operation {
+                        named[*src_image] = true;
                     }
-                    insts
                 }
-                BuildNode::SetEnv { parent, key, value } => vec![
-                    Instruction::From(From {
-                        parent: ResolvedParent::Stage(format!("n_{}", parent)),
-                        alias: Some(str_id),
-                    }),
-                    Instruction::Env(Env(format!("{}={}", key, value))),
-                ],
-                BuildNode::AppendEnvValue { parent, key, value } => {
-                    todo!()
-                }
-                BuildNode::SetUser { .. } => todo!(),
             }
-        })
-        .flatten()
-        .collect::<Vec<_>>();
+            _ => {}
+        }
+    }
+    for output in plan.outputs.iter() {
+        named[output.node] = true;
+    }
+    for (named, &children) in named.iter_mut().zip(children.iter()) {
+        *named = *named || children > 1;
+    }
+
+    // The instructions of each stage, and the node its image ends with.
+    let mut stages: Vec<(Vec<Instruction<ResolvedParent>>, NodeId)> = Vec::new();
+    let mut stage_of = vec![0; plan.nodes.len()];
+    let mut errors = Vec::new();
+    for &node in topological_order.iter() {
+        let stage = match plan.parent(node) {
+            Some(parent) if !named[parent] => stage_of[parent],
+            Some(parent) => {
+                stages.push((
+                    vec![Instruction::From(From {
+                        parent: ResolvedParent::Stage(stage_name(parent)),
+                        alias: None,
+                    })],
+                    node,
+                ));
+                stages.len() - 1
+            }
+            None => {
+                stages.push((Vec::new(), node));
+                stages.len() - 1
+            }
+        };
+        stage_of[node] = stage;
+        stages[stage].1 = node;
+        match node_instructions(&plan.nodes[node]) {
+            Ok(mut instructions) => stages[stage].0.append(&mut instructions),
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // A stage can only use the stages before it. Since the last node of a stage comes after
+    // everything the stage depends on, ordering stages by their last node is enough.
+    let position = topological_order
+        .iter()
+        .enumerate()
+        .map(|(i, &node)| (node, i))
+        .collect::<HashMap<_, _>>();
+    stages.sort_by_key(|(_, last)| position[last]);
+
+    let mut instructions = Vec::new();
+    for (mut stage_instructions, last) in stages {
+        if named[last] {
+            if let Some(Instruction::From(from)) = stage_instructions.first_mut() {
+                from.alias = Some(stage_name(last));
+            }
+        }
+        instructions.append(&mut stage_instructions);
+    }
 
     if plan.outputs.len() > 1 {
-        use crate::dockerfile::{From, Run};
+        use crate::dockerfile::Run;
         instructions.push(Instruction::From(From {
             parent: ResolvedParent::Stage("busybox".to_owned()),
             alias: Some("force_multioutput".to_owned()),
         }));
 
         for o in plan.outputs.iter() {
-            let k = format!("n_{}", o.node);
             instructions.push(Instruction::Run(Run(format!(
                 "--mount=type=bind,from={},source=/,target=/mnt true",
-                k,
+                stage_name(o.node),
             ))));
         }
     }
 
-    Dockerfile(instructions)
+    Ok(Dockerfile(instructions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(mf: &str, query: &str) -> BuildPlan {
+        imagegen::plan_from_modusfile(
+            mf.parse().unwrap(),
+            query.parse().unwrap(),
+            sld::DEFAULT_MAX_DEPTH,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn two_stage_build_to_dockerfile() {
+        let mf = r#"
+            builder :- from("golang:1.18"), run("go build -o /app"), run("strip /app").
+            app :- (
+                from("alpine:3.15"),
+                builder::copy("/app", "/usr/bin/app")
+            )::set_entrypoint(["/usr/bin/app", "--serve"])::set_env("MODE", "prod")::set_workdir("/srv").
+        "#;
+        let dockerfile = plan_to_docker(&plan(mf, "app")).unwrap();
+        assert_eq!(
+            dockerfile.to_string(),
+            r#"
+FROM golang:1.18 AS n_4
+RUN go build -o /app
+RUN strip /app
+LABEL "com.modus-continens.literal"="builder"
+
+FROM alpine:3.15 AS n_10
+COPY --from=n_4 "/app" "/usr/bin/app"
+ENTRYPOINT ["/usr/bin/app", "--serve"]
+ENV MODE="prod"
+WORKDIR /srv
+LABEL "com.modus-continens.literal"="app"
+LABEL "com.modus-continens.literal"="app"
+"#
+        );
+    }
+
+    #[test]
+    fn merge_is_written_as_its_operations() {
+        let mf = r#"app2 :- from("alpine"), (run("a"), run("b"))::merge."#;
+        let dockerfile = plan_to_docker(&plan(mf, "app2")).unwrap();
+        let instructions = dockerfile.to_string();
+        let a = instructions.find("RUN a\n").unwrap();
+        let b = instructions.find("RUN b\n").unwrap();
+        assert!(a < b);
+    }
+
+    #[test]
+    fn cache_policies_in_dockerfile() {
+        let mf = r#"app :- from("alpine"), run("make")::cache_id("v2")."#;
+        let dockerfile = plan_to_docker(&plan(mf, "app")).unwrap();
+        assert!(dockerfile
+            .to_string()
+            .contains("RUN export MODUS_CACHE_ID=\"v2\"; make\n"));
+
+        let mf = r#"app :- from("alpine"), (run("a"), run("b")::no_cache)::merge."#;
+        let errors = plan_to_docker(&plan(mf, "app")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("no_cache"));
+    }
+
+    #[test]
+    fn no_network_in_dockerfile() {
+        let mf = r#"app :- from("alpine"), run("make")::no_network, run("apk update")."#;
+        let instructions = plan_to_docker(&plan(mf, "app")).unwrap().to_string();
+        assert!(instructions.contains("RUN --network=none make\n"));
+        assert!(instructions.contains("RUN apk update\n"));
+    }
 }
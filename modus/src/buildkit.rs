@@ -57,15 +57,21 @@
 use std::{
     collections::{HashMap, HashSet},
     convert::TryInto,
+    ffi::OsString,
     fs::OpenOptions,
     path::{Path, PathBuf},
     process::{Command, ExitStatus, Stdio},
-    time::Instant,
+    time::{Instant, SystemTime},
 };
 
 use spawn_wait::{ProcessSet, SignalHandler};
 
-use modus_lib::imagegen::{BuildNode, BuildPlan, Output};
+use modus_lib::builtin::is_valid_signal;
+use modus_lib::imagegen::{
+    BuildNode, BuildPlan, MergeNode, MergeOperation, NodeId, Output, PlatformResolver, SecretMount,
+};
+use modus_lib::logic::{IRTerm, Literal};
+use modus_lib::modusfile::PlatformImage;
 
 use colored::Colorize;
 use rand::{
@@ -87,7 +93,7 @@ pub enum BuildError {
     CwdError(#[source] std::io::Error),
     #[error("Could not enter context directory: {0}")]
     EnterContextDir(#[source] std::io::Error),
-    #[error("Could not open a temporary file under the current directory: {0}. This is required to invoke docker build correctly.")]
+    #[error("Could not open a temporary file under the context or temporary directory: {0}. This is required to invoke docker build correctly.")]
     UnableToCreateTempFile(#[source] std::io::Error),
     #[error("Unable to run docker build: {0}")]
     UnableToRunDockerBuild(#[source] spawn_wait::Error),
@@ -99,6 +105,8 @@ pub enum BuildError {
     FileHasInvalidUtf8(String),
     #[error("Unable to create temporary directory: {0}")]
     UnableToCreateTempDir(#[source] std::io::Error),
+    #[error("Unable to use {0} as the temporary directory: {1}")]
+    InvalidTempDir(String, #[source] std::io::Error),
     #[error("Unable to write {0}: {1}")]
     UnableToWriteTmpFile(String, #[source] std::io::Error),
     #[error("Unable to read {0}: {1}")]
@@ -113,6 +121,24 @@ pub enum BuildError {
     ),
     #[error("Interrupted by user.")]
     Interrupted,
+    #[error("The secret {0:?} used by in_secret is not given with --secret.")]
+    MissingSecret(String),
+    #[error("{0} is built from {1}, which is not based on the required base image {2}.")]
+    BaseNotAllowed(String, String, String),
+    #[error("{0} is not a stop signal the buildkit frontend knows.")]
+    UnsupportedStopSignal(String),
+    #[error("Copy source {0} does not exist in the build context.")]
+    MissingLocalSource(String),
+    #[error("Copy source {0} is excluded from the build context by .dockerignore.")]
+    IgnoredLocalSource(String),
+    #[error("Building for {0} failed: {1} Building for a platform other than the host's needs emulation to be set up for docker.")]
+    PlatformBuildFailed(String, #[source] Box<BuildError>),
+    #[error("The build is not hermetic: {0} can use the network.")]
+    NotHermetic(String),
+    #[error("docker push {0} exited with code {1}.")]
+    DockerPushFailed(String, ExitStatus),
+    #[error("{} would all be pushed to {0}, so only the last one would be kept.", .1.join(", "))]
+    DuplicatePushRef(String, Vec<String>),
 }
 
 use BuildError::*;
@@ -124,66 +150,155 @@ pub struct DockerBuildOptions {
     pub verbose: bool,
     pub quiet: bool,
     pub no_cache: bool,
+    /// If set, ask buildkit to attach an SLSA provenance attestation to the output images.
+    pub provenance: Option<ProvenanceMode>,
+    /// Whether to ask buildkit to attach an SBOM attestation to the output images.
+    pub sbom: bool,
+    /// The platforms to build for, such as `linux/arm64`. The host's platform is used if
+    /// this is empty.
+    pub platforms: Vec<String>,
+    /// Cache hints computed by `--since`: the only nodes whose inputs may have changed. If set,
+    /// `--no-cache` and `no_cache` runs only bypass the cache for these nodes, and every other
+    /// node may be taken from the buildkit cache.
+    pub rebuild_nodes: Option<Vec<NodeId>>,
+    /// The secrets given with `--secret`, by id and the file to read each one from.
+    pub secrets: Vec<(String, PathBuf)>,
     pub additional_args: Vec<String>,
 }
 
+/// How much detail buildkit records in a provenance attestation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProvenanceMode {
+    Min,
+    Max,
+}
+
+impl ProvenanceMode {
+    pub const VALUES: [&'static str; 2] = ["min", "max"];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProvenanceMode::Min => "min",
+            ProvenanceMode::Max => "max",
+        }
+    }
+}
+
+impl std::str::FromStr for ProvenanceMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "min" => Ok(ProvenanceMode::Min),
+            "max" => Ok(ProvenanceMode::Max),
+            _ => Err(format!(
+                "invalid provenance mode {:?}, expected one of {}",
+                s,
+                ProvenanceMode::VALUES.join(", ")
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BuildOptions {
     pub frontend_image: String,
     pub resolve_concurrency: u32,
     pub export_concurrency: u32,
     pub docker_build_options: DockerBuildOptions,
+    /// Where to put temporary Dockerfiles and build contexts. If None, the
+    /// Dockerfile goes in the context directory and everything else in
+    /// std::env::temp_dir().
+    pub temp_dir: Option<PathBuf>,
+    /// If set, every output image must be built on top of this image, i.e.
+    /// its `from` must resolve to the same image as this reference.
+    pub required_base: Option<String>,
+    /// If set, refuse to build plans that fetch anything from the network while building.
+    pub require_hermetic: bool,
+    /// If set, resolve every base image through the frontend, even if a local image
+    /// already has its tag.
+    pub refresh: bool,
+    /// If set, only the nodes depending on build context files modified after this time
+    /// bypass the cache, as given by [BuildPlan::nodes_changed_since].
+    pub since: Option<SystemTime>,
+}
+
+fn output_source_literal(output: &Output) -> String {
+    output
+        .source_literal
+        .as_ref()
+        .expect("Expected source_literal to present in build plan")
+        .to_string()
 }
 
 fn make_buildkit_command(
-    dockerfile: &str,
+    dockerfile: &Path,
     tag: Option<String>,
     target: Option<String>,
     has_dockerignore: bool,
-    iidfile: Option<&str>,
+    iidfile: Option<&Path>,
     options: &DockerBuildOptions,
     cwd: Option<&Path>,
 ) -> Command {
-    let mut args = Vec::new();
-    args.push("build".to_string());
-    args.push(".".to_string());
-    args.push("-f".to_string());
-    args.push(dockerfile.to_owned());
+    let mut args: Vec<OsString> = vec!["build".into(), ".".into(), "-f".into(), dockerfile.into()];
     if let Some(tag) = tag {
-        args.push("-t".to_string());
-        args.push(tag);
+        args.push("-t".into());
+        args.push(tag.into());
     }
     if options.no_cache {
-        args.push("--no-cache".to_string());
+        // With cache hints, the frontend decides which nodes bypass the cache.
+        if options.rebuild_nodes.is_none() {
+            args.push("--no-cache".into());
+        }
         // Sometimes it isn't enough to just use --no-cache, so we also tell our frontend
         // to issue ignore_cache.
-        args.push("--build-arg".to_string());
-        args.push("no_cache=true".to_string());
+        args.push("--build-arg".into());
+        args.push("no_cache=true".into());
     } else {
-        args.push("--build-arg".to_string());
-        args.push("no_cache=false".to_string());
+        args.push("--build-arg".into());
+        args.push("no_cache=false".into());
+    }
+    if let Some(nodes) = &options.rebuild_nodes {
+        args.push("--build-arg".into());
+        args.push(rebuild_nodes_arg(nodes).into());
     }
     if let Some(target) = target {
-        args.push("--target".to_string());
-        args.push(target);
+        args.push("--target".into());
+        args.push(target.into());
     }
     if options.quiet {
-        args.push("--quiet".to_string());
+        args.push("--quiet".into());
     }
-    args.push("--build-arg".to_string());
+    args.push("--build-arg".into());
     if has_dockerignore {
-        args.push("has_dockerignore=true".to_string());
+        args.push("has_dockerignore=true".into());
     } else {
-        args.push("has_dockerignore=false".to_string());
+        args.push("has_dockerignore=false".into());
     }
     if let Some(iidfile) = iidfile {
-        args.push("--iidfile".to_string());
-        args.push(iidfile.to_owned());
+        args.push("--iidfile".into());
+        args.push(iidfile.into());
     }
     if options.verbose {
-        args.push("--progress=plain".to_string());
+        args.push("--progress=plain".into());
+    }
+    if let Some(mode) = options.provenance {
+        args.push(format!("--provenance=mode={}", mode.as_str()).into());
     }
-    args.extend_from_slice(&options.additional_args);
+    if options.sbom {
+        args.push("--sbom=true".into());
+    }
+    if !options.platforms.is_empty() {
+        args.push("--platform".into());
+        args.push(options.platforms.join(",").into());
+    }
+    for (id, src) in &options.secrets {
+        let mut secret = OsString::from(format!("id={},src=", id));
+        secret.push(src);
+        args.push("--secret".into());
+        args.push(secret);
+    }
+    args.extend(options.additional_args.iter().map(Into::into));
     let mut cmd = Command::new("docker");
     cmd.args(args);
     if let Some(cwd) = cwd {
@@ -200,11 +315,191 @@ fn make_buildkit_command(
     cmd
 }
 
+#[test]
+fn test_attestation_args() {
+    fn attestation_args(options: &DockerBuildOptions) -> Vec<String> {
+        make_buildkit_command(
+            Path::new("Dockerfile"),
+            None,
+            None,
+            false,
+            None,
+            options,
+            None,
+        )
+        .get_args()
+        .map(|a| a.to_str().unwrap().to_owned())
+        .filter(|a| a.starts_with("--provenance") || a.starts_with("--sbom"))
+        .collect()
+    }
+
+    assert!(attestation_args(&DockerBuildOptions::default()).is_empty());
+    for (mode, arg) in [
+        (ProvenanceMode::Min, "--provenance=mode=min"),
+        (ProvenanceMode::Max, "--provenance=mode=max"),
+    ] {
+        let options = DockerBuildOptions {
+            provenance: Some(mode),
+            ..Default::default()
+        };
+        assert_eq!(attestation_args(&options), vec![arg]);
+    }
+    let options = DockerBuildOptions {
+        provenance: Some(ProvenanceMode::Max),
+        sbom: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        attestation_args(&options),
+        vec!["--provenance=mode=max", "--sbom=true"]
+    );
+
+    assert_eq!("min".parse(), Ok(ProvenanceMode::Min));
+    assert_eq!("max".parse(), Ok(ProvenanceMode::Max));
+    assert!("full".parse::<ProvenanceMode>().is_err());
+}
+
+/// The frontend build argument carrying the cache hints of `--since`.
+fn rebuild_nodes_arg(nodes: &[NodeId]) -> String {
+    let nodes = nodes.iter().map(NodeId::to_string).collect::<Vec<_>>();
+    format!("rebuild_nodes=[{}]", nodes.join(","))
+}
+
+/// The nodes marked by [BuildPlan::nodes_changed_since], which are the only ones allowed to
+/// bypass the cache.
+fn rebuild_nodes(changed: &[bool]) -> Vec<NodeId> {
+    changed
+        .iter()
+        .enumerate()
+        .filter(|(_, &changed)| changed)
+        .map(|(node, _)| node)
+        .collect()
+}
+
+#[test]
+fn test_rebuild_nodes_args() {
+    fn cache_args(options: &DockerBuildOptions) -> Vec<String> {
+        make_buildkit_command(
+            Path::new("Dockerfile"),
+            None,
+            None,
+            false,
+            None,
+            options,
+            None,
+        )
+        .get_args()
+        .map(|a| a.to_str().unwrap().to_owned())
+        .filter(|a| a.contains("cache") || a.contains("rebuild_nodes"))
+        .collect()
+    }
+
+    assert_eq!(
+        rebuild_nodes(&[false, true, false, true, true]),
+        vec![1, 3, 4]
+    );
+    assert!(rebuild_nodes(&[false, false]).is_empty());
+
+    let no_cache = DockerBuildOptions {
+        no_cache: true,
+        ..Default::default()
+    };
+    assert_eq!(cache_args(&no_cache), vec!["--no-cache", "no_cache=true"]);
+    let since = DockerBuildOptions {
+        rebuild_nodes: Some(rebuild_nodes(&[false, true, false, true, true])),
+        ..no_cache.clone()
+    };
+    assert_eq!(
+        cache_args(&since),
+        vec!["no_cache=true", "rebuild_nodes=[1,3,4]"]
+    );
+    let nothing_changed = DockerBuildOptions {
+        rebuild_nodes: Some(Vec::new()),
+        ..Default::default()
+    };
+    assert_eq!(
+        cache_args(&nothing_changed),
+        vec!["no_cache=false", "rebuild_nodes=[]"]
+    );
+}
+
+#[test]
+fn test_platform_args() {
+    fn platform_args(platforms: &[&str]) -> Vec<String> {
+        let options = DockerBuildOptions {
+            platforms: platforms.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        let args = make_buildkit_command(
+            Path::new("Dockerfile"),
+            None,
+            None,
+            false,
+            None,
+            &options,
+            None,
+        )
+        .get_args()
+        .map(|a| a.to_str().unwrap().to_owned())
+        .collect::<Vec<_>>();
+        args.iter()
+            .position(|a| a == "--platform")
+            .map(|i| args[i..i + 2].to_vec())
+            .unwrap_or_default()
+    }
+
+    assert!(platform_args(&[]).is_empty());
+    assert_eq!(
+        platform_args(&["linux/arm64"]),
+        vec!["--platform", "linux/arm64"]
+    );
+    assert_eq!(
+        platform_args(&["linux/amd64", "linux/arm64"]),
+        vec!["--platform", "linux/amd64,linux/arm64"]
+    );
+}
+
+/// The "Dockerfile" given to docker build: a `#syntax=` line selecting our
+/// frontend, followed by the build plan as JSON for the frontend to read.
+pub fn frontend_dockerfile(frontend_image: &str, build_plan: &BuildPlan) -> String {
+    let mut content = String::new();
+    content.push_str("#syntax=");
+    content.push_str(frontend_image);
+    content.push('\n');
+    content.push_str(&serde_json::to_string(build_plan).expect("Unable to serialize build plan"));
+    content
+}
+
+#[test]
+fn test_frontend_dockerfile() {
+    let mut plan = BuildPlan::new();
+    let node = plan.new_node(
+        BuildNode::From {
+            image_ref: "alpine".to_owned(),
+            display_name: "alpine".to_owned(),
+        },
+        Vec::new(),
+    );
+    plan.outputs.push(Output {
+        node,
+        source_literal: None,
+    });
+
+    let content = frontend_dockerfile(FRONTEND_IMAGE, &plan);
+    let (syntax_line, json) = content.split_once('\n').unwrap();
+    assert_eq!(syntax_line, format!("#syntax={}", FRONTEND_IMAGE));
+    let parsed: BuildPlan = serde_json::from_str(json).unwrap();
+    assert_eq!(parsed.outputs.len(), 1);
+}
+
 /// A holder for a file name that deletes the file when dropped.
-struct AutoDeleteTmpFilename(String);
-/// A holder for a directory in std::env::temp_dir() that deletes the directory when dropped.
+struct AutoDeleteTmpFilename(PathBuf);
+/// A holder for a directory in std::env::temp_dir() (or a chosen temporary
+/// directory) that deletes the directory when dropped.
 struct AutoRmTmpDir(PathBuf);
 pub const TMP_PREFIX: &str = "modus_temp_";
+/// Prefix of the tags given to resolved base images, followed by the image ID.
+const TMP_TAG_PREFIX: &str = "modus_tmp_tag_";
 pub const TMP_PREFIX_IGNORE_PATTERN: &str = "modus_temp_*";
 
 pub fn gen_tmp_filename() -> String {
@@ -225,14 +520,18 @@ pub fn gen_tmp_filename() -> String {
 }
 
 impl AutoDeleteTmpFilename {
-    /// Generate a name, but does not create the file.
-    fn gen(suffix: &str) -> Self {
+    /// Generate a name, but does not create the file. The name is relative to
+    /// the current directory unless `dir` is given.
+    fn gen(suffix: &str, dir: Option<&Path>) -> Self {
         let mut name = gen_tmp_filename();
         name.push_str(suffix);
-        Self(name)
+        match dir {
+            Some(dir) => Self(dir.join(name)),
+            None => Self(PathBuf::from(name)),
+        }
     }
 
-    fn name(&self) -> &str {
+    fn name(&self) -> &Path {
         &self.0
     }
 }
@@ -243,7 +542,7 @@ impl Drop for AutoDeleteTmpFilename {
             if e.kind() != std::io::ErrorKind::NotFound {
                 eprintln!(
                     "Warning: unable to remove temporary file {}: {}",
-                    self.name(),
+                    self.name().display(),
                     e
                 );
             }
@@ -252,8 +551,8 @@ impl Drop for AutoDeleteTmpFilename {
 }
 
 impl AutoRmTmpDir {
-    fn new_empty() -> std::io::Result<Self> {
-        let mut name = std::env::temp_dir();
+    fn new_empty(dir: Option<&Path>) -> std::io::Result<Self> {
+        let mut name = dir.map_or_else(std::env::temp_dir, Path::to_path_buf);
         name.push(&gen_tmp_filename());
         std::fs::create_dir(&name)?;
         Ok(Self(name))
@@ -277,8 +576,11 @@ impl Drop for AutoRmTmpDir {
     }
 }
 
-fn write_tmp_dockerfile(content: &str) -> Result<AutoDeleteTmpFilename, std::io::Error> {
-    let tmp_file = AutoDeleteTmpFilename::gen(".Dockerfile");
+fn write_tmp_dockerfile(
+    content: &str,
+    dir: Option<&Path>,
+) -> Result<AutoDeleteTmpFilename, std::io::Error> {
+    let tmp_file = AutoDeleteTmpFilename::gen(".Dockerfile", dir);
     let mut f = OpenOptions::new()
         .create_new(true)
         .write(true)
@@ -352,18 +654,128 @@ fn test_image_ref_is_hash() {
     ));
 }
 
+#[test]
+fn test_custom_temp_dir() {
+    let base = AutoRmTmpDir::new_empty(None).unwrap();
+    let dockerfile = write_tmp_dockerfile("FROM scratch", Some(base.path())).unwrap();
+    let dockerfile_path = dockerfile.name();
+    assert_eq!(dockerfile_path.parent(), Some(base.path()));
+    assert_eq!(
+        std::fs::read_to_string(dockerfile_path).unwrap(),
+        "FROM scratch"
+    );
+
+    let iidfile = AutoDeleteTmpFilename::gen(".iid", Some(base.path()));
+    assert_eq!(iidfile.name().parent(), Some(base.path()));
+
+    let ctx = AutoRmTmpDir::new_empty(Some(base.path())).unwrap();
+    assert_eq!(ctx.path().parent(), Some(base.path()));
+    assert!(ctx.path().is_dir());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ImageToResolve {
+    Ref(String),
+    Scratch,
+}
+
+/// The ID of the local image with the given reference, if docker has one for `platform`,
+/// or for any platform if None.
+fn local_image_id(image_ref: &str, platform: Option<&str>) -> Option<String> {
+    let output = Command::new("docker")
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            "{{.Id}} {{.Os}}/{{.Architecture}}{{with .Variant}}/{{.}}{{end}}",
+            image_ref,
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    let (id, image_platform) = output.trim().split_once(' ')?;
+    if !platform.is_none_or(|platform| platform_matches(image_platform, platform)) {
+        return None;
+    }
+    Some(id.to_owned()).filter(|id| !id.is_empty())
+}
+
+/// Whether an image for `image_platform`, such as `linux/arm/v7`, can be used for
+/// `platform`. A platform without a variant accepts any variant.
+fn platform_matches(image_platform: &str, platform: &str) -> bool {
+    if platform.matches('/').count() >= 2 {
+        return image_platform == platform;
+    }
+    let image_os_arch = image_platform.splitn(3, '/').take(2).collect::<Vec<_>>();
+    image_os_arch.join("/") == platform
+}
+
+#[test]
+fn test_platform_matches() {
+    assert!(platform_matches("linux/amd64", "linux/amd64"));
+    assert!(platform_matches("linux/arm64/v8", "linux/arm64"));
+    assert!(platform_matches("linux/arm/v7", "linux/arm/v7"));
+    assert!(!platform_matches("linux/arm/v6", "linux/arm/v7"));
+    assert!(!platform_matches("linux/arm", "linux/arm/v7"));
+    assert!(!platform_matches("linux/amd64", "linux/arm64"));
+}
+
+/// Splits the images to resolve into those that `local_image_id` finds locally, paired
+/// with their image ID, and those that still have to be resolved with the frontend.
+fn partition_local_images<F: Fn(&str) -> Option<String>>(
+    queue: Vec<ImageToResolve>,
+    local_image_id: F,
+) -> (Vec<(ImageToResolve, String)>, Vec<ImageToResolve>) {
+    let mut local = Vec::new();
+    let mut remaining = Vec::new();
+    for to_resolve in queue {
+        let id = match &to_resolve {
+            ImageToResolve::Ref(image_ref) => local_image_id(image_ref),
+            ImageToResolve::Scratch => None,
+        };
+        match id {
+            Some(id) => local.push((to_resolve, id)),
+            None => remaining.push(to_resolve),
+        }
+    }
+    (local, remaining)
+}
+
+/// Tags a resolved image ID with a temporary tag, which is removed when `image_cleanup`
+/// is dropped, and returns the tag.
+fn tag_resolved_image(
+    resolved: &str,
+    image_cleanup: &mut DockerImageRmOnDrop,
+) -> Result<String, BuildError> {
+    let tmp_tag = format!("{}{}", TMP_TAG_PREFIX, resolved);
+    // tmp_tag is going to be something like modus_tmp_tag_sha256:1234....
+    // This is very much intentional.
+    let st = Command::new("docker")
+        .args(&["tag", resolved, &tmp_tag])
+        .status()?;
+    if !st.success() {
+        return Err(BuildError::DockerTagFailed(
+            resolved.to_owned(),
+            tmp_tag,
+            st,
+        ));
+    }
+    image_cleanup.add(tmp_tag.clone());
+    Ok(tmp_tag)
+}
+
 fn resolve_froms(
     build_plan: &mut BuildPlan,
     build_options: &BuildOptions,
+    temp_dir: Option<&Path>,
     sh: &mut SignalHandler,
     image_cleanup: &mut DockerImageRmOnDrop,
 ) -> Result<(), BuildError> {
-    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-    enum ImageToResolve {
-        Ref(String),
-        Scratch,
-    }
-
     let queue = build_plan
         .nodes
         .iter()
@@ -380,13 +792,35 @@ fn resolve_froms(
         .collect::<HashSet<_>>()
         .into_iter()
         .collect::<Vec<_>>();
+
+    let mut orig_to_resolved_tag = HashMap::with_capacity(queue.len());
+    let queue = if build_options.refresh {
+        queue
+    } else {
+        let platform = match &build_options.docker_build_options.platforms[..] {
+            [platform] => Some(&platform[..]),
+            _ => None,
+        };
+        let (local, remaining) =
+            partition_local_images(queue, |image_ref| local_image_id(image_ref, platform));
+        for (to_resolve, id) in local {
+            if let ImageToResolve::Ref(image_ref) = &to_resolve {
+                eprintln!(
+                    "{}",
+                    format!("Using local image for from({:?})", image_ref).blue()
+                );
+            }
+            orig_to_resolved_tag.insert(to_resolve, tag_resolved_image(&id, image_cleanup)?);
+        }
+        remaining
+    };
     if queue.is_empty() {
+        replace_resolved_froms(build_plan, &orig_to_resolved_tag);
         return Ok(());
     }
 
-    let _ctx = AutoRmTmpDir::new_empty().map_err(BuildError::UnableToCreateTempDir)?;
+    let _ctx = AutoRmTmpDir::new_empty(temp_dir).map_err(BuildError::UnableToCreateTempDir)?;
     let ctx = _ctx.path();
-    std::env::set_current_dir(ctx).map_err(EnterContextDir)?; // CWD Restored outside
 
     let mut procs =
         ProcessSet::with_concurrency_limit(build_options.resolve_concurrency.try_into().unwrap());
@@ -430,15 +864,7 @@ fn resolve_froms(
                     source_literal: None,
                 });
 
-                let mut content = String::new();
-                content.push_str("#syntax=");
-                content.push_str(&build_options.frontend_image);
-                content.push('\n');
-                content.push_str(
-                    &serde_json::to_string(&tmp_plan)
-                        .expect("Unable to serialize temporary build plan"),
-                );
-                content
+                frontend_dockerfile(&build_options.frontend_image, &tmp_plan)
             }
             ImageToResolve::Scratch => "FROM scratch".to_owned(),
         };
@@ -448,14 +874,17 @@ fn resolve_froms(
         std::fs::write(&dockerfile, content.as_bytes())
             .map_err(|e| BuildError::UnableToWriteTmpFile(dockerfile.display().to_string(), e))?;
         let cmd = make_buildkit_command(
-            dockerfile.to_str().expect("path to be utf-8"),
+            &dockerfile,
             None,
             None,
             false,
-            Some(iidfile.to_str().expect("path to be utf-8")),
+            Some(&iidfile),
             &DockerBuildOptions {
                 quiet: true,
                 verbose: false,
+                // Resolved images are only used to read their config, no need to attest them.
+                provenance: None,
+                sbom: false,
                 ..build_options.docker_build_options.clone()
             },
             Some(&ctx),
@@ -472,7 +901,6 @@ fn resolve_froms(
         "{}",
         format!("Resolving {} base images...", queue.len()).blue()
     );
-    let mut orig_to_resolved_tag = HashMap::with_capacity(queue.len());
     loop {
         use spawn_wait::WaitAnyResult::*;
         match procs.wait_any(sh) {
@@ -493,16 +921,7 @@ fn resolve_froms(
                 nb_done += 1;
                 let resolved = std::fs::read_to_string(&t.iidfile)
                     .map_err(|e| UnableToReadTmpFile(t.iidfile.display().to_string(), e))?;
-                let tmp_tag = format!("modus_tmp_tag_{}", &resolved);
-                // tmp_tag is going to be something like modus_tmp_tag_sha256:1234....
-                // This is very much intentional.
-                let st = Command::new("docker")
-                    .args(&["tag", &resolved, &tmp_tag])
-                    .status()?;
-                if !st.success() {
-                    return Err(BuildError::DockerTagFailed(resolved, tmp_tag, st));
-                }
-                image_cleanup.add(tmp_tag.clone());
+                let tmp_tag = tag_resolved_image(&resolved, image_cleanup)?;
 
                 debug_assert!(!orig_to_resolved_tag.contains_key(&t.to_resolve));
                 orig_to_resolved_tag.insert(t.to_resolve.clone(), tmp_tag);
@@ -528,6 +947,15 @@ fn resolve_froms(
     }
     debug_assert_eq!(nb_done, queue.len());
 
+    replace_resolved_froms(build_plan, &orig_to_resolved_tag);
+    Ok(())
+}
+
+/// Makes every `from` in the plan refer to the tag its image was resolved to.
+fn replace_resolved_froms(
+    build_plan: &mut BuildPlan,
+    orig_to_resolved_tag: &HashMap<ImageToResolve, String>,
+) {
     for node in build_plan.nodes.iter_mut() {
         match node {
             BuildNode::From { image_ref, .. } => {
@@ -552,8 +980,6 @@ fn resolve_froms(
             _ => {}
         }
     }
-
-    Ok(())
 }
 
 #[derive(Debug, Default)]
@@ -577,6 +1003,760 @@ impl Drop for DockerImageRmOnDrop {
     }
 }
 
+/// Makes the configured temporary directory, if any, absolute. This needs to
+/// happen before we start changing directories.
+fn canonical_temp_dir(build_options: &BuildOptions) -> Result<Option<PathBuf>, BuildError> {
+    build_options
+        .temp_dir
+        .as_ref()
+        .map(|d| {
+            d.canonicalize()
+                .map_err(|e| InvalidTempDir(d.display().to_string(), e))
+        })
+        .transpose()
+}
+
+/// The registry digest (`name@sha256:...`) of a local image, preferring the one of
+/// `image_ref`'s repository if the image was pulled under several names. Images that were
+/// never pulled from or pushed to a registry have none.
+fn repo_digest(image_id: &str, image_ref: &str) -> Option<String> {
+    let output = Command::new("docker")
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            "{{json .RepoDigests}}",
+            image_id,
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let digests: Vec<String> = serde_json::from_slice(&output.stdout).ok()?;
+    let repository = image_repository(image_ref);
+    digests
+        .iter()
+        .find(|d| image_repository(d) == repository)
+        .or_else(|| digests.first())
+        .cloned()
+}
+
+/// Resolves all base images in the plan without building anything else.
+/// Returns pairs of the image reference as written by the user and the
+/// registry digest it resolved to, in the order they first appear in the plan.
+/// Images without a registry digest, such as ones only built locally, are
+/// reported by their image ID instead.
+pub fn resolve_only(
+    build_plan: &BuildPlan,
+    build_options: &BuildOptions,
+) -> Result<Vec<(String, String)>, BuildError> {
+    let mut sh = SignalHandler::default();
+    let temp_dir = canonical_temp_dir(build_options)?;
+    let mut image_cleanup = DockerImageRmOnDrop::default();
+    let mut resolved_plan = build_plan.clone();
+    resolve_froms(
+        &mut resolved_plan,
+        build_options,
+        temp_dir.as_deref(),
+        &mut sh,
+        &mut image_cleanup,
+    )?;
+
+    let mut seen = HashSet::new();
+    let mut res = Vec::new();
+    for (orig, resolved) in build_plan.nodes.iter().zip(resolved_plan.nodes.iter()) {
+        if let (
+            BuildNode::From { display_name, .. },
+            BuildNode::From {
+                image_ref: resolved_ref,
+                ..
+            },
+        ) = (orig, resolved)
+        {
+            if seen.insert(display_name) {
+                let image_id = resolved_ref
+                    .strip_prefix(TMP_TAG_PREFIX)
+                    .unwrap_or(resolved_ref);
+                let digest =
+                    repo_digest(image_id, display_name).unwrap_or_else(|| image_id.to_owned());
+                res.push((display_name.to_owned(), digest));
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Resolves images for a platform by reading their manifest list from the registry with
+/// `docker buildx imagetools inspect`, which uses the same registry access as our builds.
+pub struct RegistryPlatformResolver;
+
+impl PlatformResolver for RegistryPlatformResolver {
+    fn resolve_platform(&self, image: &PlatformImage) -> Result<String, String> {
+        let output = Command::new("docker")
+            .args(["buildx", "imagetools", "inspect", "--raw", &image.image_ref])
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| format!("unable to run docker buildx imagetools: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "docker buildx imagetools inspect exited with {}",
+                output.status
+            ));
+        }
+        let digest = select_platform_digest(&output.stdout, &image.os, &image.arch)?;
+        Ok(format!("{}@{}", image_repository(&image.image_ref), digest))
+    }
+}
+
+/// Finds the digest of the image for the given platform in a manifest list or OCI image
+/// index. `arch` may include a variant, as in `arm/v7`.
+fn select_platform_digest(manifest_list: &[u8], os: &str, arch: &str) -> Result<String, String> {
+    let list: serde_json::Value = serde_json::from_slice(manifest_list)
+        .map_err(|e| format!("invalid manifest list: {}", e))?;
+    let manifests = list["manifests"]
+        .as_array()
+        .ok_or_else(|| "not a multi-platform image".to_owned())?;
+    let (arch, variant) = match arch.split_once('/') {
+        Some((arch, variant)) => (arch, Some(variant)),
+        None => (arch, None),
+    };
+    manifests
+        .iter()
+        .find(|m| {
+            let platform = &m["platform"];
+            platform["os"] == os
+                && platform["architecture"] == arch
+                && variant.is_none_or(|v| platform["variant"] == v)
+        })
+        .and_then(|m| m["digest"].as_str())
+        .map(ToOwned::to_owned)
+        .ok_or_else(|| "no image for this platform".to_owned())
+}
+
+/// Strips the tag and digest, if any, from an image reference.
+fn image_repository(image_ref: &str) -> &str {
+    let image_ref = image_ref.split('@').next().unwrap();
+    match image_ref.rfind(':') {
+        Some(i) if !image_ref[i..].contains('/') => &image_ref[..i],
+        _ => image_ref,
+    }
+}
+
+#[test]
+fn test_select_platform_digest() {
+    let list = br#"{
+        "schemaVersion": 2,
+        "manifests": [
+            {"digest": "sha256:amd64", "platform": {"architecture": "amd64", "os": "linux"}},
+            {"digest": "sha256:armv6", "platform": {"architecture": "arm", "os": "linux", "variant": "v6"}},
+            {"digest": "sha256:armv7", "platform": {"architecture": "arm", "os": "linux", "variant": "v7"}}
+        ]
+    }"#;
+    assert_eq!(
+        select_platform_digest(list, "linux", "amd64"),
+        Ok("sha256:amd64".to_owned())
+    );
+    assert_eq!(
+        select_platform_digest(list, "linux", "arm/v7"),
+        Ok("sha256:armv7".to_owned())
+    );
+    assert!(select_platform_digest(list, "windows", "amd64").is_err());
+    assert!(
+        select_platform_digest(br#"{"schemaVersion": 2, "layers": []}"#, "linux", "amd64").is_err()
+    );
+
+    assert_eq!(image_repository("alpine:3.15"), "alpine");
+    assert_eq!(
+        image_repository("localhost:5000/app@sha256:abc"),
+        "localhost:5000/app"
+    );
+    assert_eq!(image_repository("localhost:5000/app"), "localhost:5000/app");
+}
+
+#[test]
+fn test_partition_local_images() {
+    let queue = vec![
+        ImageToResolve::Ref("alpine:3.15".to_owned()),
+        ImageToResolve::Scratch,
+        ImageToResolve::Ref("ubuntu:22.04".to_owned()),
+    ];
+    let (local, remaining) = partition_local_images(queue, |image_ref| {
+        (image_ref == "alpine:3.15").then(|| "sha256:0123abcd".to_owned())
+    });
+    assert_eq!(
+        local,
+        vec![(
+            ImageToResolve::Ref("alpine:3.15".to_owned()),
+            "sha256:0123abcd".to_owned()
+        )]
+    );
+    assert_eq!(
+        remaining,
+        vec![
+            ImageToResolve::Scratch,
+            ImageToResolve::Ref("ubuntu:22.04".to_owned())
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+#[serial_test::serial]
+fn test_resolve_only() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A fake docker that records its arguments and pretends every build succeeded.
+    let base = AutoRmTmpDir::new_empty(None).unwrap();
+    let log = base.path().join("docker.log");
+    let fake_docker = base.path().join("docker");
+    std::fs::write(
+        &fake_docker,
+        format!(
+            "#!/bin/sh\n\
+             echo \"$@\" >> '{}'\n\
+             case \"$*\" in *RepoDigests*) echo '[\"alpine@sha256:feed\"]';; esac\n\
+             while [ $# -gt 0 ]; do\n\
+             if [ \"$1\" = --iidfile ]; then printf 'sha256:0123abcd' > \"$2\"; fi\n\
+             shift\n\
+             done\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake_docker, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let old_path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![base.path().to_owned()];
+    paths.extend(std::env::split_paths(&old_path));
+    std::env::set_var("PATH", std::env::join_paths(paths).unwrap());
+
+    let mut plan = BuildPlan::new();
+    for _ in 0..2 {
+        let node = plan.new_node(
+            BuildNode::From {
+                image_ref: "alpine".to_owned(),
+                display_name: "alpine".to_owned(),
+            },
+            Vec::new(),
+        );
+        plan.outputs.push(Output {
+            node,
+            source_literal: None,
+        });
+    }
+    let options = BuildOptions {
+        frontend_image: FRONTEND_IMAGE.to_owned(),
+        resolve_concurrency: 1,
+        export_concurrency: 1,
+        docker_build_options: DockerBuildOptions::default(),
+        temp_dir: None,
+        required_base: None,
+        require_hermetic: false,
+        refresh: false,
+        since: None,
+    };
+    let res = resolve_only(&plan, &options);
+    std::env::set_var("PATH", old_path);
+
+    assert_eq!(
+        res.unwrap(),
+        vec![("alpine".to_owned(), "alpine@sha256:feed".to_owned())]
+    );
+    let log = std::fs::read_to_string(log).unwrap();
+    let builds = log
+        .lines()
+        .filter(|l| l.starts_with("build"))
+        .collect::<Vec<_>>();
+    // One build to resolve alpine, and no build of the plan itself.
+    assert_eq!(builds.len(), 1);
+    assert!(!builds[0].contains(".Dockerfile"));
+}
+
+/// Checks that the sources of all copies from the build context exist, so that a
+/// typo is reported before anything is built. Sources with wildcards aren't checked.
+/// Only the .dockerignore patterns without wildcards are taken into account.
+fn check_local_sources(build_plan: &BuildPlan, context: &Path) -> Result<(), BuildError> {
+    let ignore_patterns = match std::fs::read_to_string(context.join(".dockerignore")) {
+        Ok(content) => content
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let normalize = |p: &str| {
+        p.trim_start_matches('/')
+            .trim_start_matches("./")
+            .trim_end_matches('/')
+            .to_owned()
+    };
+    let is_ignored = |src_path: &str| {
+        let src_path = normalize(src_path);
+        let mut ignored = false;
+        // Like docker, the last matching pattern wins.
+        for pattern in ignore_patterns.iter() {
+            let (exception, pattern) = match pattern.strip_prefix('!') {
+                Some(p) => (true, normalize(p)),
+                None => (false, normalize(pattern)),
+            };
+            if pattern.contains(&['*', '?', '['][..]) {
+                continue;
+            }
+            if pattern.is_empty()
+                || src_path == pattern
+                || src_path.starts_with(&format!("{}/", pattern))
+            {
+                ignored = !exception;
+            }
+        }
+        ignored
+    };
+
+    let local_sources = build_plan.nodes.iter().flat_map(|node| match node {
+        BuildNode::CopyFromLocal { src_path, .. } => vec![src_path],
+        BuildNode::Merge(MergeNode { operations, .. }) => operations
+            .iter()
+            .filter_map(|op| match op {
+                MergeOperation::CopyFromLocal { src_path, .. } => Some(src_path),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    });
+    for src_path in local_sources {
+        if src_path.contains(&['*', '?', '['][..]) {
+            continue;
+        }
+        if std::fs::symlink_metadata(context.join(src_path.trim_start_matches('/'))).is_err() {
+            return Err(MissingLocalSource(src_path.to_owned()));
+        }
+        if is_ignored(src_path) {
+            return Err(IgnoredLocalSource(src_path.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_local_sources() {
+    let context = AutoRmTmpDir::new_empty(None).unwrap();
+    std::fs::create_dir_all(context.path().join("src/generated")).unwrap();
+    std::fs::write(context.path().join("src/main.c"), "").unwrap();
+
+    let plan_copying = |src_path: &str| {
+        let mut plan = BuildPlan::new();
+        let from = plan.new_node(
+            BuildNode::From {
+                image_ref: "alpine".to_owned(),
+                display_name: "alpine".to_owned(),
+            },
+            Vec::new(),
+        );
+        let copy = plan.new_node(
+            BuildNode::CopyFromLocal {
+                parent: from,
+                src_path: src_path.to_owned(),
+                dst_path: "/app".to_owned(),
+                excludes: Vec::new(),
+                chown: None,
+                chmod: None,
+            },
+            vec![from],
+        );
+        plan.outputs.push(Output {
+            node: copy,
+            source_literal: None,
+        });
+        plan
+    };
+    let check = |src_path: &str| check_local_sources(&plan_copying(src_path), context.path());
+
+    assert!(check("src/main.c").is_ok());
+    assert!(check("./src").is_ok());
+    assert!(check("src/*.h").is_ok());
+    assert!(matches!(check("src/mian.c"), Err(MissingLocalSource(p)) if p == "src/mian.c"));
+
+    std::fs::write(
+        context.path().join(".dockerignore"),
+        "# build outputs\nsrc/generated\n*.o\n",
+    )
+    .unwrap();
+    assert!(check("src/main.c").is_ok());
+    assert!(matches!(check("src/generated"), Err(IgnoredLocalSource(_))));
+
+    std::fs::write(context.path().join(".dockerignore"), "src\n!src/main.c\n").unwrap();
+    assert!(check("src/main.c").is_ok());
+    assert!(matches!(check("src/generated"), Err(IgnoredLocalSource(_))));
+}
+
+/// The layers of a local image, from the bottom up. An image built on top of another one
+/// starts with all the layers of that image.
+fn image_layers(image_ref: &str) -> Option<Vec<String>> {
+    let output = Command::new("docker")
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            "{{json .RootFS.Layers}}",
+            image_ref,
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    serde_json::from_slice(&output.stdout).ok()
+}
+
+/// Checks that every output of a resolved plan is built on `required_ref`, the
+/// resolved form of `required_display`, or on an image based on it. An image is based on
+/// another one if its layers, given by `layers`, start with all the layers of the other.
+fn check_required_base(
+    build_plan: &BuildPlan,
+    required_display: &str,
+    required_ref: &str,
+    layers: &dyn Fn(&str) -> Option<Vec<String>>,
+) -> Result<(), BuildError> {
+    let required_layers = layers(required_ref).filter(|l| !l.is_empty());
+    for output in build_plan.outputs.iter() {
+        let base = match &build_plan.nodes[build_plan.base_node(output.node)] {
+            BuildNode::From { image_ref, .. } if image_ref == required_ref => continue,
+            BuildNode::From {
+                image_ref,
+                display_name,
+            } => {
+                let is_based_on_required = required_layers
+                    .as_ref()
+                    .zip(layers(image_ref))
+                    .is_some_and(|(required, base)| base.starts_with(required));
+                if is_based_on_required {
+                    continue;
+                }
+                display_name.to_owned()
+            }
+            _ => "scratch".to_owned(),
+        };
+        return Err(BaseNotAllowed(
+            output_source_literal(output),
+            base,
+            required_display.to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_required_base() {
+    use modus_lib::logic::{IRTerm, Literal, Predicate};
+
+    let mut plan = BuildPlan::new();
+    let bases = [
+        ("alpine", "sha256:aaaa"),
+        ("ubuntu", "sha256:bbbb"),
+        ("approved/alpine-tools", "sha256:cccc"),
+    ];
+    for (name, resolved) in bases {
+        let from = plan.new_node(
+            BuildNode::From {
+                image_ref: format!("{}{}", TMP_TAG_PREFIX, resolved),
+                display_name: name.to_owned(),
+            },
+            Vec::new(),
+        );
+        let run = plan.new_node(
+            BuildNode::Run {
+                parent: from,
+                command: "true".to_owned(),
+                cwd: "/".to_owned(),
+                additional_envs: HashMap::new(),
+                cache_mounts: Vec::new(),
+                secrets: Vec::new(),
+                cache: Default::default(),
+                no_network: false,
+            },
+            vec![from],
+        );
+        plan.outputs.push(Output {
+            node: run,
+            source_literal: Some(Literal {
+                positive: true,
+                position: None,
+                predicate: Predicate("app".to_owned()),
+                args: vec![IRTerm::Constant(name.to_owned())],
+            }),
+        });
+    }
+    // alpine-tools adds a layer on top of alpine.
+    let layers = |image_ref: &str| {
+        let layers: &[&str] = match image_ref.strip_prefix(TMP_TAG_PREFIX)? {
+            "sha256:aaaa" => &["sha256:1"],
+            "sha256:bbbb" => &["sha256:2"],
+            "sha256:cccc" => &["sha256:1", "sha256:3"],
+            _ => return None,
+        };
+        Some(layers.iter().map(|l| l.to_string()).collect())
+    };
+
+    let required = format!("{}sha256:aaaa", TMP_TAG_PREFIX);
+    match check_required_base(&plan, "approved/alpine", &required, &layers) {
+        Err(BaseNotAllowed(output, base, required)) => {
+            assert_eq!(output, "app(\"ubuntu\")");
+            assert_eq!(base, "ubuntu");
+            assert_eq!(required, "approved/alpine");
+        }
+        res => panic!("expected the ubuntu output to be rejected, got {:?}", res),
+    }
+
+    plan.outputs.remove(1);
+    assert!(check_required_base(&plan, "approved/alpine", &required, &layers).is_ok());
+
+    // alpine itself is not based on alpine-tools.
+    let required = format!("{}sha256:cccc", TMP_TAG_PREFIX);
+    assert!(check_required_base(&plan, "approved/alpine-tools", &required, &layers).is_err());
+}
+
+/// Rejects plans that download files with `add_url_checked` while building, or that run
+/// commands outside of `no_network`. Base images are resolved before the build and don't
+/// count.
+fn check_hermetic(build_plan: &BuildPlan) -> Result<(), BuildError> {
+    let online_run = |command: &str| NotHermetic(format!("run({:?}) outside no_network", command));
+    for node in build_plan.nodes.iter() {
+        match node {
+            BuildNode::AddUrl { url, .. } => {
+                return Err(NotHermetic(format!("add_url_checked({:?})", url)));
+            }
+            BuildNode::Run {
+                command,
+                no_network: false,
+                ..
+            } => return Err(online_run(command)),
+            BuildNode::Merge(MergeNode { operations, .. }) => {
+                for operation in operations {
+                    if let MergeOperation::Run {
+                        command,
+                        no_network: false,
+                        ..
+                    } = operation
+                    {
+                        return Err(online_run(command));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_hermetic() {
+    let mut plan = BuildPlan::new();
+    let from = plan.new_node(
+        BuildNode::From {
+            image_ref: "alpine".to_owned(),
+            display_name: "alpine".to_owned(),
+        },
+        Vec::new(),
+    );
+    let run = plan.new_node(
+        BuildNode::Run {
+            parent: from,
+            command: "make".to_owned(),
+            cwd: "".to_owned(),
+            additional_envs: HashMap::new(),
+            cache_mounts: Vec::new(),
+            secrets: Vec::new(),
+            cache: Default::default(),
+            no_network: true,
+        },
+        vec![from],
+    );
+    assert!(check_hermetic(&plan).is_ok());
+
+    let mut online = plan.clone();
+    if let BuildNode::Run { no_network, .. } = &mut online.nodes[run] {
+        *no_network = false;
+    }
+    assert!(matches!(
+        check_hermetic(&online),
+        Err(NotHermetic(node)) if node == "run(\"make\") outside no_network"
+    ));
+
+    plan.new_node(
+        BuildNode::AddUrl {
+            parent: run,
+            url: "https://example.com/tool.tar.gz".to_owned(),
+            dst_path: "/tmp/tool.tar.gz".to_owned(),
+            sha256: "0".repeat(64),
+        },
+        vec![run],
+    );
+    assert!(matches!(
+        check_hermetic(&plan),
+        Err(NotHermetic(node)) if node == "add_url_checked(\"https://example.com/tool.tar.gz\")"
+    ));
+}
+
+/// Rejects plans using features that the frontend has no way to build.
+pub fn check_frontend_support(build_plan: &BuildPlan) -> Result<(), BuildError> {
+    for node in build_plan.nodes.iter() {
+        if let BuildNode::SetStopSignal { signal, .. } = node {
+            if !is_valid_signal(signal) {
+                return Err(UnsupportedStopSignal(signal.to_owned()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The secrets mounted by every run in the plan, including those in merges.
+fn run_secrets(build_plan: &BuildPlan) -> Vec<&SecretMount> {
+    let mut res = Vec::new();
+    for node in build_plan.nodes.iter() {
+        match node {
+            BuildNode::Run { secrets, .. } => res.extend(secrets),
+            BuildNode::Merge(MergeNode { operations, .. }) => {
+                for op in operations {
+                    if let MergeOperation::Run { secrets, .. } = op {
+                        res.extend(secrets);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+/// The first secret used in the plan that isn't among the given `secrets`, if any.
+fn missing_secret<'a>(build_plan: &'a BuildPlan, secrets: &[(String, PathBuf)]) -> Option<&'a str> {
+    run_secrets(build_plan)
+        .into_iter()
+        .map(|secret| secret.id.as_str())
+        .find(|id| !secrets.iter().any(|(given, _)| given == id))
+}
+
+#[test]
+fn test_secrets() {
+    let mut plan = BuildPlan::new();
+    let from = plan.new_node(
+        BuildNode::From {
+            image_ref: "alpine".to_owned(),
+            display_name: "alpine".to_owned(),
+        },
+        Vec::new(),
+    );
+    let secret = |id: &str| SecretMount {
+        id: id.to_owned(),
+        target: None,
+    };
+    let run = plan.new_node(
+        BuildNode::Run {
+            parent: from,
+            command: "true".to_owned(),
+            cwd: "".to_owned(),
+            additional_envs: HashMap::new(),
+            cache_mounts: vec![modus_lib::imagegen::CacheMount {
+                target: "/var/cache/apt".to_owned(),
+                id: Some("apt".to_owned()),
+                sharing: Some("locked".to_owned()),
+            }],
+            secrets: vec![secret("token")],
+            cache: Default::default(),
+            no_network: false,
+        },
+        vec![from],
+    );
+    plan.new_node(
+        BuildNode::Merge(MergeNode {
+            parent: run,
+            operations: vec![MergeOperation::Run {
+                command: "true".to_owned(),
+                cwd: "".to_owned(),
+                additional_envs: HashMap::new(),
+                cache_mounts: Vec::new(),
+                secrets: vec![secret("npmrc"), secret("token")],
+                cache: Default::default(),
+                no_network: false,
+            }],
+        }),
+        vec![run],
+    );
+    assert!(check_frontend_support(&plan).is_ok());
+
+    let token = ("token".to_owned(), PathBuf::from("/run/user/token"));
+    let npmrc = ("npmrc".to_owned(), PathBuf::from("/home/user/.npmrc"));
+    assert_eq!(missing_secret(&plan, &[]), Some("token"));
+    assert_eq!(
+        missing_secret(&plan, std::slice::from_ref(&token)),
+        Some("npmrc")
+    );
+    assert_eq!(missing_secret(&plan, &[token.clone(), npmrc.clone()]), None);
+
+    let options = DockerBuildOptions {
+        secrets: vec![token, npmrc],
+        ..Default::default()
+    };
+    let args = make_buildkit_command(
+        Path::new("Dockerfile"),
+        None,
+        None,
+        false,
+        None,
+        &options,
+        None,
+    )
+    .get_args()
+    .map(|arg| arg.to_str().unwrap().to_owned())
+    .collect::<Vec<_>>();
+    let secret_args = args
+        .windows(2)
+        .filter(|w| w[0] == "--secret")
+        .map(|w| w[1].as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        secret_args,
+        vec![
+            "id=token,src=/run/user/token",
+            "id=npmrc,src=/home/user/.npmrc"
+        ]
+    );
+}
+
+#[test]
+fn test_check_frontend_support_stop_signal() {
+    let plan_with_signal = |signal: &str| {
+        let mut plan = BuildPlan::new();
+        let from = plan.new_node(
+            BuildNode::From {
+                image_ref: "alpine".to_owned(),
+                display_name: "alpine".to_owned(),
+            },
+            Vec::new(),
+        );
+        plan.new_node(
+            BuildNode::SetStopSignal {
+                parent: from,
+                signal: signal.to_owned(),
+            },
+            vec![from],
+        );
+        plan
+    };
+    assert!(check_frontend_support(&plan_with_signal("SIGINT")).is_ok());
+    assert!(check_frontend_support(&plan_with_signal("9")).is_ok());
+    assert!(matches!(
+        check_frontend_support(&plan_with_signal("SIGRTMIN")),
+        Err(UnsupportedStopSignal(signal)) if signal == "SIGRTMIN"
+    ));
+}
+
 /// Returns the image IDs on success, following the order in build_plan.outputs.
 pub fn build<P: AsRef<Path>>(
     mut build_plan: BuildPlan,
@@ -588,24 +1768,70 @@ pub fn build<P: AsRef<Path>>(
     let context = context.as_ref().canonicalize().map_err(CwdError)?;
     let previous_cwd = PathBuf::from(".").canonicalize().map_err(CwdError)?;
     let _restore_cwd = RestoreCwd(previous_cwd);
+    let temp_dir = canonical_temp_dir(build_options)?;
+    let temp_dir = temp_dir.as_deref();
+    let output_literals = build_plan
+        .outputs
+        .iter()
+        .map(output_source_literal)
+        .collect::<Vec<_>>();
+    check_frontend_support(&build_plan)?;
+    if let Some(id) = missing_secret(&build_plan, &build_options.docker_build_options.secrets) {
+        return Err(MissingSecret(id.to_owned()));
+    }
+    if build_options.require_hermetic {
+        check_hermetic(&build_plan)?;
+    }
+    check_local_sources(&build_plan, &context)?;
     let mut image_cleanup = DockerImageRmOnDrop::default();
     let resolving_start = Instant::now();
-    resolve_froms(&mut build_plan, build_options, &mut sh, &mut image_cleanup)?;
+    // The required base is resolved along with the plan's own base images, through a
+    // temporary node that is removed again before building.
+    let required_base_node = build_options.required_base.as_ref().map(|required| {
+        build_plan.new_node(
+            BuildNode::From {
+                image_ref: required.to_owned(),
+                display_name: required.to_owned(),
+            },
+            Vec::new(),
+        )
+    });
+    resolve_froms(
+        &mut build_plan,
+        build_options,
+        temp_dir,
+        &mut sh,
+        &mut image_cleanup,
+    )?;
+    if let Some(node) = required_base_node {
+        debug_assert_eq!(node, build_plan.nodes.len() - 1);
+        build_plan.dependencies.pop();
+        match build_plan.nodes.pop() {
+            Some(BuildNode::From {
+                image_ref,
+                display_name,
+            }) => check_required_base(&build_plan, &display_name, &image_ref, &image_layers)?,
+            _ => unreachable!(),
+        }
+    }
     profiling.resolving_total = resolving_start.elapsed().as_secs_f32();
+    // --since doesn't keep a cache of its own. It only tells the frontend which nodes may
+    // differ from the last build, and everything else comes from the buildkit cache.
+    let mut docker_build_options = build_options.docker_build_options.clone();
+    if let Some(since) = build_options.since {
+        let changed = build_plan.nodes_changed_since(&context, since)?;
+        docker_build_options.rebuild_nodes = Some(rebuild_nodes(&changed));
+    }
     std::env::set_current_dir(&context).map_err(EnterContextDir)?;
     let has_dockerignore = check_dockerignore()?;
-    let mut content = String::new();
-    content.push_str("#syntax=");
-    content.push_str(&build_options.frontend_image);
-    content.push('\n');
-    content.push_str(&serde_json::to_string(&build_plan).expect("Unable to serialize build plan"));
+    let content = frontend_dockerfile(&build_options.frontend_image, &build_plan);
     if sh.termination_pending() {
         return Err(Interrupted);
     }
-    let dockerfile = write_tmp_dockerfile(&content).map_err(UnableToCreateTempFile)?;
+    let dockerfile = write_tmp_dockerfile(&content, temp_dir).map_err(UnableToCreateTempFile)?;
     use spawn_wait::WaitAnyResult::*;
     eprintln!("{}", "Running docker build...".blue());
-    let main_img_iidfile = AutoDeleteTmpFilename::gen(".iid");
+    let main_img_iidfile = AutoDeleteTmpFilename::gen(".iid", temp_dir);
     let mut procs = ProcessSet::new();
     let build_start = Instant::now();
     procs.add_command(
@@ -616,7 +1842,7 @@ pub fn build<P: AsRef<Path>>(
             None,
             has_dockerignore,
             Some(main_img_iidfile.name()),
-            &build_options.docker_build_options,
+            &docker_build_options,
             None,
         ),
     );
@@ -635,7 +1861,7 @@ pub fn build<P: AsRef<Path>>(
         NoProcessesRunning => unreachable!(),
     }
     let main_img_iid = std::fs::read_to_string(main_img_iidfile.name())
-        .map_err(|e| UnableToReadTmpFile(main_img_iidfile.name().to_owned(), e))?;
+        .map_err(|e| UnableToReadTmpFile(main_img_iidfile.name().display().to_string(), e))?;
     match build_plan.outputs.len() {
         0 => unreachable!(), // not possible because if there is no solution to the initial query, there will be an SLD failure.
         1 => Ok(vec![main_img_iid]),
@@ -651,7 +1877,7 @@ pub fn build<P: AsRef<Path>>(
             let exporting_start = Instant::now();
             for i in 0..nb_outputs {
                 let target_str = format!("{}", i);
-                let iidfile = AutoDeleteTmpFilename::gen(".iid");
+                let iidfile = AutoDeleteTmpFilename::gen(".iid", temp_dir);
                 let cmd = make_buildkit_command(
                     dockerfile.name(),
                     None,
@@ -662,7 +1888,7 @@ pub fn build<P: AsRef<Path>>(
                         no_cache: false,
                         verbose: false,
                         quiet: true,
-                        ..build_options.docker_build_options.clone()
+                        ..docker_build_options.clone()
                     },
                     None,
                 );
@@ -673,11 +1899,7 @@ pub fn build<P: AsRef<Path>>(
             loop {
                 match procs.wait_any(&mut sh) {
                     Subprocess(i, r) => {
-                        let literal_str = build_plan.outputs[i]
-                            .source_literal
-                            .as_ref()
-                            .expect("Expected source_literal to present in build plan")
-                            .to_string();
+                        let literal_str = &output_literals[i];
                         if let Err(err) = r {
                             let _ = procs.sigint_all_and_wait(&mut sh);
                             return Err(UnableToRunDockerBuild(err));
@@ -696,8 +1918,9 @@ pub fn build<P: AsRef<Path>>(
                             let _ = procs.sigint_all_and_wait(&mut sh);
                             return Err(DockerBuildFailed(exit_status));
                         }
-                        let iid = std::fs::read_to_string(iidfiles[i].name())
-                            .map_err(|e| UnableToReadTmpFile(iidfiles[i].name().to_owned(), e))?;
+                        let iid = std::fs::read_to_string(iidfiles[i].name()).map_err(|e| {
+                            UnableToReadTmpFile(iidfiles[i].name().display().to_string(), e)
+                        })?;
                         res[i] = Some(iid);
                         nb_done += 1;
                         eprintln!(
@@ -728,6 +1951,212 @@ pub fn build<P: AsRef<Path>>(
     }
 }
 
+/// Builds the plan once for each of the platforms in the build options. Docker can only
+/// load the images of one platform at a time, so each platform gets a build of its own.
+/// Returns the platforms, in the order given, with the image IDs of the outputs.
+pub fn build_platforms<P: AsRef<Path>>(
+    build_plan: BuildPlan,
+    context: P,
+    build_options: &BuildOptions,
+    profiling: &mut Profiling,
+) -> Result<Vec<(String, Vec<String>)>, BuildError> {
+    let mut res = Vec::new();
+    for platform in build_options.docker_build_options.platforms.iter() {
+        eprintln!("{}", format!("=== Building for {} ===", platform).blue());
+        let platform_options = BuildOptions {
+            docker_build_options: DockerBuildOptions {
+                platforms: vec![platform.to_owned()],
+                ..build_options.docker_build_options.clone()
+            },
+            ..build_options.clone()
+        };
+        let mut platform_profiling = Profiling::default();
+        let image_ids = build(
+            build_plan.clone(),
+            context.as_ref(),
+            &platform_options,
+            &mut platform_profiling,
+        )
+        .map_err(|e| match e {
+            Interrupted => Interrupted,
+            e => PlatformBuildFailed(platform.to_owned(), Box::new(e)),
+        })?;
+        profiling.resolving_total += platform_profiling.resolving_total;
+        profiling.building += platform_profiling.building;
+        profiling.exporting_total += platform_profiling.exporting_total;
+        res.push((platform.to_owned(), image_ids));
+    }
+    Ok(res)
+}
+
+/// The reference an output image is pushed to with `--push-prefix`: the predicate of its
+/// source literal as the repository under `prefix`, and its arguments joined with `-` as
+/// the tag, such as `registry.example.com/team/app:1.0-debug` for `app("1.0", "debug")`.
+/// Characters not allowed in a tag become `_`, and an output without arguments is pushed
+/// as `latest`.
+pub fn push_ref_for_literal(prefix: &str, literal: &Literal) -> String {
+    let tag = literal
+        .args
+        .iter()
+        .map(|arg| match arg {
+            IRTerm::List(ts) => ts
+                .iter()
+                .filter_map(IRTerm::as_constant)
+                .collect::<Vec<_>>()
+                .join("-"),
+            t => t.as_constant().unwrap_or_default().to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("-");
+    let mut tag = tag
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => c,
+            _ => '_',
+        })
+        .take(128)
+        .collect::<String>();
+    if tag.is_empty() {
+        tag = "latest".to_owned();
+    } else if tag.starts_with(['.', '-']) {
+        tag.replace_range(..1, "_");
+    }
+    format!(
+        "{}/{}:{}",
+        prefix.trim_end_matches('/'),
+        literal.predicate.0.to_lowercase(),
+        tag
+    )
+}
+
+#[test]
+fn test_push_ref_for_literal() {
+    use modus_lib::logic::Predicate;
+
+    fn lit(predicate: &str, args: &[&str]) -> Literal {
+        Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate(predicate.to_owned()),
+            args: args
+                .iter()
+                .map(|a| IRTerm::Constant(a.to_string()))
+                .collect(),
+        }
+    }
+
+    assert_eq!(
+        push_ref_for_literal("registry.example.com/team/", &lit("app", &["1.0", "debug"])),
+        "registry.example.com/team/app:1.0-debug"
+    );
+    assert_eq!(
+        push_ref_for_literal("team", &lit("app", &[])),
+        "team/app:latest"
+    );
+    assert_eq!(
+        push_ref_for_literal("team", &lit("app", &["-x/y z"])),
+        "team/app:_x_y_z"
+    );
+}
+
+/// Checks that no two output images are pushed to the same reference, which would make the
+/// image pushed last silently replace the others.
+pub fn check_unique_push_refs(plan: &BuildPlan, refs: &[String]) -> Result<(), BuildError> {
+    for (i, push_ref) in refs.iter().enumerate() {
+        if refs[..i].contains(push_ref) {
+            continue;
+        }
+        let sharing = refs
+            .iter()
+            .zip(&plan.outputs)
+            .enumerate()
+            .filter(|(_, (r, _))| *r == push_ref)
+            .map(|(j, (_, o))| {
+                o.source_literal
+                    .as_ref()
+                    .map_or_else(|| format!("output {}", j), |l| l.to_string())
+            })
+            .collect::<Vec<_>>();
+        if sharing.len() > 1 {
+            return Err(DuplicatePushRef(push_ref.clone(), sharing));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_unique_push_refs() {
+    use modus_lib::imagegen::Output;
+
+    let mut plan = BuildPlan::new();
+    for args in [&["1.0", "debug"][..], &["1.0-debug"], &["2.0"]] {
+        plan.outputs.push(Output {
+            node: 0,
+            source_literal: Some(Literal {
+                positive: true,
+                position: None,
+                predicate: modus_lib::logic::Predicate("app".to_owned()),
+                args: args
+                    .iter()
+                    .map(|a| IRTerm::Constant((*a).to_owned()))
+                    .collect(),
+            }),
+        });
+    }
+    let refs = plan
+        .outputs
+        .iter()
+        .map(|o| push_ref_for_literal("team", o.source_literal.as_ref().unwrap()))
+        .collect::<Vec<_>>();
+    match check_unique_push_refs(&plan, &refs) {
+        Err(DuplicatePushRef(push_ref, outputs)) => {
+            assert_eq!(push_ref, "team/app:1.0-debug");
+            assert_eq!(
+                outputs,
+                vec!["app(\"1.0\", \"debug\")", "app(\"1.0-debug\")"]
+            );
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    let distinct = vec!["a:1".to_owned(), "a:2".to_owned(), "b:1".to_owned()];
+    assert!(check_unique_push_refs(&plan, &distinct).is_ok());
+}
+
+/// Tags each of the images with the reference at the same position and pushes it, one after
+/// another. The images stay tagged if a push fails.
+pub fn push_images(image_ids: &[String], refs: &[String]) -> Result<(), BuildError> {
+    debug_assert_eq!(image_ids.len(), refs.len());
+    let mut sh = SignalHandler::default();
+    fn run_docker(args: &[&str], sh: &mut SignalHandler) -> Result<ExitStatus, BuildError> {
+        use spawn_wait::WaitAnyResult::*;
+        let mut procs = ProcessSet::new();
+        let mut cmd = Command::new("docker");
+        cmd.args(args);
+        procs.add_command((), cmd);
+        match procs.wait_any(sh) {
+            Subprocess(_, res) => Ok(res.map_err(UnableToRunDockerBuild)?.1),
+            ReceivedTerminationSignal(_) => {
+                let _ = procs.sigint_all_and_wait(sh);
+                Err(Interrupted)
+            }
+            NoProcessesRunning => unreachable!(),
+        }
+    }
+    for (image_id, push_ref) in image_ids.iter().zip(refs) {
+        let st = run_docker(&["tag", image_id, push_ref], &mut sh)?;
+        if !st.success() {
+            return Err(DockerTagFailed(image_id.clone(), push_ref.clone(), st));
+        }
+        eprintln!("{}", format!("Pushing {}...", push_ref).blue());
+        let st = run_docker(&["push", push_ref], &mut sh)?;
+        if !st.success() {
+            return Err(DockerPushFailed(push_ref.clone(), st));
+        }
+    }
+    Ok(())
+}
+
 pub fn check_dockerignore() -> Result<bool, BuildError> {
     match std::fs::read(".dockerignore") {
         Ok(content) => {
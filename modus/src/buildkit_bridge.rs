@@ -0,0 +1,509 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The frontend's connection to buildkit. This replaces the `Bridge` of buildkit-frontend,
+//! which always builds for the host's platform. The LLB built with buildkit-llb is
+//! serialized as usual, and the ops are then changed before the definition is sent to
+//! buildkit, recomputing the digests that refer to them. This is also how ops get the
+//! settings that buildkit-llb has no way to give, see `OpPatch`.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use buildkit_frontend::oci::ImageSpecification;
+use buildkit_llb::prelude::{source::ImageSource, Terminal};
+use buildkit_proto::{
+    google::rpc::Status,
+    moby::buildkit::v1::frontend::{
+        llb_bridge_client::LlbBridgeClient, result::Result as RefResult, ReadFileRequest,
+        ResolveImageConfigRequest, Result as FrontendResult, ReturnRequest, SolveRequest,
+    },
+    pb,
+};
+use failure::{format_err, Error, ResultExt};
+use modus_lib::imagegen::CacheMount;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use tonic::transport::{Channel, Endpoint};
+use tower::service_fn;
+
+/// A result solved by buildkit, which can be read from or returned as the build result.
+#[derive(Clone, Debug)]
+pub struct OutputRef(String);
+
+#[derive(Clone)]
+pub struct Bridge {
+    client: LlbBridgeClient<Channel>,
+    platform: Option<pb::Platform>,
+}
+
+impl Bridge {
+    /// Connects to buildkit through stdio, as buildkit runs frontends. Images are resolved
+    /// and ops run for `platform`, such as `linux/arm64`, or the host's platform if None.
+    pub async fn connect(platform: Option<&str>) -> Result<Self, Error> {
+        let channel = Endpoint::from_static("http://[::]:50051")
+            .connect_with_connector(service_fn(buildkit_frontend::stdio_connector))
+            .await?;
+        Ok(Bridge {
+            client: LlbBridgeClient::new(channel),
+            platform: platform.map(parse_platform),
+        })
+    }
+
+    pub fn platform(&self) -> Option<&pb::Platform> {
+        self.platform.as_ref()
+    }
+
+    pub async fn resolve_image_config(
+        &self,
+        image: &ImageSource,
+        log: Option<&str>,
+    ) -> Result<(String, ImageSpecification), Error> {
+        let request = ResolveImageConfigRequest {
+            r#ref: image.canonical_name(),
+            platform: self.platform.clone(),
+            resolve_mode: image.resolve_mode().unwrap_or_default().to_string(),
+            log_name: log.unwrap_or_default().into(),
+        };
+        let response = self
+            .client
+            .clone()
+            .resolve_image_config(request)
+            .await
+            .context("Unable to resolve the image config")?
+            .into_inner();
+        Ok((
+            response.digest,
+            serde_json::from_slice(&response.config)
+                .context("Unable to parse image specification")?,
+        ))
+    }
+
+    pub async fn solve(&self, graph: Terminal<'_>) -> Result<OutputRef, Error> {
+        let platform = self.platform.clone();
+        let definition = patch_definition(graph.into_definition(), |op, metadata| {
+            // The terminal op has no op of its own, and so no platform.
+            if op.op.is_some() && platform.is_some() {
+                op.platform = platform.clone();
+            }
+            OpPatch::take_from(metadata)?.apply(op, metadata);
+            Ok(())
+        })?;
+        let request = SolveRequest {
+            definition: Some(definition),
+            allow_result_return: true,
+            ..Default::default()
+        };
+        let response = self
+            .client
+            .clone()
+            .solve(request)
+            .await
+            .context("Unable to solve the graph")?
+            .into_inner()
+            .result
+            .and_then(|result| result.result)
+            .ok_or_else(|| format_err!("Unable to extract solve result"))?;
+        match response {
+            RefResult::Ref(inner) => Ok(OutputRef(inner)),
+            other => Err(format_err!("Unexpected solve response: {:?}", other)),
+        }
+    }
+
+    pub async fn read_file(&self, layer: &OutputRef, path: &str) -> Result<Vec<u8>, Error> {
+        let request = ReadFileRequest {
+            r#ref: layer.0.clone(),
+            file_path: path.to_owned(),
+            range: None,
+        };
+        Ok(self
+            .client
+            .clone()
+            .read_file(request)
+            .await
+            .context("Unable to read the file")?
+            .into_inner()
+            .data)
+    }
+
+    /// Returns `output` as the build result, with `config` as its image config. The config
+    /// is JSON, since it may have fields that `ImageSpecification` doesn't.
+    pub async fn finish_with_success(
+        self,
+        output: OutputRef,
+        config: serde_json::Value,
+    ) -> Result<(), Error> {
+        let mut metadata = HashMap::new();
+        metadata.insert(
+            "containerimage.config".to_owned(),
+            serde_json::to_vec(&config)?,
+        );
+        self.finish(ReturnRequest {
+            result: Some(FrontendResult {
+                result: Some(RefResult::Ref(output.0)),
+                metadata,
+            }),
+            error: None,
+        })
+        .await
+    }
+
+    pub async fn finish_with_error(self, message: String) -> Result<(), Error> {
+        self.finish(ReturnRequest {
+            result: None,
+            error: Some(Status {
+                code: buildkit_frontend::ErrorCode::Unknown as i32,
+                message,
+                details: vec![],
+            }),
+        })
+        .await
+    }
+
+    async fn finish(mut self, request: ReturnRequest) -> Result<(), Error> {
+        self.client.r#return(request).await?;
+        Ok(())
+    }
+}
+
+/// Settings of an op that buildkit-llb has no way to give. They are carried in the custom
+/// name of the op, see `custom_name`, and set on the op when it is solved.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OpPatch {
+    /// The secrets to mount in a command, by id and target.
+    #[serde(default)]
+    pub secrets: Vec<(String, PathBuf)>,
+    /// The caches mounted in a command whose id or sharing is not the default of buildkit-llb,
+    /// which is the target as id, shared. Their targets are absolute.
+    #[serde(default)]
+    pub cache_mounts: Vec<CacheMount>,
+    /// The owner given to the files copied by a file op, as `user`, `user:group` or numeric
+    /// ids. Names are looked up in the destination of the copy.
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// The mode given to the files copied by a file op, in octal, such as `0755`.
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// Whether a command is run without network access.
+    #[serde(default)]
+    pub no_network: bool,
+}
+
+/// Separates the name of an op from its patch in its custom name.
+const PATCH_SEPARATOR: &str = "\n:: modus-patch ";
+
+impl OpPatch {
+    /// The custom name to give to an op named `name`, so that it gets this patch.
+    pub fn custom_name(&self, name: String) -> String {
+        if self == &OpPatch::default() {
+            return name;
+        }
+        let patch = serde_json::to_string(self).expect("patch to be serializable");
+        format!("{}{}{}", name, PATCH_SEPARATOR, patch)
+    }
+
+    /// Takes the patch out of the custom name in `metadata`, leaving the name of the op.
+    fn take_from(metadata: &mut pb::OpMetadata) -> Result<Self, Error> {
+        let name = match metadata.description.get_mut("llb.customname") {
+            Some(name) => name,
+            None => return Ok(OpPatch::default()),
+        };
+        let (op_name, patch) = match name.split_once(PATCH_SEPARATOR) {
+            Some((op_name, patch)) => (op_name.to_owned(), patch),
+            None => return Ok(OpPatch::default()),
+        };
+        let patch = serde_json::from_str(patch).context("Unable to parse an op patch")?;
+        *name = op_name;
+        Ok(patch)
+    }
+
+    fn apply(self, op: &mut pb::Op, metadata: &mut pb::OpMetadata) {
+        if let Some(pb::op::Op::File(file)) = &mut op.op {
+            for action in file.actions.iter_mut() {
+                let input = action.input;
+                if let Some(pb::file_action::Action::Copy(copy)) = &mut action.action {
+                    copy.owner = self.owner.as_deref().map(|owner| chown_opt(owner, input));
+                    if let Some(mode) = &self.mode {
+                        copy.mode = i32::from_str_radix(mode, 8).expect("mode to be octal");
+                    }
+                }
+            }
+        }
+        if let Some(pb::op::Op::Exec(exec)) = &mut op.op {
+            if self.no_network {
+                exec.network = pb::NetMode::None as i32;
+                metadata.caps.insert("exec.meta.network".to_owned(), true);
+            }
+            for (id, target) in self.secrets {
+                exec.mounts.push(pb::Mount {
+                    input: -1,
+                    dest: target.to_string_lossy().into_owned(),
+                    output: -1,
+                    mount_type: pb::MountType::Secret as i32,
+                    secret_opt: Some(pb::SecretOpt {
+                        id,
+                        uid: 0,
+                        gid: 0,
+                        mode: 0o400,
+                        optional: false,
+                    }),
+                    ..Default::default()
+                });
+                metadata.caps.insert("exec.mount.secret".to_owned(), true);
+            }
+            for cache_mount in self.cache_mounts {
+                let mount = exec.mounts.iter_mut().find(|mount| {
+                    mount.mount_type == pb::MountType::Cache as i32
+                        && mount.dest == cache_mount.target
+                });
+                if let Some(mount) = mount {
+                    mount.cache_opt = Some(pb::CacheOpt {
+                        id: cache_mount.id.unwrap_or(cache_mount.target),
+                        sharing: cache_sharing(cache_mount.sharing.as_deref()) as i32,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// The owner of copied files given by `owner`, such as `app:app` or `1000`, where names are
+/// looked up in the `input` of the file op.
+fn chown_opt(owner: &str, input: i64) -> pb::ChownOpt {
+    let user_opt = |user: &str| pb::UserOpt {
+        user: Some(match user.parse() {
+            Ok(id) => pb::user_opt::User::ById(id),
+            Err(_) => pb::user_opt::User::ByName(pb::NamedUserOpt {
+                name: user.to_owned(),
+                input,
+            }),
+        }),
+    };
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None),
+    };
+    pb::ChownOpt {
+        user: Some(user_opt(user)),
+        group: group.map(user_opt),
+    }
+}
+
+/// The buildkit sharing mode of a cache mount with the given `sharing`, which the
+/// `cache_mount` operator checks.
+fn cache_sharing(sharing: Option<&str>) -> pb::CacheSharingOpt {
+    match sharing {
+        Some("private") => pb::CacheSharingOpt::Private,
+        Some("locked") => pb::CacheSharingOpt::Locked,
+        _ => pb::CacheSharingOpt::Shared,
+    }
+}
+
+/// Parses a platform such as `linux/arm64` or `linux/arm/v7`.
+fn parse_platform(platform: &str) -> pb::Platform {
+    let mut parts = platform.splitn(3, '/');
+    pb::Platform {
+        os: parts.next().unwrap_or_default().to_owned(),
+        architecture: parts.next().unwrap_or_default().to_owned(),
+        variant: parts.next().unwrap_or_default().to_owned(),
+        ..Default::default()
+    }
+}
+
+/// Calls `patch` on every op of `definition`, with its metadata. Ops refer to their inputs
+/// by digest, so a changed op gets a new digest, and the ops after it are updated to refer
+/// to that. buildkit-llb puts every op after its inputs.
+fn patch_definition<F: FnMut(&mut pb::Op, &mut pb::OpMetadata) -> Result<(), Error>>(
+    definition: pb::Definition,
+    mut patch: F,
+) -> Result<pb::Definition, Error> {
+    let pb::Definition { def, mut metadata } = definition;
+    let mut new_digests: HashMap<String, String> = HashMap::with_capacity(def.len());
+    let mut res = pb::Definition {
+        def: Vec::with_capacity(def.len()),
+        metadata: HashMap::with_capacity(metadata.len()),
+    };
+    for bytes in def {
+        let digest = op_digest(&bytes);
+        let mut op = pb::Op::decode(&bytes[..]).context("Unable to decode an op")?;
+        for input in op.inputs.iter_mut() {
+            if let Some(new_digest) = new_digests.get(&input.digest) {
+                input.digest = new_digest.clone();
+            }
+        }
+        let old_metadata = metadata.remove(&digest);
+        let had_metadata = old_metadata.is_some();
+        let mut op_metadata = old_metadata.unwrap_or_default();
+        patch(&mut op, &mut op_metadata)?;
+        let mut new_bytes = Vec::with_capacity(op.encoded_len());
+        op.encode(&mut new_bytes)?;
+        let new_digest = op_digest(&new_bytes);
+        res.def.push(new_bytes);
+        if had_metadata || op_metadata != pb::OpMetadata::default() {
+            res.metadata.insert(new_digest.clone(), op_metadata);
+        }
+        new_digests.insert(digest, new_digest);
+    }
+    Ok(res)
+}
+
+fn op_digest(bytes: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(bytes))
+}
+
+#[test]
+fn test_patch_definition() {
+    use buildkit_llb::prelude::*;
+
+    let alpine = Source::image("alpine").ref_counted();
+    let command = Command::run("true")
+        .mount(Mount::ReadOnlyLayer(alpine.output(), "/"))
+        .custom_name("run(\"true\")")
+        .ref_counted();
+    let definition = Terminal::with(command.output(0)).into_definition();
+    let platform = parse_platform("linux/arm/v7");
+    assert_eq!(
+        (
+            &platform.os[..],
+            &platform.architecture[..],
+            &platform.variant[..]
+        ),
+        ("linux", "arm", "v7")
+    );
+
+    let patched = patch_definition(definition.clone(), |op, _| {
+        if op.op.is_some() {
+            op.platform = Some(platform.clone());
+        }
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(patched.def.len(), definition.def.len());
+    let ops = patched
+        .def
+        .iter()
+        .map(|bytes| (op_digest(bytes), pb::Op::decode(&bytes[..]).unwrap()))
+        .collect::<Vec<_>>();
+    for (i, (_, op)) in ops.iter().enumerate() {
+        assert_eq!(op.platform.is_some(), op.op.is_some());
+        // Every input refers to an op before it in the patched definition.
+        for input in op.inputs.iter() {
+            assert!(ops[..i].iter().any(|(digest, _)| digest == &input.digest));
+        }
+    }
+    let command_digest = &ops[ops.len() - 2].0;
+    assert_eq!(
+        patched.metadata[command_digest].description["llb.customname"],
+        "run(\"true\")"
+    );
+    assert_eq!(patched.metadata.len(), definition.metadata.len());
+}
+
+#[test]
+fn test_op_patch() {
+    use buildkit_llb::prelude::*;
+
+    let patch = OpPatch {
+        secrets: vec![("npmrc".to_owned(), PathBuf::from("/root/.npmrc"))],
+        cache_mounts: vec![CacheMount {
+            target: "/root/.npm".to_owned(),
+            id: Some("npm".to_owned()),
+            sharing: Some("locked".to_owned()),
+        }],
+        no_network: true,
+        ..Default::default()
+    };
+    let command = Command::run("npm")
+        .args(["ci"])
+        .mount(Mount::SharedCache("/root/.npm"))
+        .custom_name(patch.custom_name("run(\"npm ci\")".to_owned()))
+        .ref_counted();
+    let definition = Terminal::with(command.output(0)).into_definition();
+    let patched = patch_definition(definition, |op, metadata| {
+        OpPatch::take_from(metadata)?.apply(op, metadata);
+        Ok(())
+    })
+    .unwrap();
+    let digest = op_digest(&patched.def[0]);
+    assert_eq!(
+        patched.metadata[&digest].description["llb.customname"],
+        "run(\"npm ci\")"
+    );
+    let exec = match pb::Op::decode(&patched.def[0][..]).unwrap().op {
+        Some(pb::op::Op::Exec(exec)) => exec,
+        op => panic!("expected an exec op, got {:?}", op),
+    };
+    assert_eq!(exec.network, pb::NetMode::None as i32);
+    let mounts = exec.mounts;
+    let secret = mounts.last().unwrap();
+    assert_eq!(secret.mount_type, pb::MountType::Secret as i32);
+    assert_eq!(secret.dest, "/root/.npmrc");
+    assert_eq!(secret.secret_opt.as_ref().unwrap().id, "npmrc");
+    let cache = mounts.iter().find(|m| m.dest == "/root/.npm").unwrap();
+    assert_eq!(
+        cache.cache_opt,
+        Some(pb::CacheOpt {
+            id: "npm".to_owned(),
+            sharing: pb::CacheSharingOpt::Locked as i32,
+        })
+    );
+
+    assert_eq!(OpPatch::default().custom_name("copy".to_owned()), "copy");
+
+    let owner = chown_opt("app:1000", 0);
+    assert_eq!(
+        owner.user.unwrap().user,
+        Some(pb::user_opt::User::ByName(pb::NamedUserOpt {
+            name: "app".to_owned(),
+            input: 0,
+        }))
+    );
+    assert_eq!(
+        owner.group.unwrap().user,
+        Some(pb::user_opt::User::ById(1000))
+    );
+    assert_eq!(chown_opt("1000", 0).group, None);
+
+    let copy = FileSystem::copy()
+        .from(LayerPath::Scratch("/src"))
+        .to(OutputIdx(0), LayerPath::Scratch("/dst"))
+        .into_operation()
+        .custom_name(
+            OpPatch {
+                owner: Some("1000:1000".to_owned()),
+                mode: Some("0755".to_owned()),
+                ..Default::default()
+            }
+            .custom_name("copy".to_owned()),
+        )
+        .ref_counted();
+    let definition = Terminal::with(copy.output(0)).into_definition();
+    let patched = patch_definition(definition, |op, metadata| {
+        OpPatch::take_from(metadata)?.apply(op, metadata);
+        Ok(())
+    })
+    .unwrap();
+    let action = match pb::Op::decode(&patched.def[0][..]).unwrap().op {
+        Some(pb::op::Op::File(file)) => file.actions[0].action.clone(),
+        op => panic!("expected a file op, got {:?}", op),
+    };
+    match action {
+        Some(pb::file_action::Action::Copy(copy)) => {
+            assert_eq!(copy.mode, 0o755);
+            assert_eq!(copy.owner, Some(chown_opt("1000:1000", 0)));
+        }
+        action => panic!("expected a copy, got {:?}", action),
+    }
+}
@@ -23,25 +23,25 @@ mod reporting;
 
 use modus_lib::*;
 
+mod buildkit_bridge;
 mod buildkit_llb_types;
+use buildkit_bridge::{Bridge, OpPatch, OutputRef};
 use buildkit_llb_types::OwnedOutput;
 
 use std::{
     collections::{BTreeMap, HashMap},
     ffi::{OsStr, OsString},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
 use buildkit_frontend::{
-    oci::{ImageConfig, ImageSpecification},
-    run_frontend, Bridge, Frontend, FrontendOutput,
+    oci::{ExposedPort, ImageConfig, ImageSpecification, Signal},
+    ErrorWithCauses,
 };
 use buildkit_llb::prelude::*;
 
-use async_trait::async_trait;
-
-use imagegen::{BuildNode, BuildPlan};
+use imagegen::{BuildNode, BuildPlan, CacheMount, CachePolicy, NodeId, SecretMount};
 
 use crate::imagegen::{MergeNode, MergeOperation};
 
@@ -50,77 +50,122 @@ extern crate serde;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    run_frontend(TheFrontend).await?;
+    let options: FrontendOptions = buildkit_frontend::options::from_env(std::env::vars())
+        .map_err(|e| format!("Unable to parse options: {}", e))?;
+    let bridge = Bridge::connect(options.platform.as_deref()).await?;
+    match run(&bridge, &options).await {
+        Ok((output, config)) => bridge.finish_with_success(output, config).await?,
+        Err(e) => {
+            let e = ErrorWithCauses::single_line(e).to_string();
+            eprintln!("Frontend failed: {}", e);
+            bridge.finish_with_error(e).await?
+        }
+    }
     Ok(())
 }
 
-struct TheFrontend;
-
 #[derive(Deserialize)]
 struct FrontendOptions {
     filename: String,
     target: Option<String>,
+    /// The platform given to `docker build --platform`, such as `linux/arm64`.
+    platform: Option<String>,
     has_dockerignore: bool,
     no_cache: bool,
+    /// The cache hints of --since: the only nodes that may bypass the cache.
+    rebuild_nodes: Option<Vec<NodeId>>,
     #[serde(flatten)]
     others: HashMap<String, serde_json::Value>,
 }
 
-#[async_trait]
-impl Frontend<FrontendOptions> for TheFrontend {
-    async fn run(
-        self,
-        bridge: Bridge,
-        options: FrontendOptions,
-    ) -> Result<FrontendOutput, failure::Error> {
-        let build_plan = fetch_input(&bridge, &options).await;
-        let mut outputs = handle_build_plan(&bridge, &options, &build_plan).await;
-        let final_output;
-        if outputs.len() == 1 {
-            final_output = outputs.into_iter().next().unwrap();
-        } else if options.target.is_some() && !options.target.as_ref().unwrap().is_empty() {
-            let target_idx: usize = options
-                .target
+impl FrontendOptions {
+    /// Whether `node`, which runs commands with the given cache policy, must not be taken
+    /// from the cache.
+    fn ignores_cache_of(&self, node: NodeId, cache: &CachePolicy) -> bool {
+        (self.no_cache || *cache == CachePolicy::Never)
+            && self
+                .rebuild_nodes
                 .as_ref()
-                .unwrap()
-                .parse()
-                .expect("Expected target to be an usize");
-            final_output = outputs.swap_remove(target_idx);
-        } else {
-            let alpine = Source::image("alpine")
-                .custom_name("Getting an alpine image as a stub for the final image")
-                .ref_counted();
-            let (_, alpine_config) = bridge
-                .resolve_image_config(&alpine, Some("alpine (stub) :: resolve"))
-                .await?;
-            let mut command = Command::run("true").cwd("/").mount(Mount::Layer(
-                OutputIdx(0),
-                SingleOwnedOutput::output(&alpine),
-                "/",
-            ));
-            let mut idx = 1usize;
-            for o in &outputs {
-                command = command.mount(Mount::Layer(
-                    OutputIdx(idx as u32),
-                    o.0.output(),
-                    format!("/_{}", idx),
-                ));
-                idx += 1;
+                .is_none_or(|nodes| nodes.contains(&node))
+    }
+}
+
+/// Builds the plan, returning the output image and its config.
+async fn run(
+    bridge: &Bridge,
+    options: &FrontendOptions,
+) -> Result<(OutputRef, serde_json::Value), failure::Error> {
+    let build_plan = fetch_input(bridge, options).await;
+    // modus build checks this before invoking the frontend, but the plan may come from
+    // somewhere else.
+    buildkit::check_frontend_support(&build_plan).map_err(|e| failure::format_err!("{}", e))?;
+    let mut outputs = handle_build_plan(bridge, options, &build_plan).await;
+    let final_idx = if outputs.len() == 1 {
+        0
+    } else if options.target.is_some() && !options.target.as_ref().unwrap().is_empty() {
+        options
+            .target
+            .as_ref()
+            .unwrap()
+            .parse()
+            .expect("Expected target to be an usize")
+    } else {
+        // Without a target, every output is built so that exporting them one by one
+        // afterwards only takes them from the cache. The first one is returned, and modus
+        // build removes the resulting image.
+        let solves = outputs
+            .iter()
+            .map(|(output, _)| {
+                let bridge = bridge.clone();
+                let output = output.clone();
+                tokio::spawn(async move { bridge.solve(Terminal::with(output.output())).await })
+            })
+            .collect::<Vec<_>>();
+        for solve in solves {
+            solve.await??;
+        }
+        0
+    };
+    let final_output = outputs.swap_remove(final_idx);
+    let solved = bridge
+        .solve(Terminal::with(final_output.0.output()))
+        .await
+        .expect("Unable to solve");
+    let mut config = serde_json::to_value(&*final_output.1)?;
+    // The image config of buildkit-frontend has no healthcheck, so it is added to the JSON.
+    if let Some(healthcheck) = healthcheck_config(&build_plan, build_plan.outputs[final_idx].node) {
+        config["config"]["Healthcheck"] = healthcheck;
+    }
+    Ok((solved, config))
+}
+
+/// The `Healthcheck` of the image config of the image built by `node`, if the plan sets one.
+fn healthcheck_config(build_plan: &BuildPlan, node: NodeId) -> Option<serde_json::Value> {
+    match &build_plan.nodes[build_plan.healthcheck(node)?] {
+        BuildNode::SetHealthcheck {
+            command,
+            interval,
+            timeout,
+            retries,
+            ..
+        } => {
+            let test = std::iter::once("CMD").chain(command.iter().map(String::as_str));
+            let mut healthcheck = serde_json::json!({ "Test": test.collect::<Vec<_>>() });
+            let nanos = |duration: &str| {
+                builtin::duration_nanos(duration).expect("healthcheck duration to be valid")
+            };
+            if let Some(interval) = interval {
+                healthcheck["Interval"] = nanos(interval).into();
+            }
+            if let Some(timeout) = timeout {
+                healthcheck["Timeout"] = nanos(timeout).into();
             }
-            command = command.custom_name("Finishing multiple output images");
-            final_output = (
-                OwnedOutput::from_command(command.ref_counted(), 0),
-                Arc::new(alpine_config),
-            );
+            if let Some(retries) = retries {
+                healthcheck["Retries"] = (*retries).into();
+            }
+            Some(healthcheck)
         }
-        let solved = bridge
-            .solve(Terminal::with(final_output.0.output()))
-            .await
-            .expect("Unable to solve");
-        Ok(FrontendOutput::with_spec_and_ref(
-            (*final_output.1).clone(),
-            solved,
-        ))
+        _ => unreachable!(),
     }
 }
 
@@ -133,7 +178,7 @@ async fn read_local_file(bridge: &Bridge, filename: &str) -> Vec<u8> {
         .await
         .expect("Failed to get local context");
     let input = bridge
-        .read_file(&local_ref, filename, None)
+        .read_file(&local_ref, filename)
         .await
         .expect("Failed to read local file");
     input
@@ -187,9 +232,22 @@ async fn handle_build_plan(
             stop_signal: None,
         }
     }
-    fn scratch_spec() -> ImageSpecification {
+    /// Signal numbers are taken to be the Linux ones. Signals that `check_frontend_support`
+    /// rejects give None.
+    fn parse_stop_signal(signal: &str) -> Option<Signal> {
+        let name = modus_lib::builtin::signal_name(signal)?;
+        serde_json::from_value(serde_json::Value::String(name.to_owned())).ok()
+    }
+    /// The architecture of an image built for `platform`. Architectures the image config of
+    /// buildkit-frontend doesn't know, and the host's, are taken to be amd64.
+    fn scratch_spec(platform: Option<&buildkit_proto::pb::Platform>) -> ImageSpecification {
+        let architecture = platform
+            .and_then(|p| {
+                serde_json::from_value(serde_json::Value::String(p.architecture.clone())).ok()
+            })
+            .unwrap_or(buildkit_frontend::oci::Architecture::Amd64); // TODO: the host's
         ImageSpecification {
-            architecture: buildkit_frontend::oci::Architecture::Amd64, // TODO
+            architecture,
             author: None,
             config: Some(empty_image_config()),
             created: None,
@@ -199,9 +257,40 @@ async fn handle_build_plan(
         }
     }
 
+    /// Copies `src_path` of `source` to `dst_path` of `parent`, giving the copied files the
+    /// owner and mode given by `chown` and `chmod`. User and group names are looked up in
+    /// the parent's `/etc`.
+    fn copy_into(
+        source: OperationOutput<'static>,
+        src_path: &Path,
+        parent: &OwnedOutput,
+        dst_path: &Path,
+        chown: Option<&str>,
+        chmod: Option<&str>,
+        name: String,
+    ) -> OwnedOutput {
+        let patch = OpPatch {
+            owner: chown.map(ToOwned::to_owned),
+            mode: chmod.map(ToOwned::to_owned),
+            ..Default::default()
+        };
+        FileSystem::copy()
+            .from(LayerPath::Other(source, src_path))
+            .to(OutputIdx(0), LayerPath::Other(parent.output(), dst_path))
+            .create_path(true)
+            .recursive(true)
+            .into_operation()
+            .custom_name(patch.custom_name(name))
+            .ref_counted()
+            .into()
+    }
+
+    /// `excludes` are additional patterns relative to the context root, on top of
+    /// .dockerignore.
     async fn get_local_source_for_copy(
         bridge: &Bridge,
         should_read_ignore_file: bool,
+        excludes: &[String],
     ) -> OperationOutput<'static> {
         let mut source = Source::local("context").custom_name("Sending local context for copy");
         if should_read_ignore_file {
@@ -213,22 +302,46 @@ async fn handle_build_plan(
             }
         }
         source = source.add_exclude_pattern(buildkit::TMP_PREFIX_IGNORE_PATTERN);
+        for pattern in excludes {
+            source = source.add_exclude_pattern(pattern);
+        }
         source.ref_counted().output()
     }
 
-    let local_context = get_local_source_for_copy(bridge, options.has_dockerignore).await;
+    /// Turns the exclusion patterns of a local copy, which are relative to its source
+    /// and match at any depth, into patterns relative to the context root.
+    fn context_exclude_patterns(src_path: &str, excludes: &[String]) -> Vec<String> {
+        let base = src_path.trim_end_matches('/');
+        let prefix = if base.is_empty() || base == "." {
+            String::new()
+        } else {
+            format!("{}/", base)
+        };
+        excludes
+            .iter()
+            .flat_map(|pattern| {
+                vec![
+                    format!("{}{}", prefix, pattern),
+                    format!("{}**/{}", prefix, pattern),
+                ]
+            })
+            .collect()
+    }
+
+    let local_context = get_local_source_for_copy(bridge, options.has_dockerignore, &[]).await;
 
     for node_id in build_plan.topological_order().into_iter() {
         let node = &build_plan.nodes[node_id];
         use BuildNode::*;
 
         fn new_cmd(
+            program: &str,
             imgspec: &ImageSpecification,
             this_cwd: &str,
             parent: &OwnedOutput,
-            frontend_options: &FrontendOptions,
+            ignore_cache: bool,
         ) -> Command<'static> {
-            let mut cmd = Command::run("sh"); // TDDO: use image shell config
+            let mut cmd = Command::run(program);
             let user = imgspec
                 .config
                 .as_ref()
@@ -248,7 +361,7 @@ async fn handle_build_plan(
                 }
             }
             cmd = cmd.mount(Mount::Layer(OutputIdx(0), parent.output(), "/"));
-            if frontend_options.no_cache {
+            if ignore_cache {
                 cmd = cmd.ignore_cache(true);
             }
             cmd
@@ -267,6 +380,48 @@ async fn handle_build_plan(
             cmd
         }
 
+        /// Mounts the caches, whose relative targets are resolved against `run_cwd`, unless
+        /// one is already in `mounted`. buildkit-llb always uses the target as the id and
+        /// shares the cache, so any other id or sharing is given in `patch`.
+        fn add_cache_mounts<'a>(
+            mut cmd: Command<'a>,
+            run_cwd: &Path,
+            cache_mounts: &[CacheMount],
+            patch: &mut OpPatch,
+            mounted: &mut Vec<PathBuf>,
+        ) -> Command<'a> {
+            for cache_mount in cache_mounts {
+                let target = run_cwd.join(&cache_mount.target);
+                if mounted.contains(&target) {
+                    continue;
+                }
+                mounted.push(target.clone());
+                if cache_mount.id.is_some() || cache_mount.sharing.is_some() {
+                    patch.cache_mounts.push(CacheMount {
+                        target: target.to_string_lossy().into_owned(),
+                        ..cache_mount.clone()
+                    });
+                }
+                cmd = cmd.mount(Mount::SharedCache(target));
+            }
+            cmd
+        }
+
+        /// The secrets of a run, by id and target, with relative targets resolved against
+        /// `run_cwd`.
+        fn secret_mounts(run_cwd: &Path, secrets: &[SecretMount]) -> Vec<(String, PathBuf)> {
+            secrets
+                .iter()
+                .map(|secret| {
+                    let target = match &secret.target {
+                        Some(target) => run_cwd.join(target),
+                        None => Path::new("/run/secrets").join(&secret.id),
+                    };
+                    (secret.id.clone(), target)
+                })
+                .collect()
+        }
+
         let new_node: (OwnedOutput, Arc<ImageSpecification>) = match node {
             /*
                 resolve_image_config will fail if we try to resolve an empty
@@ -277,7 +432,10 @@ async fn handle_build_plan(
             FromScratch { scratch_ref } => {
                 let img_s =
                     Source::image(scratch_ref.as_ref().unwrap()).custom_name("from(\"scratch\")");
-                (img_s.ref_counted().into(), Arc::new(scratch_spec()))
+                (
+                    img_s.ref_counted().into(),
+                    Arc::new(scratch_spec(bridge.platform())),
+                )
             }
             From {
                 image_ref,
@@ -300,15 +458,40 @@ async fn handle_build_plan(
                 command,
                 cwd,
                 additional_envs,
+                cache_mounts,
+                secrets,
+                cache,
+                no_network,
             } => {
                 let parent = translated_nodes[*parent]
                     .as_ref()
                     .expect("Expected dependencies to already be built");
                 let parent_config = parent.1.clone();
-                let mut cmd = new_cmd(&*parent_config, &cwd[..], &parent.0, &options)
-                    .args(&["-c", &command[..]])
-                    .custom_name(format!("run({:?})", command));
+                let run_cwd = get_cwd_from_image_spec(&parent_config).join(cwd);
+                let mut patch = OpPatch {
+                    secrets: secret_mounts(&run_cwd, secrets),
+                    no_network: *no_network,
+                    ..Default::default()
+                };
+                // TODO: use the shell of the base image config, which buildkit-frontend doesn't read.
+                let default_shell = ["sh".to_owned(), "-c".to_owned()];
+                let shell = build_plan.shell(node_id).unwrap_or(&default_shell[..]);
+                let mut cmd = new_cmd(
+                    &shell[0],
+                    &*parent_config,
+                    &cwd[..],
+                    &parent.0,
+                    options.ignores_cache_of(node_id, cache),
+                )
+                .args(&shell[1..])
+                .args(&[&command[..]]);
                 cmd = add_envs(cmd, additional_envs);
+                if let CachePolicy::Id(id) = cache {
+                    // The environment is part of the cache key of a command.
+                    cmd = cmd.env("MODUS_CACHE_ID", id);
+                }
+                cmd = add_cache_mounts(cmd, &run_cwd, cache_mounts, &mut patch, &mut Vec::new());
+                cmd = cmd.custom_name(patch.custom_name(format!("run({:?})", command)));
                 let o = OwnedOutput::from_command(cmd.ref_counted(), 0);
                 (o, parent_config)
             }
@@ -317,41 +500,141 @@ async fn handle_build_plan(
                 src_image,
                 src_path: raw_src_path,
                 dst_path: raw_dst_path,
+                chown,
+                chmod,
             } => {
                 let parent = translated_nodes[*parent].as_ref().unwrap();
                 let src_image = translated_nodes[*src_image].as_ref().unwrap();
                 let src_cwd = get_cwd_from_image_spec(&src_image.1);
                 let src_path = src_cwd.join(raw_src_path);
                 let dst_path = get_cwd_from_image_spec(&parent.1).join(raw_dst_path);
-                let o = FileSystem::copy()
-                    .from(LayerPath::Other(src_image.0.output(), src_path))
-                    .to(OutputIdx(0), LayerPath::Other(parent.0.output(), dst_path))
-                    .create_path(true)
-                    .recursive(true)
-                    .into_operation()
-                    .custom_name(format!(
-                        "...::copy({:?}, {:?})",
-                        &raw_src_path, &raw_dst_path
-                    ))
-                    .ref_counted();
-                (o.into(), parent.1.clone())
+                let o = copy_into(
+                    src_image.0.output(),
+                    &src_path,
+                    &parent.0,
+                    &dst_path,
+                    chown.as_deref(),
+                    chmod.as_deref(),
+                    format!("...::copy({:?}, {:?})", &raw_src_path, &raw_dst_path),
+                );
+                (o, parent.1.clone())
             }
             CopyFromLocal {
                 parent,
                 src_path,
                 dst_path: raw_dst_path,
+                excludes,
+                chown,
+                chmod,
             } => {
                 let parent = translated_nodes[*parent].as_ref().unwrap();
                 let dst_path = get_cwd_from_image_spec(&parent.1).join(raw_dst_path);
-                let o = FileSystem::copy()
-                    .from(LayerPath::Other(local_context.clone(), src_path))
-                    .to(OutputIdx(0), LayerPath::Other(parent.0.output(), dst_path))
-                    .create_path(true)
-                    .recursive(true)
-                    .into_operation()
-                    .custom_name(format!("copy({:?}, {:?})", &src_path, &raw_dst_path))
+                let source = if excludes.is_empty() {
+                    local_context.clone()
+                } else {
+                    get_local_source_for_copy(
+                        bridge,
+                        options.has_dockerignore,
+                        &context_exclude_patterns(src_path, excludes),
+                    )
+                    .await
+                };
+                let o = copy_into(
+                    source,
+                    Path::new(src_path),
+                    &parent.0,
+                    &dst_path,
+                    chown.as_deref(),
+                    chmod.as_deref(),
+                    format!("copy({:?}, {:?})", &src_path, &raw_dst_path),
+                );
+                (o, parent.1.clone())
+            }
+            AddUrl {
+                parent,
+                url,
+                dst_path: raw_dst_path,
+                sha256,
+            } => {
+                let parent = translated_nodes[*parent].as_ref().unwrap();
+                let name = format!("add_url_checked({:?}, {:?})", url, raw_dst_path);
+                let file_name = url
+                    .split(&['?', '#'][..])
+                    .next()
+                    .and_then(|path| path.rsplit('/').next())
+                    .filter(|file_name| !file_name.is_empty())
+                    .unwrap_or("download");
+                let mut dst_path = get_cwd_from_image_spec(&parent.1).join(raw_dst_path);
+                if raw_dst_path.ends_with('/') {
+                    dst_path.push(file_name);
+                }
+                let download = Source::http(url)
+                    .with_file_name(file_name)
+                    .custom_name(format!("{} :: download", name))
                     .ref_counted();
-                (o.into(), parent.1.clone())
+                // buildkit-llb can't ask buildkit to check the digest of an http source, so
+                // it is checked in a separate step, whose output the copy then depends on.
+                let alpine = Source::image("alpine")
+                    .custom_name(format!("{} :: get checker image", name))
+                    .ref_counted();
+                let download_dir = Path::new("/__modus_download");
+                let verified = Command::run("/bin/sh")
+                    .args([
+                        "-c",
+                        &format!(
+                            "echo '{}  {}' | sha256sum -c -",
+                            sha256,
+                            download_dir.join(file_name).to_str().unwrap()
+                        ),
+                    ])
+                    .mount(Mount::ReadOnlyLayer(
+                        SingleOwnedOutput::output(&alpine),
+                        "/",
+                    ))
+                    .mount(Mount::Layer(
+                        OutputIdx(0),
+                        SingleOwnedOutput::output(&download),
+                        download_dir,
+                    ))
+                    .custom_name(format!("{} :: verify sha256", name))
+                    .ref_counted();
+                let o = copy_into(
+                    OwnedOutput::from_command(verified, 0).output(),
+                    &Path::new("/").join(file_name),
+                    &parent.0,
+                    &dst_path,
+                    None,
+                    None,
+                    name,
+                );
+                (o, parent.1.clone())
+            }
+            CreateFile {
+                parent,
+                path: raw_path,
+                contents,
+                mode,
+            } => {
+                let parent = translated_nodes[*parent].as_ref().unwrap();
+                let name = format!("add_file({:?})", raw_path);
+                let path = get_cwd_from_image_spec(&parent.1).join(raw_path);
+                let created: OwnedOutput =
+                    FileSystem::mkfile(OutputIdx(0), LayerPath::Scratch("/__modus_file"))
+                        .data(contents.as_bytes().to_vec())
+                        .into_operation()
+                        .custom_name(format!("{} :: create", name))
+                        .ref_counted()
+                        .into();
+                let o = copy_into(
+                    created.output(),
+                    Path::new("/__modus_file"),
+                    &parent.0,
+                    &path,
+                    None,
+                    mode.as_deref(),
+                    name,
+                );
+                (o, parent.1.clone())
             }
             SetWorkdir {
                 parent,
@@ -377,7 +660,8 @@ async fn handle_build_plan(
                 let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
                 let mut p_conf = (*p_conf).clone();
                 let img_conf = p_conf.config.get_or_insert_with(empty_image_config);
-                img_conf.entrypoint = Some(new_entrypoint.to_owned());
+                // An empty entrypoint is left out of the config, like `ENTRYPOINT []` does.
+                img_conf.entrypoint = Some(new_entrypoint.to_owned()).filter(|e| !e.is_empty());
                 img_conf.cmd = None;
                 (p_out, Arc::new(p_conf))
             }
@@ -385,7 +669,7 @@ async fn handle_build_plan(
                 let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
                 let mut p_conf = (*p_conf).clone();
                 let img_conf = p_conf.config.get_or_insert_with(empty_image_config);
-                img_conf.cmd = Some(new_cmd.to_owned());
+                img_conf.cmd = Some(new_cmd.to_owned()).filter(|c| !c.is_empty());
                 (p_out, Arc::new(p_conf))
             }
             SetLabel {
@@ -405,13 +689,34 @@ async fn handle_build_plan(
             }
             Merge(MergeNode { parent, operations }) => {
                 let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
-                let mut cmd = new_cmd(&*p_conf, "", &p_out, &options);
+                let mut cmd = new_cmd(
+                    "sh",
+                    &*p_conf,
+                    "",
+                    &p_out,
+                    options.ignores_cache_of(node_id, &CachePolicy::Default),
+                );
                 let mut name = Vec::new();
                 let mut script = Vec::new();
                 let image_cwd = get_cwd_from_image_spec(&*p_conf);
                 debug_assert!(image_cwd.is_absolute());
                 use shell_escape::escape;
                 let mut mount_id = 0usize;
+                // The whole merge is one command, so each cache is only mounted once.
+                let mut merge_cache_targets = Vec::new();
+                // The merge is one command, so it only runs without network if all its runs do.
+                let mut patch = OpPatch {
+                    no_network: operations.iter().all(|op| {
+                        !matches!(
+                            op,
+                            MergeOperation::Run {
+                                no_network: false,
+                                ..
+                            }
+                        )
+                    }),
+                    ..Default::default()
+                };
                 fn mkdir_pf(path: &str, script: &mut Vec<String>) {
                     script.push(format!("(mkdir -p {} || true)", escape(path.into())));
                 }
@@ -419,15 +724,44 @@ async fn handle_build_plan(
                     mkdir_pf(path, script);
                     script.push(format!("echo cd {cd} && cd {cd}", cd = escape(path.into())));
                 }
-                fn cp_content(src: PathBuf, dst: &str, script: &mut Vec<String>) {
+                fn cp_content(
+                    src: PathBuf,
+                    dst: &str,
+                    chown: Option<&str>,
+                    chmod: Option<&str>,
+                    script: &mut Vec<String>,
+                ) {
                     let src_str = src.to_str().unwrap();
                     let _s = src.join(".");
                     let src_plus_dot = _s.to_str().unwrap();
+                    if chown.is_none() && chmod.is_none() {
+                        script.push(format!(
+                            "echo COPY '->' {dst} && (if [ -d {src} ]; then cp -r {src_plus_dot} {dst}; else cp -r {src} {dst}; fi)",
+                            src=escape(src_str.into()),
+                            dst=escape(dst.into()),
+                            src_plus_dot=escape(src_plus_dot.into())
+                        ));
+                        return;
+                    }
+                    // Fix up a staged copy, so that files already at dst keep their owner and mode.
+                    let fixups = |target: &str| {
+                        chown
+                            .map(|chown| {
+                                format!(" && chown -R {} {}", escape(chown.into()), target)
+                            })
+                            .into_iter()
+                            .chain(chmod.map(|chmod| {
+                                format!(" && chmod -R {} {}", escape(chmod.into()), target)
+                            }))
+                            .collect::<String>()
+                    };
                     script.push(format!(
-                        "echo COPY '->' {dst} && (if [ -d {src} ]; then cp -r {src_plus_dot} {dst}; else cp -r {src} {dst}; fi)",
+                        "echo COPY '->' {dst} && stage=$(mktemp -d) && (if [ -d {src} ]; then cp -r {src_plus_dot} \"$stage\"{dir_fixups} && cp -a \"$stage\"/. {dst}; else cp {src} \"$stage\"/f{file_fixups} && cp -a \"$stage\"/f {dst}; fi) && rm -rf \"$stage\"",
                         src=escape(src_str.into()),
                         dst=escape(dst.into()),
-                        src_plus_dot=escape(src_plus_dot.into())
+                        src_plus_dot=escape(src_plus_dot.into()),
+                        dir_fixups=fixups("\"$stage\""),
+                        file_fixups=fixups("\"$stage\"/f")
                     ));
                 }
                 for op in operations {
@@ -436,8 +770,29 @@ async fn handle_build_plan(
                             command,
                             cwd,
                             additional_envs,
+                            cache_mounts,
+                            secrets,
+                            cache,
+                            ..
                         } => {
+                            // The merge is one command, so not caching any of its runs
+                            // means not caching any of it.
+                            if options.ignores_cache_of(node_id, cache) {
+                                cmd = cmd.ignore_cache(true);
+                            }
                             let resolved_cwd = image_cwd.join(cwd);
+                            cmd = add_cache_mounts(
+                                cmd,
+                                &resolved_cwd,
+                                cache_mounts,
+                                &mut patch,
+                                &mut merge_cache_targets,
+                            );
+                            for secret in secret_mounts(&resolved_cwd, secrets) {
+                                if !patch.secrets.contains(&secret) {
+                                    patch.secrets.push(secret);
+                                }
+                            }
                             let resolved_cwd = resolved_cwd.to_str().unwrap(); // TODO: report error if image cwd is not valid utf8.
                             cd(resolved_cwd, &mut script);
                             for (k, v) in iter_hm_sorted(additional_envs) {
@@ -447,6 +802,10 @@ async fn handle_build_plan(
                                     escape(v.into())
                                 ));
                             }
+                            // Like MODUS_CACHE_ID of a run, this changes the merge's cache key.
+                            if let CachePolicy::Id(id) = cache {
+                                script.push(format!("export MODUS_CACHE_ID={}", escape(id.into())));
+                            }
                             script.push(format!(
                                 "echo {cmd} && sh -c {cmd}",
                                 cmd = escape(command.into())
@@ -457,6 +816,8 @@ async fn handle_build_plan(
                             src_image,
                             src_path,
                             dst_path,
+                            chown,
+                            chmod,
                         } => {
                             let (src_opt, src_conf) = translated_nodes[*src_image].clone().unwrap();
                             let src_cwd = get_cwd_from_image_spec(&src_conf);
@@ -479,10 +840,22 @@ async fn handle_build_plan(
                             if let Some(par) = dst_path.parent() {
                                 mkdir_pf(par.to_str().unwrap(), &mut script);
                             }
-                            cp_content(mount_dir, dst_path.to_str().unwrap(), &mut script);
+                            cp_content(
+                                mount_dir,
+                                dst_path.to_str().unwrap(),
+                                chown.as_deref(),
+                                chmod.as_deref(),
+                                &mut script,
+                            );
                             name.push(format!("...::copy({:?}, {:?})", src_path, dst_path));
                         }
-                        MergeOperation::CopyFromLocal { src_path, dst_path } => {
+                        MergeOperation::CopyFromLocal {
+                            src_path,
+                            dst_path,
+                            excludes,
+                            chown,
+                            chmod,
+                        } => {
                             let mut mount_dir = OsString::from("/__buildkit_merge_mount_");
                             mount_dir.push(OsStr::new(&mount_id.to_string()));
                             mount_id += 1;
@@ -492,8 +865,18 @@ async fn handle_build_plan(
                             let dst_path = image_cwd.join(dst_path);
                             debug_assert!(dst_path.is_absolute());
                             let mount_dir = PathBuf::from(mount_dir);
+                            let source = if excludes.is_empty() {
+                                local_context.clone()
+                            } else {
+                                get_local_source_for_copy(
+                                    bridge,
+                                    options.has_dockerignore,
+                                    &context_exclude_patterns(src_path, excludes),
+                                )
+                                .await
+                            };
                             cmd = cmd.mount(Mount::ReadOnlySelector(
-                                local_context.clone(),
+                                source,
                                 mount_dir.clone(),
                                 PathBuf::from(src_path),
                             ));
@@ -501,13 +884,19 @@ async fn handle_build_plan(
                             if let Some(par) = dst_path.parent() {
                                 mkdir_pf(par.to_str().unwrap(), &mut script);
                             }
-                            cp_content(mount_dir, dst_path.to_str().unwrap(), &mut script);
+                            cp_content(
+                                mount_dir,
+                                dst_path.to_str().unwrap(),
+                                chown.as_deref(),
+                                chmod.as_deref(),
+                                &mut script,
+                            );
                             name.push(format!("copy({:?}, {:?})", src_path, dst_path));
                         }
                     }
                 }
                 cmd = cmd.args(&["-c", &script.join(" && ")]);
-                cmd = cmd.custom_name(format!("merge: {}", name.join(" + ")));
+                cmd = cmd.custom_name(patch.custom_name(format!("merge: {}", name.join(" + "))));
 
                 (OwnedOutput::from_command(cmd.ref_counted(), 0), p_conf)
             }
@@ -541,6 +930,53 @@ async fn handle_build_plan(
                 p_conf.config.get_or_insert_with(empty_image_config).user = Some(user.to_owned());
                 (p_out, Arc::new(p_conf))
             }
+            // The image config of buildkit-frontend has no shell, so this only affects the
+            // runs of this build, which look it up in the plan.
+            SetShell { parent, .. } => translated_nodes[*parent].clone().unwrap(),
+            SetStopSignal { parent, signal } => {
+                let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
+                let mut p_conf = (*p_conf).clone();
+                p_conf
+                    .config
+                    .get_or_insert_with(empty_image_config)
+                    .stop_signal = parse_stop_signal(signal);
+                (p_out, Arc::new(p_conf))
+            }
+            // The image config of buildkit-frontend has no healthcheck, so `run` adds it to the
+            // config of the output image.
+            SetHealthcheck { parent, .. } => translated_nodes[*parent].clone().unwrap(),
+            Squash { parent, squashed } => {
+                let parent = translated_nodes[*parent].as_ref().unwrap();
+                let squashed = translated_nodes[*squashed].as_ref().unwrap();
+                // Copying the whole result onto the parent in one go gives us a single
+                // layer on top of the parent's layers.
+                let o = FileSystem::copy()
+                    .from(LayerPath::Other(squashed.0.output(), "/"))
+                    .to(OutputIdx(0), LayerPath::Other(parent.0.output(), "/"))
+                    .recursive(true)
+                    .into_operation()
+                    .custom_name("...::squash")
+                    .ref_counted();
+                (o.into(), squashed.1.clone())
+            }
+            SetExpose { parent, port } => {
+                let (p_out, p_conf) = translated_nodes[*parent].clone().unwrap();
+                let mut p_conf = (*p_conf).clone();
+                let exposed_port = match modus_lib::builtin::parse_port_spec(port) {
+                    Some((number, "udp")) => ExposedPort::Udp(number),
+                    Some((number, _)) => ExposedPort::Tcp(number),
+                    None => panic!("Invalid port specification: {}", port),
+                };
+                let exposed_ports = p_conf
+                    .config
+                    .get_or_insert_with(empty_image_config)
+                    .exposed_ports
+                    .get_or_insert_with(Vec::new);
+                if !exposed_ports.contains(&exposed_port) {
+                    exposed_ports.push(exposed_port);
+                }
+                (p_out, Arc::new(p_conf))
+            }
         };
         translated_nodes[node_id] = Some(new_node);
     }
@@ -0,0 +1,110 @@
+// Modus, a language for building container images
+// Copyright (C) 2022 University College London
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Queries about the git repository that contains the build context.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorktreeState {
+    Clean,
+    /// Contains the `git status --porcelain` lines of the changed files.
+    Dirty(Vec<String>),
+    NotARepository,
+}
+
+/// Checks whether anything under `dir` has uncommitted changes, including
+/// untracked files that are not ignored.
+pub fn worktree_state(dir: &Path) -> std::io::Result<WorktreeState> {
+    let inside_worktree = Command::new("git")
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !inside_worktree.success() {
+        return Ok(WorktreeState::NotARepository);
+    }
+
+    let output = Command::new("git")
+        .args(["status", "--porcelain", "--", "."])
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "git status exited with {}",
+            output.status
+        )));
+    }
+    let changes = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        Ok(WorktreeState::Clean)
+    } else {
+        Ok(WorktreeState::Dirty(changes))
+    }
+}
+
+#[test]
+#[serial_test::serial]
+fn test_worktree_state() {
+    let tmp = std::env::temp_dir().join(crate::buildkit::gen_tmp_filename());
+    let repo = tmp.join("repo");
+    let not_a_repo = tmp.join("plain");
+    std::fs::create_dir_all(&repo).unwrap();
+    std::fs::create_dir_all(&not_a_repo).unwrap();
+    let git = |args: &[&str]| {
+        let status = Command::new("git")
+            .args(["-c", "user.name=modus", "-c", "user.email=modus@localhost"])
+            .args(args)
+            .current_dir(&repo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+
+    git(&["init", "-q"]);
+    std::fs::write(repo.join("Modusfile"), "a :- from(\"alpine\").\n").unwrap();
+    git(&["add", "Modusfile"]);
+    git(&["commit", "-q", "-m", "initial"]);
+    let clean = worktree_state(&repo);
+
+    std::fs::write(repo.join("Modusfile"), "a :- from(\"ubuntu\").\n").unwrap();
+    let modified = worktree_state(&repo);
+    git(&["checkout", "-q", "--", "Modusfile"]);
+    std::fs::write(repo.join("new_file"), "").unwrap();
+    let untracked = worktree_state(&repo);
+
+    let plain = worktree_state(&not_a_repo);
+    std::fs::remove_dir_all(&tmp).unwrap();
+
+    assert_eq!(clean.unwrap(), WorktreeState::Clean);
+    assert_eq!(
+        modified.unwrap(),
+        WorktreeState::Dirty(vec![" M Modusfile".to_owned()])
+    );
+    assert_eq!(
+        untracked.unwrap(),
+        WorktreeState::Dirty(vec!["?? new_file".to_owned()])
+    );
+    assert_eq!(plain.unwrap(), WorktreeState::NotARepository);
+}
@@ -15,9 +15,10 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 mod buildkit;
+mod git;
 mod reporting;
 
-use clap::{arg, crate_version, Arg, Command};
+use clap::{arg, crate_version, Arg, ArgMatches, Command};
 use codespan_reporting::{
     diagnostic::Diagnostic,
     files::SimpleFile,
@@ -32,29 +33,77 @@ use modus_lib::transpiler::render_tree;
 use modus_lib::*;
 use modus_lib::{analysis::ModusSemantics, sld::tree_from_modusfile};
 use ptree::write_tree;
-use std::{ffi::OsStr, fs, path::Path, time::Instant};
+use std::{ffi::OsStr, path::Path, time::Instant};
 use std::{io::Write, path::PathBuf};
 
-use modus_lib::modusfile::Modusfile;
+use modus_lib::modusfile::{Modusfile, ModusfileSources};
 
-use crate::buildkit::{BuildOptions, DockerBuildOptions};
+use crate::buildkit::{BuildOptions, DockerBuildOptions, ProvenanceMode};
 use crate::reporting::Profiling;
 
-fn get_file_or_exit(path: &Path) -> SimpleFile<&str, String> {
-    let file_name: &str = path
-        .file_name()
-        .map(|os_str| os_str.to_str())
-        .unwrap()
-        .unwrap();
-    let file_content: String = match fs::read_to_string(path) {
-        Ok(content) => content,
-        Err(err) => {
-            eprintln!("Error reading {}: {}", path.display(), err);
-            std::process::exit(1);
-        }
-    };
+/// Enables impure builtins, such as git_revision and env, if --allow-impure was given.
+fn configure_impure_builtins(sub: &ArgMatches, context_dir: &OsStr) {
+    builtin::set_impure_builtin_config(builtin::ImpureBuiltinConfig {
+        allow_impure: sub.is_present("ALLOW_IMPURE"),
+        context_dir: PathBuf::from(context_dir),
+    });
+}
+
+/// The directory of a Modusfile, which impure builtins use as the context of subcommands
+/// that don't take a context directory.
+fn modusfile_dir(file: &Path) -> &Path {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// The --allow-impure option, shared by every subcommand that resolves queries.
+fn allow_impure_arg() -> Arg<'static> {
+    Arg::new("ALLOW_IMPURE")
+        .long("allow-impure")
+        .help("Allow builtins that depend on the host environment, such as git_revision and env.")
+        .long_help(
+            "Allow builtins that depend on the host environment, such as git_revision and env.\n\
+             These builtins fail by default, since their results can differ between machines.",
+        )
+}
+
+/// The --max-depth option, shared by every subcommand that resolves queries.
+fn max_depth_arg() -> Arg<'static> {
+    Arg::new("MAX_DEPTH")
+        .long("max-depth")
+        .value_name("N")
+        .validator(|s| s.parse::<usize>())
+        .help("Set the maximum depth of resolution (default: 175)")
+        .long_help(
+            "Set the maximum depth of resolution (default: 175).\n\
+             Deeply recursive rules may need more than the default.",
+        )
+}
+
+/// The depth limit of resolution, from --max-depth.
+fn max_depth(sub: &ArgMatches) -> usize {
+    sub.value_of_t("MAX_DEPTH")
+        .unwrap_or(sld::DEFAULT_MAX_DEPTH)
+}
 
-    SimpleFile::new(file_name, file_content)
+/// Parses a `--secret` value, `id=ID,src=PATH`, into the id and the absolute path of the file
+/// holding the secret. The path is made absolute since docker build runs in the context.
+fn parse_secret(secret: &str) -> Result<(String, PathBuf), String> {
+    let invalid = || format!("invalid --secret {:?} - expected id=ID,src=PATH", secret);
+    let (mut id, mut src) = (None, None);
+    for field in secret.split(',') {
+        match field.split_once('=') {
+            Some(("id", value)) if !value.is_empty() => id = Some(value),
+            Some(("src" | "source", value)) if !value.is_empty() => src = Some(value),
+            _ => return Err(invalid()),
+        }
+    }
+    let (id, src) = id.zip(src).ok_or_else(invalid)?;
+    let src = std::fs::canonicalize(src)
+        .map_err(|e| format!("can not read the secret {:?} from {}: {}", id, src, e))?;
+    Ok((id.to_owned(), src))
 }
 
 fn main() {
@@ -78,6 +127,17 @@ fn main() {
                         .help("Specify the build target(s)")
                         .index(2),
                 )
+                .arg(
+                    Arg::new("WITH_SYNTAX")
+                        .long("with-syntax")
+                        .help("Output what modus build passes to docker build, starting with a #syntax= line")
+                        .long_help("Output what modus build passes to docker build, starting with a #syntax= line \
+                                    selecting the Modus buildkit frontend. The frontend reads a JSON build plan, \
+                                    so the plan is printed instead of a Dockerfile. The output can be built with \
+                                    `docker build -f <output file> <context>`.")
+                )
+                .arg(allow_impure_arg())
+                .arg(max_depth_arg())
         )
         .subcommand(
             Command::new("build")
@@ -103,7 +163,10 @@ fn main() {
                 .arg(
                     Arg::new("QUERY")
                         .required(true)
-                        .help("Specify the target query to build")
+                        .multiple_values(true)
+                        .help("Specify the target queries to build")
+                        .long_help("Specify the target queries to build.\n\
+                                    Several queries are built together, sharing the images they have in common.")
                         .index(2),
                 )
                 .arg(
@@ -119,17 +182,153 @@ fn main() {
                                     If this flag is specified without providing a file name, output is written to stdout.")
                         .allow_invalid_utf8(true)
                 )
+                .arg(
+                    Arg::new("JSON_FULL")
+                        .long("json-full")
+                        .requires("JSON_OUTPUT")
+                        .help("Also include the build plan in the JSON output")
+                        .long_help("Also include the build plan in the JSON output\n\
+                                    The output becomes an object with the images under \"images\" and the \
+                                    nodes, dependencies and outputs of the plan under \"plan\".")
+                )
                 .arg(
                     Arg::new("VERBOSE")
                         .short('v')
                         .long("verbose")
-                        .help("Tell docker to print all the output"),
+                        .multiple_occurrences(true)
+                        .help("Tell docker to print all the output")
+                        .long_help("Tell docker to print all the output\n\
+                                    Given twice, also print the build plan before building, like --very-verbose."),
+                )
+                .arg(
+                    Arg::new("VERY_VERBOSE")
+                        .long("very-verbose")
+                        .help("Like --verbose, and also print the build plan to stderr before building"),
+                )
+                .arg(max_depth_arg())
+                .arg(allow_impure_arg())
+                .arg(
+                    Arg::new("REQUIRE_CLEAN_GIT")
+                        .long("require-clean-git")
+                        .requires("ALLOW_IMPURE")
+                        .help("Refuse to build if the context directory has uncommitted changes")
+                        .long_help("Refuse to build if the context directory has uncommitted changes, according to git.\n\
+                                    Since this depends on the state of the host, it requires --allow-impure. \
+                                    If the context is not in a git repository, a warning is printed and the build continues.")
+                )
+                .arg(
+                    Arg::new("ONLY_RESOLVE")
+                        .long("only-resolve")
+                        .help("Resolve the base images and print their digests, without building")
+                        .long_help("Resolve the base images used by the build and print the registry digest (name@sha256:...) \
+                                    each one resolved to, or its image ID if it has no registry digest, \
+                                    then exit without building anything else.")
+                )
+                .arg(
+                    Arg::new("DRY_RUN")
+                        .long("dry-run")
+                        .conflicts_with("ONLY_RESOLVE")
+                        .help("Print the build plan and exit without running docker")
+                        .long_help("Print the nodes of the build plan in the order they would be built, \
+                                    then exit without running docker.")
+                )
+                .arg(
+                    Arg::new("REQUIRE_HERMETIC")
+                        .long("require-hermetic")
+                        .help("Refuse to build if the build can use the network")
+                        .long_help("Refuse to build if the build can use the network, that is if the plan \
+                                    contains add_url_checked or a run outside of no_network.\n\
+                                    Base images are resolved before the build and are not checked.")
+                )
+                .arg(
+                    Arg::new("REFRESH")
+                        .long("refresh")
+                        .help("Resolve base images from their registry even if they are present locally")
+                        .long_help("Resolve base images from their registry even if they are present locally.\n\
+                                    By default, a from whose tag docker already has uses the local image.")
+                )
+                .arg(
+                    Arg::new("REQUIRE_BASE")
+                        .long("require-base")
+                        .takes_value(true)
+                        .value_name("REF")
+                        .required(false)
+                        .help("Fail unless every output image is built on top of the given base image")
+                        .long_help("Fail unless every output image is built on top of the given base image.\n\
+                                    The `from` each output is ultimately built on is resolved, and must be what \
+                                    REF resolves to, or an image based on it: one whose layers start with all \
+                                    the layers of REF. Images that are only copied from are not checked.")
                 )
                 .arg(
                     Arg::new("NO_CACHE")
                         .long("--no-cache")
                         .help("Ignore all existing build cache"),
                 )
+                .arg(
+                    Arg::new("SINCE")
+                        .long("since")
+                        .takes_value(true)
+                        .value_name("TIMESTAMP")
+                        .help("(Experimental) Only bypass the cache for what depends on context files modified after TIMESTAMP")
+                        .long_help("(Experimental) Only bypass the cache for what depends on context files modified after TIMESTAMP, \
+                                    given in seconds since the Unix epoch.\n\
+                                    With --no-cache, or for runs with no_cache, everything else is taken from the buildkit \
+                                    cache when it is there. No cache of our own is kept, so this doesn't help once the \
+                                    buildkit cache has been pruned.")
+                )
+                .arg(
+                    Arg::new("PROVENANCE")
+                        .long("provenance")
+                        .takes_value(true)
+                        .value_name("MODE")
+                        .min_values(0)
+                        .max_values(1)
+                        .require_equals(true)
+                        .default_missing_value("min")
+                        .possible_values(ProvenanceMode::VALUES)
+                        .help("Attach an SLSA provenance attestation to the output images")
+                        .long_help("Attach an SLSA provenance attestation to the output images.\n\
+                                    MODE is either min (the default) or max, which also records the \
+                                    build plan and the sources of the build.")
+                )
+                .arg(
+                    Arg::new("PLATFORM")
+                        .long("platform")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .value_name("PLATFORM")
+                        .required(false)
+                        .help("Build the output images for the given platform, such as linux/arm64")
+                        .long_help("Build the output images for the given platform, such as linux/arm64.\n\
+                                    This can be repeated to build for several platforms, one after another. \
+                                    Building for a platform other than the host's needs emulation to be \
+                                    set up for docker.")
+                )
+                .arg(
+                    Arg::new("PUSH")
+                        .long("push")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .value_name("REF")
+                        .conflicts_with("PUSH_PREFIX")
+                        .help("Tag and push the output images to REF after building, one --push per output")
+                        .long_help("Tag and push the output images to REF after building.\n\
+                                    Give this once for each output image, in the order of the outputs.")
+                )
+                .arg(
+                    Arg::new("PUSH_PREFIX")
+                        .long("push-prefix")
+                        .takes_value(true)
+                        .value_name("REPO")
+                        .help("Tag and push each output image to REPO/<predicate>:<arguments> after building")
+                        .long_help("Tag and push each output image to REPO/<predicate>:<arguments> after building.\n\
+                                    For example, app(\"1.0\", \"debug\") is pushed as REPO/app:1.0-debug.")
+                )
+                .arg(
+                    Arg::new("SBOM")
+                        .long("sbom")
+                        .help("Attach an SBOM attestation to the output images")
+                )
                 .arg(
                     Arg::new("ADDITIONAL_OPTS")
                         .long("docker-flags")
@@ -138,6 +337,67 @@ fn main() {
                         .required(false)
                         .help("Pass additional options to docker build")
                 )
+                .arg(
+                    Arg::new("BUILD_ARG")
+                        .long("build-arg")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .value_name("NAME=VALUE")
+                        .required(false)
+                        .help("Set a build argument, overriding its default")
+                        .long_help("Set a build argument, overriding its default.\n\
+                                    Rules read build arguments with arg(NAME, Value), and a fact such as \
+                                    arg(\"NAME\", \"default\") gives a default value.\n\
+                                    This can be repeated.")
+                )
+                .arg(
+                    Arg::new("OUTPUT_BUILD_ARG")
+                        .long("output-build-arg")
+                        .takes_value(true)
+                        .number_of_values(2)
+                        .multiple_occurrences(true)
+                        .value_names(&["QUERY", "NAME=VALUE"])
+                        .required(false)
+                        .help("Set a build argument only for the images of one query")
+                        .long_help("Set a build argument only for the images of one query, overriding its default and any --build-arg.\n\
+                                    QUERY is either the index of the query, counting from 0, or the query itself, such as 'app(\"1.0\")'.\n\
+                                    This can be repeated.")
+                )
+                .arg(
+                    Arg::new("SECRET")
+                        .long("secret")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .value_name("id=ID,src=PATH")
+                        .required(false)
+                        .help("Give the secret ID used by in_secret, read from the file PATH")
+                        .long_help("Give the secret ID used by in_secret, read from the file PATH.\n\
+                                    The secret is only mounted in the runs that ask for it, and is not \
+                                    part of the output images. This can be repeated.")
+                )
+                .arg(
+                    Arg::new("RUN_AS")
+                        .long("run-as")
+                        .takes_value(true)
+                        .value_name("USER")
+                        .help("Set the user of the output images to USER")
+                        .long_help("Set the user of the output images to USER, as if each output ended with set_user(\"USER\").\n\
+                                    This overrides any user set by the Modusfile, with a warning.")
+                )
+                .arg(
+                    Arg::new("FEATURE")
+                        .long("feature")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .value_name("NAME")
+                        .required(false)
+                        .help("Enable a feature, which rules check with feature(\"NAME\")")
+                        .long_help("Enable a feature, which rules check with feature(\"NAME\").\n\
+                                    This adds a feature(\"NAME\") fact alongside any feature facts of the \
+                                    Modusfile, which are always enabled. A rule that requires a feature that \
+                                    isn't enabled does not apply.\n\
+                                    This can be repeated.")
+                )
                 .arg(
                     Arg::new("RESOLVE_CONCURRENCY")
                         .long("image-resolve-concurrency")
@@ -181,6 +441,18 @@ fn main() {
                         .long_help("Output profiling information to a JSON file.\n\
                                     The format of the output is not specified.")
                 )
+                .arg(
+                    Arg::new("TEMP_DIR")
+                        .long("temp-dir")
+                        .allow_invalid_utf8(true)
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .required(false)
+                        .help("Set the directory used for temporary build files.")
+                        .long_help("Set the directory used for temporary build files.\n\
+                                    By default, the generated Dockerfile is written to the context directory \
+                                    and other temporary files go in the system temporary directory.")
+                )
         )
         .subcommand(
             Command::new("proof")
@@ -213,7 +485,9 @@ fn main() {
                 )
                 .arg(arg!(-e --explain "Prints out an explanation of the steps taken in resolution."))
                 .arg(arg!(-g --graph "Outputs a (DOT) graph that of the SLD tree traversed in resolution."))
-                .arg(arg!(--compact "Omits logical rule resolution.")),
+                .arg(arg!(--compact "Omits logical rule resolution."))
+                .arg(allow_impure_arg())
+                .arg(max_depth_arg()),
         )
         .subcommand(
             Command::new("check")
@@ -240,12 +514,55 @@ fn main() {
                 )
                 .arg(arg!(-v --verbose "display the evaluated kinds for all the clauses"))
         )
+        .subcommand(
+            Command::new("diff")
+                .about("Compare the images two Modusfiles build for a query.")
+                .long_about("Compare the images two Modusfiles build for a query.\n\
+                             Images are matched by their literal. For each image built differently, \
+                             the instructions removed (-), added (+) or given different options (~) \
+                             are listed.")
+                .arg(
+                    Arg::new("FILE_A")
+                        .required(true)
+                        .help("Set the Modusfile before the change")
+                        .index(1)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("FILE_B")
+                        .required(true)
+                        .help("Set the Modusfile after the change")
+                        .index(2)
+                        .allow_invalid_utf8(true),
+                )
+                .arg(
+                    Arg::new("QUERY")
+                        .required(true)
+                        .help("Specify the build target(s)")
+                        .index(3),
+                )
+                .arg(allow_impure_arg())
+                .arg(max_depth_arg()),
+        )
         .get_matches();
 
     let out_writer = StandardStream::stdout(codespan_reporting::term::termcolor::ColorChoice::Auto);
     let err_writer = StandardStream::stderr(codespan_reporting::term::termcolor::ColorChoice::Auto);
     let config = codespan_reporting::term::Config::default();
 
+    fn print_modusfile_diagnostics(
+        diags: &[Diagnostic<()>],
+        writer: &mut dyn WriteColor,
+        config: &Config,
+        sources: &ModusfileSources,
+    ) {
+        for diagnostic in diags {
+            sources
+                .emit(writer, config, diagnostic)
+                .expect("Error when printing to term.")
+        }
+    }
+
     fn print_diagnostics<'files, F: codespan_reporting::files::Files<'files, FileId = ()>>(
         diags: &[Diagnostic<()>],
         writer: &mut dyn WriteColor,
@@ -260,7 +577,7 @@ fn main() {
     match matches.subcommand().unwrap() {
         ("transpile", sub) => {
             let input_file = sub.value_of("FILE").unwrap();
-            let file = get_file_or_exit(Path::new(input_file));
+            configure_impure_builtins(sub, modusfile_dir(Path::new(input_file)).as_os_str());
             let query: modusfile::Expression = match sub
                 .value_of("QUERY")
                 .map(|s| s.parse::<modusfile::Expression>())
@@ -276,14 +593,22 @@ fn main() {
                 }
             };
 
-            let mf: Modusfile = match file.source().parse() {
-                Ok(mf) => mf,
+            let (mut mf, sources) = match Modusfile::from_file_with_includes(input_file) {
+                Ok(read) => read,
                 Err(e) => {
                     eprintln!("❌ Did not parse Modusfile successfully",);
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
+                    print_diagnostics(&e.diagnostics, &mut err_writer.lock(), &config, &e.file);
                     std::process::exit(1);
                 }
             };
+            if let Err(e) = imagegen::resolve_platform_images(
+                &mut mf,
+                &buildkit::RegistryPlatformResolver,
+                sub.is_present("ALLOW_IMPURE"),
+            ) {
+                print_modusfile_diagnostics(&e, &mut err_writer.lock(), &config, &sources);
+                std::process::exit(1);
+            }
             let kind_res = mf.kinds();
             if !analysis::check_and_output_analysis(
                 &kind_res,
@@ -292,79 +617,157 @@ fn main() {
                 false,
                 &mut err_writer.lock(),
                 &config,
-                &file,
+                &sources,
             ) {
                 std::process::exit(1)
             }
 
-            let df_res = transpiler::transpile(mf, query);
-
-            match df_res {
-                Ok(df) => println!("{}", df),
+            let plan = match imagegen::plan_from_modusfile(mf, query, max_depth(sub)) {
+                Ok(plan) => plan,
                 Err(e) => {
-                    for diag_error in e {
-                        term::emit(&mut err_writer.lock(), &config, &file, &diag_error)
-                            .expect("Error when printing to stderr.")
-                    }
+                    print_modusfile_diagnostics(&e, &mut err_writer.lock(), &config, &sources);
                     std::process::exit(1)
                 }
+            };
+            print_modusfile_diagnostics(&plan.warnings, &mut err_writer.lock(), &config, &sources);
+
+            if sub.is_present("WITH_SYNTAX") {
+                println!(
+                    "{}",
+                    buildkit::frontend_dockerfile(buildkit::FRONTEND_IMAGE, &plan)
+                );
+            } else {
+                match transpiler::plan_to_docker(&plan) {
+                    Ok(dockerfile) => println!("{}", dockerfile),
+                    Err(e) => {
+                        print_modusfile_diagnostics(&e, &mut err_writer.lock(), &config, &sources);
+                        std::process::exit(1)
+                    }
+                }
             }
         }
         ("build", sub) => {
             let context_dir = sub.value_of_os("CONTEXT").unwrap();
+            configure_impure_builtins(sub, context_dir);
             let input_file = sub
                 .value_of_os("FILE")
                 .map(PathBuf::from)
                 .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
-            let file = get_file_or_exit(input_file.as_path());
-            let query: modusfile::Expression = match sub
-                .value_of("QUERY")
-                .map(|s| s.parse::<modusfile::Expression>())
+            let queries = sub
+                .values_of("QUERY")
                 .unwrap()
-            {
-                Ok(e) => e.without_position(),
-                Err(e) => {
-                    eprintln!("❌ Did not parse goal successfully",);
-                    let temp_file =
-                        SimpleFile::new("goal", sub.value_of("QUERY").unwrap_or_default());
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
-                    std::process::exit(1);
-                }
-            };
+                .map(|q| match q.parse::<modusfile::Expression>() {
+                    Ok(e) => e.without_position(),
+                    Err(e) => {
+                        eprintln!("❌ Did not parse goal successfully",);
+                        let temp_file = SimpleFile::new("goal", q);
+                        print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
+                        std::process::exit(1);
+                    }
+                })
+                .collect::<Vec<_>>();
+            // The queries are analysed together, as the alternatives of a single query.
+            let all_queries = queries[1..].iter().fold(queries[0].clone(), |acc, q| {
+                modusfile::Expression::Or(None, true, Box::new(acc), Box::new(q.clone()))
+            });
 
             let parse_start = Instant::now();
 
-            let mf: Modusfile = match file.source().parse() {
-                Ok(mf) => mf,
+            let (mut mf, sources) = match Modusfile::from_file_with_includes(&input_file) {
+                Ok(read) => read,
                 Err(e) => {
                     eprintln!("❌ Did not parse Modusfile successfully.",);
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
+                    print_diagnostics(&e.diagnostics, &mut err_writer.lock(), &config, &e.file);
                     std::process::exit(1);
                 }
             };
+            let parse_build_arg = |flag: &str, v: &str| match v.split_once('=') {
+                Some((name, value)) if !name.is_empty() => (name.to_owned(), value.to_owned()),
+                _ => print_build_error_and_exit(
+                    &format!("invalid {} {:?} - expected NAME=VALUE", flag, v),
+                    &err_writer,
+                ),
+            };
+            let build_args = sub
+                .values_of("BUILD_ARG")
+                .map(|vs| {
+                    vs.map(|v| parse_build_arg("--build-arg", v))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            mf.set_build_args(&build_args);
+            let mut query_build_args = vec![Vec::new(); queries.len()];
+            if let Some(vs) = sub.values_of("OUTPUT_BUILD_ARG") {
+                let query_strs = sub.values_of("QUERY").unwrap().collect::<Vec<_>>();
+                for pair in vs.collect::<Vec<_>>().chunks(2) {
+                    let (query, arg) = (pair[0], pair[1]);
+                    let index = query
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&i| i < queries.len())
+                        .or_else(|| query_strs.iter().position(|q| *q == query));
+                    match index {
+                        Some(i) => {
+                            query_build_args[i].push(parse_build_arg("--output-build-arg", arg))
+                        }
+                        None => print_build_error_and_exit(
+                            &format!("{:?} does not match any query", query),
+                            &err_writer,
+                        ),
+                    }
+                }
+            }
+            let features = sub
+                .values_of("FEATURE")
+                .map(|vs| vs.map(str::to_owned).collect::<Vec<_>>())
+                .unwrap_or_default();
+            mf.set_features(&features);
+            if let Err(e) = imagegen::resolve_platform_images(
+                &mut mf,
+                &buildkit::RegistryPlatformResolver,
+                sub.is_present("ALLOW_IMPURE"),
+            ) {
+                print_modusfile_diagnostics(&e, &mut err_writer.lock(), &config, &sources);
+                std::process::exit(1);
+            }
             let kind_res = mf.kinds();
             if !analysis::check_and_output_analysis(
                 &kind_res,
                 &mf,
-                Some(&query),
+                Some(&all_queries),
                 false,
                 &mut err_writer.lock(),
                 &config,
-                &file,
+                &sources,
             ) {
                 std::process::exit(1)
             }
 
-            let build_plan = match imagegen::plan_from_modusfile(mf, query) {
+            let mut build_plan = match imagegen::plan_from_modusfile_queries_with_args(
+                mf,
+                &queries,
+                &query_build_args,
+                max_depth(sub),
+            ) {
                 Ok(plan) => plan,
                 Err(e) => {
                     for diag_error in e {
-                        term::emit(&mut err_writer.lock(), &config, &file, &diag_error)
+                        sources
+                            .emit(&mut err_writer.lock(), &config, &diag_error)
                             .expect("Error when printing to stderr.")
                     }
                     std::process::exit(1)
                 }
             };
+            if let Some(user) = sub.value_of("RUN_AS") {
+                build_plan.override_output_user(user);
+            }
+            print_modusfile_diagnostics(
+                &build_plan.warnings,
+                &mut err_writer.lock(),
+                &config,
+                &sources,
+            );
 
             fn print_build_error_and_exit(e_str: &str, w: &StandardStream) -> ! {
                 let mut w = w.lock();
@@ -384,6 +787,44 @@ fn main() {
                 std::process::exit(1)
             }
 
+            if sub.is_present("DRY_RUN") {
+                if let Err(e) = reporting::write_plan_summary(std::io::stdout(), &build_plan) {
+                    print_build_error_and_exit(&format!("Error writing plan: {}", e), &err_writer);
+                }
+                return;
+            }
+
+            if sub.is_present("REQUIRE_CLEAN_GIT") {
+                match git::worktree_state(Path::new(context_dir)) {
+                    Ok(git::WorktreeState::Clean) => {}
+                    Ok(git::WorktreeState::Dirty(changes)) => print_build_error_and_exit(
+                        &format!(
+                            "the context directory has uncommitted changes:\n{}",
+                            changes.join("\n")
+                        ),
+                        &err_writer,
+                    ),
+                    Ok(git::WorktreeState::NotARepository) => eprintln!(
+                        "Warning: --require-clean-git was given, but the context directory is not in a git repository."
+                    ),
+                    Err(e) => print_build_error_and_exit(
+                        &format!("unable to check the git status of the context directory: {}", e),
+                        &err_writer,
+                    ),
+                }
+            }
+
+            let very_verbose = sub.occurrences_of("VERBOSE") >= 2 || sub.is_present("VERY_VERBOSE");
+            let secrets = sub
+                .values_of("SECRET")
+                .map(|vs| {
+                    vs.map(|v| {
+                        parse_secret(v)
+                            .unwrap_or_else(|e| print_build_error_and_exit(&e, &err_writer))
+                    })
+                    .collect()
+                })
+                .unwrap_or_default();
             let options = BuildOptions {
                 frontend_image: sub.value_of("CUSTOM_FRONTEND").unwrap().to_owned(),
                 resolve_concurrency: sub
@@ -408,24 +849,125 @@ fn main() {
                     })
                     .unwrap_or_else(|| num_cpus::get() as u32), // Cast: we're not getting 2^32 CPU computers anytime soon
                 docker_build_options: DockerBuildOptions {
-                    verbose: sub.is_present("VERBOSE"),
+                    verbose: sub.is_present("VERBOSE") || very_verbose,
                     no_cache: sub.is_present("NO_CACHE"),
                     quiet: false,
+                    provenance: sub
+                        .value_of("PROVENANCE")
+                        .map(|m| m.parse().expect("mode to be validated by clap")),
+                    sbom: sub.is_present("SBOM"),
+                    platforms: sub
+                        .values_of("PLATFORM")
+                        .map(|x| x.map(ToOwned::to_owned).collect())
+                        .unwrap_or_default(),
+                    rebuild_nodes: None,
+                    secrets,
                     additional_args: sub
                         .values_of("ADDITIONAL_OPTS")
                         .map(|x| x.map(ToOwned::to_owned).collect())
                         .unwrap_or_default(),
                 },
+                temp_dir: sub.value_of_os("TEMP_DIR").map(PathBuf::from),
+                required_base: sub.value_of("REQUIRE_BASE").map(ToOwned::to_owned),
+                require_hermetic: sub.is_present("REQUIRE_HERMETIC"),
+                refresh: sub.is_present("REFRESH"),
+                since: sub.value_of("SINCE").map(|s| {
+                    let secs = s.parse().unwrap_or_else(|_| {
+                        print_build_error_and_exit(
+                            "invalid --since - expected seconds since the Unix epoch",
+                            &err_writer,
+                        )
+                    });
+                    std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)
+                }),
             };
 
+            if sub.is_present("ONLY_RESOLVE") {
+                match buildkit::resolve_only(&build_plan, &options) {
+                    Err(e) => {
+                        print_build_error_and_exit(&e.to_string(), &err_writer);
+                    }
+                    Ok(resolved) => {
+                        let width = resolved.iter().map(|(name, _)| name.len()).max();
+                        for (name, resolved_ref) in resolved.iter() {
+                            println!(
+                                "{:width$} -> {}",
+                                name,
+                                resolved_ref,
+                                width = width.unwrap_or(0)
+                            );
+                        }
+                        return;
+                    }
+                }
+            }
+
+            let push_refs = if let Some(refs) = sub.values_of("PUSH") {
+                let refs = refs.map(ToOwned::to_owned).collect::<Vec<_>>();
+                if refs.len() != build_plan.outputs.len() {
+                    print_build_error_and_exit(
+                        &format!(
+                            "{} --push given for {} output images.",
+                            refs.len(),
+                            build_plan.outputs.len()
+                        ),
+                        &err_writer,
+                    );
+                }
+                refs
+            } else if let Some(prefix) = sub.value_of("PUSH_PREFIX") {
+                build_plan
+                    .outputs
+                    .iter()
+                    .map(|o| {
+                        buildkit::push_ref_for_literal(prefix, o.source_literal.as_ref().unwrap())
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            if let Err(e) = buildkit::check_unique_push_refs(&build_plan, &push_refs) {
+                print_build_error_and_exit(&e.to_string(), &err_writer);
+            }
+            if !push_refs.is_empty() && options.docker_build_options.platforms.len() > 1 {
+                print_build_error_and_exit(
+                    "Images built for several platforms can not be pushed yet.",
+                    &err_writer,
+                );
+            }
+
             let mut profiling = Profiling::default();
             profiling.planning = parse_start.elapsed().as_secs_f32();
 
-            match buildkit::build(build_plan.clone(), context_dir, &options, &mut profiling) {
+            if very_verbose {
+                reporting::write_plan_summary(std::io::stderr(), &build_plan).unwrap_or_else(|e| {
+                    print_build_error_and_exit(&format!("Error writing plan: {}", e), &err_writer)
+                });
+            }
+
+            enum Built {
+                Images(Vec<String>),
+                PlatformImages(Vec<(String, Vec<String>)>),
+            }
+            let built = if options.docker_build_options.platforms.len() > 1 {
+                buildkit::build_platforms(build_plan.clone(), context_dir, &options, &mut profiling)
+                    .map(Built::PlatformImages)
+            } else {
+                buildkit::build(build_plan.clone(), context_dir, &options, &mut profiling)
+                    .map(Built::Images)
+            };
+            match built {
                 Err(e) => {
                     print_build_error_and_exit(&e.to_string(), &err_writer);
                 }
-                Ok(image_ids) => {
+                Ok(built) => {
+                    if let Built::Images(image_ids) = &built {
+                        if !push_refs.is_empty() {
+                            if let Err(e) = buildkit::push_images(image_ids, &push_refs) {
+                                print_build_error_and_exit(&e.to_string(), &err_writer);
+                            }
+                        }
+                    }
                     let total_dur = parse_start.elapsed();
                     profiling.total = total_dur.as_secs_f32();
                     if sub.is_present("JSON_OUTPUT") {
@@ -456,12 +998,26 @@ fn main() {
                             json_out = &mut json_out_stdout;
                             json_out_name = OsStr::new("stdout");
                         }
-                        if let Err(e) = reporting::write_build_result(
-                            json_out,
-                            &json_out_name.to_string_lossy(),
-                            &build_plan,
-                            &image_ids[..],
-                        ) {
+                        let json_out_name = json_out_name.to_string_lossy();
+                        let res = match &built {
+                            Built::Images(image_ids) => reporting::write_build_result(
+                                json_out,
+                                &json_out_name,
+                                &build_plan,
+                                &image_ids[..],
+                                sub.is_present("JSON_FULL"),
+                            ),
+                            Built::PlatformImages(platform_image_ids) => {
+                                reporting::write_platform_build_result(
+                                    json_out,
+                                    &json_out_name,
+                                    &build_plan,
+                                    &platform_image_ids[..],
+                                    sub.is_present("JSON_FULL"),
+                                )
+                            }
+                        };
+                        if let Err(e) = res {
                             print_build_error_and_exit(&e, &err_writer);
                         }
                     }
@@ -482,11 +1038,11 @@ fn main() {
             let compact = sub.is_present("compact");
 
             let context_dir = sub.value_of_os("CONTEXT").unwrap();
+            configure_impure_builtins(sub, context_dir);
             let input_file = sub
                 .value_of_os("FILE")
                 .map(PathBuf::from)
                 .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
-            let file = get_file_or_exit(input_file.as_path());
             let query: modusfile::Expression = match sub
                 .value_of("QUERY")
                 .map(|s| s.parse::<modusfile::Expression>())
@@ -502,8 +1058,8 @@ fn main() {
                 }
             };
 
-            match file.source().parse::<Modusfile>() {
-                Ok(modus_f) => {
+            match Modusfile::from_file_with_includes(&input_file) {
+                Ok((modus_f, sources)) => {
                     let kind_res = modus_f.kinds();
                     if !analysis::check_and_output_analysis(
                         &kind_res,
@@ -512,14 +1068,13 @@ fn main() {
                         false,
                         &mut err_writer.lock(),
                         &config,
-                        &file,
+                        &sources,
                     ) {
                         std::process::exit(1)
                     }
 
-                    let max_depth = 175;
                     let (goal, clauses, sld_result) =
-                        tree_from_modusfile(modus_f, query.clone(), max_depth, true);
+                        tree_from_modusfile(modus_f, query.clone(), max_depth(sub), true);
 
                     if should_output_graph {
                         render_tree(&clauses, sld_result, &mut out_writer.lock());
@@ -551,7 +1106,8 @@ fn main() {
                                         .unwrap_or(a.code.cmp(&b.code))
                                 });
                                 for diag_error in &e {
-                                    term::emit(&mut err_writer.lock(), &config, &file, &diag_error)
+                                    sources
+                                        .emit(&mut err_writer.lock(), &config, diag_error)
                                         .expect("Error when printing to stderr.")
                                 }
                             }
@@ -560,7 +1116,7 @@ fn main() {
                 }
                 Err(e) => {
                     eprintln!("❌ Did not parse Modusfile successfully.",);
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
+                    print_diagnostics(&e.diagnostics, &mut err_writer.lock(), &config, &e.file);
                     std::process::exit(1);
                 }
             }
@@ -571,12 +1127,11 @@ fn main() {
                 .value_of_os("FILE")
                 .map(PathBuf::from)
                 .unwrap_or_else(|| Path::new(context_dir).join("Modusfile"));
-            let file = get_file_or_exit(input_file.as_path());
 
             let is_verbose = sub.is_present("verbose");
 
-            match file.source().parse::<Modusfile>() {
-                Ok(mf) => {
+            match Modusfile::from_file_with_includes(&input_file) {
+                Ok((mf, sources)) => {
                     let kind_res = mf.kinds();
                     if !analysis::check_and_output_analysis(
                         &kind_res,
@@ -585,16 +1140,93 @@ fn main() {
                         is_verbose,
                         &mut err_writer.lock(),
                         &config,
-                        &file,
+                        &sources,
                     ) {
                         std::process::exit(1)
                     }
                 }
                 Err(e) => {
                     eprintln!("❌ Did not parse Modusfile successfully.",);
-                    print_diagnostics(&e, &mut err_writer.lock(), &config, &file);
+                    print_diagnostics(&e.diagnostics, &mut err_writer.lock(), &config, &e.file);
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("diff", sub) => {
+            let query: modusfile::Expression = match sub
+                .value_of("QUERY")
+                .map(|s| s.parse::<modusfile::Expression>())
+                .unwrap()
+            {
+                Ok(e) => e.without_position(),
+                Err(e) => {
+                    eprintln!("❌ Did not parse goal successfully",);
+                    let temp_file =
+                        SimpleFile::new("goal", sub.value_of("QUERY").unwrap_or_default());
+                    print_diagnostics(&e, &mut err_writer.lock(), &config, &temp_file);
+                    std::process::exit(1);
+                }
+            };
+
+            let plan_of = |input_file: &Path| -> imagegen::BuildPlan {
+                configure_impure_builtins(sub, modusfile_dir(input_file).as_os_str());
+                let (mut mf, sources) = match Modusfile::from_file_with_includes(input_file) {
+                    Ok(read) => read,
+                    Err(e) => {
+                        eprintln!(
+                            "❌ Did not parse {} successfully.",
+                            input_file.to_string_lossy()
+                        );
+                        print_diagnostics(&e.diagnostics, &mut err_writer.lock(), &config, &e.file);
+                        std::process::exit(1);
+                    }
+                };
+                if let Err(e) = imagegen::resolve_platform_images(
+                    &mut mf,
+                    &buildkit::RegistryPlatformResolver,
+                    sub.is_present("ALLOW_IMPURE"),
+                ) {
+                    print_modusfile_diagnostics(&e, &mut err_writer.lock(), &config, &sources);
                     std::process::exit(1);
                 }
+                let kind_res = mf.kinds();
+                if !analysis::check_and_output_analysis(
+                    &kind_res,
+                    &mf,
+                    Some(&query),
+                    false,
+                    &mut err_writer.lock(),
+                    &config,
+                    &sources,
+                ) {
+                    std::process::exit(1)
+                }
+                match imagegen::plan_from_modusfile(mf, query.clone(), max_depth(sub)) {
+                    Ok(plan) => {
+                        print_modusfile_diagnostics(
+                            &plan.warnings,
+                            &mut err_writer.lock(),
+                            &config,
+                            &sources,
+                        );
+                        plan
+                    }
+                    Err(e) => {
+                        print_modusfile_diagnostics(&e, &mut err_writer.lock(), &config, &sources);
+                        std::process::exit(1)
+                    }
+                }
+            };
+            let old_plan = plan_of(Path::new(sub.value_of_os("FILE_A").unwrap()));
+            let new_plan = plan_of(Path::new(sub.value_of_os("FILE_B").unwrap()));
+
+            match reporting::write_plan_diff(std::io::stdout(), &old_plan, &new_plan) {
+                Ok(true) => {}
+                Ok(false) => println!("The two Modusfiles build the same images."),
+                Err(e) => {
+                    eprintln!("Error writing diff: {}", e);
+                    std::process::exit(1)
+                }
             }
         }
         _ => (),
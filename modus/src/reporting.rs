@@ -15,6 +15,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::{
+    collections::BTreeMap,
     fmt::Display,
     io::{self, Write},
     path::Path,
@@ -23,7 +24,7 @@ use std::{
 use serde::{ser::SerializeSeq, Serialize};
 
 use modus_lib::{
-    imagegen::BuildPlan,
+    imagegen::{BuildNode, BuildPlan, CachePolicy, MergeNode, NodeId},
     logic::{IRTerm, Literal},
 };
 
@@ -80,46 +81,559 @@ impl ConstantLiteral {
     }
 }
 
+/// The version of the JSON written for each image, increased whenever fields are added
+/// or change meaning.
+pub const BUILD_RESULT_SCHEMA_VERSION: u32 = 2;
+
 #[derive(Serialize, Debug, Clone)]
 pub struct Image {
     #[serde(flatten)]
     pub source_literal: ConstantLiteral,
-    pub digest: String,
+    /// The image ID, unless the image was built for several platforms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// The image ID for each platform, if the image was built for several.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub platforms: BTreeMap<String, String>,
+    pub schema_version: u32,
+    /// Every build node the image depends on, in the order they are built, as a record of
+    /// what went into it.
+    pub operations: Vec<Operation>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Operation {
+    pub node: NodeId,
+    pub operation: BuildNode,
+}
+
+/// The nodes that `node` depends on, including itself, in topological order.
+fn operations_of(build_plan: &BuildPlan, node: NodeId) -> Vec<Operation> {
+    let mut needed = vec![false; build_plan.nodes.len()];
+    let mut stack = vec![node];
+    while let Some(n) = stack.pop() {
+        if !needed[n] {
+            needed[n] = true;
+            stack.extend(&build_plan.dependencies[n]);
+        }
+    }
+    build_plan
+        .topological_order()
+        .into_iter()
+        .filter(|&n| needed[n])
+        .map(|n| Operation {
+            node: n,
+            operation: build_plan.nodes[n].clone(),
+        })
+        .collect()
 }
 
+/// Writes the output images with their IDs as JSON. With `full`, the images are written under
+/// `images`, next to the build plan under `plan`, so that the image of each plan output can be
+/// found in the graph of nodes and dependencies.
 pub fn write_build_result<F: Write, P: Display>(
-    mut json_out: F,
+    json_out: F,
     json_out_name: P,
     build_plan: &BuildPlan,
     image_ids: &[String],
+    full: bool,
 ) -> Result<(), String> {
     debug_assert_eq!(build_plan.outputs.len(), image_ids.len());
+    let res = output_literals(build_plan)
+        .zip(&build_plan.outputs)
+        .zip(image_ids)
+        .map(|((source_literal, output), i)| Image {
+            source_literal,
+            digest: Some(i.clone()),
+            platforms: BTreeMap::new(),
+            schema_version: BUILD_RESULT_SCHEMA_VERSION,
+            operations: operations_of(build_plan, output.node),
+        })
+        .collect::<Vec<_>>();
+    write_images(json_out, json_out_name, &res, full.then_some(build_plan))
+}
+
+/// Like `write_build_result`, but for images built for several platforms, listing the
+/// image ID of each platform.
+pub fn write_platform_build_result<F: Write, P: Display>(
+    json_out: F,
+    json_out_name: P,
+    build_plan: &BuildPlan,
+    platform_image_ids: &[(String, Vec<String>)],
+    full: bool,
+) -> Result<(), String> {
+    debug_assert!(platform_image_ids
+        .iter()
+        .all(|(_, ids)| ids.len() == build_plan.outputs.len()));
+    let res = output_literals(build_plan)
+        .zip(&build_plan.outputs)
+        .enumerate()
+        .map(|(i, (source_literal, output))| Image {
+            source_literal,
+            digest: None,
+            platforms: platform_image_ids
+                .iter()
+                .map(|(platform, ids)| (platform.clone(), ids[i].clone()))
+                .collect(),
+            schema_version: BUILD_RESULT_SCHEMA_VERSION,
+            operations: operations_of(build_plan, output.node),
+        })
+        .collect::<Vec<_>>();
+    write_images(json_out, json_out_name, &res, full.then_some(build_plan))
+}
+
+fn output_literals(build_plan: &BuildPlan) -> impl Iterator<Item = ConstantLiteral> + '_ {
     debug_assert!(build_plan
         .outputs
         .iter()
         .all(|x| x.source_literal.is_some()));
-
-    let res = build_plan
+    build_plan
         .outputs
         .iter()
-        .zip(image_ids)
-        .map(|(o, i)| Image {
-            source_literal: ConstantLiteral::from_literal(
-                o.source_literal.as_ref().unwrap().clone(),
-            ),
-            digest: i.clone(),
-        })
-        .collect::<Vec<_>>();
+        .map(|o| ConstantLiteral::from_literal(o.source_literal.as_ref().unwrap().clone()))
+}
+
+#[derive(Serialize)]
+struct FullBuildResult<'a> {
+    images: &'a [Image],
+    plan: &'a BuildPlan,
+}
 
+fn write_images<F: Write, P: Display>(
+    mut json_out: F,
+    json_out_name: P,
+    images: &[Image],
+    plan: Option<&BuildPlan>,
+) -> Result<(), String> {
+    let json = match plan {
+        Some(plan) => serde_json::to_vec_pretty(&FullBuildResult { images, plan }),
+        None => serde_json::to_vec_pretty(images),
+    };
     json_out
-        .write_all(
-            &serde_json::to_vec_pretty(&res).map_err(|e| format!("Serialization error: {}", e))?,
-        )
+        .write_all(&json.map_err(|e| format!("Serialization error: {}", e))?)
         .map_err(|e| format!("Error writing to {}: {}", json_out_name, e))?;
 
     Ok(())
 }
 
+/// Writes the nodes of a build plan in the order they are built, one per line, such as
+/// `n2 <- n1: run("make")`. Nodes producing an output image are followed by its literal.
+pub fn write_plan_summary<W: Write>(mut out: W, build_plan: &BuildPlan) -> io::Result<()> {
+    for node in build_plan.topological_order() {
+        write!(out, "n{}", node)?;
+        let mut deps = build_plan.dependencies[node].clone();
+        deps.sort_unstable();
+        for (i, dep) in deps.iter().enumerate() {
+            write!(out, "{} n{}", if i == 0 { " <-" } else { "," }, dep)?;
+        }
+        write!(
+            out,
+            ": {}",
+            node_summary(&build_plan.nodes[node], &|n| format!("n{}", n))
+        )?;
+        for output in build_plan.outputs.iter().filter(|o| o.node == node) {
+            if let Some(lit) = &output.source_literal {
+                write!(out, " => {}", lit)?;
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// A one-line description of a node, naming the other nodes it refers to with `name`.
+fn node_summary(node: &BuildNode, name: &dyn Fn(NodeId) -> String) -> String {
+    match node {
+        BuildNode::From { display_name, .. } => format!("from({:?})", display_name),
+        BuildNode::FromScratch { .. } => "from(\"scratch\")".to_owned(),
+        BuildNode::Run {
+            command,
+            cwd,
+            cache,
+            no_network,
+            ..
+        } => {
+            let mut summary = format!("run({:?})", command);
+            if !cwd.is_empty() {
+                summary.push_str(&format!(" in {:?}", cwd));
+            }
+            match cache {
+                CachePolicy::Default => {}
+                CachePolicy::Never => summary.push_str(" without cache"),
+                CachePolicy::Id(id) => summary.push_str(&format!(" with cache id {:?}", id)),
+            }
+            if *no_network {
+                summary.push_str(" without network");
+            }
+            summary
+        }
+        BuildNode::CopyFromImage {
+            src_image,
+            src_path,
+            dst_path,
+            ..
+        } => format!(
+            "copy({:?}, {:?}) from {}",
+            src_path,
+            dst_path,
+            name(*src_image)
+        ),
+        BuildNode::CopyFromLocal {
+            src_path, dst_path, ..
+        } => format!("copy({:?}, {:?})", src_path, dst_path),
+        BuildNode::AddUrl { url, dst_path, .. } => format!("add_url({:?}, {:?})", url, dst_path),
+        BuildNode::CreateFile { path, .. } => format!("add_file({:?})", path),
+        BuildNode::SetWorkdir { new_workdir, .. } => format!("set_workdir({:?})", new_workdir),
+        BuildNode::SetEntrypoint { new_entrypoint, .. } => {
+            format!("set_entrypoint({:?})", new_entrypoint)
+        }
+        BuildNode::SetCmd { new_cmd, .. } => format!("set_cmd({:?})", new_cmd),
+        BuildNode::SetLabel { label, value, .. } => format!("set_label({:?}, {:?})", label, value),
+        BuildNode::Merge(MergeNode { operations, .. }) => {
+            format!("merge of {} operations", operations.len())
+        }
+        BuildNode::SetEnv { key, value, .. } => format!("set_env({:?}, {:?})", key, value),
+        BuildNode::AppendEnvValue { key, value, .. } => {
+            format!("append_path({:?}, {:?})", key, value)
+        }
+        BuildNode::SetUser { user, .. } => format!("set_user({:?})", user),
+        BuildNode::SetExpose { port, .. } => format!("set_expose({:?})", port),
+        BuildNode::SetStopSignal { signal, .. } => format!("set_stopsignal({:?})", signal),
+        BuildNode::SetShell { shell, .. } => format!("set_shell({:?})", shell),
+        BuildNode::SetHealthcheck { command, .. } => format!("set_healthcheck({:?})", command),
+        BuildNode::Squash { squashed, .. } => format!("squash of {}", name(*squashed)),
+    }
+}
+
+/// An instruction of an image in a form that can be compared between build plans, since node
+/// ids differ from one plan to another.
+struct Instruction {
+    summary: String,
+    /// The node with its parent left out and the other nodes it refers to replaced by their
+    /// names.
+    content: serde_json::Value,
+}
+
+/// Names a node independently of its id: by the literal of the image it outputs, or else by
+/// its summary.
+fn node_name(build_plan: &BuildPlan, node: NodeId) -> String {
+    match build_plan
+        .outputs
+        .iter()
+        .find_map(|o| o.source_literal.as_ref().filter(|_| o.node == node))
+    {
+        Some(lit) => lit.to_string(),
+        None => node_summary(&build_plan.nodes[node], &|n| node_name(build_plan, n)),
+    }
+}
+
+fn instructions_of(build_plan: &BuildPlan, node: NodeId) -> Vec<Instruction> {
+    fn normalize(build_plan: &BuildPlan, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(fields) => {
+                fields.remove("parent");
+                for (key, field) in fields.iter_mut() {
+                    match (key.as_str(), field.as_u64()) {
+                        ("src_image" | "squashed", Some(n)) => {
+                            *field = node_name(build_plan, n as NodeId).into()
+                        }
+                        _ => normalize(build_plan, field),
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => {
+                values.iter_mut().for_each(|v| normalize(build_plan, v))
+            }
+            _ => {}
+        }
+    }
+
+    operations_of(build_plan, node)
+        .into_iter()
+        .map(|op| {
+            let mut content =
+                serde_json::to_value(&op.operation).expect("build nodes to serialize");
+            normalize(build_plan, &mut content);
+            Instruction {
+                summary: node_summary(&op.operation, &|n| node_name(build_plan, n)),
+                content,
+            }
+        })
+        .collect()
+}
+
+/// Writes the lines of a diff between two lists of instructions, based on their longest
+/// common subsequence.
+fn write_instructions_diff<W: Write>(
+    out: &mut W,
+    old: &[Instruction],
+    new: &[Instruction],
+) -> io::Result<()> {
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i].content == new[j].content {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i].content == new[j].content {
+            i += 1;
+            j += 1;
+        } else if i < old.len()
+            && j < new.len()
+            && old[i].summary == new[j].summary
+            && lcs[i + 1][j + 1] == lcs[i][j]
+        {
+            // Same instruction, but with different options, such as environment variables.
+            writeln!(out, "  ~ {}", old[i].summary)?;
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            writeln!(out, "  - {}", old[i].summary)?;
+            i += 1;
+        } else {
+            writeln!(out, "  + {}", new[j].summary)?;
+            j += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Writes how the output images of `new` differ from those of `old`, matching images by their
+/// literal. Images only in one of the plans are written as `- app` or `+ app`, and images in
+/// both that are built differently as `~ app`, followed by the instructions removed (`-`),
+/// added (`+`) or given different options (`~`).
+///
+/// Returns whether there were any differences.
+pub fn write_plan_diff<W: Write>(mut out: W, old: &BuildPlan, new: &BuildPlan) -> io::Result<bool> {
+    let outputs = |plan: &BuildPlan| {
+        plan.outputs
+            .iter()
+            .map(|o| (o.source_literal.as_ref().unwrap().to_string(), o.node))
+            .collect::<Vec<_>>()
+    };
+    let (old_outputs, new_outputs) = (outputs(old), outputs(new));
+    let find = |outputs: &[(String, NodeId)], name: &str| {
+        outputs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, node)| *node)
+    };
+
+    let mut changed = false;
+    for (name, old_node) in &old_outputs {
+        match find(&new_outputs, name) {
+            None => {
+                writeln!(out, "- {}", name)?;
+                changed = true;
+            }
+            Some(new_node) => {
+                let old_instructions = instructions_of(old, *old_node);
+                let new_instructions = instructions_of(new, new_node);
+                let same = old_instructions.len() == new_instructions.len()
+                    && old_instructions
+                        .iter()
+                        .zip(&new_instructions)
+                        .all(|(a, b)| a.content == b.content);
+                if !same {
+                    writeln!(out, "~ {}", name)?;
+                    write_instructions_diff(&mut out, &old_instructions, &new_instructions)?;
+                    changed = true;
+                }
+            }
+        }
+    }
+    for (name, _) in &new_outputs {
+        if find(&old_outputs, name).is_none() {
+            writeln!(out, "+ {}", name)?;
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+#[test]
+fn test_write_full_build_result() {
+    let mf: modus_lib::modusfile::Modusfile = "base :- from(\"alpine\"), run(\"apk add gcc\").\n\
+                                                app(\"debug\") :- base, run(\"make debug\")."
+        .parse()
+        .unwrap();
+    let plan = modus_lib::imagegen::plan_from_modusfile(
+        mf,
+        "app(X)".parse().unwrap(),
+        modus_lib::sld::DEFAULT_MAX_DEPTH,
+    )
+    .unwrap();
+
+    let mut out = Vec::new();
+    write_build_result(&mut out, "test", &plan, &["sha256:0123".to_owned()], true).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(json["images"][0]["digest"], "sha256:0123");
+    assert_eq!(
+        json["plan"]["dependencies"],
+        serde_json::to_value(&plan.dependencies).unwrap()
+    );
+    assert_eq!(
+        json["plan"]["nodes"].as_array().unwrap().len(),
+        plan.nodes.len()
+    );
+    assert_eq!(json["plan"]["outputs"][0]["node"], plan.outputs[0].node);
+
+    let mut out = Vec::new();
+    write_build_result(&mut out, "test", &plan, &["sha256:0123".to_owned()], false).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(json[0]["digest"], "sha256:0123");
+}
+
+#[test]
+fn test_build_result_lists_operations() {
+    let mf: modus_lib::modusfile::Modusfile = "base :- from(\"alpine\"), run(\"apk add gcc\").\n\
+                                                app :- base, run(\"make\")::in_env(\"CC\", \"gcc\").\n\
+                                                other :- base, run(\"make other\")."
+        .parse()
+        .unwrap();
+    let plan = modus_lib::imagegen::plan_from_modusfile_queries(
+        mf,
+        &["app".parse().unwrap(), "other".parse().unwrap()],
+        modus_lib::sld::DEFAULT_MAX_DEPTH,
+    )
+    .unwrap();
+
+    let mut out = Vec::new();
+    let ids = ["sha256:0123".to_owned(), "sha256:4567".to_owned()];
+    write_build_result(&mut out, "test", &plan, &ids, false).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(json[0]["predicate"], "app");
+    assert_eq!(json[0]["digest"], "sha256:0123");
+    assert_eq!(json[0]["schema_version"], BUILD_RESULT_SCHEMA_VERSION);
+
+    let runs = |image: &serde_json::Value| {
+        image["operations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|op| op["operation"]["Run"]["command"].as_str())
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(runs(&json[0]), vec!["apk add gcc", "make"]);
+    assert_eq!(runs(&json[1]), vec!["apk add gcc", "make other"]);
+    assert_eq!(
+        json[0]["operations"][0]["operation"]["From"]["image_ref"],
+        "alpine"
+    );
+}
+
+#[test]
+fn test_write_plan_summary() {
+    use modus_lib::imagegen::Output;
+    use modus_lib::logic::Predicate;
+
+    let mut plan = BuildPlan::new();
+    let from = plan.new_node(
+        BuildNode::From {
+            image_ref: "alpine@sha256:0123".to_owned(),
+            display_name: "alpine".to_owned(),
+        },
+        Vec::new(),
+    );
+    let run = plan.new_node(
+        BuildNode::Run {
+            parent: from,
+            command: "make".to_owned(),
+            cwd: "/src".to_owned(),
+            additional_envs: Default::default(),
+            cache_mounts: Vec::new(),
+            secrets: Vec::new(),
+            cache: Default::default(),
+            no_network: false,
+        },
+        vec![from],
+    );
+    plan.outputs.push(Output {
+        node: run,
+        source_literal: Some(Literal {
+            positive: true,
+            position: None,
+            predicate: Predicate("app".to_owned()),
+            args: Vec::new(),
+        }),
+    });
+
+    let mut out = Vec::new();
+    write_plan_summary(&mut out, &plan).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "n0: from(\"alpine\")\nn1 <- n0: run(\"make\") in \"/src\" => app\n"
+    );
+}
+
+#[test]
+fn test_plan_diff_shows_changed_run() {
+    use modus_lib::{imagegen, modusfile::Modusfile, sld};
+
+    let plan = |src: &str| {
+        let mf: Modusfile = src.parse().unwrap();
+        imagegen::plan_from_modusfile(mf, "app(X)".parse().unwrap(), sld::DEFAULT_MAX_DEPTH)
+            .unwrap()
+    };
+    let old = plan(
+        "base :- from(\"alpine\"), run(\"apk add gcc\").\n\
+         app(\"debug\") :- base, run(\"make debug\"), run(\"make install\").",
+    );
+    let new = plan(
+        "base :- from(\"alpine\"), run(\"apk add gcc\").\n\
+         app(\"debug\") :- base, run(\"make -j4 debug\"), run(\"make install\").",
+    );
+
+    let mut out = Vec::new();
+    assert!(!write_plan_diff(&mut out, &old, &old).unwrap());
+    assert!(out.is_empty());
+
+    let mut out = Vec::new();
+    assert!(write_plan_diff(&mut out, &old, &new).unwrap());
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "~ app(\"debug\")\n  - run(\"make debug\")\n  + run(\"make -j4 debug\")\n"
+    );
+
+    let other = plan("app(\"release\") :- from(\"alpine\"), run(\"make\").");
+    let mut out = Vec::new();
+    assert!(write_plan_diff(&mut out, &old, &other).unwrap());
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "- app(\"debug\")\n+ app(\"release\")\n"
+    );
+}
+
+#[test]
+fn test_plan_summary_lists_every_node() {
+    use modus_lib::{imagegen, modusfile::Modusfile, sld};
+
+    let mf: Modusfile = "base :- from(\"alpine\"), run(\"apk add gcc\").\n\
+                         app(\"debug\") :- (base, run(\"make debug\"))::set_cmd([\"./app\"])."
+        .parse()
+        .unwrap();
+    let plan = imagegen::plan_from_modusfile(mf, "app(X)".parse().unwrap(), sld::DEFAULT_MAX_DEPTH)
+        .unwrap();
+    // The literal of every image built is also set as a label.
+    assert_eq!(plan.nodes.len(), 7);
+
+    let mut out = Vec::new();
+    write_plan_summary(&mut out, &plan).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().count(), plan.nodes.len());
+    assert!(out.contains(r#"n4 <- n3: set_cmd(["./app"])"#));
+    assert!(out.lines().last().unwrap().ends_with(r#" => app("debug")"#));
+}
+
 #[derive(Serialize, Debug, Clone, Default)]
 pub struct Profiling {
     pub planning: f32,